@@ -0,0 +1,47 @@
+-- Tables and indexes used by the bot and the web server.
+-- Idempotent, so it also serves as the baseline for existing installations.
+-- {auth_db} is replaced with the configured auth schema (mysql.auth_schema).
+-- Needs MariaDB 10.2.1 or later: these and later migrations use defaults on
+-- TEXT columns and ALTER TABLE ... IF NOT EXISTS, which MySQL lacks.
+
+CREATE TABLE IF NOT EXISTS `batch` (
+  `id` INT UNSIGNED NOT NULL AUTO_INCREMENT,
+  `name` VARCHAR(255) NOT NULL DEFAULT '',
+  `user` INT UNSIGNED NOT NULL DEFAULT 0,
+  `site` VARCHAR(64) NOT NULL DEFAULT '',
+  `status` VARCHAR(16) NOT NULL DEFAULT 'INIT',
+  `message` TEXT NOT NULL DEFAULT '',
+  `last_item` VARCHAR(255) NOT NULL DEFAULT '',
+  `ts_created` VARCHAR(14) NOT NULL DEFAULT '',
+  `ts_last_change` VARCHAR(14) NOT NULL DEFAULT '',
+  PRIMARY KEY (`id`),
+  KEY `status` (`status`, `ts_last_change`),
+  KEY `user` (`user`)
+) DEFAULT CHARSET=utf8mb4;
+
+CREATE TABLE IF NOT EXISTS `command` (
+  `id` BIGINT UNSIGNED NOT NULL AUTO_INCREMENT,
+  `batch_id` INT UNSIGNED NOT NULL,
+  `num` INT UNSIGNED NOT NULL,
+  `json` MEDIUMTEXT NOT NULL,
+  `status` VARCHAR(16) NOT NULL DEFAULT 'INIT',
+  `message` TEXT NOT NULL DEFAULT '',
+  `ts_change` VARCHAR(14) NOT NULL DEFAULT '',
+  PRIMARY KEY (`id`),
+  KEY `batch_status_num` (`batch_id`, `status`, `num`)
+) DEFAULT CHARSET=utf8mb4;
+
+CREATE DATABASE IF NOT EXISTS {auth_db};
+
+CREATE TABLE IF NOT EXISTS {auth_db}.`user` (
+  `id` INT UNSIGNED NOT NULL AUTO_INCREMENT,
+  `name` VARCHAR(255) NOT NULL,
+  PRIMARY KEY (`id`),
+  UNIQUE KEY `name` (`name`)
+) DEFAULT CHARSET=utf8mb4;
+
+CREATE TABLE IF NOT EXISTS {auth_db}.`batch_oauth` (
+  `batch_id` INT UNSIGNED NOT NULL,
+  `serialized_json` MEDIUMTEXT NOT NULL,
+  PRIMARY KEY (`batch_id`)
+) DEFAULT CHARSET=utf8mb4;
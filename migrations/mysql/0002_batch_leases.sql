@@ -0,0 +1,8 @@
+-- Leases let several bot processes share the batch queue: a batch is only
+-- run by the process named in lease_owner, until lease_expires (a ts_*
+-- timestamp) passes without a heartbeat.
+
+ALTER TABLE `batch`
+  ADD COLUMN IF NOT EXISTS `lease_owner` VARCHAR(64) NOT NULL DEFAULT '',
+  ADD COLUMN IF NOT EXISTS `lease_expires` VARCHAR(14) NOT NULL DEFAULT '',
+  ADD KEY IF NOT EXISTS `lease` (`lease_owner`, `lease_expires`);
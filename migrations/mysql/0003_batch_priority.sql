@@ -0,0 +1,4 @@
+-- Higher priority batches are started first; see qs_scheduler.
+
+ALTER TABLE `batch`
+  ADD COLUMN IF NOT EXISTS `priority` INT NOT NULL DEFAULT 0;
@@ -0,0 +1,7 @@
+-- A batch is not started before ts_not_before (a ts_* timestamp, empty for
+-- "any time"). Batches with a recurrence rule are put back to INIT with the
+-- next ts_not_before whenever they finish; see qs_scheduler.
+
+ALTER TABLE `batch`
+  ADD COLUMN IF NOT EXISTS `ts_not_before` VARCHAR(14) NOT NULL DEFAULT '',
+  ADD COLUMN IF NOT EXISTS `recurrence` VARCHAR(16) NOT NULL DEFAULT '';
@@ -0,0 +1,12 @@
+-- Web server login sessions, identified by the session cookie. `data` holds
+-- the user's name and wiki userinfo, and the OAuth credentials of the login
+-- (JSON), so it lives next to batch_oauth in the auth schema.
+
+CREATE TABLE IF NOT EXISTS {auth_db}.`session` (
+  `id` VARCHAR(64) NOT NULL,
+  `user_id` INT UNSIGNED NOT NULL DEFAULT 0,
+  `data` MEDIUMTEXT NOT NULL,
+  `ts_expires` VARCHAR(14) NOT NULL DEFAULT '',
+  PRIMARY KEY (`id`),
+  KEY `ts_expires` (`ts_expires`)
+) DEFAULT CHARSET=utf8mb4;
@@ -0,0 +1,19 @@
+-- Personal API tokens for submitting batches without an interactive login.
+-- Only a SHA-256 hash of each token is stored. serialized_json holds the
+-- batch_oauth entry of the login that created the token, copied to every
+-- batch submitted with it.
+
+CREATE TABLE IF NOT EXISTS {auth_db}.`api_token` (
+  `id` INT UNSIGNED NOT NULL AUTO_INCREMENT,
+  `user_id` INT UNSIGNED NOT NULL,
+  `name` VARCHAR(255) NOT NULL DEFAULT '',
+  `scopes` VARCHAR(255) NOT NULL DEFAULT '',
+  `token_hash` CHAR(64) NOT NULL,
+  `serialized_json` MEDIUMTEXT NOT NULL,
+  `ts_created` VARCHAR(14) NOT NULL DEFAULT '',
+  `ts_last_used` VARCHAR(14) NOT NULL DEFAULT '',
+  `ts_revoked` VARCHAR(14) NOT NULL DEFAULT '',
+  PRIMARY KEY (`id`),
+  UNIQUE KEY `token_hash` (`token_hash`),
+  KEY `user_id` (`user_id`)
+) DEFAULT CHARSET=utf8mb4;
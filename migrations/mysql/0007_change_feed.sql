@@ -0,0 +1,5 @@
+-- The batch event stream polls for commands changed since a cursor on
+-- (ts_change, id); see qs_server::BatchEvents.
+
+ALTER TABLE `command`
+  ADD KEY IF NOT EXISTS `batch_ts_change` (`batch_id`, `ts_change`, `id`);
@@ -0,0 +1,12 @@
+-- Parsed commands from `import` with `temporary=1`, shown in the browser
+-- before they are run or submitted. `id` is a random token; rows are dropped
+-- once `ts_expires` has passed.
+
+CREATE TABLE IF NOT EXISTS `temporary_batch` (
+  `id` VARCHAR(64) NOT NULL,
+  `user_id` INT UNSIGNED NOT NULL DEFAULT 0,
+  `data` MEDIUMTEXT NOT NULL,
+  `ts_expires` VARCHAR(14) NOT NULL DEFAULT '',
+  PRIMARY KEY (`id`),
+  KEY `ts_expires` (`ts_expires`)
+) DEFAULT CHARSET=utf8mb4;
@@ -0,0 +1,6 @@
+-- Failed commands carry a typed error code (see QsError::code) next to the
+-- free-text message, so errors can be filtered and retried by kind.
+
+ALTER TABLE `command`
+  ADD COLUMN IF NOT EXISTS `error_code` VARCHAR(32) NOT NULL DEFAULT '',
+  ADD KEY IF NOT EXISTS `batch_error_code` (`batch_id`, `error_code`);
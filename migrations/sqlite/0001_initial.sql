@@ -0,0 +1,38 @@
+-- Tables and indexes used by the bot and the web server.
+-- Users and OAuth credentials, which live in a separate schema on ToolsDB,
+-- are plain tables here.
+
+CREATE TABLE IF NOT EXISTS batch (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  name TEXT NOT NULL DEFAULT '',
+  user INTEGER NOT NULL DEFAULT 0,
+  site TEXT NOT NULL DEFAULT '',
+  status TEXT NOT NULL DEFAULT 'INIT',
+  message TEXT NOT NULL DEFAULT '',
+  last_item TEXT NOT NULL DEFAULT '',
+  ts_created TEXT NOT NULL DEFAULT '',
+  ts_last_change TEXT NOT NULL DEFAULT ''
+);
+CREATE INDEX IF NOT EXISTS batch_status ON batch (status, ts_last_change);
+CREATE INDEX IF NOT EXISTS batch_user ON batch (user);
+
+CREATE TABLE IF NOT EXISTS command (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  batch_id INTEGER NOT NULL,
+  num INTEGER NOT NULL,
+  json TEXT NOT NULL,
+  status TEXT NOT NULL DEFAULT 'INIT',
+  message TEXT NOT NULL DEFAULT '',
+  ts_change TEXT NOT NULL DEFAULT ''
+);
+CREATE INDEX IF NOT EXISTS command_batch_status_num ON command (batch_id, status, num);
+
+CREATE TABLE IF NOT EXISTS user (
+  id INTEGER PRIMARY KEY,
+  name TEXT NOT NULL UNIQUE
+);
+
+CREATE TABLE IF NOT EXISTS batch_oauth (
+  batch_id INTEGER PRIMARY KEY,
+  serialized_json TEXT NOT NULL
+);
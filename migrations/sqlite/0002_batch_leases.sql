@@ -0,0 +1,7 @@
+-- Leases let several bot processes share the batch queue: a batch is only
+-- run by the process named in lease_owner, until lease_expires (a ts_*
+-- timestamp) passes without a heartbeat.
+
+ALTER TABLE batch ADD COLUMN lease_owner TEXT NOT NULL DEFAULT '';
+ALTER TABLE batch ADD COLUMN lease_expires TEXT NOT NULL DEFAULT '';
+CREATE INDEX IF NOT EXISTS batch_lease ON batch (lease_owner, lease_expires);
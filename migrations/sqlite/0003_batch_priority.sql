@@ -0,0 +1,3 @@
+-- Higher priority batches are started first; see qs_scheduler.
+
+ALTER TABLE batch ADD COLUMN priority INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,6 @@
+-- A batch is not started before ts_not_before (a ts_* timestamp, empty for
+-- "any time"). Batches with a recurrence rule are put back to INIT with the
+-- next ts_not_before whenever they finish; see qs_scheduler.
+
+ALTER TABLE batch ADD COLUMN ts_not_before TEXT NOT NULL DEFAULT '';
+ALTER TABLE batch ADD COLUMN recurrence TEXT NOT NULL DEFAULT '';
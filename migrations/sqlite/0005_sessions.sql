@@ -0,0 +1,11 @@
+-- Web server login sessions, identified by the session cookie. `data` holds
+-- the user's name and wiki userinfo, and the OAuth credentials of the login
+-- (JSON).
+
+CREATE TABLE IF NOT EXISTS session (
+  id TEXT PRIMARY KEY,
+  user_id INTEGER NOT NULL DEFAULT 0,
+  data TEXT NOT NULL,
+  ts_expires TEXT NOT NULL DEFAULT ''
+);
+CREATE INDEX IF NOT EXISTS session_expires ON session (ts_expires);
@@ -0,0 +1,17 @@
+-- Personal API tokens for submitting batches without an interactive login.
+-- Only a SHA-256 hash of each token is stored. serialized_json holds the
+-- batch_oauth entry of the login that created the token, copied to every
+-- batch submitted with it.
+
+CREATE TABLE IF NOT EXISTS api_token (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  user_id INTEGER NOT NULL,
+  name TEXT NOT NULL DEFAULT '',
+  scopes TEXT NOT NULL DEFAULT '',
+  token_hash TEXT NOT NULL UNIQUE,
+  serialized_json TEXT NOT NULL,
+  ts_created TEXT NOT NULL DEFAULT '',
+  ts_last_used TEXT NOT NULL DEFAULT '',
+  ts_revoked TEXT NOT NULL DEFAULT ''
+);
+CREATE INDEX IF NOT EXISTS api_token_user ON api_token (user_id);
@@ -0,0 +1,4 @@
+-- The batch event stream polls for commands changed since a cursor on
+-- (ts_change, id); see qs_server::BatchEvents.
+
+CREATE INDEX IF NOT EXISTS command_batch_ts_change ON command (batch_id, ts_change, id);
@@ -0,0 +1,11 @@
+-- Parsed commands from `import` with `temporary=1`, shown in the browser
+-- before they are run or submitted. `id` is a random token; rows are dropped
+-- once `ts_expires` has passed.
+
+CREATE TABLE IF NOT EXISTS temporary_batch (
+  id TEXT PRIMARY KEY,
+  user_id INTEGER NOT NULL DEFAULT 0,
+  data TEXT NOT NULL,
+  ts_expires TEXT NOT NULL DEFAULT ''
+);
+CREATE INDEX IF NOT EXISTS temporary_batch_expires ON temporary_batch (ts_expires);
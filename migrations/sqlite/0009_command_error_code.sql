@@ -0,0 +1,5 @@
+-- Failed commands carry a typed error code (see QsError::code) next to the
+-- free-text message, so errors can be filtered and retried by kind.
+
+ALTER TABLE command ADD COLUMN error_code TEXT NOT NULL DEFAULT '';
+CREATE INDEX IF NOT EXISTS command_batch_error_code ON command (batch_id, error_code);
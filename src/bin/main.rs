@@ -4,6 +4,8 @@ use log::{error, info};
 use quickstatements::qs_bot::QuickStatementsBot;
 use quickstatements::qs_command::QuickStatementsCommand;
 use quickstatements::qs_config::QuickStatements;
+use quickstatements::qs_error_report::ErrorReport;
+use quickstatements::qs_logging::{LogFormat, QsLogger};
 use quickstatements::qs_parser::QuickStatementsParser;
 use quickstatements::qs_server;
 use serde_json::json;
@@ -11,6 +13,7 @@ use std::io;
 use std::io::prelude::*;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
+use tokio::signal::unix::SignalKind;
 use tokio::sync::Mutex;
 
 #[global_allocator]
@@ -33,6 +36,7 @@ async fn run_bot_loop(mut bot: QuickStatementsBot) -> bool {
             Err(e) => {
                 consecutive_errors += 1;
                 error!(
+                    batch = bot.batch_id();
                     "Batch #{} loop error (attempt {}/{}): {}",
                     bot.batch_id().unwrap_or(0),
                     consecutive_errors,
@@ -41,6 +45,7 @@ async fn run_bot_loop(mut bot: QuickStatementsBot) -> bool {
                 );
                 if consecutive_errors >= MAX_COMMAND_RETRIES {
                     error!(
+                        batch = bot.batch_id();
                         "Batch #{}: too many consecutive errors, releasing batch",
                         bot.batch_id().unwrap_or(0)
                     );
@@ -56,7 +61,7 @@ async fn run_bot_loop(mut bot: QuickStatementsBot) -> bool {
 }
 
 async fn start_batch(config: Arc<QuickStatements>, batch_id: i64, user_id: i64) {
-    info!("Starting batch {} for user {}", batch_id, user_id);
+    info!(batch = batch_id, user = user_id; "Starting batch {} for user {}", batch_id, user_id);
     let mut bot = QuickStatementsBot::new(config.clone(), Some(batch_id), user_id);
 
     match bot.start().await {
@@ -75,18 +80,15 @@ async fn start_batch(config: Arc<QuickStatements>, batch_id: i64, user_id: i64)
             // up again once the DB is back.
             let delay = config.note_batch_start_failure(batch_id).await;
             error!(
+                batch = batch_id, user = user_id;
                 "Cannot start batch #{}: {} — retrying in {}s",
                 batch_id,
                 error,
                 delay.as_secs()
             );
-            if config.check_batch_not_stopped(batch_id).await.is_ok() {
-                let _ = config
-                    .set_batch_status("INIT", &error, batch_id, user_id)
-                    .await;
-            } else {
-                let _ = config.deactivate_batch_run(batch_id, user_id).await;
-            }
+            config
+                .requeue_batch(batch_id, user_id, &error.to_string())
+                .await;
         }
     }
 }
@@ -141,16 +143,100 @@ async fn command_bot(verbose: bool, config_file: &str) {
         }
     }
 
+    info!("Bot instance {}", config.instance_id());
+    lease_heartbeat(config.clone());
+    serve_status(config.clone());
+
     let last_bot_run = Arc::new(Mutex::new(Instant::now()));
     seppuku(config.clone(), last_bot_run.clone());
 
+    // Spawned so the handlers are installed right away, not at the first sleep
+    let mut shutdown = tokio::spawn(shutdown_signal());
     loop {
         let started = run_bot(config.clone()).await;
         if started > 0 {
             *last_bot_run.lock().await = Instant::now();
         }
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_millis(SLEEP_BETWEEN_BOT_RUNS_MS)) => {}
+            _ = &mut shutdown => break,
+        }
+    }
+    shut_down(config).await;
+    std::process::exit(0);
+}
+
+/// Resolves on SIGTERM or SIGINT
+async fn shutdown_signal() {
+    let mut sigterm = match tokio::signal::unix::signal(SignalKind::terminate()) {
+        Ok(sigterm) => sigterm,
+        Err(e) => {
+            error!("Cannot listen for SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+    tokio::select! {
+        _ = sigterm.recv() => info!("Received SIGTERM"),
+        _ = tokio::signal::ctrl_c() => info!("Received SIGINT"),
+    }
+}
+
+/// Stops claiming batches and waits for the running bots to finish their
+/// current command and hand their batch back to the queue. Whatever is still
+/// running after the timeout is released as well, so no batch is left in RUN
+/// for another bot to clean up after the lease expires.
+async fn shut_down(config: Arc<QuickStatements>) {
+    config.begin_shutdown();
+    let timeout = config.shutdown_timeout();
+    info!(
+        "Shutting down: waiting up to {}s for {} running batches",
+        timeout.as_secs(),
+        config.number_of_bots_running().await
+    );
+    let deadline = Instant::now() + timeout;
+    while config.number_of_bots_running().await > 0 && Instant::now() < deadline {
         tokio::time::sleep(Duration::from_millis(SLEEP_BETWEEN_BOT_RUNS_MS)).await;
     }
+    let left = config.number_of_bots_running().await;
+    if left > 0 {
+        error!("{} batches did not finish in time, releasing them", left);
+        config.release_all_batches("Bot shut down").await;
+    }
+    info!("Shutdown complete");
+}
+
+/// Renews the leases on all batches this process runs, so other bot processes
+/// leave them alone. If this process dies, its batches are picked up by the
+/// others once the leases expire.
+fn lease_heartbeat(config: Arc<QuickStatements>) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(config.lease_heartbeat_interval()).await;
+            config.renew_leases().await;
+        }
+    });
+}
+
+/// Serves `/metrics`, `/healthz` and `/readyz` on the `status_port` from the
+/// config, if set
+fn serve_status(config: Arc<QuickStatements>) {
+    let Some(port) = config.status_port() else {
+        return;
+    };
+    let app = qs_server::build_status_router(config);
+    tokio::spawn(async move {
+        let addr = format!("0.0.0.0:{}", port);
+        match tokio::net::TcpListener::bind(&addr).await {
+            Ok(listener) => {
+                info!("Status endpoints on http://{}", &addr);
+                if let Err(e) = axum::serve(listener, app).await {
+                    error!("Status server failed: {}", e);
+                }
+            }
+            Err(e) => error!("Cannot serve status endpoints on {}: {}", &addr, e),
+        }
+    });
 }
 
 /// Exit the process if the DB is genuinely unreachable for too long.
@@ -371,6 +457,60 @@ async fn command_debug_command(config_file: &str, command_id: i64) {
     }
 }
 
+/// Prints the failed commands of a batch, grouped by error, property and entity
+async fn command_report(config_file: &str, batch_id: i64) {
+    let config = match QuickStatements::new_from_config_json(config_file) {
+        Some(qs) => qs,
+        None => panic!("Could not create QuickStatements from config file"),
+    };
+    let report = ErrorReport::for_batch(config.storage().as_ref(), batch_id)
+        .await
+        .unwrap_or_else(|e| panic!("Cannot read commands of batch #{}: {}", batch_id, e));
+
+    println!("Batch #{}: {} failed commands", batch_id, report.errors);
+    let sections = [
+        ("By error", &report.by_message),
+        ("By property", &report.by_property),
+        ("By entity", &report.by_entity),
+    ];
+    for (title, groups) in sections {
+        if groups.is_empty() {
+            continue;
+        }
+        println!();
+        println!("{}:", title);
+        for group in groups {
+            let examples: Vec<String> = group.examples.iter().map(|id| id.to_string()).collect();
+            let key = match group.error_code.as_str() {
+                "" => group.key.to_owned(),
+                code => format!("[{}] {}", code, group.key),
+            };
+            println!(
+                "  {:>7}  {}  (e.g. #{})",
+                group.count,
+                key,
+                examples.join(", #")
+            );
+        }
+    }
+}
+
+/// Brings the database schema up to date. Safe to run repeatedly.
+async fn command_migrate(config_file: &str) {
+    let config = match QuickStatements::new_from_config_json(config_file) {
+        Some(qs) => qs,
+        None => panic!("Could not create QuickStatements from config file"),
+    };
+    match config.storage().migrate().await {
+        Ok(applied) if applied.is_empty() => println!("Database schema is up to date"),
+        Ok(applied) => println!("Applied migrations: {:?}", applied),
+        Err(e) => {
+            eprintln!("Migration failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
 async fn command_server(config_file: &str, port: u16) {
     let config = match QuickStatements::new_from_config_json(config_file) {
         Some(qs) => Arc::new(qs),
@@ -452,7 +592,7 @@ struct Args {
     #[arg(short, long)]
     verbose: bool,
 
-    /// Command [bot|parse|validate|run|server]
+    /// Command [bot|parse|validate|run|server|migrate|debug_command|report]
     #[arg(long)]
     command: String,
 
@@ -464,25 +604,36 @@ struct Args {
     #[arg(short, long, default_value_t = 8080)]
     port: u16,
 
-    /// Command ID for debug_command
+    /// Log format [text|json]
+    #[arg(long, default_value_t=format!("text"))]
+    log_format: String,
+
+    /// Command ID for debug_command, batch ID for report
     #[arg(long)]
     id: Option<i64>,
 }
 
 #[tokio::main]
 async fn main() {
-    simple_logger::init_with_level(log::Level::Info).unwrap();
     let args = Args::parse();
+    let log_format = LogFormat::new_from_str(&args.log_format)
+        .unwrap_or_else(|| panic!("Not a valid log format: {}", args.log_format));
+    QsLogger::init(log_format, log::LevelFilter::Info).unwrap();
     match args.command.as_str() {
         "bot" => command_bot(args.verbose, &args.config_file).await,
         "parse" => command_parse().await,
         "validate" => command_validate().await,
         "run" => command_run(&args.site).await,
         "server" => command_server(&args.config_file, args.port).await,
+        "migrate" => command_migrate(&args.config_file).await,
         "debug_command" => {
             let id = args.id.expect("--id is required for debug_command");
             command_debug_command(&args.config_file, id).await;
         }
+        "report" => {
+            let id = args.id.expect("--id is required for report");
+            command_report(&args.config_file, id).await;
+        }
         x => panic!("Not a valid command: {}", x),
     }
 }
@@ -1,3 +1,4 @@
+use serde_json::Value;
 use thiserror::Error;
 
 /// Unified error type for the quickstatements crate.
@@ -15,6 +16,35 @@ pub enum QsError {
     #[error("API error {code}: {info}")]
     ApiError { code: String, info: String },
 
+    /// MediaWiki API returned an error that usually goes away by itself
+    /// (read-only database, internal error, ...), so the edit can be retried.
+    #[error("Transient API error {code}: {info}")]
+    TransientApiError { code: String, info: String },
+
+    /// The API refused the edit: protected page, missing right, blocked user, ...
+    #[error("Permission denied ({code}): {info}")]
+    PermissionDenied { code: String, info: String },
+
+    /// The edit was stopped by an abuse filter or spam blacklist.
+    #[error("Abuse filter ({code}): {info}")]
+    AbuseFilter { code: String, info: String },
+
+    /// Wikibase rejected the edit as invalid, e.g. a label/description conflict.
+    #[error("Constraint violation ({code}): {info}")]
+    ConstraintViolation { code: String, info: String },
+
+    /// The value does not fit the datatype of the property.
+    #[error("Property mismatch ({code}): {info}")]
+    PropertyMismatch { code: String, info: String },
+
+    /// Someone else edited the entity in a way the edit could not be merged with.
+    #[error("Edit conflict: {0}")]
+    EditConflict(String),
+
+    /// MediaWiki API returned JSON that is neither a success nor an error.
+    #[error("Unexpected API response: {0}")]
+    UnexpectedApiResponse(String),
+
     /// MediaWiki API returned non-JSON (e.g. HTML rate-limit page).
     #[error("Non-JSON API response: {0}")]
     NonJsonResponse(String),
@@ -27,6 +57,14 @@ pub enum QsError {
     #[error("Entity not found: {0}")]
     EntityNotFound(String),
 
+    /// The statement a qualifier, reference or removal refers to does not exist.
+    #[error("Statement not found: {0}")]
+    StatementNotFound(String),
+
+    /// The command lacks parameters its action needs, or has malformed ones.
+    #[error("Invalid command: {0}")]
+    InvalidCommand(String),
+
     /// Command parsing failed.
     #[error("Parse error: {0}")]
     ParseError(String),
@@ -43,6 +81,10 @@ pub enum QsError {
     #[error("mysql_async error: {0}")]
     MysqlAsyncError(mysql_async::Error),
 
+    /// Error from the embedded SQLite backend.
+    #[error("SQLite error: {0}")]
+    SqliteError(rusqlite::Error),
+
     /// Error from the mediawiki crate.
     #[error("MediaWiki error: {0}")]
     MediaWikiError(wikibase::mediawiki::MediaWikiError),
@@ -51,10 +93,151 @@ pub enum QsError {
     #[error("Batch #{0} is not RUN or INIT")]
     BatchStatusError(i64),
 
+    /// A bot may be running the batch, so its commands cannot be reset
+    #[error("Batch #{0} is not paused, stopped or done")]
+    BatchNotIdle(i64),
+
+    /// The lease on the batch expired and another bot process may have taken it over
+    #[error("Lost the lease on batch #{0}")]
+    LeaseLost(i64),
+
+    /// The OAuth login flow or a token refresh failed
+    #[error("OAuth error: {0}")]
+    OAuthError(String),
+
+    /// HTTP request to a wiki outside of the mediawiki crate
+    #[error("HTTP error: {0}")]
+    HttpError(reqwest::Error),
+
     #[error("No match ID set")]
     NoMatchSetError,
 }
 
+/// Stable codes for failed commands, stored with their ERROR status so
+/// failures can be filtered and grouped without parsing messages
+pub const ERROR_CODES: &[&str] = &[
+    "entity-missing",
+    "statement-missing",
+    "invalid-command",
+    "property-mismatch",
+    "permission-denied",
+    "abuse-filter",
+    "constraint",
+    "throttled",
+    "conflict",
+    "transient",
+    "network",
+    "api-error",
+    "unexpected-response",
+    "database",
+    "config",
+    "oauth",
+    "internal",
+    "other",
+];
+
+/// API error codes that report a temporary problem of the wiki, not of the edit
+const TRANSIENT_API_ERROR_CODES: &[&str] = &["readonly", "badtoken"];
+
+/// API error codes for edits the user is not allowed to make
+const PERMISSION_API_ERROR_CODES: &[&str] = &[
+    "permissiondenied",
+    "protectedpage",
+    "cascadeprotected",
+    "blocked",
+    "autoblocked",
+    "writeapidenied",
+    "mwoauth-invalid-authorization",
+    "assertuserfailed",
+    "assertbotfailed",
+];
+
+impl QsError {
+    /// Classifies the `error` object of an API response. Wikibase reports
+    /// validation failures under generic codes, so the message keys are
+    /// checked as well.
+    pub fn from_api_error(error: &Value) -> Self {
+        let code = error["code"].as_str().unwrap_or_default().to_string();
+        let info = error["info"].as_str().unwrap_or_default().to_string();
+        let messages: Vec<&str> = error["messages"]
+            .as_array()
+            .map(|messages| messages.iter().filter_map(|m| m["name"].as_str()).collect())
+            .unwrap_or_default();
+        let has_message = |prefix: &str| messages.iter().any(|m| m.starts_with(prefix));
+        match code.as_str() {
+            "no-such-entity" | "no-such-entity-id" => QsError::EntityNotFound(info),
+            "no-such-claim" => QsError::StatementNotFound(info),
+            "editconflict" => QsError::EditConflict(info),
+            "maxlag" | "ratelimited" | "actionthrottled" => QsError::RateLimited { code },
+            c if PERMISSION_API_ERROR_CODES.contains(&c) => {
+                QsError::PermissionDenied { code, info }
+            }
+            c if c.starts_with("abusefilter")
+                || c == "spamblacklist"
+                || has_message("abusefilter") =>
+            {
+                QsError::AbuseFilter { code, info }
+            }
+            "invalid-snak" | "bad-value-type" => QsError::PropertyMismatch { code, info },
+            _ if has_message("wikibase-validator-bad-value-type") => {
+                QsError::PropertyMismatch { code, info }
+            }
+            "modification-failed" => QsError::ConstraintViolation { code, info },
+            _ if has_message("wikibase-validator") => QsError::ConstraintViolation { code, info },
+            c if TRANSIENT_API_ERROR_CODES.contains(&c) || c.starts_with("internal_api_error_") => {
+                QsError::TransientApiError { code, info }
+            }
+            _ => QsError::ApiError { code, info },
+        }
+    }
+
+    /// Whether the failed operation may succeed if it is simply retried later.
+    /// Errors from the mediawiki crate are network failures or unparsable
+    /// (usually HTML error page) responses. Edit conflicts are not: the bot
+    /// already reloads and retries those itself.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            QsError::TransientApiError { .. }
+                | QsError::NonJsonResponse(_)
+                | QsError::RateLimited { .. }
+                | QsError::MediaWikiError(_)
+                | QsError::HttpError(_)
+        )
+    }
+
+    /// The error code stored with a failed command, one of `ERROR_CODES`
+    pub fn code(&self) -> &'static str {
+        match self {
+            QsError::EntityNotFound(_) => "entity-missing",
+            QsError::StatementNotFound(_) => "statement-missing",
+            QsError::InvalidCommand(_) | QsError::ParseError(_) => "invalid-command",
+            QsError::PropertyMismatch { .. } => "property-mismatch",
+            QsError::PermissionDenied { .. } => "permission-denied",
+            QsError::AbuseFilter { .. } => "abuse-filter",
+            QsError::ConstraintViolation { .. } => "constraint",
+            QsError::RateLimited { .. } => "throttled",
+            QsError::EditConflict(_) => "conflict",
+            QsError::TransientApiError { .. } => "transient",
+            QsError::NonJsonResponse(_) | QsError::MediaWikiError(_) | QsError::HttpError(_) => {
+                "network"
+            }
+            QsError::ApiError { .. } => "api-error",
+            QsError::UnexpectedApiResponse(_) => "unexpected-response",
+            QsError::DatabaseError(_) | QsError::MysqlAsyncError(_) | QsError::SqliteError(_) => {
+                "database"
+            }
+            QsError::ConfigError(_) => "config",
+            QsError::OAuthError(_) => "oauth",
+            QsError::BatchStatusError(_)
+            | QsError::BatchNotIdle(_)
+            | QsError::LeaseLost(_)
+            | QsError::NoMatchSetError => "internal",
+            QsError::StringError(_) => "other",
+        }
+    }
+}
+
 impl From<String> for QsError {
     fn from(s: String) -> Self {
         QsError::StringError(s)
@@ -67,12 +250,24 @@ impl From<mysql_async::Error> for QsError {
     }
 }
 
+impl From<rusqlite::Error> for QsError {
+    fn from(e: rusqlite::Error) -> Self {
+        QsError::SqliteError(e)
+    }
+}
+
 impl From<wikibase::mediawiki::MediaWikiError> for QsError {
     fn from(e: wikibase::mediawiki::MediaWikiError) -> Self {
         QsError::MediaWikiError(e)
     }
 }
 
+impl From<reqwest::Error> for QsError {
+    fn from(e: reqwest::Error) -> Self {
+        QsError::HttpError(e)
+    }
+}
+
 impl From<&str> for QsError {
     fn from(s: &str) -> Self {
         QsError::StringError(s.to_string())
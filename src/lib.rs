@@ -8,6 +8,20 @@ pub mod property_value;
 pub mod qs_bot;
 pub mod qs_command;
 pub mod qs_config;
+pub mod qs_error_report;
+#[cfg(test)]
+mod qs_fake_wiki;
+pub mod qs_logging;
+pub mod qs_metrics;
+pub mod qs_migrations;
+pub mod qs_oauth;
 pub mod qs_parser;
+pub mod qs_rate_governor;
+pub mod qs_rest;
+pub mod qs_scheduler;
 pub mod qs_server;
+pub mod qs_storage;
+pub mod qs_storage_mysql;
+pub mod qs_storage_sqlite;
+pub mod qs_storage_timed;
 pub mod value;
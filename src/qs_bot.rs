@@ -16,31 +16,71 @@ use wikibase;
 /// e.g. revoked OAuth) are logged every this many commands.
 const CONSECUTIVE_COMMAND_ERROR_WARN_EVERY: u32 = 5;
 
+/// Logs an event with the bot's batch, command, user, site, entity and action
+/// as key-value fields, so structured logs can be filtered per batch
+macro_rules! bot_log {
+    ($bot:expr, $level:expr, $($arg:tt)+) => {
+        log::log!(
+            $level,
+            batch = $bot.batch_id,
+            command = $bot.command_id,
+            user = $bot.user_id,
+            site = $bot.site.as_deref(),
+            entity = $bot.current_entity_id.as_deref(),
+            action = $bot.action.as_deref();
+            $($arg)+
+        )
+    };
+}
+
 /// Adaptive edit pacing: run at full speed while the API is happy, back off on
 /// pushback (rate limit / throttle / lag), and decay back to full speed on
 /// success. Backoff doubles from the minimum up to the maximum; each successful
 /// edit halves the delay again, down to the configured `edit_delay_ms` floor.
 /// Per-user rate limits are enforced server-side and deliberately not raised
 /// here: the user shares their edit budget with their own manual edits.
+/// On top of this, all bots editing a site share its `RateGovernor`.
 const THROTTLE_BACKOFF_MIN_MS: u64 = 5_000;
 const THROTTLE_BACKOFF_MAX_MS: u64 = 60_000;
 
+/// Pause before retrying an edit that got a non-JSON response (usually an HTML
+/// error page from an overloaded server)
+const NON_JSON_RETRY_DELAY_S: u64 = 30;
+
+/// A command whose edit fails with a transient error (see
+/// `QsError::is_transient`) goes back to INIT and is retried up to this many
+/// times before it is marked ERROR. The pause before each retry doubles from
+/// the base delay up to the maximum.
+const TRANSIENT_RETRY_LIMIT: u64 = 5;
+const TRANSIENT_RETRY_BASE_S: u64 = 15;
+const TRANSIENT_RETRY_MAX_S: u64 = 120;
+
 #[derive(Debug, Clone)]
 pub struct QuickStatementsBot {
     batch_id: Option<i64>,
     user_id: i64,
+    /// The site this bot edits, once started
+    site: Option<String>,
     config: Arc<QuickStatements>,
     mw_api: Option<wikibase::mediawiki::api::Api>,
     pub entities: wikibase::entity_container::EntityContainer,
     last_state: LastEntityState,
     current_entity_id: Option<String>,
     current_property_id: Option<String>,
+    /// The command being executed, for log context
+    command_id: Option<i64>,
+    action: Option<String>,
     /// Current adaptive delay between edits; see THROTTLE_BACKOFF_* above.
     adaptive_delay_ms: u64,
     /// Floor for the adaptive delay, from the `edit_delay_ms` config key.
     min_delay_ms: u64,
     entity_revision: VecDeque<(String, usize)>,
     consecutive_command_errors: u32,
+    non_json_retry_delay: Duration,
+    /// Pause before the first retry of a command that failed transiently
+    transient_retry_delay: Duration,
+    /// Last known revision per entity, sent as `baserevid` with edits to it
+    base_revisions: HashMap<String, usize>,
 }
 
 impl QuickStatementsBot {
@@ -49,16 +89,22 @@ impl QuickStatementsBot {
         Self {
             batch_id,
             user_id,
+            site: None,
             config,
             mw_api: None,
             entities: wikibase::entity_container::EntityContainer::new(),
             last_state: LastEntityState::default(),
             current_entity_id: None,
             current_property_id: None,
+            command_id: None,
+            action: None,
             adaptive_delay_ms: min_delay_ms,
             min_delay_ms,
             entity_revision: VecDeque::new(),
             consecutive_command_errors: 0,
+            non_json_retry_delay: Duration::from_secs(NON_JSON_RETRY_DELAY_S),
+            transient_retry_delay: Duration::from_secs(TRANSIENT_RETRY_BASE_S),
+            base_revisions: HashMap::new(),
         }
     }
 
@@ -67,15 +113,34 @@ impl QuickStatementsBot {
     fn bump_backoff(&mut self) -> u64 {
         self.adaptive_delay_ms =
             (self.adaptive_delay_ms * 2).clamp(THROTTLE_BACKOFF_MIN_MS, THROTTLE_BACKOFF_MAX_MS);
+        self.report_delay();
         self.adaptive_delay_ms
     }
 
     /// Successful edit: decay the adaptive delay back towards the floor.
     fn decay_delay(&mut self) {
         self.adaptive_delay_ms = (self.adaptive_delay_ms / 2).max(self.min_delay_ms);
+        self.report_delay();
+        if let Some(site) = &self.site {
+            self.config.rate_governor().report_success(site);
+        }
+    }
+
+    fn report_delay(&self) {
+        if let Some(batch_id) = self.batch_id {
+            let metrics = self.config.metrics();
+            metrics.set_edit_delay(batch_id, self.adaptive_delay_ms);
+        }
     }
 
-    pub async fn start(&mut self) -> Result<(), String> {
+    /// Tells the other bots on this site to slow down too; see `RateGovernor`
+    fn report_pushback(&self, pause: Duration) {
+        if let Some(site) = &self.site {
+            self.config.rate_governor().report_pushback(site, pause);
+        }
+    }
+
+    pub async fn start(&mut self) -> QsResult<()> {
         match self.batch_id {
             Some(batch_id) => {
                 let config = self.config.clone();
@@ -85,37 +150,57 @@ impl QuickStatementsBot {
                     .ok_or("Can't (re)start batch".to_string())?;
                 // restart_batch only touches INIT/RUN batches; if the user stopped
                 // the batch in the meantime, its status is unchanged and we bail out.
-                config
-                    .check_batch_not_stopped(batch_id)
-                    .await
-                    .map_err(|e| e.to_string())?;
+                config.check_batch_not_stopped(batch_id).await?;
                 self.last_state = config.get_last_state_from_batch(batch_id).await;
-                match config.get_api_url(batch_id).await {
-                    Some(url) => {
-                        let mut mw_api = wikibase::mediawiki::api::Api::new(url)
-                            .await
-                            .map_err(|e| format!("{:?}", e))?;
-                        // Edit pacing is done adaptively by this bot (see
-                        // THROTTLE_BACKOFF_*), not with a fixed delay in the API layer.
-                        mw_api.set_edit_delay(None);
-                        mw_api.set_maxlag(config.maxlag_s());
-                        mw_api.set_max_retry_attempts(1000);
-                        config.set_bot_api_auth(&mut mw_api, batch_id).await?;
-                        self.mw_api = Some(mw_api);
-                    }
-                    None => return Err("No site/API info available".to_string()),
-                }
+                let site = config
+                    .get_site_for_batch(batch_id)
+                    .await
+                    .ok_or("No site/API info available".to_string())?;
+                let mut mw_api = self.new_api(&site).await?;
+                config.set_bot_api_auth(&mut mw_api, batch_id).await?;
+                self.mw_api = Some(mw_api);
+                self.site = Some(site);
 
                 config.set_batch_running(batch_id, self.user_id).await;
             }
             None => {
-                return Err("No batch ID set".to_string());
+                return Err(QsError::NoMatchSetError);
             }
         }
 
         Ok(())
     }
 
+    /// Prepares a bot without a batch to edit `site`, with the user's
+    /// `batch_oauth` entry, or as the bot without one
+    pub async fn start_without_batch(
+        &mut self,
+        site: &str,
+        oauth_json: Option<&str>,
+    ) -> QsResult<()> {
+        let mut mw_api = self.new_api(site).await?;
+        self.config
+            .set_single_command_api_auth(&mut mw_api, oauth_json)
+            .await?;
+        self.mw_api = Some(mw_api);
+        self.site = Some(site.to_string());
+        Ok(())
+    }
+
+    async fn new_api(&self, site: &str) -> QsResult<wikibase::mediawiki::api::Api> {
+        let url = self
+            .config
+            .get_api_for_site(site)
+            .ok_or("No site/API info available".to_string())?;
+        let mut mw_api = wikibase::mediawiki::api::Api::new(url).await?;
+        // Edit pacing is done adaptively by this bot (see
+        // THROTTLE_BACKOFF_*), not with a fixed delay in the API layer.
+        mw_api.set_edit_delay(None);
+        mw_api.set_maxlag(self.config.maxlag_s());
+        mw_api.set_max_retry_attempts(1000);
+        Ok(mw_api)
+    }
+
     pub fn batch_id(&self) -> Option<i64> {
         self.batch_id
     }
@@ -139,18 +224,22 @@ impl QuickStatementsBot {
         self.last_state = state;
     }
 
+    pub fn last_state(&self) -> &LastEntityState {
+        &self.last_state
+    }
+
     /// Execute a command for debugging: prepare params, call the API, and return
     /// both the request params and the full API response (or error).
     pub async fn debug_command(
         &mut self,
         command: &mut QuickStatementsCommand,
-    ) -> Result<(HashMap<String, String>, Value), String> {
+    ) -> QsResult<(HashMap<String, String>, Value)> {
         command.insert_last_item_into_sources_and_qualifiers(&self.last_state)?;
         let main_item = self.prepare_to_execute(command).await?;
         let action = command.action_to_execute(&main_item)?;
 
         if !action["already_done"].is_null() {
-            return Err("Command is already_done (duplicate)".to_string());
+            return Err("Command is already_done (duplicate)".into());
         }
 
         let mut params: HashMap<String, String> = HashMap::new();
@@ -169,13 +258,7 @@ impl QuickStatementsBot {
 
         // Actually execute the API call
         let mut mw_api = self.mw_api.to_owned().ok_or("No mw_api set")?;
-        params.insert(
-            "token".to_string(),
-            mw_api
-                .get_edit_token()
-                .await
-                .map_err(|e| format!("get_edit_token: {}", e))?,
-        );
+        params.insert("token".to_string(), mw_api.get_edit_token().await?);
 
         let response = match mw_api.post_query_api_json_mut(&params).await {
             Ok(json) => json,
@@ -193,23 +276,34 @@ impl QuickStatementsBot {
 
     fn log(&self, msg: String) {
         if self.config.verbose() {
-            match self.batch_id {
-                Some(id) => log::info!("Batch #{}: {}", id, msg),
-                None => log::info!("No batch ID: {}", msg),
-            }
+            bot_log!(self, log::Level::Info, "{}", msg);
         }
     }
 
     /// Returns `Ok(true)` when a command was executed, `Ok(false)` when the batch is done,
     /// or `Err` for transient failures (caller should retry).
-    pub async fn run(&mut self) -> Result<bool, String> {
+    pub async fn run(&mut self) -> QsResult<bool> {
+        if self.config.is_shutting_down() {
+            // Between commands, so the LAST state of the previous one is saved
+            if let Some(batch_id) = self.batch_id {
+                self.log(format!(
+                    "[run] Shutting down, releasing batch #{}",
+                    batch_id
+                ));
+                self.config
+                    .requeue_batch(batch_id, self.user_id, "Bot shut down")
+                    .await;
+            }
+            return Ok(false);
+        }
         self.log("[run] Getting next command".to_string());
         let command = match self.get_next_command().await {
             Ok(c) => c,
             Err(e) => {
-                let is_transient = matches!(e, QsError::MysqlAsyncError(_));
+                let is_transient =
+                    matches!(e, QsError::MysqlAsyncError(_) | QsError::SqliteError(_));
                 if is_transient {
-                    return Err(format!("Transient DB error in get_next_command: {}", e));
+                    return Err(e);
                 }
                 // Permanent: batch was stopped, or no batch_id set
                 if let Some(batch_id) = self.batch_id {
@@ -224,20 +318,24 @@ impl QuickStatementsBot {
 
         match command {
             Some(mut command) => {
+                self.command_id = Some(command.id);
+                self.action = command.json["action"].as_str().map(|s| s.to_string());
                 self.log("[run] Executing command".to_string());
                 // Mark the command RUN here: if this write fails, the command stays
                 // INIT and would be picked up again immediately, so surface it as a
                 // transient error to get the caller's backoff instead of hot-looping.
                 self.set_command_status("RUN", None, &mut command).await?;
-                match self.execute_command(&mut command).await {
+                if let Some(batch_id) = self.batch_id {
+                    self.config.command_started(batch_id, command.id).await;
+                }
+                let result = self.execute_command(&mut command).await;
+                if let Some(batch_id) = self.batch_id {
+                    self.config.command_finished(batch_id).await;
+                }
+                match result {
                     Ok(_) => self.consecutive_command_errors = 0,
                     Err(e) => {
-                        log::error!(
-                            "Batch #{} command #{}: {}",
-                            self.batch_id.unwrap_or(0),
-                            command.id,
-                            e
-                        );
+                        bot_log!(self, log::Level::Error, "Command failed: {}", e);
                         // The command itself is marked ERROR by execute_command;
                         // the batch carries on with the next one. Long runs of
                         // failures are only logged, so a systemic problem stays
@@ -247,15 +345,18 @@ impl QuickStatementsBot {
                             .consecutive_command_errors
                             .is_multiple_of(CONSECUTIVE_COMMAND_ERROR_WARN_EVERY)
                         {
-                            log::warn!(
-                                "Batch #{}: {} consecutive command errors, still running (systemic problem? e.g. revoked OAuth)",
-                                self.batch_id.unwrap_or(0),
+                            bot_log!(
+                                self,
+                                log::Level::Warn,
+                                "{} consecutive command errors, still running (systemic problem? e.g. revoked OAuth)",
                                 self.consecutive_command_errors
                             );
                         }
                     }
                 }
                 self.log("[run] Command executed".to_string());
+                self.command_id = None;
+                self.action = None;
                 Ok(true)
             }
             None => {
@@ -271,6 +372,7 @@ impl QuickStatementsBot {
     async fn get_next_command(&self) -> QsResult<Option<QuickStatementsCommand>> {
         match self.batch_id {
             Some(batch_id) => {
+                self.config.check_lease(batch_id).await?;
                 self.config.check_batch_not_stopped(batch_id).await?;
                 self.config.get_next_command(batch_id).await
             }
@@ -305,7 +407,7 @@ impl QuickStatementsBot {
     async fn prepare_to_execute(
         &mut self,
         command: &QuickStatementsCommand,
-    ) -> Result<Option<wikibase::Entity>, String> {
+    ) -> QsResult<Option<wikibase::Entity>> {
         let command_action = command.get_action()?;
         self.log(format!("[prepare_to_execute] Action '{}'", &command_action));
         // Form/sense creation: resolve LAST but don't load entity
@@ -347,7 +449,11 @@ impl QuickStatementsBot {
             self.resolve_current_entity_id();
             let q = match &self.current_entity_id {
                 Some(q) => q.to_string(),
-                None => return Err("No (last) item available".to_string()),
+                None => {
+                    return Err(QsError::EntityNotFound(
+                        "No (last) item available".to_string(),
+                    ))
+                }
             };
 
             let item = self.load_entity(q).await?;
@@ -357,7 +463,7 @@ impl QuickStatementsBot {
         }
     }
 
-    async fn load_entity(&mut self, entity_id: String) -> Result<wikibase::Entity, String> {
+    async fn load_entity(&mut self, entity_id: String) -> QsResult<wikibase::Entity> {
         let mw_api = self
             .mw_api
             .to_owned()
@@ -370,14 +476,94 @@ impl QuickStatementsBot {
             .map(|er| er.1)
             .next();
 
-        match self
-            .entities
-            .load_entity_revision(&mw_api, entity_id.to_string(), revision)
-            .await
-        {
-            Ok(item) => Ok(item.to_owned()),
-            Err(e) => self.try_create_fake_entity(entity_id, revision, e.to_string()),
+        let loaded = match revision {
+            None if self.entities.get_entity(entity_id.as_str()).is_none() => {
+                self.fetch_entity(&mw_api, &entity_id).await
+            }
+            _ => self
+                .entities
+                .load_entity_revision(&mw_api, entity_id.to_string(), revision)
+                .await
+                .map(|item| item.to_owned())
+                .map_err(|e| QsError::EntityNotFound(e.to_string())),
+        };
+        match loaded {
+            Ok(item) => {
+                if let Some(revision) = revision {
+                    self.base_revisions.insert(entity_id, revision);
+                }
+                Ok(item)
+            }
+            Err(e) => self.try_create_fake_entity(entity_id, revision, e),
+        }
+    }
+
+    /// Loads the latest revision into the entity cache, noting its revision ID
+    async fn fetch_entity(
+        &mut self,
+        mw_api: &wikibase::mediawiki::api::Api,
+        entity_id: &str,
+    ) -> QsResult<wikibase::Entity> {
+        let params = mw_api.params_into(&[("action", "wbgetentities"), ("ids", entity_id)]);
+        let res = mw_api.get_query_api_json(&params).await?;
+        // Redirects are resolved by the API, so the entity may have another ID
+        let entity_json = res["entities"]
+            .as_object()
+            .and_then(|entities| entities.values().next())
+            .filter(|j| j.get("missing").is_none())
+            .ok_or_else(|| QsError::EntityNotFound(entity_id.to_string()))?;
+        if let Some(revision) = entity_json["lastrevid"].as_u64() {
+            self.base_revisions
+                .insert(entity_id.to_string(), revision as usize);
+        }
+        self.entities
+            .set_entity_from_json(entity_json)
+            .map_err(|e| e.to_string())?;
+        let id = entity_json["id"].as_str().unwrap_or(entity_id);
+        self.entities.get_entity(id).ok_or_else(|| {
+            QsError::EntityNotFound(format!("Entity {} not cached after loading", id))
+        })
+    }
+
+    /// Drops the cached entity and any revision pin, so the latest revision is
+    /// loaded next time
+    fn forget_entity(&mut self, entity_id: &str) {
+        self.entities.remove_entity(entity_id);
+        self.entity_revision.retain(|er| er.0 != entity_id);
+        self.base_revisions.remove(entity_id);
+    }
+
+    async fn reload_entity(&mut self, entity_id: String) -> QsResult<wikibase::Entity> {
+        self.forget_entity(&entity_id);
+        self.load_entity(entity_id).await
+    }
+
+    /// Recomputes the action against the latest revision of the main entity, for
+    /// commands that check it for already existing statements, qualifiers etc.
+    async fn recompute_action(&mut self, command: &mut QuickStatementsCommand) -> QsResult<Value> {
+        let loads_entity = matches!(command.get_action()?.as_str(), "add" | "remove")
+            && !Self::is_lexeme_subentity_command(command);
+        let main_item = match (&self.current_entity_id, loads_entity) {
+            (Some(q), true) => Some(self.reload_entity(q.to_owned()).await?),
+            _ => None,
+        };
+        command.action_to_execute(&main_item)
+    }
+
+    /// After an edit with unknown outcome or an edit conflict: recomputes the
+    /// action against the latest revision. Returns the parameters to retry with,
+    /// or `None` if the edit is already done.
+    async fn params_for_retry(
+        &mut self,
+        command: &mut QuickStatementsCommand,
+    ) -> QsResult<Option<HashMap<String, String>>> {
+        let action = self.recompute_action(command).await?;
+        if !action["already_done"].is_null() {
+            self.log("[run_action] Edit is already done".to_string());
+            self.reset_entities(&json!({}), command);
+            return Ok(None);
         }
+        self.action_params(&action, command).map(Some)
     }
 
     /// Commons MediaInfo entities have a designated ID but might not exists, yet are still good to edit.
@@ -386,8 +572,8 @@ impl QuickStatementsBot {
         &mut self,
         entity_id: String,
         revision: Option<usize>,
-        original_error: String,
-    ) -> Result<wikibase::Entity, String> {
+        original_error: QsError,
+    ) -> QsResult<wikibase::Entity> {
         static RE_MEDIA_INFO: LazyLock<Regex> = LazyLock::new(|| {
             Regex::new(r#"^M\d+$"#)
                 .expect("QuickStatementsBot::try_create_fake_entity:RE_MEDIA_INFO does not compile")
@@ -398,10 +584,15 @@ impl QuickStatementsBot {
             .to_owned()
             .ok_or("QuickStatementsBot::try_create_fake_entity has no mw_api".to_string())?;
 
-        let the_error = Err(format!(
+        // A network failure may go away on a retry; anything else means the
+        // entity cannot be loaded
+        if original_error.is_transient() {
+            return Err(original_error);
+        }
+        let the_error = Err(QsError::EntityNotFound(format!(
             "Error while loading into entities: {} rev. {:?} '{}'",
             entity_id, revision, original_error
-        ));
+        )));
 
         if revision.is_none()
             && mw_api.api_url() == COMMONS_API
@@ -430,7 +621,7 @@ impl QuickStatementsBot {
     async fn check_if_user_is_blocked(
         &self,
         command: &mut QuickStatementsCommand,
-    ) -> Result<bool, String> {
+    ) -> QsResult<bool> {
         // Only check randomly every 20 commands to keep API load down
         if command.id % 20 != 0 {
             return Ok(false);
@@ -448,57 +639,101 @@ impl QuickStatementsBot {
             .clone()
             .ok_or("No mw_api available for block check".to_string())?;
 
-        QuickStatements::is_user_blocked(&mut mw_api, &user_name)
-            .await
-            .map_err(|e| e.to_string())
+        QuickStatements::is_user_blocked(&mut mw_api, &user_name).await
     }
 
+    /// Runs the command and returns the API result, or `{"already_done":1}`
+    /// if there was nothing to do. The command is marked DONE or ERROR.
     pub async fn execute_command(
         &mut self,
         command: &mut QuickStatementsCommand,
-    ) -> Result<(), String> {
-        if Ok(true) == self.check_if_user_is_blocked(command).await {
+    ) -> QsResult<Value> {
+        if matches!(self.check_if_user_is_blocked(command).await, Ok(true)) {
             let _ = self.set_command_status("BLOCKED", None, command).await;
-            let _ = self
-                .config
-                .set_batch_status("BLOCKED", "", self.batch_id.unwrap_or(0), self.user_id)
-                .await;
-            return Err("User is blocked".to_string());
+            if let Some(batch_id) = self.batch_id {
+                let _ = self
+                    .config
+                    .set_batch_status("BLOCKED", "", batch_id, self.user_id)
+                    .await;
+            }
+            return Err(QsError::PermissionDenied {
+                code: "blocked".to_string(),
+                info: "User is blocked".to_string(),
+            });
         }
         self.log("[execute_command] Init".to_string());
         self.current_property_id = None;
         self.current_entity_id = None;
 
-        self.log("[execute_command] Prep".to_string());
         // Preparation failures (unresolvable LAST, entity fails to load, ...) must
         // mark the command ERROR too, or it stays RUN in the DB forever.
-        if let Err(e) = command.insert_last_item_into_sources_and_qualifiers(&self.last_state) {
-            self.set_command_status("ERROR", Some(&e), command).await?;
-            return Err(e);
-        }
-        let main_item = match self.prepare_to_execute(command).await {
-            Ok(main_item) => main_item,
+        match self.prepare_and_run(command).await {
+            Ok(res) => {
+                self.set_command_status("DONE", None, command).await?;
+                Ok(res)
+            }
+            Err(e) if e.is_transient() && self.batch_id.is_some() => {
+                self.retry_later(e, command).await
+            }
             Err(e) => {
                 self.set_command_status("ERROR", Some(&e), command).await?;
-                return Err(e);
+                Err(e)
             }
-        };
-        let action = command.action_to_execute(&main_item);
+        }
+    }
+
+    async fn prepare_and_run(&mut self, command: &mut QuickStatementsCommand) -> QsResult<Value> {
+        self.log("[execute_command] Prep".to_string());
+        command.insert_last_item_into_sources_and_qualifiers(&self.last_state)?;
+        let main_item = self.prepare_to_execute(command).await?;
+        let action = command.action_to_execute(&main_item)?;
 
         self.log("[execute_command] Go".to_string());
-        match action {
-            Ok(action) => match self.run_action(action, command).await {
-                Ok(_) => self.set_command_status("DONE", None, command).await,
-                Err(e) => {
-                    self.set_command_status("ERROR", Some(&e), command).await?;
-                    Err(e)
-                }
-            },
-            Err(e) => {
-                self.set_command_status("ERROR", Some(&e), command).await?;
-                Err(e)
+        self.run_action(action, command).await
+    }
+
+    /// Puts a command that failed transiently back to INIT and waits before the
+    /// bot picks it up again; marks it ERROR once it has used up its retries.
+    /// The retry count is kept in the command's `meta`.
+    async fn retry_later(
+        &mut self,
+        error: QsError,
+        command: &mut QuickStatementsCommand,
+    ) -> QsResult<Value> {
+        let retries = command.json["meta"]["retries"].as_u64().unwrap_or(0);
+        if retries >= TRANSIENT_RETRY_LIMIT {
+            // A reset of the failed command starts with a fresh retry budget
+            if let Some(meta) = command.json["meta"].as_object_mut() {
+                meta.remove("retries");
             }
+            self.set_command_status("ERROR", Some(&error), command)
+                .await?;
+            return Err(error);
+        }
+        command.json["meta"]["retries"] = json!(retries + 1);
+        // The edit may have been made before e.g. the connection broke, so the
+        // retry has to check it against the latest revision
+        if let Some(q) = self.current_entity_id.clone() {
+            self.forget_entity(&q);
         }
+        self.set_command_status("INIT", Some(&error), command)
+            .await?;
+        let delay = self
+            .transient_retry_delay
+            .saturating_mul(1 << retries)
+            .min(Duration::from_secs(TRANSIENT_RETRY_MAX_S));
+        bot_log!(
+            self,
+            log::Level::Warn,
+            "Transient error, retrying command in {:?} ({}/{}): {}",
+            delay,
+            retries + 1,
+            TRANSIENT_RETRY_LIMIT,
+            error
+        );
+        // The command is INIT again, so a shutdown may hand the batch back now
+        self.config.sleep_unless_shutting_down(delay).await;
+        Err(error)
     }
 
     fn reset_entities(&mut self, res: &Value, command: &QuickStatementsCommand) {
@@ -532,6 +767,8 @@ impl QuickStatementsBot {
                 self.last_state.last = Some(q.to_string());
                 self.entities.remove_entity(q);
                 if let Some(revision_id) = res["pageinfo"]["lastrevid"].as_u64() {
+                    self.base_revisions
+                        .insert(q.to_string(), revision_id as usize);
                     self.entity_revision.retain(|er| er.0 != q);
                     self.entity_revision
                         .push_front((q.to_string(), revision_id as usize));
@@ -563,8 +800,18 @@ impl QuickStatementsBot {
                         self.last_state.last_form = None;
                         self.last_state.last_sense = None;
                     }
+                    if let Some(revision_id) = entity_json["lastrevid"].as_u64() {
+                        self.base_revisions
+                            .insert(q.to_string(), revision_id as usize);
+                    }
                     if let Err(e) = self.entities.set_entity_from_json(entity_json) {
-                        log::error!("Failed to set entity from JSON for {}: {}", q, e);
+                        bot_log!(
+                            self,
+                            log::Level::Error,
+                            "Failed to set entity from JSON for {}: {}",
+                            q,
+                            e
+                        );
                     }
                     // The full entity is now cached; drop any revision pin so the next
                     // load uses the cache instead of an anonymous fetch from a possibly
@@ -580,10 +827,18 @@ impl QuickStatementsBot {
         params: &mut HashMap<String, String>,
         command: &mut QuickStatementsCommand,
     ) {
-        let summary: String = format!(
-            "[[:toollabs:quickstatements/#/batch/{}|batch #{}]]",
-            command.batch_id, command.batch_id
-        );
+        let summary: String = match self.batch_id {
+            Some(_) => format!(
+                "[[:toollabs:quickstatements/#/batch/{}|batch #{}]]",
+                command.batch_id, command.batch_id
+            ),
+            // Commands run from the browser bring their own summary, with the
+            // temporary batch ID that EditGroups groups the edits by
+            None => match command.json["summary"].as_str() {
+                Some(summary) => summary.to_string(),
+                None => return,
+            },
+        };
         let new_summary = match &params.get("summary") {
             Some(s) => s.to_string() + "; " + &summary,
             None => summary,
@@ -591,17 +846,12 @@ impl QuickStatementsBot {
         params.insert("summary".to_string(), new_summary);
     }
 
-    async fn run_action(
-        &mut self,
-        j: Value,
+    /// API parameters for an action from `action_to_execute`, with edit summary
+    fn action_params(
+        &self,
+        j: &Value,
         command: &mut QuickStatementsCommand,
-    ) -> Result<(), String> {
-        if !j["already_done"].is_null() {
-            return Ok(());
-        }
-
-        self.log("[run_action] Init".to_string());
-
+    ) -> QsResult<HashMap<String, String>> {
         let mut params: HashMap<String, String> = HashMap::new();
         for (k, v) in j
             .as_object()
@@ -618,6 +868,30 @@ impl QuickStatementsBot {
             );
         }
         self.add_summary(&mut params, command);
+        // Lets the API detect edits by others since we loaded the entity
+        if let Some(revision) = self
+            .current_entity_id
+            .as_ref()
+            .and_then(|q| self.base_revisions.get(q))
+        {
+            params.insert("baserevid".to_string(), revision.to_string());
+        }
+        Ok(params)
+    }
+
+    /// Makes the edit, returning the API result, or `j` if it is already done
+    async fn run_action(
+        &mut self,
+        j: Value,
+        command: &mut QuickStatementsCommand,
+    ) -> QsResult<Value> {
+        if !j["already_done"].is_null() {
+            return Ok(j);
+        }
+
+        self.log("[run_action] Init".to_string());
+
+        let mut params = self.action_params(&j, command)?;
         self.log("[run_action] Summary added".to_string());
 
         let mut mw_api = self.mw_api.to_owned().ok_or(format!(
@@ -627,22 +901,22 @@ impl QuickStatementsBot {
 
         const MAX_JSON_RETRIES: usize = 3;
         const MAX_THROTTLE_RETRIES: usize = 10;
+        const MAX_CONFLICT_RETRIES: usize = 3;
         let mut json_retries = 0usize;
+        let mut conflict_retries = 0usize;
         let mut throttle_retries = 0usize;
         loop {
-            params.insert(
-                "token".to_string(),
-                mw_api.get_edit_token().await.map_err(|e| {
-                    format!("QuickStatementsBot::run_action get_edit_token '{}'", e)
-                })?,
-            );
+            params.insert("token".to_string(), mw_api.get_edit_token().await?);
 
+            if let Some(site) = &self.site {
+                self.config.rate_governor().acquire(site).await;
+            }
             self.log("[run_action] Pre  post_query_api_json_mut".to_string());
             let res = match mw_api.post_query_api_json_mut(&params).await {
                 Ok(x) => x,
-                // Usually an HTML rate-limit page, i.e. the edit was rejected. If the
-                // edit was applied and only the response was lost, this retry may
-                // duplicate it; MediaWiki offers no idempotency token to prevent that.
+                // Usually an HTML error page, and the edit may or may not have been
+                // applied. MediaWiki offers no idempotency token, so redo the
+                // already-done check against the latest revision before retrying.
                 Err(wikibase::mediawiki::MediaWikiError::Serde(_))
                     if json_retries < MAX_JSON_RETRIES =>
                 {
@@ -651,14 +925,38 @@ impl QuickStatementsBot {
                         "[run_action] Non-JSON API response, retrying ({}/{})",
                         json_retries, MAX_JSON_RETRIES
                     ));
-                    tokio::time::sleep(Duration::from_secs(30)).await;
+                    tokio::time::sleep(self.non_json_retry_delay).await;
+                    match self.params_for_retry(command).await? {
+                        Some(new_params) => params = new_params,
+                        None => return Ok(json!({"already_done":1})),
+                    }
                     continue;
                 }
-                Err(e) => return Err(format!("Wiki editing failed: {:?}", e)),
+                Err(e) => return Err(e.into()),
             };
             self.log("[run_action] Post post_query_api_json_mut".to_string());
+            if let Some(code) = res["error"]["code"].as_str() {
+                self.config.metrics().api_error(code);
+            }
+
+            // Someone else edited the entity since we loaded it; their edit may
+            // already contain ours, or change what ours has to be
+            if res["error"]["code"].as_str() == Some("editconflict")
+                && conflict_retries < MAX_CONFLICT_RETRIES
+            {
+                conflict_retries += 1;
+                self.log(format!(
+                    "[run_action] Edit conflict, retrying ({}/{})",
+                    conflict_retries, MAX_CONFLICT_RETRIES
+                ));
+                match self.params_for_retry(command).await? {
+                    Some(new_params) => params = new_params,
+                    None => return Ok(json!({"already_done":1})),
+                }
+                continue;
+            }
 
-            let retry_after = self.check_run_action_result(res, &params, command)?;
+            let retry_after = self.check_run_action_result(res.clone(), &params, command)?;
             match retry_after {
                 None => {
                     // Pace edits with the current adaptive delay, then relax it
@@ -666,15 +964,17 @@ impl QuickStatementsBot {
                         tokio::time::sleep(Duration::from_millis(self.adaptive_delay_ms)).await;
                     }
                     self.decay_delay();
-                    return Ok(());
+                    return Ok(res);
                 }
                 Some(d) => {
                     throttle_retries += 1;
                     if throttle_retries > MAX_THROTTLE_RETRIES {
-                        return Err(format!(
-                            "Too many throttle retries ({}) for command #{}",
-                            throttle_retries, command.id
-                        ));
+                        return Err(QsError::RateLimited {
+                            code: res["error"]["code"]
+                                .as_str()
+                                .unwrap_or("throttled")
+                                .to_string(),
+                        });
                     }
                     tokio::time::sleep(d).await;
                 }
@@ -683,13 +983,14 @@ impl QuickStatementsBot {
     }
 
     /// Checks the command result.
-    /// Returns Ok(None) when done, Ok(Some(duration)) to retry after sleeping, Err on fatal error.
+    /// Returns Ok(None) when done, Ok(Some(duration)) to retry after sleeping, Err on
+    /// failure, classified by `QsError::from_api_error` into transient or permanent.
     fn check_run_action_result(
         &mut self,
         res: Value,
         params: &HashMap<String, String>,
         command: &mut QuickStatementsCommand,
-    ) -> Result<Option<Duration>, String> {
+    ) -> QsResult<Option<Duration>> {
         static RE_QUAL_OK: LazyLock<Regex> = LazyLock::new(|| {
             Regex::new("^The statement has already a qualifier with hash")
                 .expect("QuickStatementsBot::run_action:RE_QUAL_OK does not compile")
@@ -705,7 +1006,10 @@ impl QuickStatementsBot {
                     self.reset_entities(&res, command);
                     Ok(None)
                 } else {
-                    Err(format!("Success flag is '{}' in API result", num))
+                    Err(QsError::UnexpectedApiResponse(format!(
+                        "Success flag is '{}'",
+                        num
+                    )))
                 }
             }
             None => {
@@ -715,9 +1019,14 @@ impl QuickStatementsBot {
                     let lag = res["error"]["lag"].as_f64().unwrap_or(5.0);
                     let lag_ms = (lag.ceil() as u64 + 1) * 1000;
                     let sleep_ms = self.bump_backoff().max(lag_ms);
-                    log::warn!(
-                        "Batch #{}: Maxlag exceeded (lag: {}s), sleeping {}ms",
-                        self.batch_id.unwrap_or(0),
+                    self.report_pushback(Duration::from_millis(lag_ms));
+                    self.config
+                        .metrics()
+                        .pushback_sleep("maxlag", Duration::from_millis(sleep_ms));
+                    bot_log!(
+                        self,
+                        log::Level::Warn,
+                        "Maxlag exceeded (lag: {}s), sleeping {}ms",
                         lag,
                         sleep_ms
                     );
@@ -725,9 +1034,14 @@ impl QuickStatementsBot {
                 }
                 if matches!(error_code, "ratelimited" | "actionthrottled") {
                     let sleep_ms = self.bump_backoff();
-                    log::warn!(
-                        "Batch #{}: Rate limited by API (code: {}), sleeping {}ms",
-                        self.batch_id.unwrap_or(0),
+                    self.report_pushback(Duration::ZERO);
+                    self.config
+                        .metrics()
+                        .pushback_sleep("ratelimited", Duration::from_millis(sleep_ms));
+                    bot_log!(
+                        self,
+                        log::Level::Warn,
+                        "Rate limited by API (code: {}), sleeping {}ms",
                         error_code,
                         sleep_ms
                     );
@@ -742,9 +1056,14 @@ impl QuickStatementsBot {
                     });
                     if throttled {
                         let sleep_ms = self.bump_backoff();
-                        log::warn!(
-                            "Batch #{}: Throttled by API, sleeping {}ms",
-                            self.batch_id.unwrap_or(0),
+                        self.report_pushback(Duration::ZERO);
+                        self.config
+                            .metrics()
+                            .pushback_sleep("throttled", Duration::from_millis(sleep_ms));
+                        bot_log!(
+                            self,
+                            log::Level::Warn,
+                            "Throttled by API, sleeping {}ms",
                             sleep_ms
                         );
                         return Ok(Some(Duration::from_millis(sleep_ms)));
@@ -759,8 +1078,20 @@ impl QuickStatementsBot {
                         return Ok(None);
                     }
                 }
-                log::error!("COMMAND ERROR #{}:\n{:?}\n{}", command.id, params, res);
-                Err("No success flag set in API result".to_string())
+                // One line, so log aggregation keeps the event together
+                bot_log!(
+                    self,
+                    log::Level::Error,
+                    "Command error: params {} result {}",
+                    json!(params),
+                    res
+                );
+                match res["error"]["code"].is_string() {
+                    true => Err(QsError::from_api_error(&res["error"])),
+                    false => Err(QsError::UnexpectedApiResponse(
+                        "No success flag set".to_string(),
+                    )),
+                }
             }
         }
     }
@@ -771,9 +1102,9 @@ impl QuickStatementsBot {
     async fn set_command_status(
         &self,
         status: &str,
-        message: Option<&str>,
+        error: Option<&QsError>,
         command: &mut QuickStatementsCommand,
-    ) -> Result<(), String> {
+    ) -> QsResult<()> {
         if self.batch_id.is_none() {
             return Ok(());
         }
@@ -790,12 +1121,14 @@ impl QuickStatementsBot {
                 self.batch_id.unwrap()
             ))?;
         self.config
-            .set_command_status(command, status, message.map(|s| s.to_string()))
+            .set_command_status(command, status, error)
             .await
             .ok_or(format!(
                 "Can't config.set_command_status for batch #{}",
                 self.batch_id.unwrap() // Safe
             ))?;
+        let action = command.json["action"].as_str().unwrap_or("unknown");
+        self.config.metrics().command_status(action, status);
 
         Ok(())
     }
@@ -804,12 +1137,31 @@ impl QuickStatementsBot {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::qs_fake_wiki::{FakeWiki, Fault, TEST_USER_ID};
 
     fn test_bot() -> QuickStatementsBot {
         let config = Arc::new(QuickStatements::new_for_tests());
         QuickStatementsBot::new(config, Some(1), 0)
     }
 
+    #[tokio::test]
+    async fn shutdown_requeues_batch_between_commands() {
+        let config = Arc::new(QuickStatements::new_for_tests());
+        let batch_id = config
+            .storage()
+            .create_batch("test", 1, "wikidata", &[], None)
+            .await
+            .unwrap();
+        let mut bot = QuickStatementsBot::new(config.clone(), Some(batch_id), 1);
+
+        config.begin_shutdown();
+
+        assert!(matches!(bot.run().await, Ok(false)));
+        let batch = config.storage().get_batch(batch_id).await.unwrap().unwrap();
+        assert_eq!(batch.status, "INIT");
+        assert_eq!(batch.message, "Bot shut down");
+    }
+
     #[test]
     fn check_run_action_result_success_updates_last_state() {
         let mut bot = test_bot();
@@ -820,7 +1172,7 @@ mod tests {
 
         let result = bot.check_run_action_result(res, &HashMap::new(), &mut command);
 
-        assert_eq!(result, Ok(None));
+        assert_eq!(result.ok(), Some(None));
         assert_eq!(bot.last_state.last, Some("Q5".to_string()));
         assert_eq!(bot.entity_revision.front(), Some(&("Q5".to_string(), 123)));
     }
@@ -838,7 +1190,7 @@ mod tests {
 
         let result = bot.check_run_action_result(res, &HashMap::new(), &mut command);
 
-        assert_eq!(result, Ok(None));
+        assert_eq!(result.ok(), Some(None));
         assert_eq!(bot.last_state.last, Some("Q123".to_string()));
     }
 
@@ -853,7 +1205,7 @@ mod tests {
 
         let result = bot.check_run_action_result(res, &HashMap::new(), &mut command);
 
-        assert_eq!(result, Ok(None));
+        assert_eq!(result.ok(), Some(None));
         assert_eq!(bot.last_state.last, Some("Q123".to_string()));
     }
 
@@ -869,6 +1221,85 @@ mod tests {
         assert_eq!(bot.last_state.last, None);
     }
 
+    #[test]
+    fn check_run_action_result_classifies_errors() {
+        let mut bot = test_bot();
+        let mut command = QuickStatementsCommand::new_from_json(&json!({"item":"Q123"}));
+        let readonly = json!({"error":{"code":"readonly","info":"The wiki is in read-only mode"}});
+        let internal = json!({"error":{"code":"internal_api_error_DBQueryError","info":"x"}});
+        let permission = json!({"error":{"code":"permissiondenied","info":"x"}});
+
+        let readonly = bot.check_run_action_result(readonly, &HashMap::new(), &mut command);
+        let internal = bot.check_run_action_result(internal, &HashMap::new(), &mut command);
+        let permission = bot.check_run_action_result(permission, &HashMap::new(), &mut command);
+
+        assert!(readonly.unwrap_err().is_transient());
+        assert!(internal.unwrap_err().is_transient());
+        assert!(!permission.unwrap_err().is_transient());
+    }
+
+    // Wikibase reports most rejected edits under generic codes, so the message
+    // keys decide what kind of failure gets stored with the command
+    #[test]
+    fn check_run_action_result_error_codes() {
+        let mut bot = test_bot();
+        let mut command = QuickStatementsCommand::new_from_json(&json!({"item":"Q123"}));
+        let cases = [
+            (
+                json!({"code":"no-such-entity","info":"x"}),
+                "entity-missing",
+            ),
+            (
+                json!({"code":"no-such-claim","info":"x"}),
+                "statement-missing",
+            ),
+            (
+                json!({"code":"protectedpage","info":"x"}),
+                "permission-denied",
+            ),
+            (
+                json!({"code":"failed-save","info":"x","messages":[{"name":"abusefilter-disallowed"}]}),
+                "abuse-filter",
+            ),
+            (
+                json!({"code":"modification-failed","info":"x",
+                    "messages":[{"name":"wikibase-validator-bad-value-type"}]}),
+                "property-mismatch",
+            ),
+            (
+                json!({"code":"failed-save","info":"x",
+                    "messages":[{"name":"wikibase-validator-label-with-description-conflict"}]}),
+                "constraint",
+            ),
+            (json!({"code":"editconflict","info":"x"}), "conflict"),
+            (json!({"code":"failed-save","info":"x"}), "api-error"),
+        ];
+
+        for (error, code) in cases {
+            let res = json!({ "error": error });
+            let result = bot.check_run_action_result(res, &HashMap::new(), &mut command);
+            assert_eq!(result.unwrap_err().code(), code, "for {}", error);
+        }
+    }
+
+    #[test]
+    fn check_run_action_result_without_success_or_error() {
+        let mut bot = test_bot();
+        let mut command = QuickStatementsCommand::new_from_json(&json!({"item":"Q123"}));
+        for res in [json!({"success": 0}), json!({"warnings": {}})] {
+            let error = bot
+                .check_run_action_result(res.clone(), &HashMap::new(), &mut command)
+                .unwrap_err();
+            assert!(
+                matches!(error, QsError::UnexpectedApiResponse(_)),
+                "for {}",
+                res
+            );
+            assert_eq!(error.code(), "unexpected-response");
+            assert!(!error.is_transient());
+        }
+    }
+
     #[test]
     fn check_run_action_result_maxlag_is_retried() {
         let mut bot = test_bot();
@@ -877,7 +1308,24 @@ mod tests {
 
         let result = bot.check_run_action_result(res, &HashMap::new(), &mut command);
 
-        assert_eq!(result, Ok(Some(Duration::from_millis(5000))));
+        assert_eq!(result.ok(), Some(Some(Duration::from_millis(5000))));
+    }
+
+    // Lag is a problem of the site, so one bot seeing it slows down all bots
+    // editing that site
+    #[test]
+    fn maxlag_slows_down_the_site() {
+        let mut bot = test_bot();
+        bot.site = Some("wikidata".to_string());
+        let full_rate = bot.config.rate_governor().edits_per_minute("wikidata");
+        let mut command = QuickStatementsCommand::new_from_json(&json!({"item":"Q123"}));
+        let res = json!({"error":{"code":"maxlag","lag":3.2}});
+
+        let _ = bot.check_run_action_result(res, &HashMap::new(), &mut command);
+
+        let governor = bot.config.rate_governor();
+        assert_eq!(governor.edits_per_minute("wikidata"), full_rate / 2.0);
+        assert_eq!(governor.edits_per_minute("commons"), full_rate);
     }
 
     // Repeated pushback must double the backoff (capped), and successful edits
@@ -925,12 +1373,12 @@ mod tests {
         let second = bot.check_run_action_result(res, &HashMap::new(), &mut command);
 
         assert_eq!(
-            first,
-            Ok(Some(Duration::from_millis(THROTTLE_BACKOFF_MIN_MS)))
+            first.ok(),
+            Some(Some(Duration::from_millis(THROTTLE_BACKOFF_MIN_MS)))
         );
         assert_eq!(
-            second,
-            Ok(Some(Duration::from_millis(2 * THROTTLE_BACKOFF_MIN_MS)))
+            second.ok(),
+            Some(Some(Duration::from_millis(2 * THROTTLE_BACKOFF_MIN_MS)))
         );
     }
 
@@ -945,8 +1393,195 @@ mod tests {
         let result = bot.check_run_action_result(res, &HashMap::new(), &mut command);
 
         assert_eq!(
-            result,
-            Ok(Some(Duration::from_millis(THROTTLE_BACKOFF_MIN_MS)))
+            result.ok(),
+            Some(Some(Duration::from_millis(THROTTLE_BACKOFF_MIN_MS)))
         );
     }
+
+    // A lost response must not lead to the same edit being made twice
+    #[tokio::test]
+    async fn lost_response_is_not_repeated() {
+        let wiki = FakeWiki::start().await;
+        wiki.add_entity(json!({"type":"item","id":"Q1"}));
+        let config = Arc::new(QuickStatements::new_for_tests());
+        let batch_id = wiki
+            .create_batch(&config, "Q1\tAen\t\"alias\"\nQ1\tP31\tQ5\tP580\t\"x\"")
+            .await;
+        let mut bot = QuickStatementsBot::new(config, Some(batch_id), TEST_USER_ID);
+        bot.non_json_retry_delay = Duration::ZERO;
+        // Alias, statement and qualifier edits all go through, but their responses get lost
+        for _ in 0..3 {
+            wiki.inject(Fault::LostResponse);
+        }
+
+        wiki.run_bot(&mut bot).await;
+
+        let q1 = wiki.entity("Q1").unwrap();
+        let p31 = q1["claims"]["P31"].as_array().unwrap();
+        assert_eq!(p31.len(), 1);
+        assert_eq!(p31[0]["qualifiers"]["P580"].as_array().unwrap().len(), 1);
+        assert_eq!(q1["aliases"]["en"].as_array().unwrap().len(), 1);
+        assert_eq!(wiki.edits().len(), 3);
+    }
+
+    // The edit goes through on the last attempt, but its response is lost too,
+    // so the command is requeued; the retry must find the edit already made
+    #[tokio::test]
+    async fn requeued_command_sees_its_own_edit() {
+        let wiki = FakeWiki::start().await;
+        wiki.add_entity(json!({"type":"item","id":"Q1"}));
+        let config = Arc::new(QuickStatements::new_for_tests());
+        let batch_id = wiki.create_batch(&config, "Q1\tP31\tQ5").await;
+        let mut bot = QuickStatementsBot::new(config.clone(), Some(batch_id), TEST_USER_ID);
+        bot.non_json_retry_delay = Duration::ZERO;
+        bot.transient_retry_delay = Duration::ZERO;
+        for _ in 0..3 {
+            wiki.inject(Fault::NonJson);
+        }
+        wiki.inject(Fault::LostResponse);
+
+        wiki.run_bot(&mut bot).await;
+
+        let q1 = wiki.entity("Q1").unwrap();
+        assert_eq!(q1["claims"]["P31"].as_array().unwrap().len(), 1);
+        assert_eq!(wiki.edits().len(), 1);
+        let rows = config
+            .storage()
+            .get_commands(batch_id, &[], None, 0, -1)
+            .await
+            .unwrap();
+        assert_eq!(rows[0].status, "DONE");
+        let command: Value = serde_json::from_str(&rows[0].json).unwrap();
+        assert_eq!(command["meta"]["retries"], json!(1));
+    }
+
+    // Conflicts are retried against the latest revision right away; once those
+    // retries are used up, the command fails instead of being requeued
+    #[tokio::test]
+    async fn repeated_edit_conflicts_fail_the_command() {
+        let wiki = FakeWiki::start().await;
+        wiki.add_entity(json!({"type":"item","id":"Q1"}));
+        for num in 0..4 {
+            let label = format!("label {}", num);
+            let foreign_edit = [
+                ("action", "wbsetlabel"),
+                ("id", "Q1"),
+                ("language", "en"),
+                ("value", label.as_str()),
+            ]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+            wiki.inject(Fault::ForeignEdit(foreign_edit));
+        }
+        let config = Arc::new(QuickStatements::new_for_tests());
+
+        let batch_id = wiki.run_batch(config.clone(), "Q1\tP31\tQ5").await;
+
+        let rows = config
+            .storage()
+            .get_commands(batch_id, &[], None, 0, -1)
+            .await
+            .unwrap();
+        assert_eq!(rows[0].status, "ERROR");
+        assert_eq!(rows[0].error_code, "conflict");
+        let command: Value = serde_json::from_str(&rows[0].json).unwrap();
+        assert!(command["meta"]["retries"].is_null());
+        assert!(wiki.edits().is_empty());
+    }
+
+    // A read-only wiki is a passing problem: the command is requeued and retried,
+    // and only marked ERROR once it runs out of retries
+    #[tokio::test]
+    async fn transient_error_is_retried() {
+        let wiki = FakeWiki::start().await;
+        wiki.add_entity(json!({"type":"item","id":"Q1"}));
+        wiki.add_entity(json!({"type":"item","id":"Q2"}));
+        let config = Arc::new(QuickStatements::new_for_tests());
+        let batch_id = wiki
+            .create_batch(&config, "Q1\tLen\t\"one\"\nQ2\tLen\t\"two\"")
+            .await;
+        let mut bot = QuickStatementsBot::new(config.clone(), Some(batch_id), TEST_USER_ID);
+        bot.transient_retry_delay = Duration::ZERO;
+        wiki.inject(Fault::ReadOnly);
+        wiki.inject(Fault::ReadOnly);
+        for _ in 0..=TRANSIENT_RETRY_LIMIT {
+            wiki.inject(Fault::ReadOnly);
+        }
+
+        wiki.run_bot(&mut bot).await;
+
+        let rows = config
+            .storage()
+            .get_commands(batch_id, &[], None, 0, -1)
+            .await
+            .unwrap();
+        let statuses: Vec<&str> = rows.iter().map(|row| row.status.as_str()).collect();
+        assert_eq!(statuses, vec!["DONE", "ERROR"]);
+        let first: Value = serde_json::from_str(&rows[0].json).unwrap();
+        assert_eq!(first["meta"]["retries"], json!(2));
+        assert!(rows[1].message.contains("readonly"));
+        assert_eq!(rows[1].error_code, "transient");
+        assert_eq!(wiki.edits().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn edits_carry_base_revision() {
+        let wiki = FakeWiki::start().await;
+        wiki.add_entity(json!({"type":"item","id":"Q1"}));
+        let revision = wiki.entity("Q1").unwrap()["lastrevid"].to_string();
+        let config = Arc::new(QuickStatements::new_for_tests());
+
+        wiki.run_batch(config, "Q1\tLen\t\"label\"").await;
+
+        assert_eq!(wiki.edits()[0]["baserevid"], revision);
+    }
+
+    // Single commands run from the browser have no batch to store state in
+    #[tokio::test]
+    async fn command_without_batch() {
+        let wiki = FakeWiki::start().await;
+        let config = Arc::new(QuickStatements::new_for_tests());
+        let mut bot = QuickStatementsBot::new(config, None, TEST_USER_ID);
+        bot.set_mw_api(wiki.api().await);
+        let mut command = QuickStatementsCommand::new_from_json(
+            &json!({"action":"create","type":"item","summary":"#temporary_batch_1"}),
+        );
+
+        let res = bot.execute_command(&mut command).await.unwrap();
+
+        let new_id = res["entity"]["id"].as_str().unwrap();
+        assert_eq!(bot.last_state().last.as_deref(), Some(new_id));
+        assert_eq!(wiki.edits()[0]["summary"], "#temporary_batch_1");
+    }
+
+    // Another user adds the same statement between our load and our write
+    #[tokio::test]
+    async fn edit_conflict_recomputes_action() {
+        let wiki = FakeWiki::start().await;
+        wiki.add_entity(json!({"type":"item","id":"Q1"}));
+        let foreign_edit = [
+            ("action", "wbcreateclaim"),
+            ("entity", "Q1"),
+            ("property", "P31"),
+            ("snaktype", "value"),
+            (
+                "value",
+                r#"{"entity-type":"item","numeric-id":5,"id":"Q5"}"#,
+            ),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        wiki.inject(Fault::ForeignEdit(foreign_edit));
+        let config = Arc::new(QuickStatements::new_for_tests());
+
+        let batch_id = wiki.run_batch(config.clone(), "Q1\tP31\tQ5").await;
+
+        let batch = config.storage().get_batch(batch_id).await.unwrap().unwrap();
+        assert_eq!(batch.status, "DONE");
+        let q1 = wiki.entity("Q1").unwrap();
+        assert_eq!(q1["claims"]["P31"].as_array().unwrap().len(), 1);
+        assert!(wiki.edits().is_empty());
+    }
 }
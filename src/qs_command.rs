@@ -1,7 +1,8 @@
+use crate::error::{QsError, QsResult};
+use crate::qs_storage::CommandRow;
 use regex::Regex;
 use serde_json::{json, Value};
 use std::sync::LazyLock;
-use wikibase::*;
 
 /// Holds the current LAST / LAST_FORM / LAST_SENSE entity IDs.
 /// Mirrors the PHP fields last_item / last_form / last_sense.
@@ -69,23 +70,26 @@ pub struct QuickStatementsCommand {
     pub status: String,
     pub message: String,
     pub ts_change: String,
+    /// `QsError::code` of the failure, empty unless the command failed
+    pub error_code: String,
 }
 
 impl QuickStatementsCommand {
-    pub fn from_row(r: &(i64, i64, i64, String, String, String, String)) -> Self {
+    pub fn from_row(r: &CommandRow) -> Self {
         Self {
-            id: r.0,
-            batch_id: r.1,
-            num: r.2,
+            id: r.id,
+            batch_id: r.batch_id,
+            num: r.num,
             // Must be a JSON object: `json["meta"]["status"] = ...` panics on
             // arrays/strings/numbers (e.g. "[]" from PHP's json_encode)
-            json: serde_json::from_str(&r.3)
+            json: serde_json::from_str(&r.json)
                 .ok()
                 .filter(Value::is_object)
                 .unwrap_or(json!({})),
-            status: r.4.to_owned(),
-            message: r.5.to_owned(),
-            ts_change: r.6.to_owned(),
+            status: r.status.to_owned(),
+            message: r.message.to_owned(),
+            ts_change: r.ts_change.to_owned(),
+            error_code: r.error_code.to_owned(),
         }
     }
 
@@ -98,24 +102,28 @@ impl QuickStatementsCommand {
             status: String::new(),
             message: String::new(),
             ts_change: String::new(),
+            error_code: String::new(),
         }
     }
 
-    fn is_valid_command(&self) -> Result<(), String> {
+    fn is_valid_command(&self) -> QsResult<()> {
         if !self.json.is_object() {
-            return Err(format!("Not a valid command: {:?}", &self));
+            return Err(QsError::InvalidCommand(format!(
+                "Not a valid command: {:?}",
+                &self
+            )));
         }
         Ok(())
     }
 
-    pub fn action_remove_statement(&self, statement_id: String) -> Result<Value, String> {
+    pub fn action_remove_statement(&self, statement_id: String) -> QsResult<Value> {
         Ok(json!({"action":"wbremoveclaims","claim":statement_id}))
     }
 
-    pub fn action_remove_sitelink(&self, item: &wikibase::Entity) -> Result<Value, String> {
+    pub fn action_remove_sitelink(&self, item: &wikibase::Entity) -> QsResult<Value> {
         let site = match self.json["site"].as_str() {
             Some(s) => s,
-            None => return Err("site not set".to_string()),
+            None => return Err(QsError::InvalidCommand("site not set".to_string())),
         };
         // Removing a sitelink that isn't there is a no-op, not an API error
         let has_sitelink = item
@@ -130,14 +138,14 @@ impl QuickStatementsCommand {
         self.action_set_sitelink_with_title(item, site, "")
     }
 
-    pub fn action_set_sitelink(&self, item: &wikibase::Entity) -> Result<Value, String> {
+    pub fn action_set_sitelink(&self, item: &wikibase::Entity) -> QsResult<Value> {
         let site = match &self.json["site"].as_str() {
             Some(s) => s.to_owned(),
-            None => return Err("site not set".to_string()),
+            None => return Err(QsError::InvalidCommand("site not set".to_string())),
         };
         let title = match &self.json["value"].as_str() {
             Some(s) => s.to_owned(),
-            None => return Err("value (title) not set".to_string()),
+            None => return Err(QsError::InvalidCommand("value (title) not set".to_string())),
         };
         self.action_set_sitelink_with_title(item, site, title)
     }
@@ -147,7 +155,7 @@ impl QuickStatementsCommand {
         item: &wikibase::Entity,
         site: &str,
         title: &str,
-    ) -> Result<Value, String> {
+    ) -> QsResult<Value> {
         // Check if this same sitelink is already set
         if let Some(sitelinks) = item.sitelinks() {
             let title_underscores = title.replace(' ', "_");
@@ -166,11 +174,11 @@ impl QuickStatementsCommand {
         }))
     }
 
-    fn already_done(&self) -> Result<Value, String> {
+    fn already_done(&self) -> QsResult<Value> {
         Ok(json!({"already_done":1}))
     }
 
-    fn action_add_statement(&self, item: &wikibase::Entity) -> Result<Value, String> {
+    fn action_add_statement(&self, item: &wikibase::Entity) -> QsResult<Value> {
         // "!P123"-style commands force a new statement even if an identical one exists
         let force_new = self.json["new_statement"].as_i64().unwrap_or(0) != 0
             || self.json["new_statement"].as_bool().unwrap_or(false);
@@ -183,7 +191,7 @@ impl QuickStatementsCommand {
         let q = item.id().to_string();
         let property = match self.json["property"].as_str() {
             Some(p) => p.to_owned(),
-            None => return Err("Property not found".to_string()),
+            None => return Err(QsError::InvalidCommand("Property not found".to_string())),
         };
         let snaktype = self.get_snak_type_for_datavalue(&self.json["datavalue"])?;
 
@@ -201,13 +209,13 @@ impl QuickStatementsCommand {
         Ok(ret)
     }
 
-    fn action_set_label(&self, item: &wikibase::Entity) -> Result<Value, String> {
+    fn action_set_label(&self, item: &wikibase::Entity) -> QsResult<Value> {
         let language = self.json["language"]
             .as_str()
-            .ok_or("Can't find language".to_string())?;
+            .ok_or_else(|| QsError::InvalidCommand("Can't find language".to_string()))?;
         let text = self.json["value"]
             .as_str()
-            .ok_or("Can't find text (=value)".to_string())?;
+            .ok_or_else(|| QsError::InvalidCommand("Can't find text (=value)".to_string()))?;
         if let Some(s) = item.label_in_locale(language) {
             if s == text {
                 return self.already_done();
@@ -218,13 +226,13 @@ impl QuickStatementsCommand {
         )
     }
 
-    fn action_set_description(&self, item: &wikibase::Entity) -> Result<Value, String> {
+    fn action_set_description(&self, item: &wikibase::Entity) -> QsResult<Value> {
         let language = self.json["language"]
             .as_str()
-            .ok_or("Can't find language".to_string())?;
+            .ok_or_else(|| QsError::InvalidCommand("Can't find language".to_string()))?;
         let text = self.json["value"]
             .as_str()
-            .ok_or("Can't find text (=value)".to_string())?;
+            .ok_or_else(|| QsError::InvalidCommand("Can't find text (=value)".to_string()))?;
         if let Some(s) = item.description_in_locale(language) {
             if s == text {
                 return self.already_done();
@@ -235,7 +243,7 @@ impl QuickStatementsCommand {
         )
     }
 
-    fn replace_last_item(&self, v: &mut Value, state: &LastEntityState) -> Result<(), String> {
+    fn replace_last_item(&self, v: &mut Value, state: &LastEntityState) -> QsResult<()> {
         if !v.is_object() {
             return Ok(());
         }
@@ -260,7 +268,7 @@ impl QuickStatementsCommand {
     pub fn insert_last_item_into_sources_and_qualifiers(
         &mut self,
         state: &LastEntityState,
-    ) -> Result<(), String> {
+    ) -> QsResult<()> {
         let mut json = self.json.clone();
         if let Some(item_str) = self.json["item"].as_str() {
             let upper = item_str.trim().to_uppercase();
@@ -290,32 +298,43 @@ impl QuickStatementsCommand {
         Ok(())
     }
 
-    fn action_add_alias(&self, item: &wikibase::Entity) -> Result<Value, String> {
+    fn action_add_alias(&self, item: &wikibase::Entity) -> QsResult<Value> {
         let language = self.json["language"]
             .as_str()
-            .ok_or("Can't find language".to_string())?;
+            .ok_or_else(|| QsError::InvalidCommand("Can't find language".to_string()))?;
         let text = self.json["value"]
             .as_str()
-            .ok_or("Can't find text (=value)".to_string())?;
+            .ok_or_else(|| QsError::InvalidCommand("Can't find text (=value)".to_string()))?;
+        if item
+            .aliases()
+            .iter()
+            .any(|alias| alias.language() == language && alias.value() == text)
+        {
+            return self.already_done();
+        }
         Ok(
             json!({"action":"wbsetaliases","id":self.get_prefixed_id(item.id()),"language":language,"add":text}),
         )
     }
 
-    fn action_add_qualifier(&self, item: &wikibase::Entity) -> Result<Value, String> {
+    fn action_add_qualifier(&self, item: &wikibase::Entity) -> QsResult<Value> {
         let statement_id = match self.get_statement_id(item)? {
             Some(id) => id,
             None => {
-                return Err(format!(
+                return Err(QsError::StatementNotFound(format!(
                     "add_qualifier: Could not get statement ID for {:?}",
                     self
-                ))
+                )))
             }
         };
 
         let qual_prop = match self.json["qualifier"]["prop"].as_str() {
             Some(p) => self.check_prop(p)?,
-            None => return Err("Incomplete command parameters: prop".to_string()),
+            None => {
+                return Err(QsError::InvalidCommand(
+                    "Incomplete command parameters: prop".to_string(),
+                ))
+            }
         };
 
         // The datavalue is at qualifier.value, one level below the qualifier itself
@@ -331,7 +350,9 @@ impl QuickStatementsCommand {
         if snaktype == "value" {
             let qual_value = &self.json["qualifier"]["value"]["value"];
             if !qual_value.is_string() && !qual_value.is_object() {
-                return Err("Incomplete command parameters: value.value".to_string());
+                return Err(QsError::InvalidCommand(
+                    "Incomplete command parameters: value.value".to_string(),
+                ));
             }
             ret["value"] =
                 json!(serde_json::to_string(&qual_value).map_err(|e| format!("{:?}", e))?);
@@ -339,14 +360,14 @@ impl QuickStatementsCommand {
         Ok(ret)
     }
 
-    fn action_add_sources(&self, item: &wikibase::Entity) -> Result<Value, String> {
+    fn action_add_sources(&self, item: &wikibase::Entity) -> QsResult<Value> {
         let statement_id = match self.get_statement_id(item)? {
             Some(id) => id,
             None => {
-                return Err(format!(
+                return Err(QsError::StatementNotFound(format!(
                     "add_sources: Could not get statement ID for {:?}",
                     self
-                ))
+                )))
             }
         };
 
@@ -357,7 +378,11 @@ impl QuickStatementsCommand {
                     //println!("SOURCE: {}", &source);
                     let prop = match source["prop"].as_str() {
                         Some(prop) => prop,
-                        None => return Err("No prop value in source".to_string()),
+                        None => {
+                            return Err(QsError::InvalidCommand(
+                                "No prop value in source".to_string(),
+                            ))
+                        }
                     };
                     let prop = self.check_prop(prop)?;
                     // The datavalue is at source.value, one level below the source itself
@@ -386,7 +411,11 @@ impl QuickStatementsCommand {
                 }
                 snaks
             }
-            None => return Err("Incomplete command parameters: sources".to_string()),
+            None => {
+                return Err(QsError::InvalidCommand(
+                    "Incomplete command parameters: sources".to_string(),
+                ))
+            }
         };
 
         Ok(json!({
@@ -396,14 +425,16 @@ impl QuickStatementsCommand {
         }))
     }
 
-    fn action_set_lemma(&self) -> Result<Value, String> {
-        let item = self.json["item"].as_str().ok_or("SetLemma: item not set")?;
+    fn action_set_lemma(&self) -> QsResult<Value> {
+        let item = self.json["item"]
+            .as_str()
+            .ok_or_else(|| QsError::InvalidCommand("SetLemma: item not set".to_string()))?;
         let language = self.json["language"]
             .as_str()
-            .ok_or("SetLemma: language not set")?;
+            .ok_or_else(|| QsError::InvalidCommand("SetLemma: language not set".to_string()))?;
         let value = self.json["value"]
             .as_str()
-            .ok_or("SetLemma: value not set")?;
+            .ok_or_else(|| QsError::InvalidCommand("SetLemma: value not set".to_string()))?;
         let data = serde_json::to_string(&json!({
             "lemmas": {
                 language: {"language": language, "value": value}
@@ -417,13 +448,13 @@ impl QuickStatementsCommand {
         }))
     }
 
-    fn action_set_lexical_category(&self) -> Result<Value, String> {
-        let item = self.json["item"]
-            .as_str()
-            .ok_or("SetLexicalCategory: item not set")?;
-        let value = self.json["value"]
-            .as_str()
-            .ok_or("SetLexicalCategory: value not set")?;
+    fn action_set_lexical_category(&self) -> QsResult<Value> {
+        let item = self.json["item"].as_str().ok_or_else(|| {
+            QsError::InvalidCommand("SetLexicalCategory: item not set".to_string())
+        })?;
+        let value = self.json["value"].as_str().ok_or_else(|| {
+            QsError::InvalidCommand("SetLexicalCategory: value not set".to_string())
+        })?;
         let data = serde_json::to_string(&json!({
             "lexicalCategory": value
         }))
@@ -435,13 +466,13 @@ impl QuickStatementsCommand {
         }))
     }
 
-    fn action_set_language(&self) -> Result<Value, String> {
+    fn action_set_language(&self) -> QsResult<Value> {
         let item = self.json["item"]
             .as_str()
-            .ok_or("SetLanguage: item not set")?;
+            .ok_or_else(|| QsError::InvalidCommand("SetLanguage: item not set".to_string()))?;
         let value = self.json["value"]
             .as_str()
-            .ok_or("SetLanguage: value not set")?;
+            .ok_or_else(|| QsError::InvalidCommand("SetLanguage: value not set".to_string()))?;
         let data = serde_json::to_string(&json!({
             "language": value
         }))
@@ -453,16 +484,16 @@ impl QuickStatementsCommand {
         }))
     }
 
-    fn action_set_form_representation(&self) -> Result<Value, String> {
-        let item = self.json["item"]
-            .as_str()
-            .ok_or("SetFormRepresentation: item not set")?;
-        let language = self.json["language"]
-            .as_str()
-            .ok_or("SetFormRepresentation: language not set")?;
-        let value = self.json["value"]
-            .as_str()
-            .ok_or("SetFormRepresentation: value not set")?;
+    fn action_set_form_representation(&self) -> QsResult<Value> {
+        let item = self.json["item"].as_str().ok_or_else(|| {
+            QsError::InvalidCommand("SetFormRepresentation: item not set".to_string())
+        })?;
+        let language = self.json["language"].as_str().ok_or_else(|| {
+            QsError::InvalidCommand("SetFormRepresentation: language not set".to_string())
+        })?;
+        let value = self.json["value"].as_str().ok_or_else(|| {
+            QsError::InvalidCommand("SetFormRepresentation: value not set".to_string())
+        })?;
         let data = serde_json::to_string(&json!({
             "representations": {
                 language: {"language": language, "value": value}
@@ -476,14 +507,14 @@ impl QuickStatementsCommand {
         }))
     }
 
-    fn action_set_grammatical_feature(&self) -> Result<Value, String> {
-        let item = self.json["item"]
-            .as_str()
-            .ok_or("SetGrammaticalFeature: item not set")?;
+    fn action_set_grammatical_feature(&self) -> QsResult<Value> {
+        let item = self.json["item"].as_str().ok_or_else(|| {
+            QsError::InvalidCommand("SetGrammaticalFeature: item not set".to_string())
+        })?;
         let features = &self.json["value"];
-        let features_arr = features
-            .as_array()
-            .ok_or("SetGrammaticalFeature: value not an array")?;
+        let features_arr = features.as_array().ok_or_else(|| {
+            QsError::InvalidCommand("SetGrammaticalFeature: value not an array".to_string())
+        })?;
         let data = serde_json::to_string(&json!({
             "grammaticalFeatures": features_arr
         }))
@@ -495,16 +526,16 @@ impl QuickStatementsCommand {
         }))
     }
 
-    fn action_set_sense_gloss(&self) -> Result<Value, String> {
+    fn action_set_sense_gloss(&self) -> QsResult<Value> {
         let item = self.json["item"]
             .as_str()
-            .ok_or("SetSenseGloss: item not set")?;
-        let language = self.json["language"]
-            .as_str()
-            .ok_or("SetSenseGloss: language not set")?;
+            .ok_or_else(|| QsError::InvalidCommand("SetSenseGloss: item not set".to_string()))?;
+        let language = self.json["language"].as_str().ok_or_else(|| {
+            QsError::InvalidCommand("SetSenseGloss: language not set".to_string())
+        })?;
         let value = self.json["value"]
             .as_str()
-            .ok_or("SetSenseGloss: value not set")?;
+            .ok_or_else(|| QsError::InvalidCommand("SetSenseGloss: value not set".to_string()))?;
         let data = serde_json::to_string(&json!({
             "glosses": {
                 language: {"language": language, "value": value}
@@ -518,7 +549,7 @@ impl QuickStatementsCommand {
         }))
     }
 
-    fn action_create_entity(&self) -> Result<Value, String> {
+    fn action_create_entity(&self) -> QsResult<Value> {
         let data = match &self.json["data"].as_object() {
             Some(_) => match serde_json::to_string(&self.json["data"]) {
                 Ok(s) => s,
@@ -528,13 +559,15 @@ impl QuickStatementsCommand {
         };
         let new_type = match self.json["type"].as_str() {
             Some(t) => t,
-            None => return Err("No type set".to_string()),
+            None => return Err(QsError::InvalidCommand("No type set".to_string())),
         };
 
         // Forms and senses use specialized API actions
         match new_type {
             "form" => {
-                let item = self.json["item"].as_str().ok_or("ADD_FORM: item not set")?;
+                let item = self.json["item"]
+                    .as_str()
+                    .ok_or_else(|| QsError::InvalidCommand("ADD_FORM: item not set".to_string()))?;
                 Ok(json!({
                     "action": "wbladdform",
                     "lexemeId": item,
@@ -542,9 +575,9 @@ impl QuickStatementsCommand {
                 }))
             }
             "sense" => {
-                let item = self.json["item"]
-                    .as_str()
-                    .ok_or("ADD_SENSE: item not set")?;
+                let item = self.json["item"].as_str().ok_or_else(|| {
+                    QsError::InvalidCommand("ADD_SENSE: item not set".to_string())
+                })?;
                 Ok(json!({
                     "action": "wbladdsense",
                     "lexemeId": item,
@@ -559,15 +592,15 @@ impl QuickStatementsCommand {
         }
     }
 
-    fn action_merge_entities(&self) -> Result<Value, String> {
+    fn action_merge_entities(&self) -> QsResult<Value> {
         self.is_valid_command()?;
         let item1 = match self.json["item1"].as_str() {
             Some(t) => t,
-            None => return Err("item1 not set".to_string()),
+            None => return Err(QsError::InvalidCommand("item1 not set".to_string())),
         };
         let item2 = match self.json["item2"].as_str() {
             Some(t) => t,
-            None => return Err("item2 not set".to_string()),
+            None => return Err(QsError::InvalidCommand("item2 not set".to_string())),
         };
 
         Ok(json!({
@@ -578,7 +611,7 @@ impl QuickStatementsCommand {
         }))
     }
 
-    fn add_to_entity(&mut self, item: &Option<wikibase::Entity>) -> Result<Value, String> {
+    fn add_to_entity(&mut self, item: &Option<wikibase::Entity>) -> QsResult<Value> {
         // Lexeme commands that don't require loading an entity
         match self.json["what"].as_str() {
             Some("lemma") => return self.action_set_lemma(),
@@ -592,7 +625,7 @@ impl QuickStatementsCommand {
 
         let item = item
             .as_ref()
-            .ok_or("add_to_entity: item is None".to_string())?;
+            .ok_or_else(|| QsError::EntityNotFound("add_to_entity: item is None".to_string()))?;
         match self.json["what"].as_str() {
             Some("label") => self.action_set_label(item),
             Some("alias") => self.action_add_alias(item),
@@ -601,47 +634,138 @@ impl QuickStatementsCommand {
             Some("statement") => self.action_add_statement(item),
             Some("qualifier") => self.action_add_qualifier(item),
             Some("sources") => self.action_add_sources(item),
-            other => Err(format!("Bad 'what': '{:?}'", other)),
+            other => Err(QsError::InvalidCommand(format!(
+                "Bad 'what': '{:?}'",
+                other
+            ))),
         }
     }
 
-    fn remove_from_entity(&self, item: &Option<wikibase::Entity>) -> Result<Value, String> {
-        let item = item
-            .as_ref()
-            .ok_or("remove_from_entity: item is None".to_string())?;
+    fn remove_from_entity(&self, item: &Option<wikibase::Entity>) -> QsResult<Value> {
+        let item = item.as_ref().ok_or_else(|| {
+            QsError::EntityNotFound("remove_from_entity: item is None".to_string())
+        })?;
         match self.json["what"].as_str() {
             Some("statement") => {
                 let statement_id = match self.get_statement_id(item)? {
                     Some(id) => id,
-                    None => return Err("remove_statement: Statement not found".to_string()),
+                    None => {
+                        return Err(QsError::StatementNotFound(
+                            "remove_statement: Statement not found".to_string(),
+                        ))
+                    }
                 };
                 self.action_remove_statement(statement_id)
             }
             Some("sitelink") => self.action_remove_sitelink(item),
-            other => Err(format!("Bad 'what': '{:?}'", other)),
+            other => Err(QsError::InvalidCommand(format!(
+                "Bad 'what': '{:?}'",
+                other
+            ))),
         }
     }
 
-    pub fn get_action(&self) -> Result<String, String> {
+    pub fn get_action(&self) -> QsResult<String> {
         let cj = self.json["action"].clone();
         match cj.as_str() {
-            None => Err("No action in command".to_string()),
-            Some("") => Err("Empty action in command".to_string()),
+            None => Err(QsError::InvalidCommand("No action in command".to_string())),
+            Some("") => Err(QsError::InvalidCommand(
+                "Empty action in command".to_string(),
+            )),
             Some(s) => Ok(s.to_string()),
         }
     }
 
-    pub fn action_to_execute(
-        &mut self,
-        main_item: &Option<wikibase::Entity>,
-    ) -> Result<Value, String> {
+    pub fn action_to_execute(&mut self, main_item: &Option<wikibase::Entity>) -> QsResult<Value> {
         match self.get_action()?.as_str() {
             "add" => self.add_to_entity(main_item),
             "create" => self.action_create_entity(),
             "merge" => self.action_merge_entities(),
             "remove" => self.remove_from_entity(main_item),
-            other => Err(format!("Unknown action '{}'", &other)),
+            other => Err(QsError::InvalidCommand(format!(
+                "Unknown action '{}'",
+                &other
+            ))),
+        }
+    }
+
+    /// Checks that the command has the fields `action_to_execute` needs for
+    /// its action, without loading any entity
+    pub fn validate(&self) -> QsResult<()> {
+        self.is_valid_command()?;
+        let action = self.get_action()?;
+        match (action.as_str(), self.json["what"].as_str()) {
+            ("create", _) => match self.json["type"].as_str() {
+                Some("form") | Some("sense") => self.require_strings(&["item"]),
+                Some(_) => Ok(()),
+                None => Err(QsError::InvalidCommand("No type set".to_string())),
+            },
+            ("merge", _) => self.require_strings(&["item1", "item2"]),
+            ("add", Some("lemma" | "representation" | "gloss"))
+            | ("add", Some("label" | "description" | "alias")) => {
+                self.require_strings(&["item", "language", "value"])
+            }
+            ("add", Some("lexical_category" | "language")) => {
+                self.require_strings(&["item", "value"])
+            }
+            ("add", Some("grammatical_feature")) => {
+                self.require_strings(&["item"])?;
+                match self.json["value"].is_array() {
+                    true => Ok(()),
+                    false => Err(QsError::InvalidCommand("value is not an array".to_string())),
+                }
+            }
+            ("add", Some("sitelink")) => self.require_strings(&["item", "site", "value"]),
+            ("remove", Some("sitelink")) => self.require_strings(&["item", "site"]),
+            ("add", Some("statement")) | ("remove", Some("statement")) => self.validate_statement(),
+            ("add", Some("qualifier")) => {
+                self.validate_statement()?;
+                self.validate_snak(&self.json["qualifier"])
+            }
+            ("add", Some("sources")) => {
+                self.validate_statement()?;
+                let sources = self.json["sources"].as_array().ok_or_else(|| {
+                    QsError::InvalidCommand("Incomplete command parameters: sources".to_string())
+                })?;
+                sources
+                    .iter()
+                    .try_for_each(|source| self.validate_snak(source))
+            }
+            ("add", other) | ("remove", other) => Err(QsError::InvalidCommand(format!(
+                "Bad 'what': '{:?}'",
+                other
+            ))),
+            (other, _) => Err(QsError::InvalidCommand(format!(
+                "Unknown action '{}'",
+                &other
+            ))),
+        }
+    }
+
+    fn require_strings(&self, keys: &[&str]) -> QsResult<()> {
+        match keys.iter().find(|key| !self.json[**key].is_string()) {
+            Some(key) => Err(QsError::InvalidCommand(format!("{} not set", key))),
+            None => Ok(()),
+        }
+    }
+
+    /// A statement is given by its ID, or by entity, property and value
+    fn validate_statement(&self) -> QsResult<()> {
+        if self.json["id"].is_string() {
+            return Ok(());
         }
+        self.require_strings(&["item", "property"])?;
+        self.get_snak_type_for_datavalue(&self.json["datavalue"])
+            .map(|_| ())
+    }
+
+    /// A qualifier or source: `{"prop":"P123","value":{datavalue}}`
+    fn validate_snak(&self, snak: &Value) -> QsResult<()> {
+        let prop = snak["prop"].as_str().ok_or_else(|| {
+            QsError::InvalidCommand("Incomplete command parameters: prop".to_string())
+        })?;
+        self.check_prop(prop)?;
+        self.get_snak_type_for_datavalue(&snak["value"]).map(|_| ())
     }
 
     fn is_same_datavalue(&self, dv1: &wikibase::DataValue, dv2: &Value) -> Option<bool> {
@@ -695,7 +819,7 @@ impl QuickStatementsCommand {
         s.to_string() // TODO necessary?
     }
 
-    fn get_snak_type_for_datavalue(&self, dv: &Value) -> Result<String, String> {
+    fn get_snak_type_for_datavalue(&self, dv: &Value) -> QsResult<String> {
         // The datavalue type field is authoritative when present
         if let Some(t) = dv["type"].as_str() {
             let ret = match t {
@@ -712,12 +836,17 @@ impl QuickStatementsCommand {
             Some("novalue") => "novalue",
             Some("somevalue") => "somevalue",
             Some(_) => "value",
-            None => return Err(format!("Cannot determine snak type: {}", dv)),
+            None => {
+                return Err(QsError::InvalidCommand(format!(
+                    "Cannot determine snak type: {}",
+                    dv
+                )))
+            }
         };
         Ok(ret.to_string())
     }
 
-    fn get_statement_id(&self, item: &wikibase::Entity) -> Result<Option<String>, String> {
+    fn get_statement_id(&self, item: &wikibase::Entity) -> QsResult<Option<String>> {
         // Try directly by statement ID, as string
         if let Some(id) = self.json["id"].as_str() {
             return Ok(Some(id.to_string()));
@@ -727,10 +856,10 @@ impl QuickStatementsCommand {
         let property = match self.json["property"].as_str() {
             Some(p) => p,
             None => {
-                return Err(
+                return Err(QsError::InvalidCommand(
                     "QuickStatementsCommand::get_statement_id: Property expected but not set"
                         .to_string(),
-                )
+                ))
             }
         };
 
@@ -764,14 +893,17 @@ impl QuickStatementsCommand {
         Ok(None)
     }
 
-    fn check_prop(&self, s: &str) -> Result<String, String> {
+    fn check_prop(&self, s: &str) -> QsResult<String> {
         static RE_PROP: LazyLock<Regex> = LazyLock::new(|| {
             Regex::new(r#"^P\d+$"#)
                 .expect("QuickStatementsBot::check_prop:RE_PROP does not compile")
         });
         match RE_PROP.is_match(s) {
             true => Ok(s.to_string()),
-            false => Err(format!("'{}' is not a property", &s)),
+            false => Err(QsError::InvalidCommand(format!(
+                "'{}' is not a property",
+                &s
+            ))),
         }
     }
 
@@ -808,10 +940,10 @@ mod tests {
     #[test]
     fn check_prop() {
         let c = QuickStatementsCommand::new_from_json(&json!({}));
-        assert_eq!(c.check_prop("P12345"), Ok("P12345".to_string()));
+        assert_eq!(c.check_prop("P12345").unwrap(), "P12345".to_string());
         assert_eq!(
-            c.check_prop("xP12345"),
-            Err("'xP12345' is not a property".to_string())
+            c.check_prop("xP12345").unwrap_err().to_string(),
+            "Invalid command: 'xP12345' is not a property".to_string()
         );
     }
 
@@ -841,15 +973,16 @@ mod tests {
     fn action_remove_statement() {
         let c = QuickStatementsCommand::new_from_json(&json!({}));
         assert_eq!(
-            c.action_remove_statement("dummy_statement_id".to_string()),
-            Ok(json!({"action":"wbremoveclaims","claim":"dummy_statement_id"}))
+            c.action_remove_statement("dummy_statement_id".to_string())
+                .unwrap(),
+            json!({"action":"wbremoveclaims","claim":"dummy_statement_id"})
         );
     }
 
     #[test]
     fn already_done() {
         let c = QuickStatementsCommand::new_from_json(&json!({}));
-        assert_eq!(c.already_done(), Ok(json!({"already_done":1})));
+        assert_eq!(c.already_done().unwrap(), json!({"already_done":1}));
     }
 
     #[test]
@@ -857,13 +990,13 @@ mod tests {
         let c =
             QuickStatementsCommand::new_from_json(&json!({"site":"enwiki","value":"Jimbo_Wales"}));
         assert_eq!(
-            c.action_set_sitelink(&empty_test_item()),
-            Ok(json!({
+            c.action_set_sitelink(&empty_test_item()).unwrap(),
+            json!({
                 "action":"wbsetsitelink",
                 "id":"Q12345",
                 "linksite":"enwiki",
                 "linktitle":"Jimbo_Wales",
-            }))
+            })
         );
     }
 
@@ -873,13 +1006,13 @@ mod tests {
         let mut item = empty_test_item();
         item.set_sitelink(wikibase::SiteLink::new("enwiki", "Jimbo_Wales", vec![]));
         assert_eq!(
-            c.action_remove_sitelink(&item),
-            Ok(json!({
+            c.action_remove_sitelink(&item).unwrap(),
+            json!({
                 "action":"wbsetsitelink",
                 "id":"Q12345",
                 "linksite":"enwiki",
                 "linktitle":"",
-            }))
+            })
         );
     }
 
@@ -888,13 +1021,13 @@ mod tests {
         let c =
             QuickStatementsCommand::new_from_json(&json!({"language":"it","value":"Dummy text"}));
         assert_eq!(
-            c.action_set_label(&empty_test_item()),
-            Ok(json!({
+            c.action_set_label(&empty_test_item()).unwrap(),
+            json!({
                 "action":"wbsetlabel",
                 "id":"Q12345",
                 "language":"it",
                 "value":"Dummy text",
-            }))
+            })
         );
     }
 
@@ -903,13 +1036,13 @@ mod tests {
         let c =
             QuickStatementsCommand::new_from_json(&json!({"language":"it","value":"Dummy text"}));
         assert_eq!(
-            c.action_set_description(&empty_test_item()),
-            Ok(json!({
+            c.action_set_description(&empty_test_item()).unwrap(),
+            json!({
                 "action":"wbsetdescription",
                 "id":"Q12345",
                 "language":"it",
                 "value":"Dummy text",
-            }))
+            })
         );
     }
 
@@ -918,13 +1051,13 @@ mod tests {
         let c =
             QuickStatementsCommand::new_from_json(&json!({"language":"it","value":"Dummy text"}));
         assert_eq!(
-            c.action_add_alias(&empty_test_item()),
-            Ok(json!({
+            c.action_add_alias(&empty_test_item()).unwrap(),
+            json!({
                 "action":"wbsetaliases",
                 "id":"Q12345",
                 "language":"it",
                 "add":"Dummy text",
-            }))
+            })
         );
     }
 
@@ -932,8 +1065,8 @@ mod tests {
     fn action_create_entity_without_data() {
         let c = QuickStatementsCommand::new_from_json(&json!({"type":"item"}));
         assert_eq!(
-            c.action_create_entity(),
-            Ok(json!({"action":"wbeditentity","new":"item","data":"{}"}))
+            c.action_create_entity().unwrap(),
+            json!({"action":"wbeditentity","new":"item","data":"{}"})
         );
     }
 
@@ -941,8 +1074,8 @@ mod tests {
     fn action_create_entity_with_data() {
         let c = QuickStatementsCommand::new_from_json(&json!({"type":"item","data":{"k":"v"}}));
         assert_eq!(
-            c.action_create_entity(),
-            Ok(json!({"action":"wbeditentity","new":"item","data":"{\"k\":\"v\"}"}))
+            c.action_create_entity().unwrap(),
+            json!({"action":"wbeditentity","new":"item","data":"{\"k\":\"v\"}"})
         );
     }
 
@@ -950,13 +1083,13 @@ mod tests {
     fn action_merge_entities() {
         let c = QuickStatementsCommand::new_from_json(&json!({"item1":"Q123","item2":"Q456"}));
         assert_eq!(
-            c.action_merge_entities(),
-            Ok(json!({
+            c.action_merge_entities().unwrap(),
+            json!({
                 "action":"wbmergeitems",
                 "fromid":"Q123",
                 "toid":"Q456",
                 "ignoreconflicts":"description"
-            }))
+            })
         );
     }
 
@@ -1066,39 +1199,43 @@ mod tests {
     fn get_snak_type_for_datavalue() {
         let c = QuickStatementsCommand::new_from_json(&json!({}));
         assert_eq!(
-            c.get_snak_type_for_datavalue(&json!({"value":{}})),
-            Ok("value".to_string())
+            c.get_snak_type_for_datavalue(&json!({"value":{}})).unwrap(),
+            "value".to_string()
         );
         assert_eq!(
-            c.get_snak_type_for_datavalue(&json!({"value":"novalue"})),
-            Ok("novalue".to_string())
+            c.get_snak_type_for_datavalue(&json!({"value":"novalue"}))
+                .unwrap(),
+            "novalue".to_string()
         );
         assert_eq!(
-            c.get_snak_type_for_datavalue(&json!({"value":"somevalue"})),
-            Ok("somevalue".to_string())
+            c.get_snak_type_for_datavalue(&json!({"value":"somevalue"}))
+                .unwrap(),
+            "somevalue".to_string()
         );
         assert_eq!(
-            c.get_snak_type_for_datavalue(&json!({"value":"foobar"})),
-            Ok("value".to_string())
+            c.get_snak_type_for_datavalue(&json!({"value":"foobar"}))
+                .unwrap(),
+            "value".to_string()
         );
         let dv = json!({"foo":"bar"});
         assert_eq!(
-            c.get_snak_type_for_datavalue(&dv),
-            Err(format!("Cannot determine snak type: {}", &dv))
+            c.get_snak_type_for_datavalue(&dv).unwrap_err().to_string(),
+            format!("Invalid command: Cannot determine snak type: {}", &dv)
         );
     }
 
     #[test]
     fn from_row() {
-        let row = (
-            1_i64,
-            2_i64,
-            3_i64,
-            r#"{"action":"add"}"#.to_string(),
-            "INIT".to_string(),
-            "some message".to_string(),
-            "20230101120000".to_string(),
-        );
+        let row = CommandRow {
+            id: 1,
+            batch_id: 2,
+            num: 3,
+            json: r#"{"action":"add"}"#.to_string(),
+            status: "INIT".to_string(),
+            message: "some message".to_string(),
+            ts_change: "20230101120000".to_string(),
+            error_code: String::new(),
+        };
         let cmd = QuickStatementsCommand::from_row(&row);
         assert_eq!(cmd.id, 1);
         assert_eq!(cmd.batch_id, 2);
@@ -1111,15 +1248,14 @@ mod tests {
 
     #[test]
     fn from_row_invalid_json() {
-        let row = (
-            1_i64,
-            2_i64,
-            3_i64,
-            "not valid json".to_string(),
-            "INIT".to_string(),
-            "".to_string(),
-            "".to_string(),
-        );
+        let row = CommandRow {
+            id: 1,
+            batch_id: 2,
+            num: 3,
+            json: "not valid json".to_string(),
+            status: "INIT".to_string(),
+            ..Default::default()
+        };
         let cmd = QuickStatementsCommand::from_row(&row);
         assert_eq!(cmd.json, json!({}));
     }
@@ -1130,15 +1266,14 @@ mod tests {
     #[test]
     fn from_row_non_object_json() {
         for bad in ["[]", "\"foo\"", "42", "null"] {
-            let row = (
-                1_i64,
-                2_i64,
-                3_i64,
-                bad.to_string(),
-                "INIT".to_string(),
-                "".to_string(),
-                "".to_string(),
-            );
+            let row = CommandRow {
+                id: 1,
+                batch_id: 2,
+                num: 3,
+                json: bad.to_string(),
+                status: "INIT".to_string(),
+                ..Default::default()
+            };
             let cmd = QuickStatementsCommand::from_row(&row);
             assert_eq!(cmd.json, json!({}), "for input {}", bad);
         }
@@ -1171,7 +1306,7 @@ mod tests {
     #[test]
     fn get_action_ok() {
         let c = QuickStatementsCommand::new_from_json(&json!({"action":"add"}));
-        assert_eq!(c.get_action(), Ok("add".to_string()));
+        assert_eq!(c.get_action().unwrap(), "add".to_string());
     }
 
     #[test]
@@ -1183,7 +1318,10 @@ mod tests {
     #[test]
     fn get_action_empty() {
         let c = QuickStatementsCommand::new_from_json(&json!({"action":""}));
-        assert_eq!(c.get_action(), Err("Empty action in command".to_string()));
+        assert_eq!(
+            c.get_action().unwrap_err().to_string(),
+            "Invalid command: Empty action in command".to_string()
+        );
     }
 
     #[test]
@@ -1205,6 +1343,42 @@ mod tests {
         assert_eq!(result.unwrap()["action"], "wbmergeitems");
     }
 
+    #[test]
+    fn validate_requires_fields_of_action() {
+        let valid = json!({"action":"add","what":"label","item":"Q1","language":"en","value":"x"});
+        assert!(QuickStatementsCommand::new_from_json(&valid)
+            .validate()
+            .is_ok());
+        let no_language = json!({"action":"add","what":"label","item":"Q1","value":"x"});
+        assert_eq!(
+            QuickStatementsCommand::new_from_json(&no_language)
+                .validate()
+                .unwrap_err()
+                .to_string(),
+            "Invalid command: language not set".to_string()
+        );
+        let bad_qualifier = json!({"action":"add","what":"qualifier","item":"Q1","property":"P31",
+            "datavalue":{"type":"string","value":"x"},"qualifier":{"prop":"Q5","value":{"value":"y"}}});
+        assert!(QuickStatementsCommand::new_from_json(&bad_qualifier)
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn validate_rejects_unknown_commands() {
+        for j in [
+            json!([]),
+            json!({"what":"label"}),
+            json!({"action":"fly"}),
+            json!({"action":"remove","what":"label","item":"Q1"}),
+            json!({"action":"create"}),
+        ] {
+            assert!(QuickStatementsCommand::new_from_json(&j)
+                .validate()
+                .is_err());
+        }
+    }
+
     #[test]
     fn action_to_execute_unknown_action() {
         let mut c = QuickStatementsCommand::new_from_json(&json!({"action":"unknown_action"}));
@@ -1247,6 +1421,24 @@ mod tests {
         assert_eq!(result.unwrap()["action"], "wbsetaliases");
     }
 
+    #[test]
+    fn action_to_execute_add_existing_alias_is_already_done() {
+        let mut c = QuickStatementsCommand::new_from_json(
+            &json!({"action":"add","what":"alias","language":"en","value":"Test Alias"}),
+        );
+        let item = wikibase::Entity::new_item(
+            "Q12345".to_string(),
+            vec![],
+            vec![],
+            vec![wikibase::LocaleString::new("en", "Test Alias")],
+            vec![],
+            None,
+            false,
+        );
+        let result = c.action_to_execute(&Some(item));
+        assert_eq!(result.unwrap(), json!({"already_done":1}));
+    }
+
     #[test]
     fn action_to_execute_add_sitelink() {
         let mut c = QuickStatementsCommand::new_from_json(
@@ -1274,7 +1466,10 @@ mod tests {
         let c = QuickStatementsCommand::new_from_json(
             &json!({"language":"en","value":"Existing Label"}),
         );
-        assert_eq!(c.action_set_label(&item), Ok(json!({"already_done":1})));
+        assert_eq!(
+            c.action_set_label(&item).unwrap(),
+            json!({"already_done":1})
+        );
     }
 
     #[test]
@@ -1297,8 +1492,8 @@ mod tests {
             &json!({"language":"en","value":"Existing Desc"}),
         );
         assert_eq!(
-            c.action_set_description(&item),
-            Ok(json!({"already_done":1}))
+            c.action_set_description(&item).unwrap(),
+            json!({"already_done":1})
         );
     }
 
@@ -1320,7 +1515,10 @@ mod tests {
         item.set_sitelink(wikibase::SiteLink::new("enwiki", "Test Page", vec![]));
         let c =
             QuickStatementsCommand::new_from_json(&json!({"site":"enwiki","value":"Test Page"}));
-        assert_eq!(c.action_set_sitelink(&item), Ok(json!({"already_done":1})));
+        assert_eq!(
+            c.action_set_sitelink(&item).unwrap(),
+            json!({"already_done":1})
+        );
     }
 
     #[test]
@@ -1329,7 +1527,10 @@ mod tests {
         item.set_sitelink(wikibase::SiteLink::new("enwiki", "Test Page", vec![]));
         let c =
             QuickStatementsCommand::new_from_json(&json!({"site":"enwiki","value":"Test_Page"}));
-        assert_eq!(c.action_set_sitelink(&item), Ok(json!({"already_done":1})));
+        assert_eq!(
+            c.action_set_sitelink(&item).unwrap(),
+            json!({"already_done":1})
+        );
     }
 
     #[test]
@@ -1372,8 +1573,9 @@ mod tests {
     fn get_snak_type_for_datavalue_object() {
         let c = QuickStatementsCommand::new_from_json(&json!({}));
         assert_eq!(
-            c.get_snak_type_for_datavalue(&json!({"value":{"key":"val"}})),
-            Ok("value".to_string())
+            c.get_snak_type_for_datavalue(&json!({"value":{"key":"val"}}))
+                .unwrap(),
+            "value".to_string()
         );
     }
 
@@ -1387,8 +1589,8 @@ mod tests {
     #[test]
     fn check_prop_valid() {
         let c = QuickStatementsCommand::new_from_json(&json!({}));
-        assert_eq!(c.check_prop("P1"), Ok("P1".to_string()));
-        assert_eq!(c.check_prop("P999999"), Ok("P999999".to_string()));
+        assert_eq!(c.check_prop("P1").unwrap(), "P1".to_string());
+        assert_eq!(c.check_prop("P999999").unwrap(), "P999999".to_string());
     }
 
     #[test]
@@ -1909,7 +2111,7 @@ mod tests {
     fn get_statement_id_from_json_id() {
         let c = QuickStatementsCommand::new_from_json(&json!({"id":"Q42$some-guid-here"}));
         let result = c.get_statement_id(&empty_test_item());
-        assert_eq!(result, Ok(Some("Q42$some-guid-here".to_string())));
+        assert_eq!(result.unwrap(), Some("Q42$some-guid-here".to_string()));
     }
 
     #[test]
@@ -1926,7 +2128,7 @@ mod tests {
             "datavalue":{"type":"wikibase-entityid","value":{"entity-type":"item","id":"Q42"}}
         }));
         let result = c.get_statement_id(&empty_test_item());
-        assert_eq!(result, Ok(None));
+        assert_eq!(result.unwrap(), None);
     }
 
     #[test]
@@ -2202,8 +2404,8 @@ mod tests {
         // Without the flag, the existing statement (found via id) means already_done
         let c = QuickStatementsCommand::new_from_json(&base);
         assert_eq!(
-            c.action_add_statement(&empty_test_item()),
-            Ok(json!({"already_done":1}))
+            c.action_add_statement(&empty_test_item()).unwrap(),
+            json!({"already_done":1})
         );
 
         // With the flag, a new statement is created regardless
@@ -2270,13 +2472,15 @@ mod tests {
     fn get_snak_type_respects_type_field() {
         let c = QuickStatementsCommand::new_from_json(&json!({}));
         assert_eq!(
-            c.get_snak_type_for_datavalue(&json!({"type":"string","value":"novalue"})),
-            Ok("value".to_string())
+            c.get_snak_type_for_datavalue(&json!({"type":"string","value":"novalue"}))
+                .unwrap(),
+            "value".to_string()
         );
         // A novalue datavalue without a value key (PHP shape) is still detected
         assert_eq!(
-            c.get_snak_type_for_datavalue(&json!({"type":"novalue"})),
-            Ok("novalue".to_string())
+            c.get_snak_type_for_datavalue(&json!({"type":"novalue"}))
+                .unwrap(),
+            "novalue".to_string()
         );
     }
 
@@ -2285,8 +2489,8 @@ mod tests {
         let c = QuickStatementsCommand::new_from_json(&json!({"site":"enwiki","value":"Foo"}));
         // Item has no enwiki sitelink: removal is a no-op, not an API error
         assert_eq!(
-            c.action_remove_sitelink(&empty_test_item()),
-            Ok(json!({"already_done":1}))
+            c.action_remove_sitelink(&empty_test_item()).unwrap(),
+            json!({"already_done":1})
         );
     }
 
@@ -1,37 +1,78 @@
 use crate::error::{QsError, QsResult};
 use crate::qs_command::QuickStatementsCommand;
-use chrono::prelude::Utc;
+use crate::qs_metrics::Metrics;
+use crate::qs_oauth::{Credentials, OAuthClient};
+use crate::qs_rate_governor::RateGovernor;
+use crate::qs_scheduler::{next_occurrence, parse_recurrence, schedule, SchedulerConfig};
+use crate::qs_storage::{timestamp_after, OpenBatch, QsStorage};
+use crate::qs_storage_mysql::MysqlStorage;
+use crate::qs_storage_sqlite::SqliteStorage;
+use crate::qs_storage_timed::TimedStorage;
 use config::*;
 use log;
-use mysql_async as my;
-use mysql_async::from_row;
-use mysql_async::prelude::*;
 use serde_json::Value;
 use std::collections::{HashMap, HashSet};
 use std::fs::File;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
-use tokio::sync::RwLock;
+use tokio::sync::{Notify, RwLock};
 use wikibase::mediawiki::Api;
 
-/// Row layout of the `command` table: (id, batch_id, num, json, status, message, ts_change)
-type CommandRow = (i64, i64, i64, String, String, String, String);
-
 /// A batch that cannot be started (bad site, revoked OAuth, ...) goes back into
 /// the queue rather than being failed, so it must not be retried at full speed.
 /// Delay before the next attempt: base << failures, capped at max.
 const BATCH_START_RETRY_BASE_S: u64 = 60;
 const BATCH_START_RETRY_MAX_S: u64 = 1800;
 
+/// A batch stays leased to the bot process running it for this long after the
+/// last heartbeat; a crashed process's batches are reclaimed once it expires.
+/// Overridden by the `lease_s` config key.
+const DEFAULT_LEASE_S: u64 = 120;
+
+/// On SIGTERM/SIGINT, running bots get this long to finish their current
+/// command before their batches are released anyway. Overridden by the
+/// `shutdown_timeout_s` config key.
+const DEFAULT_SHUTDOWN_TIMEOUT_S: u64 = 60;
+
+/// Temporary batches from `import` with `temporary=1` can be loaded for this
+/// many days. Overridden by the `temporary_batch_days` config key.
+const DEFAULT_TEMPORARY_BATCH_DAYS: u64 = 7;
+
+/// Upper limit of `temporary_batch_days`
+const MAX_TEMPORARY_BATCH_DAYS: u64 = 365;
+
+/// The credentials stored for a batch in `batch_oauth`
+enum BatchOAuth {
+    OAuth1(wikibase::mediawiki::api::OAuthParams),
+    OAuth2(Credentials),
+}
+
 #[derive(Debug, Clone)]
 pub struct QuickStatements {
     params: Value,
-    pool: my::Pool,
+    storage: Arc<dyn QsStorage>,
     running_batch_ids: Arc<RwLock<HashSet<i64>>>,
     user_counter: Arc<RwLock<HashMap<i64, i64>>>,
     /// Batches that failed to start: batch_id -> (failures, earliest next attempt)
     start_cooldown: Arc<RwLock<HashMap<i64, (u32, Instant)>>>,
     max_batches_per_user: i64,
+    scheduler: SchedulerConfig,
+    rate_governor: Arc<RateGovernor>,
+    metrics: Arc<Metrics>,
+    /// The tool's OAuth consumer, if the `oauth` config key is set
+    oauth: Option<Arc<OAuthClient>>,
+    /// Identifies this process as the owner of batch leases in the database
+    instance_id: String,
+    lease_duration: Duration,
+    /// Running batches whose lease was taken over by another process
+    lost_leases: Arc<RwLock<HashSet<i64>>>,
+    /// Set once the process was asked to exit: no new batches are claimed
+    shutting_down: Arc<AtomicBool>,
+    /// Wakes up waits that a shutdown cuts short
+    shutdown_notify: Arc<Notify>,
+    /// Commands being executed: batch_id -> (command_id, start)
+    in_flight: Arc<RwLock<HashMap<i64, (i64, Instant)>>>,
     verbose: bool,
 }
 
@@ -56,13 +97,49 @@ impl QuickStatements {
         };
 
         let max_batches_per_user = params["max_batches_per_user"].as_i64().unwrap_or(2);
+        let scheduler = SchedulerConfig::new_from_json(&params["scheduler"]);
+        let rate_governor = Arc::new(RateGovernor::new_from_json(&params["site_limits"]));
+        let instance_id = params["instance_id"]
+            .as_str()
+            .map(|s| s.to_string())
+            .unwrap_or_else(Self::default_instance_id);
+        let lease_duration =
+            Duration::from_secs(params["lease_s"].as_u64().unwrap_or(DEFAULT_LEASE_S));
+        let metrics = Arc::new(Metrics::new());
+        let storage = match Self::create_storage(&params) {
+            Ok(storage) => Arc::new(TimedStorage::new(storage, metrics.clone())),
+            Err(e) => {
+                eprintln!("Cannot open storage: {}", e);
+                return None;
+            }
+        };
+        let oauth = match params.get("oauth") {
+            Some(j) => match OAuthClient::new_from_json(j) {
+                Ok(oauth) => Some(Arc::new(oauth)),
+                Err(e) => {
+                    eprintln!("Cannot set up OAuth: {}", e);
+                    return None;
+                }
+            },
+            None => None,
+        };
         let ret = Self {
-            pool: Self::create_mysql_pool(&params),
+            storage,
             params,
             running_batch_ids: Arc::new(RwLock::new(HashSet::new())),
             user_counter: Arc::new(RwLock::new(HashMap::new())),
             start_cooldown: Arc::new(RwLock::new(HashMap::new())),
             max_batches_per_user,
+            scheduler,
+            rate_governor,
+            metrics,
+            oauth,
+            instance_id,
+            lease_duration,
+            lost_leases: Arc::new(RwLock::new(HashSet::new())),
+            shutting_down: Arc::new(AtomicBool::new(false)),
+            shutdown_notify: Arc::new(Notify::new()),
+            in_flight: Arc::new(RwLock::new(HashMap::new())),
             verbose: false,
         };
         Some(ret)
@@ -76,6 +153,77 @@ impl QuickStatements {
         self.verbose
     }
 
+    /// Unique per process unless `instance_id` is set in the config, which must
+    /// then differ between all bots sharing a database
+    fn default_instance_id() -> String {
+        let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "localhost".to_string());
+        format!(
+            "{}:{}:{:08x}",
+            host,
+            std::process::id(),
+            rand::random::<u32>()
+        )
+    }
+
+    pub fn instance_id(&self) -> &str {
+        &self.instance_id
+    }
+
+    /// How often leases are renewed: often enough to survive a missed heartbeat
+    pub fn lease_heartbeat_interval(&self) -> Duration {
+        self.lease_duration / 3
+    }
+
+    /// Stops claiming new batches; running bots hand their batch back to the
+    /// queue once their current command is done
+    pub fn begin_shutdown(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        self.shutdown_notify.notify_waiters();
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    /// Sleeps for `duration`, or until a shutdown begins
+    pub async fn sleep_unless_shutting_down(&self, duration: Duration) {
+        // Created before checking the flag, so a shutdown in between wakes it
+        let shutdown = self.shutdown_notify.notified();
+        if self.is_shutting_down() {
+            return;
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(duration) => {}
+            _ = shutdown => {}
+        }
+    }
+
+    /// Port of the bot process's `/metrics`, `/healthz` and `/readyz`
+    /// endpoints; none if unset
+    pub fn status_port(&self) -> Option<u16> {
+        self.params["status_port"]
+            .as_u64()
+            .and_then(|p| u16::try_from(p).ok())
+    }
+
+    /// How long a shutdown waits for running bots
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(
+            self.params["shutdown_timeout_s"]
+                .as_u64()
+                .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_S),
+        )
+    }
+
+    /// How long temporary batches are kept
+    pub fn temporary_batch_lifetime(&self) -> Duration {
+        let days = self.params["temporary_batch_days"]
+            .as_u64()
+            .unwrap_or(DEFAULT_TEMPORARY_BATCH_DAYS)
+            .min(MAX_TEMPORARY_BATCH_DAYS);
+        Duration::from_secs(days * 24 * 3600)
+    }
+
     pub fn get_api_for_site(&self, site: &str) -> Option<&str> {
         self.params["config"]["sites"][site]["api"].as_str()
     }
@@ -90,19 +238,68 @@ impl QuickStatements {
         &self.params["config"]
     }
 
-    /// Get a database connection from the pool
-    pub async fn get_db_conn(&self) -> QsResult<my::Conn> {
-        Ok(self.pool.get_conn().await?)
+    /// Edit pacing shared by all bots editing the same site
+    pub fn rate_governor(&self) -> &RateGovernor {
+        &self.rate_governor
+    }
+
+    /// Prometheus metrics of this process
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// The OAuth consumer users log in with on the web server
+    pub fn oauth(&self) -> Option<&OAuthClient> {
+        self.oauth.as_deref()
+    }
+
+    /// The database backend, selected by the `storage` config key
+    pub fn storage(&self) -> &Arc<dyn QsStorage> {
+        &self.storage
     }
 
     /// Lightweight check: can we reach the database at all?
     pub async fn db_ping(&self) -> bool {
-        match self.pool.get_conn().await {
-            Ok(mut conn) => conn.query_drop("SELECT 1").await.is_ok(),
-            Err(_) => false,
+        self.storage.ping().await
+    }
+
+    /// Whether a wiki user may change all batches, by the `admins` config key
+    /// (a list of user names)
+    pub fn is_admin(&self, user_name: &str) -> bool {
+        let user_name = user_name.replace('_', " ");
+        self.params["admins"].as_array().is_some_and(|admins| {
+            admins
+                .iter()
+                .filter_map(|admin| admin.as_str())
+                .any(|admin| admin.replace('_', " ") == user_name)
+        })
+    }
+
+    /// The batch priority a user may set: admins any, everyone else at most
+    /// `scheduler.max_user_priority`
+    pub fn allowed_priority(&self, priority: i64, user_name: Option<&str>) -> i64 {
+        match user_name {
+            Some(user_name) if self.is_admin(user_name) => priority,
+            _ => priority.min(self.scheduler.max_user_priority),
         }
     }
 
+    /// Whether the session cookie is only sent over HTTPS, by the
+    /// `oauth.secure_cookie` config key (default true); false for a server
+    /// reached over plain HTTP, like a local one
+    pub fn secure_cookie(&self) -> bool {
+        self.params["oauth"]["secure_cookie"]
+            .as_bool()
+            .unwrap_or(true)
+    }
+
+    /// Whether requests without a login may change batches, by the
+    /// `open_batch_actions` config key; for a standalone server without OAuth.
+    /// Sessions and API tokens are always limited to their own batches.
+    pub fn open_batch_actions(&self) -> bool {
+        self.params["open_batch_actions"].as_bool().unwrap_or(false)
+    }
+
     pub fn edit_delay_ms(&self) -> Option<u64> {
         Some(self.params["edit_delay_ms"].as_u64().unwrap_or(1000))
     }
@@ -120,70 +317,66 @@ impl QuickStatements {
     /// Returns the site of a batch, or `Ok(None)` if the batch has no (usable) site.
     /// DB errors are propagated, so callers can tell "no site" from "DB down".
     pub async fn get_site_from_batch(&self, batch_id: i64) -> QsResult<Option<String>> {
-        let sql = r#"SELECT site FROM batch WHERE id=:batch_id"#;
-        let rows = self
-            .pool
-            .get_conn()
-            .await?
-            .exec_iter(sql, params! {batch_id})
-            .await?
-            .map_and_drop(from_row::<Option<String>>)
-            .await?;
-        Ok(rows.first().cloned().flatten().filter(|s| !s.is_empty()))
+        let batch = self.storage.get_batch(batch_id).await?;
+        Ok(batch.map(|b| b.site).filter(|s| !s.is_empty()))
     }
 
     pub async fn number_of_bots_running(&self) -> usize {
         self.running_batch_ids.read().await.len()
     }
 
+    pub async fn command_started(&self, batch_id: i64, command_id: i64) {
+        let mut in_flight = self.in_flight.write().await;
+        in_flight.insert(batch_id, (command_id, Instant::now()));
+    }
+
+    pub async fn command_finished(&self, batch_id: i64) {
+        self.in_flight.write().await.remove(&batch_id);
+    }
+
+    /// (batch_id, command_id, age) of the longest-running command in this process
+    pub async fn oldest_in_flight_command(&self) -> Option<(i64, i64, Duration)> {
+        self.in_flight
+            .read()
+            .await
+            .iter()
+            .min_by_key(|(_, (_, start))| *start)
+            .map(|(batch_id, (command_id, start))| (*batch_id, *command_id, start.elapsed()))
+    }
+
     pub fn timestamp(&self) -> String {
-        let now = Utc::now();
-        now.format("%Y%m%d%H%M%S").to_string()
+        crate::qs_storage::timestamp()
     }
 
     pub async fn restart_batch(&self, batch_id: i64) -> Option<()> {
-        let mut conn = self.pool.get_conn().await.ok()?;
-        let ts = self.timestamp();
-        // Only (re)start batches that are still INIT or RUN, so a user STOP issued
-        // between batch selection and this update is not overwritten.
-        conn.exec_drop(r#"UPDATE `batch` SET `status`="RUN",`message`="",`ts_last_change`=:ts WHERE id=:batch_id AND `status` IN ("INIT","RUN")"#, params!{ts,batch_id}).await.ok()?;
-        let ts = self.timestamp();
-        conn.exec_drop(r#"UPDATE `command` SET `status`="INIT",`message`="",`ts_change`=:ts WHERE `status` IN ("RUN","BLOCKED") AND `batch_id`=:batch_id"#, params!{ts,batch_id}).await.ok()
-    }
-
-    /// Puts batches left behind by a previous process back into the queue:
-    /// `RUN` ones this process now owns nothing of, and `ERROR` ones written by
-    /// older versions (batches are no longer ever set to ERROR).
+        self.storage.restart_batch(batch_id).await.ok()
+    }
+
+    /// Puts batches left behind by a crashed process back into the queue:
+    /// `RUN` ones whose lease has expired, and `ERROR` ones written by older
+    /// versions (batches are no longer ever set to ERROR). Batches leased by
+    /// other running bots are left alone.
     pub async fn reset_stale_batches(&self) -> QsResult<()> {
-        let mut conn = self.pool.get_conn().await?;
-        let ts = self.timestamp();
-        conn.exec_drop(r#"UPDATE `batch` SET `status`="INIT",`message`="",`ts_last_change`=:ts WHERE `status` IN ("RUN","ERROR")"#, params!{ts}).await?;
-        // Commands left mid-execution (status=RUN) are reset per batch by
-        // restart_batch() when the batch is picked up again; a global
-        // `UPDATE command WHERE status="RUN"` would full-scan the huge
-        // command table and exceed max_statement_time on ToolsDB.
-        Ok(())
+        self.storage.reset_stale_batches(&self.timestamp()).await
     }
 
-    pub async fn get_api_url(&self, batch_id: i64) -> Option<&str> {
-        let site: String = match self.get_site_from_batch(batch_id).await {
-            Ok(Some(site)) => site,
+    /// The site a batch edits: its own, or the configured default
+    pub async fn get_site_for_batch(&self, batch_id: i64) -> Option<String> {
+        match self.get_site_from_batch(batch_id).await {
+            Ok(Some(site)) => Some(site),
             // No/empty site set for this batch: use the configured default
-            Ok(None) => match self.params["config"]["site"].as_str() {
-                Some(s) => s.to_string(),
-                None => return None,
-            },
+            Ok(None) => self.default_site().map(|s| s.to_string()),
             // A DB error must not fall back to the default site — that could
             // run the batch against the wrong wiki
             Err(e) => {
-                log::error!(
-                    "get_api_url: cannot get site for batch #{}: {}",
-                    batch_id,
-                    e
-                );
-                return None;
+                log::error!(batch = batch_id; "Cannot get site for batch #{}: {}", batch_id, e);
+                None
             }
-        };
+        }
+    }
+
+    pub async fn get_api_url(&self, batch_id: i64) -> Option<&str> {
+        let site = self.get_site_for_batch(batch_id).await?;
         self.get_api_for_site(&site)
     }
 
@@ -202,39 +395,27 @@ impl QuickStatements {
         Ok(res["query"]["users"][0]["blockid"].is_number())
     }
 
-    fn create_mysql_pool(params: &Value) -> my::Pool {
-        if !params["mysql"].is_object() {
-            panic!("QuickStatementsConfig::create_mysql_pool: No mysql info in params");
+    /// `"storage": "sqlite"` (with `"sqlite": {"path": ...}`) selects the embedded
+    /// backend; anything else uses the `mysql` settings.
+    fn create_storage(params: &Value) -> QsResult<Arc<dyn QsStorage>> {
+        match params["storage"].as_str().unwrap_or("mysql") {
+            "sqlite" => {
+                let path = params["sqlite"]["path"]
+                    .as_str()
+                    .unwrap_or("quickstatements.sqlite");
+                Ok(Arc::new(SqliteStorage::open(path)?))
+            }
+            "mysql" => Ok(Arc::new(MysqlStorage::new_from_params(params))),
+            other => Err(QsError::ConfigError(format!("Unknown storage '{}'", other))),
         }
-        let port = params["mysql"]["port"].as_u64().unwrap_or(3306) as u16;
-        let host = params["mysql"]["host"].as_str().expect("No host");
-        let schema = params["mysql"]["schema"].as_str().expect("No schema");
-        let user = params["mysql"]["user"].as_str().expect("No user");
-        let pass = params["mysql"]["pass"].as_str().expect("No pass");
-        let opts = my::OptsBuilder::default()
-            .ip_or_hostname(host)
-            .db_name(Some(schema))
-            .user(Some(user))
-            .pass(Some(pass))
-            .tcp_port(port);
-
-        mysql_async::Pool::new(opts)
     }
 
     pub async fn get_last_item_from_batch(&self, batch_id: i64) -> Option<String> {
-        let sql = r#"SELECT last_item FROM batch WHERE `id`=:batch_id"#;
-        self.pool
-            .get_conn()
+        self.storage
+            .get_batch(batch_id)
             .await
             .ok()?
-            .exec_iter(sql, params! {batch_id})
-            .await
-            .ok()?
-            .map_and_drop(from_row::<String>)
-            .await
-            .ok()?
-            .first()
-            .cloned()
+            .map(|b| b.last_item)
     }
 
     /// Decode the raw DB value into a LastEntityState. Uses pipe-delimited format
@@ -249,80 +430,241 @@ impl QuickStatements {
         }
     }
 
-    /// Returns all batches that can be started right now, respecting per-user limits.
-    /// The returned batches are already claimed (running set + user counter), so the
-    /// caller must hand each one to a bot that eventually calls deactivate_batch_run().
+    /// Returns all batches that can be started right now, in scheduler order and
+    /// respecting per-user limits across all bot processes. The returned batches
+    /// are already claimed (running set + user counter + DB lease), so the caller
+    /// must hand each one to a bot that eventually calls deactivate_batch_run().
     pub async fn get_next_batches(&self) -> Vec<(i64, i64)> {
-        let sql =
-            "SELECT id,user FROM batch WHERE `status` IN ('INIT','RUN') ORDER BY `ts_last_change`";
-
-        let results: Vec<(i64, i64)> = match self.pool.get_conn().await {
-            Ok(mut conn) => match conn.exec_iter(sql, ()).await {
-                Ok(result) => result
-                    .map_and_drop(from_row::<(i64, i64)>)
-                    .await
-                    .unwrap_or_default(),
-                Err(e) => {
-                    log::error!("get_next_batches: query failed: {}", e);
-                    return vec![];
-                }
-            },
+        if self.is_shutting_down() {
+            return vec![];
+        }
+        let small_batch_commands = self.scheduler.small_batch_commands;
+        let now = self.timestamp();
+        let results = match self
+            .storage
+            .get_open_batches(small_batch_commands, &now)
+            .await
+        {
+            Ok(results) => results,
+            Err(e) => {
+                log::error!("get_next_batches: query failed: {}", e);
+                return vec![];
+            }
+        };
+        let leases = match self.storage.get_live_leases(&now).await {
+            Ok(leases) => leases,
             Err(e) => {
-                log::error!("get_next_batches: DB connection failed: {}", e);
+                log::error!("get_next_batches: lease query failed: {}", e);
                 return vec![];
             }
         };
 
-        self.claim_batches(results).await
+        // Batches run by other processes count towards their users' limits
+        let mut leased_elsewhere = HashSet::new();
+        let mut elsewhere_per_user: HashMap<i64, i64> = HashMap::new();
+        for (batch_id, user_id, owner) in leases {
+            if owner != self.instance_id {
+                leased_elsewhere.insert(batch_id);
+                *elsewhere_per_user.entry(user_id).or_insert(0) += 1;
+            }
+        }
+        let results = results
+            .into_iter()
+            .map(|b| OpenBatch {
+                site: self.site_or_default(b.site),
+                ..b
+            })
+            .collect::<Vec<_>>();
+
+        // Batches already running, here or elsewhere, only weigh on the schedule
+        // and on the per-site limits
+        let running_here = self.running_batch_ids.read().await.clone();
+        let (running, waiting): (Vec<_>, Vec<_>) = results
+            .into_iter()
+            .partition(|b| running_here.contains(&b.id) || leased_elsewhere.contains(&b.id));
+        let mut running_per_site: HashMap<String, usize> = HashMap::new();
+        for batch in &running {
+            *running_per_site.entry(batch.site.to_owned()).or_insert(0) += 1;
+        }
+        let candidates = schedule(&self.scheduler, waiting, &running);
+
+        let mut ret = vec![];
+        for (batch_id, user_id) in self
+            .claim_batches(candidates, &elsewhere_per_user, &running_per_site)
+            .await
+        {
+            // Another process may have leased it since we looked
+            match self.acquire_lease(batch_id).await {
+                Ok(true) => ret.push((batch_id, user_id)),
+                Ok(false) => {
+                    self.deactivate_batch_run(batch_id, user_id).await;
+                }
+                Err(e) => {
+                    log::error!(batch = batch_id; "Cannot lease batch #{}: {}", batch_id, e);
+                    self.deactivate_batch_run(batch_id, user_id).await;
+                }
+            }
+        }
+        ret
+    }
+
+    fn site_or_default(&self, site: String) -> String {
+        match site.is_empty() {
+            true => self.default_site().unwrap_or_default().to_string(),
+            false => site,
+        }
     }
 
     /// Claims candidate batches: adds them to the running set and increments the
     /// per-user counters, all under write locks. Claiming at selection time means
     /// every later deactivate_batch_run() balances exactly once — including for
-    /// batches that subsequently fail to start.
-    async fn claim_batches(&self, candidates: Vec<(i64, i64)>) -> Vec<(i64, i64)> {
+    /// batches that subsequently fail to start. `elsewhere_per_user` counts the
+    /// batches other processes run for each user, `running_per_site` all running
+    /// batches per site.
+    async fn claim_batches(
+        &self,
+        candidates: Vec<OpenBatch>,
+        elsewhere_per_user: &HashMap<i64, i64>,
+        running_per_site: &HashMap<String, usize>,
+    ) -> Vec<(i64, i64)> {
         let mut running = self.running_batch_ids.write().await;
         let mut user_counts = self.user_counter.write().await;
         let cooldown = self.start_cooldown.read().await;
         let now = Instant::now();
+        let mut site_counts = running_per_site.clone();
         let mut ret = vec![];
-        for (id, user_id) in candidates {
+        for OpenBatch {
+            id,
+            user: user_id,
+            site,
+            ..
+        } in candidates
+        {
             if running.contains(&id) {
                 continue;
             }
+            if self
+                .scheduler
+                .max_running_batches
+                .is_some_and(|max| running.len() >= max)
+            {
+                break;
+            }
             // Still cooling down after a failed start attempt
             if cooldown.get(&id).is_some_and(|(_, next)| *next > now) {
                 continue;
             }
+            let max_site_batches = self.rate_governor.limits(&site).max_batches;
+            let site_count = site_counts.entry(site.clone()).or_insert(0);
+            if max_site_batches.is_some_and(|max| *site_count >= max) {
+                continue;
+            }
             let cnt = user_counts.entry(user_id).or_insert(0);
-            if *cnt >= self.max_batches_per_user {
+            let elsewhere = elsewhere_per_user.get(&user_id).copied().unwrap_or(0);
+            if *cnt + elsewhere >= self.max_batches_per_user {
                 continue;
             }
             *cnt += 1;
+            *site_count += 1;
             running.insert(id);
+            self.metrics.batch_started(id, user_id, &site);
             ret.push((id, user_id));
         }
         ret
     }
 
+    async fn acquire_lease(&self, batch_id: i64) -> QsResult<bool> {
+        let expires = timestamp_after(self.lease_duration);
+        self.storage
+            .acquire_lease(batch_id, &self.instance_id, &self.timestamp(), &expires)
+            .await
+    }
+
+    /// Heartbeat: extends the leases on all batches this process runs. A lease
+    /// that was taken over (after this process stalled past its expiry) is
+    /// recorded as lost, so the bot running that batch stops.
+    pub async fn renew_leases(&self) {
+        let batch_ids: Vec<i64> = self
+            .running_batch_ids
+            .read()
+            .await
+            .iter()
+            .copied()
+            .collect();
+        for batch_id in batch_ids {
+            match self.acquire_lease(batch_id).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    log::warn!(batch = batch_id; "Lost the lease on batch #{}", batch_id);
+                    self.lost_leases.write().await.insert(batch_id);
+                }
+                Err(e) => {
+                    log::error!(batch = batch_id; "Cannot renew lease on batch #{}: {}", batch_id, e)
+                }
+            }
+        }
+    }
+
+    /// Fails once another process has taken over the batch
+    pub async fn check_lease(&self, batch_id: i64) -> QsResult<()> {
+        match self.lost_leases.read().await.contains(&batch_id) {
+            true => Err(QsError::LeaseLost(batch_id)),
+            false => Ok(()),
+        }
+    }
+
+    /// Frees a batch's slot and puts it back in the queue, unless the user
+    /// stopped it in the meantime; a stopped batch keeps its status.
+    pub async fn requeue_batch(&self, batch_id: i64, user_id: i64, message: &str) {
+        if self.check_batch_not_stopped(batch_id).await.is_ok() {
+            let _ = self
+                .set_batch_status("INIT", message, batch_id, user_id)
+                .await;
+        } else {
+            self.deactivate_batch_run(batch_id, user_id).await;
+        }
+    }
+
+    /// Requeues all batches this process still runs, for a shutdown whose bots
+    /// did not finish in time. Their commands left in RUN are reset when the
+    /// batch is started again.
+    pub async fn release_all_batches(&self, message: &str) {
+        let batch_ids: Vec<i64> = self
+            .running_batch_ids
+            .read()
+            .await
+            .iter()
+            .copied()
+            .collect();
+        for batch_id in batch_ids {
+            let user_id = match self.storage.get_batch(batch_id).await {
+                Ok(Some(batch)) => batch.user,
+                Ok(None) => 0,
+                Err(e) => {
+                    // The lease expires on its own, and another bot resets the batch
+                    log::error!(batch = batch_id; "Cannot release batch #{}: {}", batch_id, e);
+                    continue;
+                }
+            };
+            self.requeue_batch(batch_id, user_id, message).await;
+        }
+    }
+
     pub async fn reinitialize_open_batches(&self) -> Option<()> {
         // Legacy PHP-era batches (below this ID) must not be auto-reinitialized
         const MIN_AUTO_REINIT_BATCH_ID: i64 = 12000;
-        let sql = "UPDATE batch SET status='INIT' WHERE status='DONE' AND id IN (SELECT DISTINCT batch_id FROM command WHERE status='INIT' and batch_id>:min_id)";
-        self.pool
-            .get_conn()
-            .await
-            .ok()?
-            .exec_drop(sql, params! {"min_id" => MIN_AUTO_REINIT_BATCH_ID})
+        self.storage
+            .reinitialize_open_batches(MIN_AUTO_REINIT_BATCH_ID)
             .await
             .ok()
     }
 
     pub async fn set_batch_running(&self, batch_id: i64, user_id: i64) {
-        log::info!("Starting batch #{} for user {}", batch_id, user_id);
+        log::info!(batch = batch_id; "Starting batch #{} for user {}", batch_id, user_id);
 
         if self.reinitialize_open_batches().await.is_none() {
             log::warn!(
+                batch = batch_id;
                 "Failed to reinitialize open batches for batch #{}",
                 batch_id
             );
@@ -336,13 +678,26 @@ impl QuickStatements {
         );
     }
 
-    /// Removes a batch from the running set and frees its per-user slot.
-    /// Idempotent: only adjusts the user counter if the batch was actually running,
-    /// so multiple deactivations (e.g. BLOCKED then STOP) can't leak slots.
+    /// Removes a batch from the running set, frees its per-user slot and releases
+    /// its lease. Idempotent: only adjusts the user counter if the batch was
+    /// actually running, so multiple deactivations (e.g. BLOCKED then STOP) can't
+    /// leak slots.
     pub async fn deactivate_batch_run(&self, batch_id: i64, user_id: i64) -> Option<()> {
         if !self.running_batch_ids.write().await.remove(&batch_id) {
             return Some(());
         }
+        self.metrics.batch_stopped(batch_id);
+        self.in_flight.write().await.remove(&batch_id);
+        // Only releases a lease still held by this process; a lost one is
+        // left to its new owner
+        self.lost_leases.write().await.remove(&batch_id);
+        if let Err(e) = self
+            .storage
+            .release_lease(batch_id, &self.instance_id)
+            .await
+        {
+            log::error!(batch = batch_id; "Cannot release lease on batch #{}: {}", batch_id, e);
+        }
         // Read-modify-write under a single write lock, or concurrent
         // deactivations lose updates and leak user slots.
         {
@@ -375,26 +730,39 @@ impl QuickStatements {
         self.start_cooldown.write().await.remove(&batch_id);
     }
 
+    /// Sets the batch DONE, or, if it recurs, puts it back into the queue for
+    /// its next start
     pub async fn set_batch_finished(&self, batch_id: i64, user_id: i64) -> Option<()> {
-        log::info!("Batch #{} finished", batch_id);
-        self.set_batch_status("DONE", "", batch_id, user_id).await
+        log::info!(batch = batch_id; "Batch #{} finished", batch_id);
+        let batch = match self.storage.get_batch(batch_id).await {
+            Ok(batch) => batch,
+            Err(e) => {
+                // Left as it is; a later run finds nothing to do and tries again
+                log::error!(batch = batch_id; "Cannot read finished batch #{}: {}", batch_id, e);
+                self.deactivate_batch_run(batch_id, user_id).await;
+                return None;
+            }
+        };
+        let recurring = batch.and_then(|b| {
+            let interval = parse_recurrence(&b.recurrence)?;
+            next_occurrence(&b.ts_not_before, interval, chrono::Utc::now())
+        });
+        match recurring {
+            Some(next) => {
+                log::info!(batch = batch_id; "Batch #{} runs again at {}", batch_id, next);
+                self.deactivate_batch_run(batch_id, user_id).await;
+                self.storage.reschedule_batch(batch_id, &next).await.ok()
+            }
+            None => self.set_batch_status("DONE", "", batch_id, user_id).await,
+        }
     }
 
     pub async fn check_batch_not_stopped(&self, batch_id: i64) -> QsResult<()> {
-        let sql = r#"SELECT id FROM batch WHERE id=:batch_id AND `status` NOT IN ('RUN','INIT')"#;
-
-        let results = self
-            .pool
-            .get_conn()
-            .await?
-            .exec_iter(sql, params! {batch_id})
-            .await?
-            .map_and_drop(from_row::<usize>)
-            .await?;
-        if results.is_empty() {
-            Ok(())
-        } else {
-            Err(QsError::BatchStatusError(batch_id))
+        match self.storage.get_batch(batch_id).await? {
+            Some(batch) if !matches!(batch.status.as_str(), "RUN" | "INIT") => {
+                Err(QsError::BatchStatusError(batch_id))
+            }
+            _ => Ok(()),
         }
     }
 
@@ -407,79 +775,49 @@ impl QuickStatements {
     ) -> Option<()> {
         // Free the in-memory slot first, so a failing DB update cannot leak it
         self.deactivate_batch_run(batch_id, user_id).await;
-        let ts = self.timestamp();
-        let sql = r#"UPDATE `batch` SET `status`=:status,`message`=:message,`ts_last_change`=:ts WHERE id=:batch_id"#;
-        self.pool
-            .get_conn()
-            .await
-            .ok()?
-            .exec_drop(sql, params! {status,message,ts,batch_id})
+        self.storage
+            .set_batch_status(batch_id, status, Some(message))
             .await
             .ok()
     }
 
-    /// Runs a query against the `command` table and returns the first result.
     /// DB errors are propagated, so callers can tell "no command" from "DB down".
-    async fn fetch_first_command(
-        &self,
-        sql: &str,
-        params: my::Params,
-    ) -> QsResult<Option<QuickStatementsCommand>> {
-        let rows = self
-            .pool
-            .get_conn()
-            .await?
-            .exec_iter(sql, params)
-            .await?
-            .map_and_drop(from_row::<CommandRow>)
-            .await?;
-        Ok(rows.first().map(QuickStatementsCommand::from_row))
-    }
-
     pub async fn get_command_by_id(
         &self,
         command_id: i64,
     ) -> QsResult<Option<QuickStatementsCommand>> {
-        let sql = r#"SELECT id,batch_id,num,json,`status`,message,ts_change FROM command WHERE id=:command_id"#;
-        self.fetch_first_command(sql, params! {command_id}).await
+        let row = self.storage.get_command(command_id).await?;
+        Ok(row.as_ref().map(QuickStatementsCommand::from_row))
     }
 
     pub async fn get_next_command(
         &self,
         batch_id: i64,
     ) -> QsResult<Option<QuickStatementsCommand>> {
-        let sql = r#"SELECT id,batch_id,num,json,`status`,message,ts_change FROM command WHERE batch_id=:batch_id AND status IN ('INIT') ORDER BY num LIMIT 1"#;
-        self.fetch_first_command(sql, params! {batch_id}).await
+        let row = self.storage.get_next_command(batch_id).await?;
+        Ok(row.as_ref().map(QuickStatementsCommand::from_row))
     }
 
     pub async fn set_command_status(
         &self,
         command: &mut QuickStatementsCommand,
         new_status: &str,
-        new_message: Option<String>,
+        error: Option<&QsError>,
     ) -> Option<()> {
         let status = new_status.trim().to_uppercase();
-        let message = new_message.as_deref().unwrap_or("");
+        let message = error.map(|e| e.to_string()).unwrap_or_default();
+        let error_code = error.map(QsError::code).unwrap_or_default();
 
         // Keep the in-memory struct fields in sync with the JSON blob.
         command.status = status.clone();
-        command.message = message.to_string();
+        command.message = message.clone();
+        command.error_code = error_code.to_string();
         command.json["meta"]["status"] = json!(&status);
-        command.json["meta"]["message"] = json!(message);
+        command.json["meta"]["message"] = json!(&message);
 
         let json = serde_json::to_string(&command.json).unwrap_or_else(|_| "{}".to_string());
-
-        let command_id = command.id;
-        let ts = self.timestamp();
-        let sql = r#"UPDATE `command` SET `ts_change`=:ts,`json`=:json,`status`=:new_status,`message`=:message WHERE `id`=:command_id"#;
-        self.pool
-            .get_conn()
-            .await
-            .ok()?
-            .exec_drop(
-                sql,
-                params! {ts,json,"new_status"=>status,"message"=>message,"command_id"=>command_id},
-            )
+        self.storage
+            .set_command_status(command.id, &status, &message, error_code, &json)
             .await
             .ok()
     }
@@ -490,15 +828,7 @@ impl QuickStatements {
         last_item: &Option<String>,
     ) -> Option<()> {
         let last_item = last_item.as_deref().unwrap_or("");
-        let ts = self.timestamp();
-        let sql = r#"UPDATE `batch` SET `ts_last_change`=:ts,`last_item`=:last_item WHERE `id`=:batch_id"#;
-        self.pool
-            .get_conn()
-            .await
-            .ok()?
-            .exec_drop(sql, params! {ts,last_item,batch_id})
-            .await
-            .ok()
+        self.storage.set_last_item(batch_id, last_item).await.ok()
     }
 
     /// Persist a full LastEntityState (LAST + LAST_FORM + LAST_SENSE) to the DB.
@@ -512,64 +842,81 @@ impl QuickStatements {
     }
 
     pub async fn get_user_name(&self, user_id: i64) -> Option<String> {
-        let auth_db = "s53220__quickstatements_auth";
-        let sql = format!(r#"SELECT name FROM {}.user WHERE id=:user_id"#, auth_db);
-
-        let mut conn = match self.pool.get_conn().await {
-            Ok(c) => c,
-            Err(e) => {
-                log::error!(
-                    "get_user_name: cannot get DB conn for user {}: {}",
-                    user_id,
-                    e
-                );
-                return None;
-            }
-        };
-        let rows: Vec<String> = match conn.exec_iter(sql, params! {user_id}).await {
-            Ok(result) => result
-                .map_and_drop(from_row::<String>)
-                .await
-                .unwrap_or_default(),
+        match self.storage.get_user_name(user_id).await {
+            Ok(name) => name, // None if user not found (legitimate)
             Err(e) => {
                 log::error!("get_user_name: query failed for user {}: {}", user_id, e);
-                return None;
+                None
             }
-        };
-        rows.first().cloned() // None if user not found (legitimate)
+        }
     }
 
     /// Returns the OAuth credentials for a batch, or `Ok(None)` if the batch has none.
     /// DB and decoding errors are propagated: they must not be mistaken for "no OAuth",
     /// or the caller would silently fall back to the global bot account.
-    async fn get_oauth_for_batch(
-        &self,
-        batch_id: i64,
-    ) -> QsResult<Option<wikibase::mediawiki::api::OAuthParams>> {
-        let auth_db = "s53220__quickstatements_auth";
-        let sql = format!(
-            r#"SELECT serialized_json FROM {}.batch_oauth WHERE batch_id=:batch_id"#,
-            auth_db
-        );
-
-        let rows = self
-            .pool
-            .get_conn()
-            .await?
-            .exec_iter(sql, params! {batch_id})
-            .await?
-            .map_and_drop(from_row::<String>)
-            .await?;
-        let first = match rows.first() {
-            Some(first) => first,
+    async fn get_oauth_for_batch(&self, batch_id: i64) -> QsResult<Option<BatchOAuth>> {
+        let serialized = match self.storage.get_oauth_for_batch(batch_id).await? {
+            Some(serialized) => serialized,
             None => return Ok(None),
         };
-        let j = serde_json::from_str(first).map_err(|e| {
-            QsError::ConfigError(format!("Corrupt OAuth JSON for batch #{}: {}", batch_id, e))
+        Self::parse_batch_oauth(&serialized, &format!("batch #{}", batch_id)).map(Some)
+    }
+
+    /// Decodes a `batch_oauth` entry; `context` names its owner in errors
+    fn parse_batch_oauth(serialized: &str, context: &str) -> QsResult<BatchOAuth> {
+        let j = serde_json::from_str(serialized).map_err(|e| {
+            QsError::ConfigError(format!("Corrupt OAuth JSON for {}: {}", context, e))
         })?;
-        Ok(Some(wikibase::mediawiki::api::OAuthParams::new_from_json(
-            &j,
-        )))
+        Ok(match Credentials::from_batch_json(&j) {
+            Some(credentials) => BatchOAuth::OAuth2(credentials),
+            None => BatchOAuth::OAuth1(wikibase::mediawiki::api::OAuthParams::new_from_json(&j)),
+        })
+    }
+
+    async fn batch_has_user(&self, batch_id: i64) -> Result<bool, String> {
+        match self.storage.get_batch(batch_id).await {
+            Ok(batch) => Ok(batch.is_some_and(|batch| batch.user != 0)),
+            Err(e) => Err(format!("Cannot read batch #{}: {}", batch_id, e)),
+        }
+    }
+
+    /// Refreshes OAuth 2.0 credentials. The new tokens replace every stored
+    /// copy of the old ones (batches and API tokens of the same login), as the
+    /// wiki revokes those.
+    pub async fn refresh_oauth(&self, credentials: &Credentials) -> QsResult<Credentials> {
+        let oauth = self
+            .oauth()
+            .ok_or_else(|| QsError::ConfigError("OAuth is not configured".to_string()))?;
+        let refreshed = oauth.refresh(credentials).await?;
+        self.storage
+            .replace_oauth_credentials(
+                &oauth.batch_json(credentials).to_string(),
+                &oauth.batch_json(&refreshed).to_string(),
+            )
+            .await?;
+        Ok(refreshed)
+    }
+
+    /// The OAuth 2.0 access token, refreshed if it is about to expire
+    async fn oauth2_access_token(
+        &self,
+        context: &str,
+        credentials: Credentials,
+    ) -> QsResult<String> {
+        let credentials = if self.oauth.is_some() && credentials.needs_refresh() {
+            let refreshed = self.refresh_oauth(&credentials).await?;
+            log::info!("Refreshed OAuth 2.0 access token for {}", context);
+            refreshed
+        } else {
+            credentials
+        };
+        match credentials {
+            Credentials::V2 { access_token, .. } => Ok(access_token),
+            Credentials::V1 { .. } => Err(QsError::OAuthError(format!(
+                "No OAuth 2.0 token for {}",
+                context
+            ))),
+        }
     }
 
     pub async fn set_bot_api_auth(
@@ -577,23 +924,63 @@ impl QuickStatements {
         mw_api: &mut wikibase::mediawiki::api::Api,
         batch_id: i64,
     ) -> Result<(), String> {
-        let oauth_params = self
+        let oauth = self
             .get_oauth_for_batch(batch_id)
             .await
             .map_err(|e| format!("Cannot read OAuth for batch #{}: {}", batch_id, e))?;
-        match oauth_params {
-            Some(oauth_params) => {
+        if oauth.is_none() && self.oauth.is_some() && self.batch_has_user(batch_id).await? {
+            // The credentials are stored right after the batch; a batch a
+            // user submitted must never run as the bot account
+            return Err(format!(
+                "No OAuth credentials stored for batch #{}",
+                batch_id
+            ));
+        }
+        self.set_api_auth(mw_api, oauth, &format!("batch #{}", batch_id))
+            .await
+    }
+
+    /// Authenticates the API for a command run outside a batch, with the
+    /// `batch_oauth` entry of the user, or as the bot without one
+    pub async fn set_single_command_api_auth(
+        &self,
+        mw_api: &mut wikibase::mediawiki::api::Api,
+        serialized_json: Option<&str>,
+    ) -> Result<(), String> {
+        let context = "single command";
+        let oauth = serialized_json
+            .map(|serialized| Self::parse_batch_oauth(serialized, context))
+            .transpose()
+            .map_err(|e| e.to_string())?;
+        self.set_api_auth(mw_api, oauth, context).await
+    }
+
+    /// Sets the OAuth credentials on the API, or logs in as the bot from
+    /// `bot_config_file` without them; `context` names the edits in errors
+    async fn set_api_auth(
+        &self,
+        mw_api: &mut wikibase::mediawiki::api::Api,
+        oauth: Option<BatchOAuth>,
+        context: &str,
+    ) -> Result<(), String> {
+        match oauth {
+            Some(BatchOAuth::OAuth1(oauth_params)) => {
                 mw_api.set_oauth(Some(oauth_params));
                 Ok(())
             }
+            Some(BatchOAuth::OAuth2(credentials)) => {
+                let access_token = self
+                    .oauth2_access_token(context, credentials)
+                    .await
+                    .map_err(|e| format!("Cannot refresh OAuth for {}: {}", context, e))?;
+                mw_api.set_oauth2(&access_token);
+                Ok(())
+            }
             None => {
                 let filename = self.params["config"]["bot_config_file"]
                     .as_str()
                     .ok_or_else(|| {
-                        format!(
-                            "Neither OAuth nor bot info available for batch #{}",
-                            batch_id
-                        )
+                        format!("Neither OAuth nor bot info available for {}", context)
                     })?;
 
                 // Read config file off the async runtime to avoid blocking
@@ -623,20 +1010,41 @@ impl QuickStatements {
         }
     }
 
-    /// A QuickStatements with an unconnected pool, for testing DB-free methods.
+    /// A QuickStatements backed by a fresh in-memory SQLite database.
     #[cfg(test)]
     pub(crate) fn new_for_tests() -> Self {
-        let params = json!({"mysql":{"host":"127.0.0.1","schema":"s","user":"u","pass":"p"}});
+        let params = json!({"storage":"sqlite"});
         Self {
-            pool: Self::create_mysql_pool(&params),
+            storage: Arc::new(SqliteStorage::open_in_memory().expect("in-memory SQLite")),
             params,
             running_batch_ids: Arc::new(RwLock::new(HashSet::new())),
             user_counter: Arc::new(RwLock::new(HashMap::new())),
             start_cooldown: Arc::new(RwLock::new(HashMap::new())),
             max_batches_per_user: 2,
+            scheduler: SchedulerConfig::default(),
+            rate_governor: Arc::new(RateGovernor::default()),
+            metrics: Arc::new(Metrics::new()),
+            oauth: None,
+            instance_id: "test".to_string(),
+            lease_duration: Duration::from_secs(DEFAULT_LEASE_S),
+            lost_leases: Arc::new(RwLock::new(HashSet::new())),
+            shutting_down: Arc::new(AtomicBool::new(false)),
+            shutdown_notify: Arc::new(Notify::new()),
+            in_flight: Arc::new(RwLock::new(HashMap::new())),
             verbose: false,
         }
     }
+
+    #[cfg(test)]
+    pub(crate) fn set_param_for_tests(&mut self, key: &str, value: Value) {
+        self.params[key] = value;
+    }
+
+    #[cfg(test)]
+    pub(crate) fn set_oauth_for_tests(&mut self, oauth: Value) {
+        let client = OAuthClient::new_from_json(&oauth).expect("Bad OAuth test config");
+        self.oauth = Some(Arc::new(client));
+    }
 }
 
 #[cfg(test)]
@@ -698,6 +1106,18 @@ mod tests {
         QuickStatements::new_for_tests()
     }
 
+    fn open_batches(batches: &[(i64, i64)]) -> Vec<OpenBatch> {
+        batches
+            .iter()
+            .map(|(id, user)| OpenBatch {
+                id: *id,
+                user: *user,
+                site: "wikidata".to_string(),
+                ..Default::default()
+            })
+            .collect()
+    }
+
     // A batch that failed to start goes back into the queue, so it must be held
     // back for a while — otherwise a permanently broken batch is retried in a
     // tight loop
@@ -708,7 +1128,10 @@ mod tests {
         let delay = qs.note_batch_start_failure(42).await;
 
         assert_eq!(delay, Duration::from_secs(BATCH_START_RETRY_BASE_S));
-        assert!(qs.claim_batches(vec![(42, 1)]).await.is_empty());
+        assert!(qs
+            .claim_batches(open_batches(&[(42, 1)]), &HashMap::new(), &HashMap::new())
+            .await
+            .is_empty());
     }
 
     #[tokio::test]
@@ -739,7 +1162,11 @@ mod tests {
 
         qs.note_batch_start_success(42).await;
 
-        assert_eq!(qs.claim_batches(vec![(42, 1)]).await, vec![(42, 1)]);
+        assert_eq!(
+            qs.claim_batches(open_batches(&[(42, 1)]), &HashMap::new(), &HashMap::new())
+                .await,
+            vec![(42, 1)]
+        );
     }
 
     #[tokio::test]
@@ -770,7 +1197,13 @@ mod tests {
     #[tokio::test]
     async fn test_claim_batches_claims_and_counts() {
         let qs = test_qs();
-        let claimed = qs.claim_batches(vec![(10, 1), (11, 1), (20, 2)]).await;
+        let claimed = qs
+            .claim_batches(
+                open_batches(&[(10, 1), (11, 1), (20, 2)]),
+                &HashMap::new(),
+                &HashMap::new(),
+            )
+            .await;
 
         assert_eq!(claimed, vec![(10, 1), (11, 1), (20, 2)]);
         assert!(qs.running_batch_ids.read().await.contains(&10));
@@ -786,7 +1219,11 @@ mod tests {
 
         // Batch 10 is already running; user 1 may only get 2 of the remaining 3
         let claimed = qs
-            .claim_batches(vec![(10, 1), (11, 1), (12, 1), (13, 1)])
+            .claim_batches(
+                open_batches(&[(10, 1), (11, 1), (12, 1), (13, 1)]),
+                &HashMap::new(),
+                &HashMap::new(),
+            )
             .await;
 
         assert_eq!(claimed, vec![(11, 1), (12, 1)]);
@@ -798,7 +1235,9 @@ mod tests {
     #[tokio::test]
     async fn test_claim_then_deactivate_balances() {
         let qs = test_qs();
-        let claimed = qs.claim_batches(vec![(10, 1)]).await;
+        let claimed = qs
+            .claim_batches(open_batches(&[(10, 1)]), &HashMap::new(), &HashMap::new())
+            .await;
         assert_eq!(claimed, vec![(10, 1)]);
 
         qs.deactivate_batch_run(10, 1).await;
@@ -817,4 +1256,255 @@ mod tests {
 
         assert_eq!(*qs.user_counter.read().await.get(&1).unwrap(), 0);
     }
+
+    /// A second bot process on the same database
+    fn other_bot(qs: &QuickStatements) -> QuickStatements {
+        let mut other = test_qs();
+        other.storage = qs.storage.clone();
+        other.instance_id = "other".to_string();
+        other
+    }
+
+    async fn create_batches(qs: &QuickStatements, user_id: i64, count: usize) -> Vec<i64> {
+        let mut ret = vec![];
+        for _ in 0..count {
+            let batch_id = qs
+                .storage
+                .create_batch("test", user_id, "wikidata", &[], None)
+                .await
+                .unwrap();
+            ret.push(batch_id);
+        }
+        ret
+    }
+
+    #[tokio::test]
+    async fn test_batch_leased_by_another_bot_is_skipped() {
+        let qs = test_qs();
+        let other = other_bot(&qs);
+        let batch_ids = create_batches(&qs, 1, 1).await;
+
+        assert_eq!(qs.get_next_batches().await, vec![(batch_ids[0], 1)]);
+        assert!(other.get_next_batches().await.is_empty());
+
+        qs.deactivate_batch_run(batch_ids[0], 1).await;
+
+        assert_eq!(other.get_next_batches().await, vec![(batch_ids[0], 1)]);
+    }
+
+    #[tokio::test]
+    async fn test_user_limit_spans_bots() {
+        let qs = test_qs();
+        let other = other_bot(&qs);
+        create_batches(&qs, 1, 3).await;
+
+        assert_eq!(qs.get_next_batches().await.len(), 2);
+        assert!(other.get_next_batches().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_lost_lease_stops_batch() {
+        let qs = test_qs();
+        let batch_ids = create_batches(&qs, 1, 1).await;
+        qs.get_next_batches().await;
+        qs.check_lease(batch_ids[0]).await.unwrap();
+
+        // This bot stalled past the expiry, and another one took over
+        qs.storage
+            .acquire_lease(batch_ids[0], "other", "99991231235959", "99991231235959")
+            .await
+            .unwrap();
+        qs.renew_leases().await;
+
+        assert!(qs.check_lease(batch_ids[0]).await.is_err());
+        qs.deactivate_batch_run(batch_ids[0], 1).await;
+        assert_eq!(
+            qs.storage.get_live_leases(&qs.timestamp()).await.unwrap(),
+            vec![(batch_ids[0], 1, "other".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_stops_claiming_and_releases_batches() {
+        let qs = test_qs();
+        let batch_ids = create_batches(&qs, 1, 2).await;
+        assert_eq!(qs.get_next_batches().await.len(), 2);
+        for batch_id in &batch_ids {
+            qs.storage
+                .set_batch_status(*batch_id, "RUN", None)
+                .await
+                .unwrap();
+        }
+        qs.storage
+            .set_batch_status(batch_ids[1], "STOP", None)
+            .await
+            .unwrap();
+
+        qs.begin_shutdown();
+        qs.release_all_batches("Bot shut down").await;
+
+        assert_eq!(qs.number_of_bots_running().await, 0);
+        assert!(qs.get_next_batches().await.is_empty());
+        let mut statuses = vec![];
+        for batch_id in &batch_ids {
+            let batch = qs.storage.get_batch(*batch_id).await.unwrap().unwrap();
+            statuses.push(batch.status);
+        }
+        assert_eq!(statuses, vec!["INIT", "STOP"]);
+        let leases = qs.storage.get_live_leases(&qs.timestamp()).await;
+        assert!(leases.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_is_admin() {
+        let mut qs = test_qs();
+        assert!(!qs.is_admin("Magnus Manske"));
+
+        qs.params["admins"] = json!(["Magnus_Manske"]);
+        assert!(qs.is_admin("Magnus Manske"));
+        assert!(!qs.is_admin("Someone Else"));
+    }
+
+    #[test]
+    fn test_allowed_priority() {
+        let mut qs = test_qs();
+        qs.params["admins"] = json!(["Magnus Manske"]);
+        assert_eq!(qs.allowed_priority(5, Some("Someone Else")), 0);
+        assert_eq!(qs.allowed_priority(5, None), 0);
+        assert_eq!(qs.allowed_priority(-3, Some("Someone Else")), -3);
+        assert_eq!(qs.allowed_priority(5, Some("Magnus Manske")), 5);
+
+        qs.scheduler.max_user_priority = 2;
+        assert_eq!(qs.allowed_priority(5, Some("Someone Else")), 2);
+    }
+
+    #[test]
+    fn test_temporary_batch_lifetime_is_capped() {
+        let mut qs = test_qs();
+        assert_eq!(
+            qs.temporary_batch_lifetime(),
+            Duration::from_secs(DEFAULT_TEMPORARY_BATCH_DAYS * 86_400)
+        );
+
+        qs.params["temporary_batch_days"] = json!(u64::MAX);
+        assert_eq!(
+            qs.temporary_batch_lifetime(),
+            Duration::from_secs(MAX_TEMPORARY_BATCH_DAYS * 86_400)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_cuts_sleep_short() {
+        let qs = Arc::new(test_qs());
+        let sleeper = tokio::spawn({
+            let qs = qs.clone();
+            async move {
+                qs.sleep_unless_shutting_down(Duration::from_secs(600))
+                    .await
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        qs.begin_shutdown();
+        tokio::time::timeout(Duration::from_secs(5), sleeper)
+            .await
+            .expect("sleep did not end on shutdown")
+            .unwrap();
+
+        // Once shutting down, there is no sleep at all
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            qs.sleep_unless_shutting_down(Duration::from_secs(600)),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_oldest_in_flight_command() {
+        let qs = test_qs();
+        assert_eq!(qs.oldest_in_flight_command().await, None);
+
+        qs.command_started(1, 10).await;
+        qs.command_started(2, 20).await;
+        qs.command_finished(1).await;
+
+        let (batch_id, command_id, _) = qs.oldest_in_flight_command().await.unwrap();
+        assert_eq!((batch_id, command_id), (2, 20));
+    }
+
+    #[tokio::test]
+    async fn test_claim_batches_respects_max_running_batches() {
+        let mut qs = test_qs();
+        qs.scheduler.max_running_batches = Some(1);
+
+        let claimed = qs
+            .claim_batches(
+                open_batches(&[(10, 1), (20, 2)]),
+                &HashMap::new(),
+                &HashMap::new(),
+            )
+            .await;
+
+        assert_eq!(claimed, vec![(10, 1)]);
+    }
+
+    #[tokio::test]
+    async fn test_next_batches_follow_priority() {
+        let mut qs = test_qs();
+        qs.scheduler.max_running_batches = Some(1);
+        let batch_ids = create_batches(&qs, 1, 2).await;
+        qs.storage
+            .set_batch_priority(batch_ids[1], 1)
+            .await
+            .unwrap();
+
+        assert_eq!(qs.get_next_batches().await, vec![(batch_ids[1], 1)]);
+    }
+
+    #[tokio::test]
+    async fn test_scheduled_batch_is_not_started_early() {
+        let qs = test_qs();
+        let batch_ids = create_batches(&qs, 1, 1).await;
+        qs.storage
+            .set_batch_schedule(batch_ids[0], "99991231235959", "")
+            .await
+            .unwrap();
+
+        assert!(qs.get_next_batches().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_recurring_batch_is_rescheduled_when_finished() {
+        let qs = test_qs();
+        let batch_ids = create_batches(&qs, 1, 1).await;
+        qs.storage
+            .set_batch_schedule(batch_ids[0], "", "daily")
+            .await
+            .unwrap();
+        qs.get_next_batches().await;
+
+        qs.set_batch_finished(batch_ids[0], 1).await;
+
+        let batch = qs.storage.get_batch(batch_ids[0]).await.unwrap().unwrap();
+        assert_eq!(batch.status, "INIT");
+        assert!(batch.ts_not_before > qs.timestamp());
+        assert_eq!(qs.number_of_bots_running().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_claim_batches_respects_max_batches_per_site() {
+        let mut qs = test_qs();
+        qs.rate_governor = Arc::new(RateGovernor::new_from_json(
+            &json!({"wikidata":{"max_batches":2}}),
+        ));
+        let running_per_site = HashMap::from([("wikidata".to_string(), 1)]);
+        let mut candidates = open_batches(&[(10, 1), (11, 2), (12, 3)]);
+        candidates[1].site = "commons".to_string();
+
+        let claimed = qs
+            .claim_batches(candidates, &HashMap::new(), &running_per_site)
+            .await;
+
+        assert_eq!(claimed, vec![(10, 1), (11, 2)]);
+    }
 }
@@ -0,0 +1,239 @@
+//! Summary of the failed commands of a batch. Errors are grouped by error
+//! code and message, by property and by entity, so that thousands of ERROR
+//! commands can be told apart without paging through all of them.
+
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+use crate::error::QsResult;
+use crate::qs_storage::QsStorage;
+
+/// Failed commands read from the database at a time
+const PAGE_SIZE: i64 = 1000;
+
+/// Groups per list, largest first
+const MAX_GROUPS: usize = 50;
+
+/// Command IDs given as examples per group
+const MAX_EXAMPLES: usize = 5;
+
+/// Failed commands that share a key
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct ErrorGroup {
+    /// Normalized message, property or entity ID
+    pub key: String,
+    /// Error code of the commands; empty for property and entity groups
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub error_code: String,
+    pub count: usize,
+    /// IDs of the first commands in the group
+    pub examples: Vec<i64>,
+}
+
+/// The failed commands of a batch, grouped
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Default)]
+pub struct ErrorReport {
+    pub batch_id: i64,
+    /// Number of failed commands
+    pub errors: usize,
+    /// By error code and normalized message
+    pub by_message: Vec<ErrorGroup>,
+    /// By the property of the command, if it has one
+    pub by_property: Vec<ErrorGroup>,
+    /// By the entity the command changes, if any
+    pub by_entity: Vec<ErrorGroup>,
+}
+
+/// Accumulates the groups of one list
+#[derive(Default)]
+struct Grouper {
+    groups: HashMap<(String, String), ErrorGroup>,
+}
+
+impl Grouper {
+    fn add(&mut self, key: String, error_code: &str, command_id: i64) {
+        let group = self
+            .groups
+            .entry((error_code.to_string(), key.clone()))
+            .or_insert_with(|| ErrorGroup {
+                key,
+                error_code: error_code.to_string(),
+                count: 0,
+                examples: vec![],
+            });
+        group.count += 1;
+        if group.examples.len() < MAX_EXAMPLES {
+            group.examples.push(command_id);
+        }
+    }
+
+    /// The largest groups; ties in order of their first command
+    fn into_groups(self) -> Vec<ErrorGroup> {
+        let mut groups: Vec<ErrorGroup> = self.groups.into_values().collect();
+        groups.sort_by(|a, b| {
+            b.count
+                .cmp(&a.count)
+                .then_with(|| a.examples.first().cmp(&b.examples.first()))
+        });
+        groups.truncate(MAX_GROUPS);
+        groups
+    }
+}
+
+impl ErrorReport {
+    /// Reads all ERROR commands of the batch
+    pub async fn for_batch(storage: &dyn QsStorage, batch_id: i64) -> QsResult<Self> {
+        let mut by_message = Grouper::default();
+        let mut by_property = Grouper::default();
+        let mut by_entity = Grouper::default();
+        let mut errors = 0;
+        let mut start = 0;
+        loop {
+            let rows = storage
+                .get_commands(batch_id, &["ERROR"], None, start, PAGE_SIZE)
+                .await?;
+            for row in &rows {
+                let json: Value = serde_json::from_str(&row.json).unwrap_or_default();
+                errors += 1;
+                by_message.add(normalize_message(&row.message), &row.error_code, row.id);
+                if let Some(property) = json["property"].as_str() {
+                    by_property.add(property.to_string(), "", row.id);
+                }
+                if let Some(entity) = json["item"].as_str() {
+                    by_entity.add(entity.to_string(), "", row.id);
+                }
+            }
+            if (rows.len() as i64) < PAGE_SIZE {
+                break;
+            }
+            start += PAGE_SIZE;
+        }
+        Ok(Self {
+            batch_id,
+            errors,
+            by_message: by_message.into_groups(),
+            by_property: by_property.into_groups(),
+            by_entity: by_entity.into_groups(),
+        })
+    }
+}
+
+/// Replaces the IDs, revisions and values in an error message with
+/// placeholders, so that the same error on different entities looks the same
+pub fn normalize_message(message: &str) -> String {
+    static RE_STATEMENT_ID: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r"(?i)\b[QPLM]\d+\$[0-9a-f]{8}(-[0-9a-f]{4}){3}-[0-9a-f]{12}\b")
+            .expect("normalize_message:RE_STATEMENT_ID does not compile")
+    });
+    static RE_ENTITY_ID: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r"\b([QPLM])\d+(-[FS]\d+)?\b")
+            .expect("normalize_message:RE_ENTITY_ID does not compile")
+    });
+    static RE_NUMBER: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"\d+").expect("normalize_message:RE_NUMBER does not compile"));
+    static RE_QUOTED: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r#""[^"]*"|'[^']*'"#).expect("normalize_message:RE_QUOTED does not compile")
+    });
+    let message = RE_STATEMENT_ID.replace_all(message.trim(), "<statement>");
+    let message = RE_QUOTED.replace_all(&message, "'…'");
+    let message = RE_ENTITY_ID.replace_all(&message, "${1}…");
+    let message = RE_NUMBER.replace_all(&message, "#");
+    message.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qs_storage_sqlite::SqliteStorage;
+
+    #[test]
+    fn test_normalize_message() {
+        assert_eq!(
+            normalize_message("Invalid command: Entity Q42 not found"),
+            "Invalid command: Entity Q… not found"
+        );
+        assert_eq!(
+            normalize_message("Statement Q1$5627445f-43cb-ed6d-3adb-760e85bd17ee not found"),
+            "Statement <statement> not found"
+        );
+        assert_eq!(
+            normalize_message("Error while loading into entities: L7-F2 rev. Some(123) 'gone'"),
+            "Error while loading into entities: L… rev. Some(#) '…'"
+        );
+        assert_eq!(normalize_message("  two\n lines "), "two lines");
+    }
+
+    #[tokio::test]
+    async fn groups_failed_commands() {
+        let storage = SqliteStorage::open_in_memory().unwrap();
+        let commands: Vec<String> = [
+            json!({"action":"add","item":"Q1","property":"P31","what":"statement"}),
+            json!({"action":"add","item":"Q2","property":"P31","what":"statement"}),
+            json!({"action":"add","item":"Q2","property":"P17","what":"statement"}),
+            json!({"action":"create","type":"item"}),
+            json!({"action":"add","item":"Q3","property":"P31","what":"statement"}),
+        ]
+        .iter()
+        .map(|c| c.to_string())
+        .collect();
+        let batch_id = storage
+            .create_batch("test", 1, "wikidata", &commands, None)
+            .await
+            .unwrap();
+        let failures = [
+            ("Entity Q1 not found", "entity-missing"),
+            ("Entity Q2 not found", "entity-missing"),
+            (
+                "Bad value type string, expected wikibase-entityid",
+                "property-mismatch",
+            ),
+            ("Entity Q9 not found", "entity-missing"),
+        ];
+        let rows = storage
+            .get_commands(batch_id, &[], None, 0, 0)
+            .await
+            .unwrap();
+        for (row, (message, code)) in rows.iter().zip(failures) {
+            storage
+                .set_command_status(row.id, "ERROR", message, code, &row.json)
+                .await
+                .unwrap();
+        }
+        let ids: Vec<i64> = rows.iter().map(|row| row.id).collect();
+
+        let report = ErrorReport::for_batch(&storage, batch_id).await.unwrap();
+        assert_eq!(report.errors, 4);
+        assert_eq!(
+            report.by_message,
+            vec![
+                ErrorGroup {
+                    key: "Entity Q… not found".to_string(),
+                    error_code: "entity-missing".to_string(),
+                    count: 3,
+                    examples: vec![ids[0], ids[1], ids[3]],
+                },
+                ErrorGroup {
+                    key: "Bad value type string, expected wikibase-entityid".to_string(),
+                    error_code: "property-mismatch".to_string(),
+                    count: 1,
+                    examples: vec![ids[2]],
+                },
+            ]
+        );
+        let keys = |groups: &[ErrorGroup]| -> Vec<(String, usize)> {
+            groups.iter().map(|g| (g.key.clone(), g.count)).collect()
+        };
+        assert_eq!(
+            keys(&report.by_property),
+            [("P31".to_string(), 2), ("P17".to_string(), 1)]
+        );
+        assert_eq!(
+            keys(&report.by_entity),
+            [("Q2".to_string(), 2), ("Q1".to_string(), 1)]
+        );
+    }
+}
@@ -0,0 +1,1477 @@
+use crate::qs_bot::QuickStatementsBot;
+use crate::qs_config::QuickStatements;
+use crate::qs_parser::QuickStatementsParser;
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use wikibase::mediawiki::api::Api;
+use wiremock::matchers::any;
+use wiremock::{Mock, MockServer, Request, Respond, ResponseTemplate};
+
+type Params = HashMap<String, String>;
+
+const ERROR_PAGE: &str = "<html><body><h1>Wikimedia Error</h1></body></html>";
+
+/// User that batches run by `FakeWiki::run_batch` belong to
+pub const TEST_USER_ID: i64 = 1;
+
+/// One-shot failure for the next write request, as a live wiki produces under load
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fault {
+    /// `maxlag` error with the given lag in seconds
+    Maxlag(f64),
+    /// `ratelimited` error
+    RateLimited,
+    /// `readonly` error, as during database maintenance
+    ReadOnly,
+    /// An HTML error page instead of JSON
+    NonJson,
+    /// The edit is made, but the response is an HTML error page
+    LostResponse,
+    /// Another user's edit (API parameters) lands just before the next write
+    ForeignEdit(HashMap<String, String>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum FakeResponse {
+    Json(Value),
+    Html(String),
+}
+
+/// Everything the fake wiki knows. Entities are kept as canonical Wikibase JSON,
+/// so `wbgetentities` can return them as-is. Errors are API error responses.
+#[derive(Debug, Default)]
+struct FakeWikiState {
+    siteinfo: Value,
+    entities: HashMap<String, Value>,
+    redirects: HashMap<String, String>,
+    titles: HashMap<String, String>,
+    revision: u64,
+    guid_counter: u64,
+    faults: VecDeque<Fault>,
+    edits: Vec<Params>,
+    /// Latest revision per entity made by someone other than the bot
+    foreign_revisions: HashMap<String, u64>,
+}
+
+impl FakeWikiState {
+    fn new(siteinfo: Value) -> Self {
+        Self {
+            siteinfo,
+            ..Default::default()
+        }
+    }
+
+    fn add_entity(&mut self, entity: Value) {
+        let id = entity["id"].as_str().unwrap_or_default().to_uppercase();
+        let mut entity = entity;
+        let skeleton = empty_entity(entity["type"].as_str().unwrap_or("item"), &id);
+        if let (Some(entity), Some(skeleton)) = (entity.as_object_mut(), skeleton.as_object()) {
+            for (key, value) in skeleton {
+                entity.entry(key.to_owned()).or_insert(value.to_owned());
+            }
+        }
+        self.entities.insert(id.to_owned(), entity);
+        self.bump(&id);
+    }
+
+    fn handle(&mut self, params: &Params) -> FakeResponse {
+        let action = params.get("action").map(|s| s.as_str()).unwrap_or("");
+        match action {
+            "query" => return FakeResponse::Json(self.query(params)),
+            "wbgetentities" => return FakeResponse::Json(self.get_entities(params)),
+            _ => {}
+        }
+
+        // Everything else is an edit, which is where a live wiki pushes back
+        let fault = self.faults.pop_front();
+        let lost_response = fault == Some(Fault::LostResponse);
+        match fault {
+            None | Some(Fault::LostResponse) => {}
+            Some(Fault::ForeignEdit(foreign)) => {
+                if let Err(error) = self.edit(&foreign, true) {
+                    panic!("Foreign edit failed: {}", error);
+                }
+            }
+            Some(Fault::Maxlag(lag)) => {
+                return FakeResponse::Json(json!({"error":{
+                    "code":"maxlag",
+                    "info":format!("Waiting for a database server: {} seconds lagged.", lag),
+                    "host":"db1",
+                    "lag":lag,
+                }}))
+            }
+            Some(Fault::RateLimited) => {
+                return FakeResponse::Json(api_error(
+                    "ratelimited",
+                    "As an anti-abuse measure, you are limited from performing this action too many times in a short space of time.",
+                ))
+            }
+            Some(Fault::ReadOnly) => {
+                return FakeResponse::Json(api_error(
+                    "readonly",
+                    "The wiki is currently in read-only mode.",
+                ))
+            }
+            Some(Fault::NonJson) => return FakeResponse::Html(ERROR_PAGE.to_string()),
+        }
+
+        match self.edit(params, false) {
+            Ok(_) if lost_response => FakeResponse::Html(ERROR_PAGE.to_string()),
+            Ok(res) => FakeResponse::Json(res),
+            Err(error) => FakeResponse::Json(error),
+        }
+    }
+
+    /// Edits with a `baserevid` conflict if another user edited the entity since
+    /// that revision. Stricter than Wikibase, which may still merge such edits.
+    fn edit(&mut self, params: &Params, foreign: bool) -> Result<Value, Value> {
+        let action = params.get("action").map(|s| s.as_str()).unwrap_or("");
+        let entity_id = edited_entity(params);
+        if let (Some(id), Some(base)) = (&entity_id, params.get("baserevid")) {
+            let base: u64 = base
+                .parse()
+                .map_err(|_| api_error("badinteger", "Invalid value for \"baserevid\"."))?;
+            if self
+                .foreign_revisions
+                .get(id)
+                .is_some_and(|rev| *rev > base)
+            {
+                return Err(api_error("editconflict", "Edit conflict."));
+            }
+        }
+        let result = match action {
+            "wbeditentity" => self.edit_entity(params),
+            "wbcreateclaim" => self.create_claim(params),
+            "wbsetqualifier" => self.set_qualifier(params),
+            "wbsetreference" => self.set_reference(params),
+            "wbremoveclaims" => self.remove_claims(params),
+            "wbsetlabel" => self.set_term(params, "labels"),
+            "wbsetdescription" => self.set_term(params, "descriptions"),
+            "wbsetaliases" => self.set_aliases(params),
+            "wbsetsitelink" => self.set_sitelink(params),
+            "wbmergeitems" => self.merge_items(params),
+            "wbladdform" => self.add_sub_entity(params, "forms"),
+            "wbladdsense" => self.add_sub_entity(params, "senses"),
+            "wbleditformelements" => self.edit_sub_entity(params, "forms", "formId"),
+            "wbleditsenseelements" => self.edit_sub_entity(params, "senses", "senseId"),
+            other => Err(api_error(
+                "badvalue",
+                &format!("Unrecognized value for parameter \"action\": {}.", other),
+            )),
+        }?;
+        match (foreign, entity_id) {
+            (true, Some(id)) => {
+                self.foreign_revisions.insert(id, self.revision);
+            }
+            (true, None) => {}
+            (false, _) => {
+                let mut params = params.to_owned();
+                params.remove("token");
+                self.edits.push(params);
+            }
+        }
+        Ok(result)
+    }
+
+    fn query(&self, params: &Params) -> Value {
+        let meta = params.get("meta").map(|s| s.as_str()).unwrap_or("");
+        if meta.contains("siteinfo") {
+            return self.siteinfo.to_owned();
+        }
+        if meta.contains("tokens") {
+            return json!({"batchcomplete":"","query":{"tokens":{"csrftoken":"+\\"}}});
+        }
+        if params.get("list").map(|s| s.as_str()) == Some("users") {
+            let users: Vec<Value> = split(params.get("ususers"))
+                .iter()
+                .enumerate()
+                .map(|(num, name)| json!({"userid":num+1,"name":name}))
+                .collect();
+            return json!({"batchcomplete":"","query":{"users":users}});
+        }
+        if params.contains_key("titles") {
+            let mut pages = json!({});
+            for (num, title) in split(params.get("titles")).iter().enumerate() {
+                match self.titles.get(title) {
+                    Some(id) => {
+                        let page_id = (num + 1).to_string();
+                        pages[page_id.as_str()] = json!({"pageid":num+1,"ns":0,"title":title,"pageprops":{"wikibase_item":id}});
+                    }
+                    None => {
+                        let page_id = format!("-{}", num + 1);
+                        pages[page_id.as_str()] = json!({"ns":0,"title":title,"missing":""});
+                    }
+                }
+            }
+            return json!({"batchcomplete":"","query":{"pages":pages}});
+        }
+        json!({"batchcomplete":"","query":{}})
+    }
+
+    /// Redirects are followed, like the live API does
+    fn lookup(&self, id: &str) -> Option<&Value> {
+        let id = id.to_uppercase();
+        let id = self.redirects.get(&id).unwrap_or(&id);
+        self.entities.get(id)
+    }
+
+    fn get_entities(&self, params: &Params) -> Value {
+        let mut entities = json!({});
+        for id in split(params.get("ids")) {
+            entities[id.as_str()] = match self.lookup(&id) {
+                Some(entity) => entity.to_owned(),
+                None => json!({"id":id,"missing":""}),
+            };
+        }
+        json!({"entities":entities,"success":1})
+    }
+
+    /// Body for `Special:EntityData/<id>.json`, used for specific revisions
+    fn entity_data(&self, id: &str) -> Option<Value> {
+        let entity = self.lookup(id)?;
+        Some(json!({"entities":{id: entity}}))
+    }
+
+    fn bump(&mut self, id: &str) -> u64 {
+        self.revision += 1;
+        if let Some(entity) = self.entities.get_mut(id) {
+            entity["lastrevid"] = json!(self.revision);
+        }
+        self.revision
+    }
+
+    fn next_entity_id(&self, prefix: &str) -> String {
+        let max = self
+            .entities
+            .keys()
+            .chain(self.redirects.keys())
+            .filter_map(|id| id.strip_prefix(prefix)?.parse::<u64>().ok())
+            .max()
+            .unwrap_or(0);
+        format!("{}{}", prefix, max + 1)
+    }
+
+    fn edit_entity(&mut self, params: &Params) -> Result<Value, Value> {
+        let data = json_param(params, "data")?;
+        let id = match (params.get("new"), params.get("id")) {
+            (Some(kind), _) => {
+                let prefix = match kind.as_str() {
+                    "item" => "Q",
+                    "property" => "P",
+                    "lexeme" => "L",
+                    other => {
+                        return Err(api_error(
+                            "invalid-entity-type",
+                            &format!("Unknown entity type '{}'", other),
+                        ))
+                    }
+                };
+                let id = self.next_entity_id(prefix);
+                self.entities.insert(id.to_owned(), empty_entity(kind, &id));
+                id
+            }
+            (None, Some(id)) => id.to_uppercase(),
+            (None, None) => return Err(api_error("param-missing", "Either provide the item \"id\" or pairs of \"site\" and \"title\" for a corresponding page")),
+        };
+        let entity = entity_mut(&mut self.entities, &id)?;
+        apply_data(entity, &id, &data, &mut self.guid_counter)?;
+        self.bump(&id);
+        Ok(json!({"entity":self.entities[&id],"success":1}))
+    }
+
+    fn create_claim(&mut self, params: &Params) -> Result<Value, Value> {
+        let id = param(params, "entity")?.to_uppercase();
+        let snak = snak_from_params(params)?;
+        let entity = entity_mut(&mut self.entities, &id)?;
+        let claim = new_claim(&id, snak, &mut self.guid_counter);
+        push_claim(entity, claim.to_owned());
+        let revision = self.bump(&id);
+        Ok(json!({"pageinfo":{"lastrevid":revision},"success":1,"claim":claim}))
+    }
+
+    fn set_qualifier(&mut self, params: &Params) -> Result<Value, Value> {
+        let guid = param(params, "claim")?;
+        let snak = snak_from_params(params)?;
+        let (id, claim) = claim_mut(&mut self.entities, guid)?;
+        add_qualifier(claim, snak)?;
+        let claim = claim.to_owned();
+        let revision = self.bump(&id);
+        Ok(json!({"pageinfo":{"lastrevid":revision},"success":1,"claim":claim}))
+    }
+
+    fn set_reference(&mut self, params: &Params) -> Result<Value, Value> {
+        let guid = param(params, "statement")?;
+        let snaks = json_param(params, "snaks")?;
+        let (id, claim) = claim_mut(&mut self.entities, guid)?;
+        let reference = add_reference(claim, &snaks)?;
+        let revision = self.bump(&id);
+        Ok(json!({"pageinfo":{"lastrevid":revision},"success":1,"reference":reference}))
+    }
+
+    fn remove_claims(&mut self, params: &Params) -> Result<Value, Value> {
+        let guids = split(params.get("claim"));
+        // All or nothing, like the live API
+        for guid in &guids {
+            claim_mut(&mut self.entities, guid)?;
+        }
+        let mut revision = self.revision;
+        for guid in &guids {
+            let id = entity_id_from_guid(guid);
+            if let Some(claims) = self
+                .entities
+                .get_mut(&id)
+                .and_then(|e| e["claims"].as_object_mut())
+            {
+                for list in claims.values_mut().filter_map(|l| l.as_array_mut()) {
+                    list.retain(|c| !same_guid(c, guid));
+                }
+                claims.retain(|_, list| list.as_array().is_some_and(|l| !l.is_empty()));
+            }
+            revision = self.bump(&id);
+        }
+        Ok(json!({"pageinfo":{"lastrevid":revision},"success":1,"claims":guids}))
+    }
+
+    /// Like the live API, term and sitelink edits only return the changed part of the entity
+    fn partial_entity(&self, id: &str, key: &str, changed: Value) -> Value {
+        let entity = &self.entities[id];
+        let mut ret = json!({"id":id,"type":entity["type"],"lastrevid":entity["lastrevid"]});
+        ret[key] = changed;
+        json!({"entity":ret,"success":1})
+    }
+
+    fn set_term(&mut self, params: &Params, key: &str) -> Result<Value, Value> {
+        let id = param(params, "id")?.to_uppercase();
+        let language = param(params, "language")?;
+        let value = params.get("value").map(|s| s.as_str()).unwrap_or("");
+        let entity = entity_mut(&mut self.entities, &id)?;
+        set_term(&mut entity[key], language, value);
+        self.bump(&id);
+        let changed = match value {
+            "" => json!({language: {"language":language,"removed":""}}),
+            value => json!({language: {"language":language,"value":value}}),
+        };
+        Ok(self.partial_entity(&id, key, changed))
+    }
+
+    fn set_aliases(&mut self, params: &Params) -> Result<Value, Value> {
+        let id = param(params, "id")?.to_uppercase();
+        let language = param(params, "language")?;
+        let entity = entity_mut(&mut self.entities, &id)?;
+        let mut aliases: Vec<String> = match params.get("set") {
+            Some(set) => split(Some(set)),
+            None => entity["aliases"][language]
+                .as_array()
+                .map(|list| {
+                    list.iter()
+                        .filter_map(|a| a["value"].as_str().map(|s| s.to_string()))
+                        .collect()
+                })
+                .unwrap_or_default(),
+        };
+        for alias in split(params.get("add")) {
+            if !aliases.contains(&alias) {
+                aliases.push(alias);
+            }
+        }
+        let remove = split(params.get("remove"));
+        aliases.retain(|a| !remove.contains(a));
+        let aliases: Vec<Value> = aliases
+            .iter()
+            .map(|a| json!({"language":language,"value":a}))
+            .collect();
+        entity["aliases"][language] = json!(aliases);
+        self.bump(&id);
+        Ok(self.partial_entity(&id, "aliases", json!({ language: aliases })))
+    }
+
+    fn set_sitelink(&mut self, params: &Params) -> Result<Value, Value> {
+        let id = param(params, "id")?.to_uppercase();
+        let site = param(params, "linksite")?;
+        let title = params.get("linktitle").map(|s| s.as_str()).unwrap_or("");
+        let entity = entity_mut(&mut self.entities, &id)?;
+        set_sitelink(entity, site, title);
+        self.bump(&id);
+        let changed = match title {
+            "" => json!({site: {"site":site,"title":"","removed":""}}),
+            title => json!({site: {"site":site,"title":title,"badges":[]}}),
+        };
+        Ok(self.partial_entity(&id, "sitelinks", changed))
+    }
+
+    /// Terms and sitelinks the target lacks are copied over, all statements are
+    /// moved, and the source becomes a redirect
+    fn merge_items(&mut self, params: &Params) -> Result<Value, Value> {
+        let from_id = param(params, "fromid")?.to_uppercase();
+        let to_id = param(params, "toid")?.to_uppercase();
+        let from = entity_mut(&mut self.entities, &from_id)?.to_owned();
+        let to = entity_mut(&mut self.entities, &to_id)?;
+        for key in ["labels", "descriptions", "sitelinks"] {
+            for (k, v) in from[key].as_object().into_iter().flatten() {
+                if to[key].get(k).is_none() {
+                    to[key][k] = v.to_owned();
+                }
+            }
+        }
+        for (language, list) in from["aliases"].as_object().into_iter().flatten() {
+            for alias in list.as_array().into_iter().flatten() {
+                if !to["aliases"][language].is_array() {
+                    to["aliases"][language] = json!([]);
+                }
+                if let Some(aliases) = to["aliases"][language].as_array_mut() {
+                    if !aliases.contains(alias) {
+                        aliases.push(alias.to_owned());
+                    }
+                }
+            }
+        }
+        for claim in data_claims(&from["claims"]) {
+            let mut claim = claim;
+            claim["id"] = json!(new_guid(&to_id, &mut self.guid_counter));
+            push_claim(to, claim);
+        }
+        self.entities.remove(&from_id);
+        self.redirects.insert(from_id.to_owned(), to_id.to_owned());
+        self.revision += 1;
+        let from_revision = self.revision;
+        let to_revision = self.bump(&to_id);
+        Ok(json!({
+            "success":1,
+            "redirected":1,
+            "from":{"id":from_id,"lastrevid":from_revision},
+            "to":{"id":to_id,"lastrevid":to_revision},
+        }))
+    }
+
+    fn add_sub_entity(&mut self, params: &Params, key: &str) -> Result<Value, Value> {
+        let id = param(params, "lexemeId")?.to_uppercase();
+        let data = json_param(params, "data")?;
+        let entity = entity_mut(&mut self.entities, &id)?;
+        let (prefix, result_key) = match key {
+            "forms" => ("F", "form"),
+            _ => ("S", "sense"),
+        };
+        let max = entity[key]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|f| f["id"].as_str()?.rsplit(prefix).next()?.parse::<u64>().ok())
+            .max()
+            .unwrap_or(0);
+        let sub_id = format!("{}-{}{}", id, prefix, max + 1);
+        let mut sub_entity = match key {
+            "forms" => {
+                json!({"id":sub_id,"representations":{},"grammaticalFeatures":[],"claims":{}})
+            }
+            _ => json!({"id":sub_id,"glosses":{},"claims":{}}),
+        };
+        apply_sub_entity_data(&mut sub_entity, &data);
+        if !entity[key].is_array() {
+            entity[key] = json!([]);
+        }
+        if let Some(list) = entity[key].as_array_mut() {
+            list.push(sub_entity.to_owned());
+        }
+        let revision = self.bump(&id);
+        let mut ret = json!({"lastrevid":revision,"success":1});
+        ret[result_key] = sub_entity;
+        Ok(ret)
+    }
+
+    fn edit_sub_entity(
+        &mut self,
+        params: &Params,
+        key: &str,
+        id_param: &str,
+    ) -> Result<Value, Value> {
+        let sub_id = param(params, id_param)?.to_uppercase();
+        let data = json_param(params, "data")?;
+        let id = sub_id.split('-').next().unwrap_or_default().to_string();
+        let entity = entity_mut(&mut self.entities, &id)?;
+        let sub_entity = entity[key]
+            .as_array_mut()
+            .into_iter()
+            .flatten()
+            .find(|f| f["id"].as_str() == Some(sub_id.as_str()))
+            .ok_or_else(|| no_such_entity(&sub_id))?;
+        apply_sub_entity_data(sub_entity, &data);
+        let sub_entity = sub_entity.to_owned();
+        let revision = self.bump(&id);
+        let mut ret = json!({"lastrevid":revision,"success":1});
+        ret[&key[..key.len() - 1]] = sub_entity;
+        Ok(ret)
+    }
+}
+
+fn api_error(code: &str, info: &str) -> Value {
+    json!({"error":{"code":code,"info":info}})
+}
+
+fn no_such_entity(id: &str) -> Value {
+    api_error(
+        "no-such-entity",
+        &format!("Could not find an entity with the ID \"{}\".", id),
+    )
+}
+
+fn param<'a>(params: &'a Params, key: &str) -> Result<&'a str, Value> {
+    params.get(key).map(|s| s.as_str()).ok_or_else(|| {
+        api_error(
+            "missingparam",
+            &format!("The \"{}\" parameter must be set.", key),
+        )
+    })
+}
+
+fn json_param(params: &Params, key: &str) -> Result<Value, Value> {
+    serde_json::from_str(param(params, key)?).map_err(|e| {
+        api_error(
+            "invalid-json",
+            &format!("Invalid JSON in \"{}\": {}", key, e),
+        )
+    })
+}
+
+/// A `|`-separated list parameter
+fn split(value: Option<&String>) -> Vec<String> {
+    value
+        .map(|v| {
+            v.split('|')
+                .filter(|s| !s.is_empty())
+                .map(|s| s.to_string())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// The entity an edit applies to
+fn edited_entity(params: &Params) -> Option<String> {
+    [
+        "id",
+        "entity",
+        "lexemeId",
+        "claim",
+        "statement",
+        "formId",
+        "senseId",
+    ]
+    .iter()
+    .find_map(|key| params.get(*key))
+    .map(|id| {
+        let id = entity_id_from_guid(id);
+        id.split('-').next().unwrap_or_default().to_string()
+    })
+}
+
+fn entity_mut<'a>(
+    entities: &'a mut HashMap<String, Value>,
+    id: &str,
+) -> Result<&'a mut Value, Value> {
+    entities.get_mut(id).ok_or_else(|| no_such_entity(id))
+}
+
+fn empty_entity(kind: &str, id: &str) -> Value {
+    match kind {
+        "lexeme" => {
+            json!({"type":"lexeme","id":id,"lemmas":{},"lexicalCategory":"","language":"","claims":{},"forms":[],"senses":[]})
+        }
+        "property" => {
+            json!({"type":"property","datatype":"string","id":id,"labels":{},"descriptions":{},"aliases":{},"claims":{}})
+        }
+        "mediainfo" => {
+            json!({"type":"mediainfo","id":id,"labels":{},"descriptions":{},"statements":{}})
+        }
+        _ => {
+            json!({"type":"item","id":id,"labels":{},"descriptions":{},"aliases":{},"claims":{},"sitelinks":{}})
+        }
+    }
+}
+
+/// Sets or (with an empty value) removes one term in a labels/descriptions/lemmas map
+fn set_term(terms: &mut Value, language: &str, value: &str) {
+    if !terms.is_object() {
+        *terms = json!({});
+    }
+    if let Some(terms) = terms.as_object_mut() {
+        match value {
+            "" => terms.remove(language),
+            value => terms.insert(
+                language.to_string(),
+                json!({"language":language,"value":value}),
+            ),
+        };
+    }
+}
+
+fn set_sitelink(entity: &mut Value, site: &str, title: &str) {
+    if !entity["sitelinks"].is_object() {
+        entity["sitelinks"] = json!({});
+    }
+    if let Some(sitelinks) = entity["sitelinks"].as_object_mut() {
+        match title {
+            "" => sitelinks.remove(site),
+            title => sitelinks.insert(
+                site.to_string(),
+                json!({"site":site,"title":title,"badges":[]}),
+            ),
+        };
+    }
+}
+
+/// `wbeditentity` data: terms and sitelinks are merged, statements added
+fn apply_data(
+    entity: &mut Value,
+    id: &str,
+    data: &Value,
+    guid_counter: &mut u64,
+) -> Result<(), Value> {
+    for key in ["labels", "descriptions", "lemmas"] {
+        for (language, term) in data[key].as_object().into_iter().flatten() {
+            let value = match term.get("remove") {
+                Some(_) => "",
+                None => term["value"].as_str().unwrap_or_default(),
+            };
+            set_term(&mut entity[key], language, value);
+        }
+    }
+    for (language, list) in data["aliases"].as_object().into_iter().flatten() {
+        let aliases: Vec<Value> = list
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|a| json!({"language":language,"value":a["value"]}))
+            .collect();
+        entity["aliases"][language] = json!(aliases);
+    }
+    for (site, sitelink) in data["sitelinks"].as_object().into_iter().flatten() {
+        let title = match sitelink.get("remove") {
+            Some(_) => "",
+            None => sitelink["title"].as_str().unwrap_or_default(),
+        };
+        set_sitelink(entity, site, title);
+    }
+    for key in ["lexicalCategory", "language", "datatype"] {
+        if let Some(value) = data[key].as_str() {
+            entity[key] = json!(value);
+        }
+    }
+    for claim in data_claims(&data["claims"]) {
+        let property = claim["mainsnak"]["property"].as_str().unwrap_or_default();
+        let snaktype = claim["mainsnak"]["snaktype"].as_str().unwrap_or("value");
+        let snak = make_snak(property, snaktype, claim["mainsnak"].get("datavalue"));
+        let mut new_claim = new_claim(id, snak, guid_counter);
+        new_claim["rank"] = json!(claim["rank"].as_str().unwrap_or("normal"));
+        let qualifiers = match &claim["qualifiers"] {
+            Value::Object(by_property) => by_property
+                .values()
+                .filter_map(|l| l.as_array())
+                .flatten()
+                .cloned()
+                .collect(),
+            Value::Array(list) => list.to_owned(),
+            _ => vec![],
+        };
+        for qualifier in qualifiers {
+            let property = qualifier["property"].as_str().unwrap_or_default();
+            let snaktype = qualifier["snaktype"].as_str().unwrap_or("value");
+            add_qualifier(
+                &mut new_claim,
+                make_snak(property, snaktype, qualifier.get("datavalue")),
+            )?;
+        }
+        for reference in claim["references"].as_array().into_iter().flatten() {
+            add_reference(&mut new_claim, &reference["snaks"])?;
+        }
+        push_claim(entity, new_claim);
+    }
+    Ok(())
+}
+
+/// `wbleditformelements`/`wbleditsenseelements`/`wbladdform`/`wbladdsense` data
+fn apply_sub_entity_data(sub_entity: &mut Value, data: &Value) {
+    for key in ["representations", "glosses"] {
+        for (language, term) in data[key].as_object().into_iter().flatten() {
+            set_term(
+                &mut sub_entity[key],
+                language,
+                term["value"].as_str().unwrap_or_default(),
+            );
+        }
+    }
+    if data["grammaticalFeatures"].is_array() {
+        sub_entity["grammaticalFeatures"] = data["grammaticalFeatures"].to_owned();
+    }
+}
+
+/// Statements in `wbeditentity` data can be a list or keyed by property
+fn data_claims(claims: &Value) -> Vec<Value> {
+    match claims {
+        Value::Array(list) => list.to_owned(),
+        Value::Object(by_property) => by_property
+            .values()
+            .filter_map(|l| l.as_array())
+            .flatten()
+            .cloned()
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// Datavalue type and property datatype, guessed from the value since the fake
+/// wiki has no property definitions
+fn value_types(value: &Value) -> (&'static str, String) {
+    if value.is_string() {
+        return ("string", "string".to_string());
+    }
+    if let Some(entity_type) = value["entity-type"].as_str() {
+        return ("wikibase-entityid", format!("wikibase-{}", entity_type));
+    }
+    if let Some(id) = value["id"].as_str() {
+        let entity_type = match id.chars().next() {
+            Some('P') => "property",
+            Some('L') if id.contains("-F") => "form",
+            Some('L') if id.contains("-S") => "sense",
+            Some('L') => "lexeme",
+            _ => "item",
+        };
+        return ("wikibase-entityid", format!("wikibase-{}", entity_type));
+    }
+    if value["time"].is_string() {
+        return ("time", "time".to_string());
+    }
+    if value["latitude"].is_number() {
+        return ("globecoordinate", "globe-coordinate".to_string());
+    }
+    if !value["amount"].is_null() {
+        return ("quantity", "quantity".to_string());
+    }
+    if value["text"].is_string() {
+        return ("monolingualtext", "monolingualtext".to_string());
+    }
+    ("string", "string".to_string())
+}
+
+fn content_hash(value: &Value) -> String {
+    let mut hasher = DefaultHasher::new();
+    value.to_string().hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// `datavalue` is `{"value":...}`, with or without `type`
+fn make_snak(property: &str, snaktype: &str, datavalue: Option<&Value>) -> Value {
+    let mut snak = json!({"snaktype":snaktype,"property":property,"datatype":"string"});
+    if let Some(value) = datavalue
+        .filter(|_| snaktype == "value")
+        .map(|dv| &dv["value"])
+    {
+        let (value_type, datatype) = value_types(value);
+        let value_type = datavalue
+            .and_then(|dv| dv["type"].as_str())
+            .unwrap_or(value_type);
+        snak["datavalue"] = json!({"value":value,"type":value_type});
+        snak["datatype"] = json!(datatype);
+    }
+    snak["hash"] = json!(content_hash(&json!([
+        snak["property"],
+        snak["snaktype"],
+        snak["datavalue"]
+    ])));
+    snak
+}
+
+/// Snak from `property`/`snaktype`/`value` parameters, as used by `wbcreateclaim`
+/// and `wbsetqualifier`
+fn snak_from_params(params: &Params) -> Result<Value, Value> {
+    let property = param(params, "property")?;
+    let snaktype = params
+        .get("snaktype")
+        .map(|s| s.as_str())
+        .unwrap_or("value");
+    let datavalue = match snaktype {
+        "value" => Some(json!({"value":json_param(params, "value")?})),
+        _ => None,
+    };
+    Ok(make_snak(property, snaktype, datavalue.as_ref()))
+}
+
+fn new_guid(entity_id: &str, guid_counter: &mut u64) -> String {
+    *guid_counter += 1;
+    format!(
+        "{}$00000000-0000-4000-8000-{:012X}",
+        entity_id, guid_counter
+    )
+}
+
+fn new_claim(entity_id: &str, mainsnak: Value, guid_counter: &mut u64) -> Value {
+    json!({
+        "mainsnak":mainsnak,
+        "type":"statement",
+        "id":new_guid(entity_id, guid_counter),
+        "rank":"normal",
+    })
+}
+
+fn push_claim(entity: &mut Value, claim: Value) {
+    let property = claim["mainsnak"]["property"]
+        .as_str()
+        .unwrap_or_default()
+        .to_string();
+    if !entity["claims"][&property].is_array() {
+        entity["claims"][&property] = json!([]);
+    }
+    if let Some(list) = entity["claims"][&property].as_array_mut() {
+        list.push(claim);
+    }
+}
+
+fn entity_id_from_guid(guid: &str) -> String {
+    guid.split('$').next().unwrap_or_default().to_uppercase()
+}
+
+fn same_guid(claim: &Value, guid: &str) -> bool {
+    claim["id"]
+        .as_str()
+        .is_some_and(|id| id.eq_ignore_ascii_case(guid))
+}
+
+fn claim_mut<'a>(
+    entities: &'a mut HashMap<String, Value>,
+    guid: &str,
+) -> Result<(String, &'a mut Value), Value> {
+    let id = entity_id_from_guid(guid);
+    let no_such_claim = || {
+        api_error(
+            "no-such-claim",
+            &format!("Could not find the statement \"{}\".", guid),
+        )
+    };
+    let claims = entities
+        .get_mut(&id)
+        .and_then(|entity| entity["claims"].as_object_mut())
+        .ok_or_else(no_such_claim)?;
+    let claim = claims
+        .values_mut()
+        .filter_map(|list| list.as_array_mut())
+        .flatten()
+        .find(|claim| same_guid(claim, guid))
+        .ok_or_else(no_such_claim)?;
+    Ok((id, claim))
+}
+
+/// Fails with the same message as the live API if the qualifier already exists
+fn add_qualifier(claim: &mut Value, snak: Value) -> Result<(), Value> {
+    let property = snak["property"].as_str().unwrap_or_default().to_string();
+    let hash = snak["hash"].as_str().unwrap_or_default().to_string();
+    if !claim["qualifiers"][&property].is_array() {
+        claim["qualifiers"][&property] = json!([]);
+    }
+    if let Some(qualifiers) = claim["qualifiers"][&property].as_array_mut() {
+        if qualifiers.iter().any(|q| q["hash"] == json!(hash)) {
+            return Err(api_error(
+                "modification-failed",
+                &format!("The statement has already a qualifier with hash {}", hash),
+            ));
+        }
+        qualifiers.push(snak);
+    }
+    if !claim["qualifiers-order"].is_array() {
+        claim["qualifiers-order"] = json!([]);
+    }
+    if let Some(order) = claim["qualifiers-order"].as_array_mut() {
+        if !order.contains(&json!(property)) {
+            order.push(json!(property));
+        }
+    }
+    Ok(())
+}
+
+/// Fails with the same message as the live API if the reference already exists
+fn add_reference(claim: &mut Value, snaks: &Value) -> Result<Value, Value> {
+    let mut reference_snaks = json!({});
+    let mut order = vec![];
+    for (property, list) in snaks.as_object().into_iter().flatten() {
+        let list: Vec<Value> = list
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|s| {
+                let snaktype = s["snaktype"].as_str().unwrap_or("value");
+                make_snak(property, snaktype, s.get("datavalue"))
+            })
+            .collect();
+        reference_snaks[property] = json!(list);
+        order.push(property.to_owned());
+    }
+    let hashes: Vec<&Value> = order
+        .iter()
+        .flat_map(|p| reference_snaks[p].as_array().into_iter().flatten())
+        .map(|s| &s["hash"])
+        .collect();
+    let hash = content_hash(&json!(hashes));
+    if !claim["references"].is_array() {
+        claim["references"] = json!([]);
+    }
+    let reference = json!({"hash":hash,"snaks":reference_snaks,"snaks-order":order});
+    if let Some(references) = claim["references"].as_array_mut() {
+        if references.iter().any(|r| r["hash"] == json!(hash)) {
+            return Err(api_error(
+                "modification-failed",
+                &format!("The statement has already a reference with hash {}", hash),
+            ));
+        }
+        references.push(reference.to_owned());
+    }
+    Ok(reference)
+}
+
+struct Responder(Arc<Mutex<FakeWikiState>>);
+
+impl Respond for Responder {
+    fn respond(&self, request: &Request) -> ResponseTemplate {
+        let mut state = self.0.lock().expect("Fake wiki state poisoned");
+        let path = request.url.path().replace("%3A", ":");
+        if let Some(file) = path.split("Special:EntityData/").nth(1) {
+            return match state.entity_data(file.trim_end_matches(".json")) {
+                Some(json) => ResponseTemplate::new(200).set_body_json(json),
+                None => ResponseTemplate::new(404),
+            };
+        }
+
+        // Parameters come in the query string (GET) or as a form body (POST)
+        let mut params: Params = request.url.query_pairs().into_owned().collect();
+        let mut body = request.url.clone();
+        body.set_query(Some(&String::from_utf8_lossy(&request.body)));
+        params.extend(body.query_pairs().into_owned());
+
+        match state.handle(&params) {
+            FakeResponse::Json(json) => ResponseTemplate::new(200).set_body_json(json),
+            FakeResponse::Html(html) => ResponseTemplate::new(200).set_body_raw(html, "text/html"),
+        }
+    }
+}
+
+/// A local stand-in for a Wikibase API, with entities in memory, so parser and
+/// bot can be tested end to end without a live wiki.
+pub struct FakeWiki {
+    state: Arc<Mutex<FakeWikiState>>,
+    server: MockServer,
+}
+
+impl FakeWiki {
+    pub async fn start() -> Self {
+        let server = MockServer::start().await;
+        let mut siteinfo: Value =
+            serde_json::from_str(include_str!("../test_data/siteinfo_wikidata.json"))
+                .expect("Bad siteinfo test data");
+        // Entity revisions are loaded from Special:EntityData on the wiki server
+        siteinfo["query"]["general"]["server"] = json!(server.uri());
+        let state = Arc::new(Mutex::new(FakeWikiState::new(siteinfo)));
+        Mock::given(any())
+            .respond_with(Responder(state.clone()))
+            .mount(&server)
+            .await;
+        Self { state, server }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, FakeWikiState> {
+        self.state.lock().expect("Fake wiki state poisoned")
+    }
+
+    /// The `api.php` URL, e.g. for the `sites` config
+    pub fn api_url(&self) -> String {
+        format!("{}/w/api.php", self.server.uri())
+    }
+
+    pub async fn api(&self) -> Api {
+        Api::new(&self.api_url())
+            .await
+            .expect("Cannot connect to fake wiki")
+    }
+
+    /// Missing parts (labels, claims, ...) are filled in by entity type
+    pub fn add_entity(&self, entity: Value) {
+        self.state().add_entity(entity);
+    }
+
+    /// Makes `title` resolve to `entity_id` in page title lookups
+    pub fn add_title(&self, title: &str, entity_id: &str) {
+        self.state()
+            .titles
+            .insert(title.to_string(), entity_id.to_string());
+    }
+
+    /// The current entity JSON, following redirects
+    pub fn entity(&self, entity_id: &str) -> Option<Value> {
+        self.state().lookup(entity_id).cloned()
+    }
+
+    pub fn redirect_target(&self, entity_id: &str) -> Option<String> {
+        self.state().redirects.get(entity_id).cloned()
+    }
+
+    /// Faults are used up in order, one per write request
+    pub fn inject(&self, fault: Fault) {
+        self.state().faults.push_back(fault);
+    }
+
+    /// Parameters (without token) of all successful edits by the bot, in order
+    pub fn edits(&self) -> Vec<HashMap<String, String>> {
+        self.state().edits.clone()
+    }
+
+    /// Parses V1 commands the way the import does (with page title lookups
+    /// against this wiki), and stores them as a batch. Returns the batch ID.
+    pub async fn create_batch(&self, config: &QuickStatements, commands: &str) -> i64 {
+        let api = self.api().await;
+        let mut parsers = vec![];
+        for line in commands.lines().map(|l| l.trim()).filter(|l| !l.is_empty()) {
+            match QuickStatementsParser::new_from_line(line, Some(&api)).await {
+                Ok(parser) => parsers.push(parser),
+                Err(e) => panic!("Cannot parse '{}': {}", line, e),
+            }
+        }
+        QuickStatementsParser::compress(&mut parsers);
+        let commands: Vec<String> = parsers
+            .iter()
+            .flat_map(|p| p.to_json().expect("Cannot convert command to JSON"))
+            .map(|c| c.to_string())
+            .collect();
+        config
+            .storage()
+            .create_batch("e2e", TEST_USER_ID, "wikidata", &commands, None)
+            .await
+            .expect("Cannot create batch")
+    }
+
+    /// Runs the bot against this wiki until its batch is done
+    pub async fn run_bot(&self, bot: &mut QuickStatementsBot) {
+        bot.set_mw_api(self.api().await);
+        while bot.run().await.expect("Transient error in bot") {}
+    }
+
+    /// `create_batch` and `run_bot` in one go
+    pub async fn run_batch(&self, config: Arc<QuickStatements>, commands: &str) -> i64 {
+        let batch_id = self.create_batch(&config, commands).await;
+        let mut bot = QuickStatementsBot::new(config, Some(batch_id), TEST_USER_ID);
+        self.run_bot(&mut bot).await;
+        batch_id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> FakeWikiState {
+        let mut state = FakeWikiState::new(json!({}));
+        state.add_entity(json!({"type":"item","id":"Q1"}));
+        state
+    }
+
+    fn params(pairs: &[(&str, &str)]) -> Params {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn json_of(response: FakeResponse) -> Value {
+        match response {
+            FakeResponse::Json(json) => json,
+            FakeResponse::Html(html) => panic!("Unexpected HTML response: {}", html),
+        }
+    }
+
+    fn create_claim(state: &mut FakeWikiState, value: &str) -> String {
+        let res = json_of(state.handle(&params(&[
+            ("action", "wbcreateclaim"),
+            ("entity", "Q1"),
+            ("property", "P31"),
+            ("snaktype", "value"),
+            ("value", value),
+        ])));
+        assert_eq!(res["success"], json!(1));
+        res["claim"]["id"].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn created_claim_is_returned_by_wbgetentities() {
+        let mut state = state();
+        let guid = create_claim(&mut state, r#"{"entity-type":"item","id":"Q5"}"#);
+
+        let res = json_of(state.handle(&params(&[("action", "wbgetentities"), ("ids", "Q1|Q2")])));
+
+        let claim = &res["entities"]["Q1"]["claims"]["P31"][0];
+        assert_eq!(claim["id"], json!(guid));
+        assert_eq!(
+            claim["mainsnak"]["datavalue"]["type"],
+            json!("wikibase-entityid")
+        );
+        assert_eq!(claim["mainsnak"]["datatype"], json!("wikibase-item"));
+        assert_eq!(res["entities"]["Q2"]["missing"], json!(""));
+    }
+
+    // The bot treats these exact messages as "already done"
+    #[test]
+    fn duplicate_qualifier_and_reference_are_rejected() {
+        let mut state = state();
+        let guid = create_claim(&mut state, r#"{"entity-type":"item","id":"Q5"}"#);
+        let qualifier = params(&[
+            ("action", "wbsetqualifier"),
+            ("claim", &guid),
+            ("property", "P580"),
+            ("snaktype", "value"),
+            ("value", r#""x""#),
+        ]);
+        let reference = params(&[
+            ("action", "wbsetreference"),
+            ("statement", &guid),
+            (
+                "snaks",
+                r#"{"P143":[{"property":"P143","snaktype":"value","datavalue":{"type":"string","value":"y"}}]}"#,
+            ),
+        ]);
+
+        assert_eq!(json_of(state.handle(&qualifier))["success"], json!(1));
+        assert_eq!(json_of(state.handle(&reference))["success"], json!(1));
+        let qualifier_again = json_of(state.handle(&qualifier));
+        let reference_again = json_of(state.handle(&reference));
+
+        assert!(qualifier_again["error"]["info"]
+            .as_str()
+            .unwrap()
+            .starts_with("The statement has already a qualifier with hash"));
+        assert!(reference_again["error"]["info"]
+            .as_str()
+            .unwrap()
+            .starts_with("The statement has already a reference with hash"));
+        assert_eq!(state.edits.len(), 3);
+    }
+
+    #[test]
+    fn removed_claims_are_gone() {
+        let mut state = state();
+        let guid = create_claim(&mut state, r#""a""#);
+
+        let res = json_of(state.handle(&params(&[("action", "wbremoveclaims"), ("claim", &guid)])));
+        let again =
+            json_of(state.handle(&params(&[("action", "wbremoveclaims"), ("claim", &guid)])));
+
+        assert_eq!(res["success"], json!(1));
+        assert_eq!(again["error"]["code"], json!("no-such-claim"));
+        assert_eq!(state.entities["Q1"]["claims"], json!({}));
+    }
+
+    #[test]
+    fn new_entities_get_the_next_free_id() {
+        let mut state = state();
+        state.add_entity(json!({"type":"item","id":"Q41"}));
+
+        let res = json_of(state.handle(&params(&[
+            ("action", "wbeditentity"),
+            ("new", "item"),
+            ("data", r#"{"labels":{"en":{"language":"en","value":"x"}},"claims":[{"mainsnak":{"snaktype":"value","property":"P31","datavalue":{"value":"y","type":"string"}},"qualifiers":[{"snaktype":"value","property":"P580","datavalue":{"value":"z","type":"string"}}],"type":"statement","rank":"normal"}]}"#),
+        ])));
+
+        assert_eq!(res["entity"]["id"], json!("Q42"));
+        assert_eq!(res["entity"]["labels"]["en"]["value"], json!("x"));
+        let claim = &res["entity"]["claims"]["P31"][0];
+        assert!(claim["id"].as_str().unwrap().starts_with("Q42$"));
+        assert_eq!(
+            claim["qualifiers"]["P580"][0]["datavalue"]["value"],
+            json!("z")
+        );
+    }
+
+    #[test]
+    fn terms_and_sitelinks_return_partial_entity() {
+        let mut state = state();
+
+        let label = json_of(state.handle(&params(&[
+            ("action", "wbsetlabel"),
+            ("id", "Q1"),
+            ("language", "en"),
+            ("value", "foo"),
+        ])));
+        state.handle(&params(&[
+            ("action", "wbsetaliases"),
+            ("id", "Q1"),
+            ("language", "en"),
+            ("add", "a|b"),
+        ]));
+        state.handle(&params(&[
+            ("action", "wbsetsitelink"),
+            ("id", "Q1"),
+            ("linksite", "enwiki"),
+            ("linktitle", "Foo"),
+        ]));
+        state.handle(&params(&[
+            ("action", "wbsetsitelink"),
+            ("id", "Q1"),
+            ("linksite", "enwiki"),
+            ("linktitle", ""),
+        ]));
+
+        assert_eq!(label["entity"]["labels"]["en"]["value"], json!("foo"));
+        assert!(label["entity"]["claims"].is_null());
+        let entity = &state.entities["Q1"];
+        assert_eq!(entity["labels"]["en"]["value"], json!("foo"));
+        assert_eq!(entity["aliases"]["en"].as_array().unwrap().len(), 2);
+        assert_eq!(entity["sitelinks"], json!({}));
+    }
+
+    #[test]
+    fn merge_leaves_redirect() {
+        let mut state = state();
+        state.add_entity(
+            json!({"type":"item","id":"Q2","labels":{"de":{"language":"de","value":"Zwei"}}}),
+        );
+
+        let res = json_of(state.handle(&params(&[
+            ("action", "wbmergeitems"),
+            ("fromid", "Q2"),
+            ("toid", "Q1"),
+        ])));
+
+        assert_eq!(res["success"], json!(1));
+        assert_eq!(state.redirects["Q2"], "Q1");
+        assert_eq!(state.lookup("Q2").unwrap()["id"], json!("Q1"));
+        assert_eq!(
+            state.lookup("Q2").unwrap()["labels"]["de"]["value"],
+            json!("Zwei")
+        );
+    }
+
+    #[test]
+    fn lexeme_forms_and_senses() {
+        let mut state = state();
+        state.add_entity(json!({"type":"lexeme","id":"L1"}));
+
+        let form = json_of(state.handle(&params(&[
+            ("action", "wbladdform"),
+            ("lexemeId", "L1"),
+            ("data", r#"{"representations":{"en":{"language":"en","value":"runs"}},"grammaticalFeatures":["Q1"]}"#),
+        ])));
+        let edited = json_of(state.handle(&params(&[
+            ("action", "wbleditformelements"),
+            ("formId", "L1-F1"),
+            ("data", r#"{"grammaticalFeatures":["Q2"]}"#),
+        ])));
+        let sense = json_of(state.handle(&params(&[
+            ("action", "wbladdsense"),
+            ("lexemeId", "L1"),
+            (
+                "data",
+                r#"{"glosses":{"en":{"language":"en","value":"to move"}}}"#,
+            ),
+        ])));
+
+        assert_eq!(form["form"]["id"], json!("L1-F1"));
+        assert_eq!(edited["form"]["grammaticalFeatures"], json!(["Q2"]));
+        assert_eq!(
+            edited["form"]["representations"]["en"]["value"],
+            json!("runs")
+        );
+        assert_eq!(sense["sense"]["id"], json!("L1-S1"));
+    }
+
+    #[test]
+    fn faults_hit_the_next_write_only() {
+        let mut state = state();
+        state.faults.push_back(Fault::Maxlag(2.0));
+        state.faults.push_back(Fault::NonJson);
+        let write = params(&[
+            ("action", "wbsetlabel"),
+            ("id", "Q1"),
+            ("language", "en"),
+            ("value", "foo"),
+        ]);
+
+        // Reads are not affected
+        json_of(state.handle(&params(&[("action", "wbgetentities"), ("ids", "Q1")])));
+        let first = json_of(state.handle(&write));
+        let second = state.handle(&write);
+        let third = json_of(state.handle(&write));
+
+        assert_eq!(first["error"]["code"], json!("maxlag"));
+        assert_eq!(first["error"]["lag"], json!(2.0));
+        assert!(matches!(second, FakeResponse::Html(_)));
+        assert_eq!(third["success"], json!(1));
+        assert_eq!(state.edits.len(), 1);
+    }
+
+    #[test]
+    fn foreign_edit_after_base_revision_conflicts() {
+        let mut state = state();
+        let base = state.revision.to_string();
+        state.faults.push_back(Fault::ForeignEdit(params(&[
+            ("action", "wbsetlabel"),
+            ("id", "Q1"),
+            ("language", "en"),
+            ("value", "theirs"),
+        ])));
+        let write = params(&[
+            ("action", "wbsetlabel"),
+            ("id", "Q1"),
+            ("language", "en"),
+            ("value", "ours"),
+            ("baserevid", &base),
+        ]);
+
+        let conflict = json_of(state.handle(&write));
+        let current = state.revision.to_string();
+        let retry = params(&[
+            ("action", "wbsetlabel"),
+            ("id", "Q1"),
+            ("language", "en"),
+            ("value", "ours"),
+            ("baserevid", &current),
+        ]);
+        let res = json_of(state.handle(&retry));
+
+        assert_eq!(conflict["error"]["code"], json!("editconflict"));
+        assert_eq!(res["success"], json!(1));
+        assert_eq!(state.entities["Q1"]["labels"]["en"]["value"], json!("ours"));
+        assert_eq!(state.edits.len(), 1);
+    }
+
+    #[test]
+    fn page_titles_resolve_to_entities() {
+        let mut state = state();
+        state
+            .titles
+            .insert("Douglas Adams".to_string(), "Q42".to_string());
+
+        let res = json_of(state.handle(&params(&[
+            ("action", "query"),
+            ("prop", "pageprops"),
+            ("titles", "Douglas Adams|Nope"),
+        ])));
+
+        let pages = res["query"]["pages"].as_object().unwrap();
+        assert_eq!(pages["1"]["pageprops"]["wikibase_item"], json!("Q42"));
+        assert_eq!(pages["-2"]["missing"], json!(""));
+    }
+
+    // ---- End-to-end: parser and bot against the fake wiki ----
+
+    async fn commands_qs_wiki() -> (FakeWiki, Arc<QuickStatements>) {
+        let wiki = FakeWiki::start().await;
+        for id in ["Q123", "Q456", "Q4115189"] {
+            wiki.add_entity(json!({"type":"item","id":id}));
+        }
+        (wiki, Arc::new(QuickStatements::new_for_tests()))
+    }
+
+    fn claims<'a>(entity: &'a Value, property: &str) -> Vec<&'a Value> {
+        entity["claims"][property]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn commands_qs_runs_end_to_end() {
+        let (wiki, config) = commands_qs_wiki().await;
+
+        let batch_id = wiki
+            .run_batch(config.clone(), include_str!("../test_data/commands.qs"))
+            .await;
+
+        let storage = config.storage();
+        let batch = storage.get_batch(batch_id).await.unwrap().unwrap();
+        assert_eq!(batch.status, "DONE");
+        let unfinished = storage
+            .get_commands(batch_id, &["INIT", "RUN"], None, 0, -1)
+            .await
+            .unwrap();
+        assert!(unfinished.is_empty());
+        // Removing labels, descriptions and aliases is not supported by the bot
+        let errors = storage
+            .get_commands(batch_id, &["ERROR"], None, 0, -1)
+            .await
+            .unwrap();
+        let failed: Vec<Value> = errors
+            .iter()
+            .map(|row| serde_json::from_str::<Value>(&row.json).unwrap())
+            .map(|j| json!([j["action"], j["what"]]))
+            .collect();
+        assert_eq!(
+            failed,
+            vec![
+                json!(["remove", "label"]),
+                json!(["remove", "description"]),
+                json!(["remove", "alias"]),
+            ]
+        );
+
+        // Repeated statement lines add qualifiers/references once, not new statements
+        let q = wiki.entity("Q4115189").unwrap();
+        let p31 = claims(&q, "P31");
+        assert_eq!(p31.len(), 1);
+        assert_eq!(p31[0]["qualifiers"]["P570"].as_array().unwrap().len(), 1);
+        assert_eq!(p31[0]["references"].as_array().unwrap().len(), 2);
+        assert!(claims(&q, "P1476").is_empty());
+        assert_eq!(q["labels"]["en"]["value"], json!("test label"));
+        assert!(q["sitelinks"]["enwiki"].is_null());
+        let merges: Vec<_> = wiki
+            .edits()
+            .into_iter()
+            .filter(|e| e["action"] == "wbmergeitems")
+            .collect();
+        assert_eq!(merges.len(), 1);
+        assert_eq!(
+            wiki.redirect_target(&merges[0]["fromid"]),
+            Some(merges[0]["toid"].to_owned())
+        );
+
+        // The LAST lines after the third CREATE are compressed into it
+        let created = wiki.entity("Q4115192").unwrap();
+        assert_eq!(created["labels"]["en"]["value"], json!("test label"));
+        assert_eq!(
+            created["descriptions"]["de"]["value"],
+            json!("test description")
+        );
+        assert_eq!(created["aliases"]["it"].as_array().unwrap().len(), 2);
+        assert_eq!(created["sitelinks"]["enwiki"]["title"], json!("test link"));
+        let p31 = claims(&created, "P31");
+        assert_eq!(p31.len(), 1);
+        assert_eq!(
+            p31[0]["references"][0]["snaks-order"],
+            json!(["P143", "P214"])
+        );
+        assert_eq!(claims(&created, "P32").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rate_limited_edit_is_retried_once() {
+        let (wiki, config) = commands_qs_wiki().await;
+        wiki.inject(Fault::RateLimited);
+
+        let batch_id = wiki.run_batch(config.clone(), "Q123\tP31\tQ5").await;
+
+        let batch = config.storage().get_batch(batch_id).await.unwrap().unwrap();
+        assert_eq!(batch.status, "DONE");
+        let creates = wiki
+            .edits()
+            .iter()
+            .filter(|e| e["action"] == "wbcreateclaim")
+            .count();
+        assert_eq!(creates, 1);
+        assert_eq!(claims(&wiki.entity("Q123").unwrap(), "P31").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn page_titles_are_resolved_by_the_parser() {
+        let (wiki, config) = commands_qs_wiki().await;
+        wiki.add_title("Some page", "Q456");
+
+        wiki.run_batch(config, "Some page\tP31\tQ5").await;
+
+        assert_eq!(claims(&wiki.entity("Q456").unwrap(), "P31").len(), 1);
+    }
+}
@@ -0,0 +1,211 @@
+use chrono::{SecondsFormat, Utc};
+use log::kv::{self, Key, Value as KvValue, VisitSource, VisitValue};
+use log::{LevelFilter, Log, Metadata, Record};
+use serde_json::{Map, Value};
+use std::io::Write;
+
+/// How log events are written to stdout
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// `<timestamp> <LEVEL> [<target>] <message> key=value ...`
+    Text,
+    /// One JSON object per line, with the key-value fields of the event
+    /// (`batch`, `command`, `user`, `site`, `entity`, `action`) as top-level keys
+    Json,
+}
+
+impl LogFormat {
+    pub fn new_from_str(s: &str) -> Option<Self> {
+        match s {
+            "text" => Some(Self::Text),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct QsLogger {
+    format: LogFormat,
+    level: LevelFilter,
+}
+
+impl QsLogger {
+    /// Installs the logger for the whole process
+    pub fn init(format: LogFormat, level: LevelFilter) -> Result<(), log::SetLoggerError> {
+        log::set_boxed_logger(Box::new(Self { format, level }))?;
+        log::set_max_level(level);
+        Ok(())
+    }
+
+    fn format(&self, record: &Record) -> String {
+        let ts = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        let fields = fields(record);
+        match self.format {
+            LogFormat::Text => {
+                let mut line = format!(
+                    "{} {:<5} [{}] {}",
+                    ts,
+                    record.level(),
+                    record.target(),
+                    record.args()
+                );
+                for (key, value) in fields {
+                    match value {
+                        Value::String(s) => line += &format!(" {}={}", key, s),
+                        value => line += &format!(" {}={}", key, value),
+                    }
+                }
+                line
+            }
+            LogFormat::Json => {
+                let mut j = Map::new();
+                j.insert("ts".to_string(), json!(ts));
+                j.insert("level".to_string(), json!(record.level().as_str()));
+                j.insert("target".to_string(), json!(record.target()));
+                j.insert("msg".to_string(), json!(record.args().to_string()));
+                j.extend(fields);
+                Value::Object(j).to_string()
+            }
+        }
+    }
+}
+
+impl Log for QsLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = self.format(record);
+        let _ = writeln!(std::io::stdout().lock(), "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stdout().flush();
+    }
+}
+
+/// The key-value fields of an event; fields without a value are left out
+fn fields(record: &Record) -> Map<String, Value> {
+    let mut visitor = Fields(Map::new());
+    let _ = record.key_values().visit(&mut visitor);
+    visitor.0
+}
+
+struct Fields(Map<String, Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: KvValue<'kvs>) -> Result<(), kv::Error> {
+        let mut visitor = JsonValue(Value::Null);
+        value.visit(&mut visitor)?;
+        if !visitor.0.is_null() {
+            self.0.insert(key.to_string(), visitor.0);
+        }
+        Ok(())
+    }
+}
+
+/// Keeps numbers and booleans typed in the JSON output
+struct JsonValue(Value);
+
+impl<'v> VisitValue<'v> for JsonValue {
+    fn visit_any(&mut self, value: KvValue) -> Result<(), kv::Error> {
+        self.0 = json!(value.to_string());
+        Ok(())
+    }
+
+    fn visit_null(&mut self) -> Result<(), kv::Error> {
+        self.0 = Value::Null;
+        Ok(())
+    }
+
+    fn visit_u64(&mut self, value: u64) -> Result<(), kv::Error> {
+        self.0 = json!(value);
+        Ok(())
+    }
+
+    fn visit_i64(&mut self, value: i64) -> Result<(), kv::Error> {
+        self.0 = json!(value);
+        Ok(())
+    }
+
+    fn visit_f64(&mut self, value: f64) -> Result<(), kv::Error> {
+        self.0 = json!(value);
+        Ok(())
+    }
+
+    fn visit_bool(&mut self, value: bool) -> Result<(), kv::Error> {
+        self.0 = json!(value);
+        Ok(())
+    }
+
+    fn visit_str(&mut self, value: &str) -> Result<(), kv::Error> {
+        self.0 = json!(value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(format: LogFormat, record: &Record) -> Value {
+        let logger = QsLogger {
+            format,
+            level: LevelFilter::Info,
+        };
+        let line = logger.format(record);
+        match format {
+            LogFormat::Json => serde_json::from_str(&line).unwrap(),
+            LogFormat::Text => json!(line.split_once(' ').unwrap().1),
+        }
+    }
+
+    #[test]
+    fn fields_become_json_keys() {
+        let kvs: [(&str, KvValue); 4] = [
+            ("batch", KvValue::from(12_i64)),
+            ("command", KvValue::null()),
+            ("site", KvValue::from("wikidata")),
+            ("retry", KvValue::from(true)),
+        ];
+        let record = Record::builder()
+            .args(format_args!("Command failed"))
+            .level(log::Level::Error)
+            .target("quickstatements::qs_bot")
+            .key_values(&kvs)
+            .build();
+
+        let j = format(LogFormat::Json, &record);
+
+        assert_eq!(j["level"], "ERROR");
+        assert_eq!(j["msg"], "Command failed");
+        assert_eq!(j["batch"], 12);
+        assert_eq!(j["site"], "wikidata");
+        assert_eq!(j["retry"], true);
+        assert!(j.get("command").is_none());
+    }
+
+    #[test]
+    fn text_lines_append_fields() {
+        let kvs: [(&str, KvValue); 2] = [
+            ("batch", KvValue::from(12_i64)),
+            ("site", KvValue::from("wikidata")),
+        ];
+        let record = Record::builder()
+            .args(format_args!("Command failed"))
+            .level(log::Level::Warn)
+            .target("quickstatements::qs_bot")
+            .key_values(&kvs)
+            .build();
+
+        assert_eq!(
+            format(LogFormat::Text, &record),
+            "WARN  [quickstatements::qs_bot] Command failed batch=12 site=wikidata"
+        );
+    }
+}
@@ -0,0 +1,220 @@
+use prometheus::{
+    CounterVec, Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Content type of `Metrics::render`
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Buckets for DB latency, in seconds
+const DB_LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// Prometheus metrics of one process, served at `/metrics`. The bots and the
+/// storage layer record events here; gauges follow the batches this process
+/// runs, so a web server process reports its API and DB activity only.
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+    commands: IntCounterVec,
+    api_errors: IntCounterVec,
+    pushback_sleeps: IntCounterVec,
+    pushback_sleep_seconds: CounterVec,
+    edit_delay: IntGaugeVec,
+    running_batches: IntGaugeVec,
+    db_latency: HistogramVec,
+    /// (user, site) labels of the running batches, to decrement their gauge
+    running: Mutex<HashMap<i64, (String, String)>>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        let commands = IntCounterVec::new(
+            Opts::new("qs_commands_total", "Command status changes"),
+            &["action", "status"],
+        )
+        .expect("qs_commands_total");
+        let api_errors = IntCounterVec::new(
+            Opts::new(
+                "qs_api_errors_total",
+                "Error codes returned by the wiki API",
+            ),
+            &["code"],
+        )
+        .expect("qs_api_errors_total");
+        let pushback_sleeps = IntCounterVec::new(
+            Opts::new(
+                "qs_pushback_sleeps_total",
+                "Sleeps before retrying an edit the wiki pushed back on",
+            ),
+            &["reason"],
+        )
+        .expect("qs_pushback_sleeps_total");
+        let pushback_sleep_seconds = CounterVec::new(
+            Opts::new(
+                "qs_pushback_sleep_seconds_total",
+                "Time spent in pushback sleeps",
+            ),
+            &["reason"],
+        )
+        .expect("qs_pushback_sleep_seconds_total");
+        let edit_delay = IntGaugeVec::new(
+            Opts::new(
+                "qs_batch_edit_delay_ms",
+                "Current adaptive delay between edits",
+            ),
+            &["batch"],
+        )
+        .expect("qs_batch_edit_delay_ms");
+        let running_batches = IntGaugeVec::new(
+            Opts::new("qs_running_batches", "Batches this process runs"),
+            &["user", "site"],
+        )
+        .expect("qs_running_batches");
+        let db_latency = HistogramVec::new(
+            HistogramOpts::new("qs_db_query_duration_seconds", "Database call latency")
+                .buckets(DB_LATENCY_BUCKETS.to_vec()),
+            &["operation"],
+        )
+        .expect("qs_db_query_duration_seconds");
+
+        for collector in [
+            Box::new(commands.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(api_errors.clone()),
+            Box::new(pushback_sleeps.clone()),
+            Box::new(pushback_sleep_seconds.clone()),
+            Box::new(edit_delay.clone()),
+            Box::new(running_batches.clone()),
+            Box::new(db_latency.clone()),
+        ] {
+            registry.register(collector).expect("unique metric names");
+        }
+
+        Self {
+            registry,
+            commands,
+            api_errors,
+            pushback_sleeps,
+            pushback_sleep_seconds,
+            edit_delay,
+            running_batches,
+            db_latency,
+            running: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn command_status(&self, action: &str, status: &str) {
+        self.commands.with_label_values(&[action, status]).inc();
+    }
+
+    pub fn api_error(&self, code: &str) {
+        self.api_errors.with_label_values(&[code]).inc();
+    }
+
+    /// A sleep on maxlag, rate limiting or throttling
+    pub fn pushback_sleep(&self, reason: &str, duration: Duration) {
+        self.pushback_sleeps.with_label_values(&[reason]).inc();
+        self.pushback_sleep_seconds
+            .with_label_values(&[reason])
+            .inc_by(duration.as_secs_f64());
+    }
+
+    pub fn set_edit_delay(&self, batch_id: i64, delay_ms: u64) {
+        self.edit_delay
+            .with_label_values(&[&batch_id.to_string()])
+            .set(delay_ms as i64);
+    }
+
+    pub fn batch_started(&self, batch_id: i64, user_id: i64, site: &str) {
+        let labels = (user_id.to_string(), site.to_string());
+        let mut running = self.running.lock().unwrap_or_else(|e| e.into_inner());
+        if running.contains_key(&batch_id) {
+            return;
+        }
+        self.running_batches
+            .with_label_values(&[&labels.0, &labels.1])
+            .inc();
+        running.insert(batch_id, labels);
+    }
+
+    /// Drops the batch from the running and edit delay gauges
+    pub fn batch_stopped(&self, batch_id: i64) {
+        let _ = self
+            .edit_delay
+            .remove_label_values(&[&batch_id.to_string()]);
+        let mut running = self.running.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((user, site)) = running.remove(&batch_id) {
+            let gauge = self.running_batches.with_label_values(&[&user, &site]);
+            gauge.dec();
+            if gauge.get() <= 0 {
+                let _ = self.running_batches.remove_label_values(&[&user, &site]);
+            }
+        }
+    }
+
+    pub fn observe_db(&self, operation: &str, duration: Duration) {
+        self.db_latency
+            .with_label_values(&[operation])
+            .observe(duration.as_secs_f64());
+    }
+
+    /// All metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut buffer = vec![];
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            log::error!("Cannot encode metrics: {}", e);
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn running_batches_follow_start_and_stop() {
+        let metrics = Metrics::new();
+        metrics.batch_started(1, 7, "wikidata");
+        metrics.batch_started(1, 7, "wikidata");
+        metrics.batch_started(2, 7, "wikidata");
+        metrics.set_edit_delay(1, 5000);
+
+        let text = metrics.render();
+        assert!(text.contains(r#"qs_running_batches{site="wikidata",user="7"} 2"#));
+        assert!(text.contains(r#"qs_batch_edit_delay_ms{batch="1"} 5000"#));
+
+        metrics.batch_stopped(1);
+        metrics.batch_stopped(2);
+
+        let text = metrics.render();
+        assert!(!text.contains("qs_running_batches{"));
+        assert!(!text.contains("qs_batch_edit_delay_ms{"));
+    }
+
+    #[test]
+    fn counters_are_rendered() {
+        let metrics = Metrics::new();
+        metrics.command_status("add", "DONE");
+        metrics.api_error("maxlag");
+        metrics.pushback_sleep("maxlag", Duration::from_millis(1500));
+        metrics.observe_db("get_batch", Duration::from_millis(3));
+
+        let text = metrics.render();
+        assert!(text.contains(r#"qs_commands_total{action="add",status="DONE"} 1"#));
+        assert!(text.contains(r#"qs_api_errors_total{code="maxlag"} 1"#));
+        assert!(text.contains(r#"qs_pushback_sleep_seconds_total{reason="maxlag"} 1.5"#));
+        assert!(text.contains(r#"qs_db_query_duration_seconds_count{operation="get_batch"} 1"#));
+    }
+}
@@ -0,0 +1,175 @@
+/// A versioned schema change. Applied migrations are recorded in the
+/// `schema_migrations` table, so each one runs exactly once per database.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+/// Migrations for MariaDB (10.2.1 or later), in order. They use
+/// `ADD COLUMN IF NOT EXISTS` and defaults on TEXT columns, which MySQL does
+/// not support. `{auth_db}` in the SQL is replaced with the configured auth
+/// schema.
+pub const MYSQL_MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial",
+        sql: include_str!("../migrations/mysql/0001_initial.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "batch_leases",
+        sql: include_str!("../migrations/mysql/0002_batch_leases.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "batch_priority",
+        sql: include_str!("../migrations/mysql/0003_batch_priority.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "batch_schedule",
+        sql: include_str!("../migrations/mysql/0004_batch_schedule.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "sessions",
+        sql: include_str!("../migrations/mysql/0005_sessions.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "api_tokens",
+        sql: include_str!("../migrations/mysql/0006_api_tokens.sql"),
+    },
+    Migration {
+        version: 7,
+        name: "change_feed",
+        sql: include_str!("../migrations/mysql/0007_change_feed.sql"),
+    },
+    Migration {
+        version: 8,
+        name: "temporary_batches",
+        sql: include_str!("../migrations/mysql/0008_temporary_batches.sql"),
+    },
+    Migration {
+        version: 9,
+        name: "command_error_code",
+        sql: include_str!("../migrations/mysql/0009_command_error_code.sql"),
+    },
+];
+
+/// Migrations for the embedded SQLite backend, in order.
+pub const SQLITE_MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial",
+        sql: include_str!("../migrations/sqlite/0001_initial.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "batch_leases",
+        sql: include_str!("../migrations/sqlite/0002_batch_leases.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "batch_priority",
+        sql: include_str!("../migrations/sqlite/0003_batch_priority.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "batch_schedule",
+        sql: include_str!("../migrations/sqlite/0004_batch_schedule.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "sessions",
+        sql: include_str!("../migrations/sqlite/0005_sessions.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "api_tokens",
+        sql: include_str!("../migrations/sqlite/0006_api_tokens.sql"),
+    },
+    Migration {
+        version: 7,
+        name: "change_feed",
+        sql: include_str!("../migrations/sqlite/0007_change_feed.sql"),
+    },
+    Migration {
+        version: 8,
+        name: "temporary_batches",
+        sql: include_str!("../migrations/sqlite/0008_temporary_batches.sql"),
+    },
+    Migration {
+        version: 9,
+        name: "command_error_code",
+        sql: include_str!("../migrations/sqlite/0009_command_error_code.sql"),
+    },
+];
+
+/// The migrations not yet in `applied`, in order.
+pub fn pending<'a>(migrations: &'a [Migration], applied: &[u32]) -> Vec<&'a Migration> {
+    migrations
+        .iter()
+        .filter(|m| !applied.contains(&m.version))
+        .collect()
+}
+
+/// Splits a migration into single statements, dropping `--` comment lines.
+/// Not a SQL parser: statements must end with `;` at the end of a line.
+pub fn statements(sql: &str) -> Vec<String> {
+    let mut ret = vec![];
+    let mut current = String::new();
+    for line in sql.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with("--") {
+            continue;
+        }
+        current += line;
+        current += "\n";
+        if trimmed.ends_with(';') {
+            ret.push(current.trim().trim_end_matches(';').to_string());
+            current.clear();
+        }
+    }
+    if !current.trim().is_empty() {
+        ret.push(current.trim().to_string());
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migration_versions_are_ascending() {
+        for migrations in [MYSQL_MIGRATIONS, SQLITE_MIGRATIONS] {
+            let versions: Vec<u32> = migrations.iter().map(|m| m.version).collect();
+            let mut sorted = versions.clone();
+            sorted.sort();
+            sorted.dedup();
+            assert_eq!(versions, sorted);
+        }
+    }
+
+    #[test]
+    fn pending_skips_applied() {
+        let pending = pending(SQLITE_MIGRATIONS, &[1]);
+        assert!(pending.iter().all(|m| m.version != 1));
+        assert_eq!(
+            super::pending(SQLITE_MIGRATIONS, &[]).len(),
+            SQLITE_MIGRATIONS.len()
+        );
+    }
+
+    #[test]
+    fn statements_splits_and_drops_comments() {
+        let sql = "-- comment\nCREATE TABLE a (\n  x INT\n);\n\nCREATE TABLE b (y INT);\n";
+        assert_eq!(
+            statements(sql),
+            vec!["CREATE TABLE a (\n  x INT\n)", "CREATE TABLE b (y INT)"]
+        );
+    }
+}
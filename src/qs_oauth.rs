@@ -0,0 +1,547 @@
+use crate::error::{QsError, QsResult};
+use crate::qs_storage::timestamp_after;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::header::AUTHORIZATION;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha1::Sha1;
+use std::time::Duration;
+
+/// RFC 3986 unreserved characters stay, everything else is percent-encoded
+const OAUTH_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// OAuth 2.0 access tokens are refreshed when they expire within this time
+const REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+const DEFAULT_INDEX_URL: &str = "https://meta.wikimedia.org/w/index.php";
+const DEFAULT_REST_URL: &str = "https://meta.wikimedia.org/w/rest.php";
+const DEFAULT_API_URL: &str = "https://meta.wikimedia.org/w/api.php";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OAuthVersion {
+    V1,
+    V2,
+}
+
+/// A login that was started and waits for the wiki to redirect the user back
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "version")]
+pub enum PendingLogin {
+    /// The request token of the OAuth 1.0a handshake
+    #[serde(rename = "1.0a")]
+    V1 {
+        token_key: String,
+        token_secret: String,
+    },
+    /// The `state` sent to the OAuth 2.0 authorization endpoint
+    #[serde(rename = "2.0")]
+    V2 { state: String },
+}
+
+/// The tokens a user granted the tool
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "version")]
+pub enum Credentials {
+    #[serde(rename = "1.0a")]
+    V1 {
+        token_key: String,
+        token_secret: String,
+    },
+    #[serde(rename = "2.0")]
+    V2 {
+        access_token: String,
+        refresh_token: String,
+        /// `YYYYMMDDHHMMSS`; empty if the token does not expire
+        expires: String,
+    },
+}
+
+impl Credentials {
+    /// The OAuth 2.0 credentials in a `batch_oauth` entry; OAuth 1.0a entries
+    /// are read by `OAuthParams::new_from_json` instead
+    pub fn from_batch_json(j: &Value) -> Option<Self> {
+        let j = j.get("oauth2")?;
+        Some(Self::V2 {
+            access_token: j["access_token"].as_str()?.to_string(),
+            refresh_token: j["refresh_token"].as_str().unwrap_or_default().to_string(),
+            expires: j["expires"].as_str().unwrap_or_default().to_string(),
+        })
+    }
+
+    /// An OAuth 2.0 access token that expires soon and has a refresh token
+    pub fn needs_refresh(&self) -> bool {
+        match self {
+            Self::V1 { .. } => false,
+            Self::V2 {
+                refresh_token,
+                expires,
+                ..
+            } => {
+                !refresh_token.is_empty()
+                    && !expires.is_empty()
+                    && *expires <= timestamp_after(REFRESH_MARGIN)
+            }
+        }
+    }
+}
+
+/// The consumer side of MediaWiki's OAuth extension (`Special:OAuth` for 1.0a,
+/// `rest.php/oauth2` for 2.0), configured by the `oauth` object in config_rs.json:
+/// `version` ("1.0a" or "2.0"), `consumer_key`, `consumer_secret`, and optionally
+/// `index_url`, `rest_url` and `api_url` of the wiki that registered the consumer
+/// (default: Meta-Wiki) and the `callback_url` registered for 2.0.
+#[derive(Debug, Clone)]
+pub struct OAuthClient {
+    version: OAuthVersion,
+    consumer_key: String,
+    consumer_secret: String,
+    index_url: String,
+    rest_url: String,
+    api_url: String,
+    callback_url: Option<String>,
+    http: reqwest::Client,
+}
+
+impl OAuthClient {
+    pub fn new_from_json(j: &Value) -> QsResult<Self> {
+        let string = |key: &str, default: &str| -> String {
+            j[key]
+                .as_str()
+                .unwrap_or(default)
+                .trim_end_matches('/')
+                .to_string()
+        };
+        let version = match j["version"].as_str().unwrap_or("1.0a") {
+            "1.0a" => OAuthVersion::V1,
+            "2.0" => OAuthVersion::V2,
+            other => {
+                return Err(QsError::ConfigError(format!(
+                    "Unknown OAuth version '{}'",
+                    other
+                )))
+            }
+        };
+        let consumer_key = string("consumer_key", "");
+        let consumer_secret = string("consumer_secret", "");
+        if consumer_key.is_empty() || consumer_secret.is_empty() {
+            return Err(QsError::ConfigError(
+                "oauth needs consumer_key and consumer_secret".to_string(),
+            ));
+        }
+        let http = reqwest::Client::builder()
+            .user_agent(format!("quickstatements_rs/{}", env!("CARGO_PKG_VERSION")))
+            .timeout(Duration::from_secs(30))
+            .build()?;
+        Ok(Self {
+            version,
+            consumer_key,
+            consumer_secret,
+            index_url: string("index_url", DEFAULT_INDEX_URL),
+            rest_url: string("rest_url", DEFAULT_REST_URL),
+            api_url: string("api_url", DEFAULT_API_URL),
+            callback_url: j["callback_url"].as_str().map(|s| s.to_string()),
+            http,
+        })
+    }
+
+    pub fn version(&self) -> OAuthVersion {
+        self.version
+    }
+
+    /// Step 1: the wiki URL to send the user to, and what to remember in the
+    /// session until the wiki redirects back
+    pub async fn start_login(&self) -> QsResult<(String, PendingLogin)> {
+        match self.version {
+            OAuthVersion::V1 => {
+                let j = self
+                    .oauth1_get(
+                        &self.index_url,
+                        &[("title", "Special:OAuth/initiate"), ("format", "json")],
+                        None,
+                        &[("oauth_callback", "oob")],
+                    )
+                    .await?;
+                let (token_key, token_secret) = Self::key_and_secret(&j)?;
+                let mut url = self.url(&self.index_url)?;
+                url.query_pairs_mut()
+                    .append_pair("title", "Special:OAuth/authorize")
+                    .append_pair("oauth_token", &token_key)
+                    .append_pair("oauth_consumer_key", &self.consumer_key);
+                let pending = PendingLogin::V1 {
+                    token_key,
+                    token_secret,
+                };
+                Ok((url.to_string(), pending))
+            }
+            OAuthVersion::V2 => {
+                let state = random_token();
+                let mut url = self.url(&format!("{}/oauth2/authorize", self.rest_url))?;
+                url.query_pairs_mut()
+                    .append_pair("response_type", "code")
+                    .append_pair("client_id", &self.consumer_key)
+                    .append_pair("state", &state);
+                if let Some(callback_url) = &self.callback_url {
+                    url.query_pairs_mut()
+                        .append_pair("redirect_uri", callback_url);
+                }
+                Ok((url.to_string(), PendingLogin::V2 { state }))
+            }
+        }
+    }
+
+    /// Step 2, on the callback: checks that it belongs to the pending login and
+    /// exchanges it for access credentials. `token` is the `oauth_token` (1.0a)
+    /// or `state` (2.0) parameter of the callback, `verifier` the
+    /// `oauth_verifier` (1.0a) or `code` (2.0).
+    pub async fn finish_login(
+        &self,
+        pending: &PendingLogin,
+        token: &str,
+        verifier: &str,
+    ) -> QsResult<Credentials> {
+        match pending {
+            PendingLogin::V1 {
+                token_key,
+                token_secret,
+            } => {
+                if token != token_key {
+                    return Err(QsError::OAuthError(
+                        "Callback does not match the pending login".to_string(),
+                    ));
+                }
+                let j = self
+                    .oauth1_get(
+                        &self.index_url,
+                        &[("title", "Special:OAuth/token"), ("format", "json")],
+                        Some((token_key, token_secret)),
+                        &[("oauth_verifier", verifier)],
+                    )
+                    .await?;
+                let (token_key, token_secret) = Self::key_and_secret(&j)?;
+                Ok(Credentials::V1 {
+                    token_key,
+                    token_secret,
+                })
+            }
+            PendingLogin::V2 { state } => {
+                if token != state {
+                    return Err(QsError::OAuthError(
+                        "Callback does not match the pending login".to_string(),
+                    ));
+                }
+                let mut form = vec![
+                    ("grant_type", "authorization_code"),
+                    ("code", verifier),
+                    ("client_id", &self.consumer_key),
+                    ("client_secret", &self.consumer_secret),
+                ];
+                if let Some(callback_url) = &self.callback_url {
+                    form.push(("redirect_uri", callback_url));
+                }
+                self.oauth2_token(&form).await
+            }
+        }
+    }
+
+    /// New OAuth 2.0 tokens for credentials that `needs_refresh`
+    pub async fn refresh(&self, credentials: &Credentials) -> QsResult<Credentials> {
+        match credentials {
+            Credentials::V1 { .. } => Ok(credentials.clone()),
+            Credentials::V2 { refresh_token, .. } => {
+                self.oauth2_token(&[
+                    ("grant_type", "refresh_token"),
+                    ("refresh_token", refresh_token),
+                    ("client_id", &self.consumer_key),
+                    ("client_secret", &self.consumer_secret),
+                ])
+                .await
+            }
+        }
+    }
+
+    /// The `userinfo` of the user who granted the credentials, with groups and
+    /// block information
+    pub async fn userinfo(&self, credentials: &Credentials) -> QsResult<Value> {
+        let params = [
+            ("action", "query"),
+            ("meta", "userinfo"),
+            ("uiprop", "groups|blockinfo"),
+            ("format", "json"),
+        ];
+        let j = match credentials {
+            Credentials::V1 {
+                token_key,
+                token_secret,
+            } => {
+                self.oauth1_get(&self.api_url, &params, Some((token_key, token_secret)), &[])
+                    .await?
+            }
+            Credentials::V2 { access_token, .. } => {
+                let response = self
+                    .http
+                    .get(&self.api_url)
+                    .query(&params)
+                    .bearer_auth(access_token)
+                    .send()
+                    .await?;
+                Self::json_response(response).await?
+            }
+        };
+        let userinfo = j["query"]["userinfo"].clone();
+        match userinfo["id"].as_i64() {
+            Some(id) if id > 0 => Ok(userinfo),
+            _ => Err(QsError::OAuthError(
+                "The wiki did not identify the user".to_string(),
+            )),
+        }
+    }
+
+    /// The `batch_oauth` entry for credentials. OAuth 1.0a uses the layout of
+    /// the PHP tool that `OAuthParams::new_from_json` reads.
+    pub fn batch_json(&self, credentials: &Credentials) -> Value {
+        match credentials {
+            Credentials::V1 {
+                token_key,
+                token_secret,
+            } => json!({
+                "gConsumerKey": self.consumer_key,
+                "gConsumerSecret": self.consumer_secret,
+                "gTokenKey": token_key,
+                "gTokenSecret": token_secret,
+            }),
+            Credentials::V2 {
+                access_token,
+                refresh_token,
+                expires,
+            } => json!({
+                "oauth2": {
+                    "access_token": access_token,
+                    "refresh_token": refresh_token,
+                    "expires": expires,
+                }
+            }),
+        }
+    }
+
+    fn url(&self, url: &str) -> QsResult<Url> {
+        Url::parse(url).map_err(|e| QsError::ConfigError(format!("Bad OAuth URL {}: {}", url, e)))
+    }
+
+    async fn oauth1_get(
+        &self,
+        url: &str,
+        params: &[(&str, &str)],
+        token: Option<(&str, &str)>,
+        extra: &[(&str, &str)],
+    ) -> QsResult<Value> {
+        let header = oauth1_header(
+            "GET",
+            url,
+            params,
+            (&self.consumer_key, &self.consumer_secret),
+            token,
+            extra,
+            &random_token(),
+            &chrono::Utc::now().timestamp().to_string(),
+        );
+        let response = self
+            .http
+            .get(url)
+            .query(params)
+            .header(AUTHORIZATION, header)
+            .send()
+            .await?;
+        Self::json_response(response).await
+    }
+
+    async fn oauth2_token(&self, form: &[(&str, &str)]) -> QsResult<Credentials> {
+        let response = self
+            .http
+            .post(format!("{}/oauth2/access_token", self.rest_url))
+            .form(form)
+            .send()
+            .await?;
+        let j = Self::json_response(response).await?;
+        let access_token = j["access_token"]
+            .as_str()
+            .ok_or_else(|| QsError::OAuthError("No access token in response".to_string()))?;
+        let expires = match j["expires_in"].as_u64() {
+            Some(seconds) => timestamp_after(Duration::from_secs(seconds)),
+            None => String::new(),
+        };
+        Ok(Credentials::V2 {
+            access_token: access_token.to_string(),
+            refresh_token: j["refresh_token"].as_str().unwrap_or_default().to_string(),
+            expires,
+        })
+    }
+
+    async fn json_response(response: reqwest::Response) -> QsResult<Value> {
+        let text = response.text().await?;
+        let j: Value = serde_json::from_str(&text).map_err(|_| {
+            QsError::OAuthError(format!(
+                "Not a JSON response: {}",
+                text.chars().take(200).collect::<String>()
+            ))
+        })?;
+        match j.get("error") {
+            Some(Value::String(error)) => {
+                let description = j["error_description"]
+                    .as_str()
+                    .or_else(|| j["message"].as_str())
+                    .unwrap_or_default();
+                Err(QsError::OAuthError(
+                    format!("{} {}", error, description).trim().to_string(),
+                ))
+            }
+            Some(error) => Err(QsError::OAuthError(error.to_string())),
+            None => Ok(j),
+        }
+    }
+
+    fn key_and_secret(j: &Value) -> QsResult<(String, String)> {
+        match (j["key"].as_str(), j["secret"].as_str()) {
+            (Some(key), Some(secret)) => Ok((key.to_string(), secret.to_string())),
+            _ => Err(QsError::OAuthError(format!("No token in response: {}", j))),
+        }
+    }
+}
+
+/// A random 128-bit hex string, for nonces, `state` and session IDs
+pub fn random_token() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+fn encode(s: &str) -> String {
+    utf8_percent_encode(s, OAUTH_ENCODE_SET).to_string()
+}
+
+/// The `Authorization` header of an OAuth 1.0a request signed with HMAC-SHA1.
+/// `params` are the query parameters of the request, `extra` protocol
+/// parameters like `oauth_callback` or `oauth_verifier`.
+#[allow(clippy::too_many_arguments)]
+fn oauth1_header(
+    method: &str,
+    url: &str,
+    params: &[(&str, &str)],
+    consumer: (&str, &str),
+    token: Option<(&str, &str)>,
+    extra: &[(&str, &str)],
+    nonce: &str,
+    timestamp: &str,
+) -> String {
+    let mut oauth = vec![
+        ("oauth_consumer_key", consumer.0),
+        ("oauth_nonce", nonce),
+        ("oauth_signature_method", "HMAC-SHA1"),
+        ("oauth_timestamp", timestamp),
+        ("oauth_version", "1.0"),
+    ];
+    if let Some((token_key, _)) = token {
+        oauth.push(("oauth_token", token_key));
+    }
+    oauth.extend_from_slice(extra);
+
+    let all: Vec<(&str, &str)> = params.iter().chain(oauth.iter()).copied().collect();
+    let base = signature_base_string(method, url, &all);
+    let key = format!(
+        "{}&{}",
+        encode(consumer.1),
+        encode(token.map(|(_, secret)| secret).unwrap_or_default())
+    );
+    let signature = hmac_sha1_base64(&key, &base);
+
+    let mut header: Vec<String> = oauth
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", encode(k), encode(v)))
+        .collect();
+    header.push(format!("oauth_signature=\"{}\"", encode(&signature)));
+    format!("OAuth {}", header.join(", "))
+}
+
+/// RFC 5849 section 3.4.1
+fn signature_base_string(method: &str, url: &str, params: &[(&str, &str)]) -> String {
+    let mut pairs: Vec<(String, String)> =
+        params.iter().map(|(k, v)| (encode(k), encode(v))).collect();
+    pairs.sort();
+    let normalized = pairs
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join("&");
+    format!(
+        "{}&{}&{}",
+        method.to_uppercase(),
+        encode(url),
+        encode(&normalized)
+    )
+}
+
+fn hmac_sha1_base64(key: &str, data: &str) -> String {
+    let mut mac =
+        Hmac::<Sha1>::new_from_slice(key.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(data.as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_with_hmac_sha1() {
+        assert_eq!(
+            hmac_sha1_base64("key", "The quick brown fox jumps over the lazy dog"),
+            "3nybhbi3iqa8ino29wqQcBydtNk="
+        );
+    }
+
+    #[test]
+    fn base_string_sorts_and_encodes_params() {
+        let base = signature_base_string(
+            "get",
+            "https://meta.wikimedia.org/w/index.php",
+            &[
+                ("title", "Special:OAuth/initiate"),
+                ("oauth_callback", "oob"),
+                ("format", "json"),
+            ],
+        );
+        assert_eq!(
+            base,
+            "GET&https%3A%2F%2Fmeta.wikimedia.org%2Fw%2Findex.php&\
+             format%3Djson%26oauth_callback%3Doob%26title%3DSpecial%253AOAuth%252Finitiate"
+        );
+    }
+
+    #[test]
+    fn oauth2_credentials_round_trip_through_batch_json() {
+        let client = OAuthClient::new_from_json(&json!({
+            "version": "2.0",
+            "consumer_key": "ck",
+            "consumer_secret": "cs",
+        }))
+        .unwrap();
+        let credentials = Credentials::V2 {
+            access_token: "at".to_string(),
+            refresh_token: "rt".to_string(),
+            expires: "20000101000000".to_string(),
+        };
+
+        let j = client.batch_json(&credentials);
+
+        assert_eq!(Credentials::from_batch_json(&j), Some(credentials.clone()));
+        assert!(credentials.needs_refresh());
+        assert_eq!(
+            Credentials::from_batch_json(&json!({"gTokenKey": "x"})),
+            None
+        );
+    }
+}
@@ -3568,4 +3568,36 @@ mod tests {
         let result = QuickStatementsParser::parse_value("en:\"hello\"".to_string());
         assert!(result.is_some());
     }
+
+    // Whatever the import produces must pass the check batch creation does
+    #[tokio::test]
+    async fn parsed_commands_validate() {
+        let lines = [
+            "CREATE",
+            "MERGE\tQ123\tQ456",
+            "Q123\tLen\t\"label\"",
+            "Q123\tAit\t\"alias\"",
+            "Q123\tSenwiki\t\"Page\"",
+            "Q123\tP456\tQ789\tP321\tQ654\tS143\tQ999",
+            "Q123\tP456\tsomevalue",
+            "-Q123\tP456\tQ789",
+            "LAST\tADD_FORM\ten:\"waters\"\tQ146786",
+            "L123\tADD_SENSE\ten:\"transparent liquid\"",
+            "L123\tLemma_en\t\"water\"",
+            "L123\tLEXICAL_CATEGORY\tQ1084",
+            "L123-F1\tGRAMMATICAL_FEATURE\tQ1,Q2,Q3",
+            "L123-S1\tGloss_en\t\"act of running\"",
+        ];
+        for line in lines {
+            let qsp = QuickStatementsParser::new_from_line(line, None)
+                .await
+                .unwrap();
+            for j in qsp.to_json().unwrap() {
+                let command = crate::qs_command::QuickStatementsCommand::new_from_json(&j);
+                if let Err(e) = command.validate() {
+                    panic!("{}: {}", j, e);
+                }
+            }
+        }
+    }
 }
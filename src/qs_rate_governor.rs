@@ -0,0 +1,302 @@
+use crate::qs_storage::timestamp;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Edits per minute for a site without its own `site_limits` entry
+const DEFAULT_EDITS_PER_MINUTE: f64 = 600.0;
+
+/// Edits that can be made back to back after a quiet period
+const BURST: f64 = 5.0;
+
+/// However much pushback a site gives, its rate stays above this fraction of
+/// the maximum, so the bots keep probing whether the site has recovered
+const MIN_RATE_FRACTION: f64 = 1.0 / 60.0;
+
+/// Each successful edit wins back this fraction of the maximum rate
+/// (additive increase; pushback halves the rate)
+const RATE_RECOVERY_FRACTION: f64 = 0.02;
+
+/// Limits for one site, from the `site_limits` object in config_rs.json:
+///
+/// ```json
+/// "site_limits": {
+///     "default": {"edits_per_minute": 600},
+///     "wikidata": {"edits_per_minute": 1200, "max_batches": 20}
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct SiteLimits {
+    pub edits_per_minute: f64,
+    /// Batches editing the site at once, across all bot processes; no limit if unset
+    pub max_batches: Option<usize>,
+}
+
+impl Default for SiteLimits {
+    fn default() -> Self {
+        Self {
+            edits_per_minute: DEFAULT_EDITS_PER_MINUTE,
+            max_batches: None,
+        }
+    }
+}
+
+impl SiteLimits {
+    fn new_from_json(j: &Value, fallback: &SiteLimits) -> Self {
+        Self {
+            edits_per_minute: j["edits_per_minute"]
+                .as_f64()
+                .filter(|r| *r > 0.0)
+                .unwrap_or(fallback.edits_per_minute),
+            max_batches: j["max_batches"]
+                .as_u64()
+                .map(|n| n as usize)
+                .or(fallback.max_batches),
+        }
+    }
+}
+
+/// A token bucket whose refill rate follows the site's feedback
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    /// Tokens per second
+    rate: f64,
+    max_rate: f64,
+    last_refill: Instant,
+    paused_until: Instant,
+}
+
+impl Bucket {
+    fn new(limits: &SiteLimits, now: Instant) -> Self {
+        let max_rate = limits.edits_per_minute / 60.0;
+        Self {
+            tokens: BURST,
+            rate: max_rate,
+            max_rate,
+            last_refill: now,
+            paused_until: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(BURST);
+        self.last_refill = self.last_refill.max(now);
+    }
+
+    /// Takes a token, or returns how long to wait before trying again
+    fn try_take(&mut self, now: Instant) -> Result<(), Duration> {
+        if now < self.paused_until {
+            return Err(self.paused_until - now);
+        }
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+    }
+
+    fn success(&mut self) {
+        self.rate = (self.rate + self.max_rate * RATE_RECOVERY_FRACTION).min(self.max_rate);
+    }
+
+    fn pushback(&mut self, pause: Duration, now: Instant) {
+        self.refill(now);
+        self.rate = (self.rate / 2.0).max(self.max_rate * MIN_RATE_FRACTION);
+        if !pause.is_zero() {
+            self.paused_until = self.paused_until.max(now + pause);
+            self.tokens = 0.0;
+            // Nothing accumulates during the pause
+            self.last_refill = self.paused_until;
+        }
+    }
+}
+
+/// Edit pacing shared by all bots of this process that edit the same site, so
+/// twenty batches against one wiki back off together instead of each hitting
+/// maxlag on its own. Every edit takes a token from the site's bucket; the
+/// bots report how the site responded, which sets the refill rate.
+#[derive(Debug, Default)]
+pub struct RateGovernor {
+    default_limits: SiteLimits,
+    site_limits: HashMap<String, SiteLimits>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+    /// Timestamp of the last successful edit per site
+    last_success: Mutex<HashMap<String, String>>,
+}
+
+impl RateGovernor {
+    /// From the `site_limits` object in config_rs.json; sites without an entry
+    /// use the `default` one
+    pub fn new_from_json(j: &Value) -> Self {
+        let default_limits = SiteLimits::new_from_json(&j["default"], &SiteLimits::default());
+        let site_limits = j
+            .as_object()
+            .map(|o| {
+                o.iter()
+                    .filter(|(site, _)| *site != "default")
+                    .map(|(site, limits)| {
+                        (
+                            site.to_owned(),
+                            SiteLimits::new_from_json(limits, &default_limits),
+                        )
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self {
+            default_limits,
+            site_limits,
+            buckets: Mutex::new(HashMap::new()),
+            last_success: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn limits(&self, site: &str) -> &SiteLimits {
+        self.site_limits.get(site).unwrap_or(&self.default_limits)
+    }
+
+    /// Runs `f` on the site's bucket, creating it on first use
+    fn with_bucket<T>(&self, site: &str, f: impl FnOnce(&mut Bucket, Instant) -> T) -> T {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let bucket = buckets
+            .entry(site.to_owned())
+            .or_insert_with(|| Bucket::new(self.limits(site), now));
+        f(bucket, now)
+    }
+
+    /// Waits until the site may be edited
+    pub async fn acquire(&self, site: &str) {
+        while let Err(wait) = self.with_bucket(site, |bucket, now| bucket.try_take(now)) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    pub fn report_success(&self, site: &str) {
+        self.with_bucket(site, |bucket, _| bucket.success());
+        let mut last_success = self.last_success.lock().unwrap_or_else(|e| e.into_inner());
+        last_success.insert(site.to_owned(), timestamp());
+    }
+
+    /// (site, timestamp) of the last successful edit on each site, by site
+    pub fn last_successful_edits(&self) -> Vec<(String, String)> {
+        let last_success = self.last_success.lock().unwrap_or_else(|e| e.into_inner());
+        let mut ret: Vec<_> = last_success
+            .iter()
+            .map(|(site, ts)| (site.to_owned(), ts.to_owned()))
+            .collect();
+        ret.sort();
+        ret
+    }
+
+    /// The site pushed back: halve its rate, and stop editing it for `pause`.
+    /// Replication lag is a site-wide problem and warrants a pause; rate limits
+    /// are per user, so they only slow the site down (`Duration::ZERO`).
+    pub fn report_pushback(&self, site: &str, pause: Duration) {
+        self.with_bucket(site, |bucket, now| bucket.pushback(pause, now));
+    }
+
+    /// Current edit rate for the site, in edits per minute
+    pub fn edits_per_minute(&self, site: &str) -> f64 {
+        self.with_bucket(site, |bucket, _| bucket.rate * 60.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(edits_per_minute: f64) -> SiteLimits {
+        SiteLimits {
+            edits_per_minute,
+            max_batches: None,
+        }
+    }
+
+    fn limits_with(edits_per_minute: f64, max_batches: usize) -> SiteLimits {
+        SiteLimits {
+            edits_per_minute,
+            max_batches: Some(max_batches),
+        }
+    }
+
+    #[test]
+    fn bucket_allows_a_burst_then_paces() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(&limits(60.0), now);
+
+        for _ in 0..BURST as usize {
+            assert!(bucket.try_take(now).is_ok());
+        }
+        let wait = bucket.try_take(now).unwrap_err();
+        assert_eq!(wait, Duration::from_secs(1));
+        assert!(bucket.try_take(now + wait).is_ok());
+    }
+
+    #[test]
+    fn pushback_pauses_and_slows_down_until_successes() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(&limits(60.0), now);
+
+        bucket.pushback(Duration::from_secs(10), now);
+
+        assert_eq!(
+            bucket.try_take(now + Duration::from_secs(4)),
+            Err(Duration::from_secs(6))
+        );
+        assert_eq!(bucket.rate, 0.5);
+        // The first token after the pause takes 1/rate seconds to refill
+        let after_pause = now + Duration::from_secs(10);
+        assert_eq!(bucket.try_take(after_pause), Err(Duration::from_secs(2)));
+
+        for _ in 0..100 {
+            bucket.success();
+        }
+        assert_eq!(bucket.rate, 1.0);
+    }
+
+    #[test]
+    fn rate_has_a_floor() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(&limits(60.0), now);
+
+        for _ in 0..20 {
+            bucket.pushback(Duration::ZERO, now);
+        }
+
+        assert_eq!(bucket.rate, MIN_RATE_FRACTION);
+    }
+
+    #[test]
+    fn successful_edits_are_recorded_per_site() {
+        let governor = RateGovernor::default();
+        governor.report_success("wikidata");
+        governor.report_success("commons");
+
+        let sites: Vec<String> = governor
+            .last_successful_edits()
+            .into_iter()
+            .map(|(site, _)| site)
+            .collect();
+        assert_eq!(sites, vec!["commons", "wikidata"]);
+    }
+
+    #[test]
+    fn site_limits_fall_back_to_default() {
+        let governor = RateGovernor::new_from_json(&json!({
+            "default": {"edits_per_minute": 120, "max_batches": 5},
+            "wikidata": {"max_batches": 20},
+        }));
+
+        assert_eq!(governor.limits("commons"), &limits_with(120.0, 5));
+        assert_eq!(governor.limits("wikidata"), &limits_with(120.0, 20));
+        assert_eq!(
+            RateGovernor::new_from_json(&json!(null)).limits("wikidata"),
+            &SiteLimits::default()
+        );
+    }
+}
@@ -0,0 +1,978 @@
+//! Versioned REST API under `/api/v1`, next to the legacy `api.php`. Requests
+//! and responses are typed, errors come with a proper HTTP status, and the
+//! OpenAPI document at `/api/v1/openapi.json` is generated from the types.
+
+use axum::extract::rejection::{JsonRejection, QueryRejection};
+use axum::extract::{Path, Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Json, Response};
+use axum::routing::get;
+use axum::Router;
+use schemars::generate::SchemaSettings;
+use schemars::{JsonSchema, SchemaGenerator};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+use crate::error::ERROR_CODES;
+use crate::qs_config::QuickStatements;
+use crate::qs_error_report::ErrorReport;
+use crate::qs_server::{
+    authenticate, authorize, batch_owner, check_batch_transition, create_batch, load_session,
+    AppState, BatchError, Caller, Denied, TransitionError,
+};
+use crate::qs_storage::{BatchRow, COMMAND_STATUSES};
+
+/// Batches listed when no `limit` is given, and the most one request may ask for
+const DEFAULT_BATCH_LIMIT: i64 = 50;
+const MAX_BATCH_LIMIT: i64 = 500;
+
+/// Commands listed when no `limit` is given, and the most one request may ask for
+const DEFAULT_COMMAND_LIMIT: i64 = 100;
+const MAX_COMMAND_LIMIT: i64 = 1000;
+
+/// A batch with the number of its commands in each status
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct Batch {
+    pub id: i64,
+    pub name: String,
+    /// Name of the user the batch belongs to
+    pub user: String,
+    pub site: String,
+    /// INIT, RUN, PAUSE, STOP, DONE or BLOCKED
+    pub status: String,
+    pub message: String,
+    /// Entity created by the last CREATE command
+    pub last_item: String,
+    /// `YYYYMMDDHHMMSS`
+    pub ts_last_change: String,
+    /// Higher priority batches are started first
+    pub priority: i64,
+    /// Not started before this `YYYYMMDDHHMMSS`; empty for any time
+    pub ts_not_before: String,
+    /// Recurrence rule; empty for none
+    pub recurrence: String,
+    /// Number of commands per command status
+    pub commands: BTreeMap<String, i64>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+pub struct BatchList {
+    pub batches: Vec<Batch>,
+}
+
+/// One command of a batch
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct Command {
+    pub id: i64,
+    /// Position in the batch
+    pub num: i64,
+    /// The command, as created by the parser
+    pub json: Value,
+    /// INIT, RUN, DONE, ERROR, BLOCKED or STOP
+    pub status: String,
+    pub message: String,
+    /// `YYYYMMDDHHMMSS`
+    pub ts_change: String,
+    /// Why the command failed, one of the error codes; empty unless it did
+    pub error_code: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+pub struct CommandList {
+    pub commands: Vec<Command>,
+}
+
+/// Body of `POST /batches`
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+pub struct NewBatch {
+    #[serde(default)]
+    pub name: String,
+    /// Defaults to the configured default site
+    pub site: Option<String>,
+    /// Commands as created by the parser (`action=import`)
+    pub commands: Vec<Value>,
+    /// Capped at `scheduler.max_user_priority` unless the caller is an admin
+    pub priority: Option<i32>,
+}
+
+/// Status a batch can be set to: INIT (re)starts or resumes it, STOP stops
+/// it, PAUSE stops it to be resumed later
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq)]
+pub enum RequestedStatus {
+    #[serde(rename = "INIT")]
+    Init,
+    #[serde(rename = "STOP")]
+    Stop,
+    #[serde(rename = "PAUSE")]
+    Pause,
+}
+
+impl RequestedStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Init => "INIT",
+            Self::Stop => "STOP",
+            Self::Pause => "PAUSE",
+        }
+    }
+
+    /// The `api.php` action whose permissions apply
+    fn action(&self) -> &'static str {
+        match self {
+            Self::Init => "start_batch",
+            Self::Stop => "stop_batch",
+            Self::Pause => "pause_batch",
+        }
+    }
+}
+
+/// Body of `PATCH /batches/{id}`; at least one field must be given
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default)]
+pub struct BatchUpdate {
+    pub status: Option<RequestedStatus>,
+    /// Capped at `scheduler.max_user_priority` unless the caller is an admin
+    pub priority: Option<i32>,
+}
+
+/// Query of `GET /batches`
+#[derive(Deserialize, JsonSchema, Debug, Default)]
+pub struct BatchQuery {
+    /// Only batches of this user
+    pub user: Option<String>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+/// Query of `GET /batches/{id}/commands`
+#[derive(Deserialize, JsonSchema, Debug, Default)]
+pub struct CommandQuery {
+    /// Comma-separated command statuses
+    pub status: Option<String>,
+    /// Only commands that failed with this error code
+    pub error_code: Option<String>,
+    /// Skip commands before this position
+    pub start: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+/// Body of every error response
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+pub struct ErrorBody {
+    pub error: String,
+    /// The commands a new batch was rejected for
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<CommandError>,
+}
+
+/// A command that cannot be run
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct CommandError {
+    /// Position in the batch, from 0
+    pub num: usize,
+    pub error: String,
+}
+
+/// An error response
+#[derive(Debug)]
+pub struct RestError {
+    status: StatusCode,
+    message: String,
+    errors: Vec<CommandError>,
+}
+
+impl RestError {
+    fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+            errors: vec![],
+        }
+    }
+
+    fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    fn not_found(batch_id: i64) -> Self {
+        Self::new(
+            StatusCode::NOT_FOUND,
+            format!("Batch {} not found", batch_id),
+        )
+    }
+
+    fn internal(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, message)
+    }
+
+    /// 409 for a status the batch cannot change to
+    fn transition(error: TransitionError, batch_id: i64) -> Self {
+        match error {
+            TransitionError::NotFound => Self::not_found(batch_id),
+            TransitionError::Invalid(message) => Self::new(StatusCode::CONFLICT, message),
+            TransitionError::Failed => Self::internal("Could not read batch"),
+        }
+    }
+}
+
+impl From<Denied> for RestError {
+    fn from(denied: Denied) -> Self {
+        Self::new(denied.status, denied.message)
+    }
+}
+
+impl From<BatchError> for RestError {
+    fn from(error: BatchError) -> Self {
+        match error {
+            BatchError::Invalid(errors) => Self {
+                status: StatusCode::BAD_REQUEST,
+                message: format!("{} commands are invalid", errors.len()),
+                errors: errors
+                    .into_iter()
+                    .map(|(num, error)| CommandError { num, error })
+                    .collect(),
+            },
+            BatchError::Failed => Self::internal("Could not create batch"),
+        }
+    }
+}
+
+impl From<JsonRejection> for RestError {
+    fn from(rejection: JsonRejection) -> Self {
+        match rejection {
+            // A body of the wrong shape, like a priority beyond 32 bits, is
+            // an invalid request like any other
+            JsonRejection::JsonDataError(_) => Self::bad_request(rejection.body_text()),
+            _ => Self::new(rejection.status(), rejection.body_text()),
+        }
+    }
+}
+
+impl From<QueryRejection> for RestError {
+    fn from(rejection: QueryRejection) -> Self {
+        Self::bad_request(rejection.body_text())
+    }
+}
+
+impl IntoResponse for RestError {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            error: self.message,
+            errors: self.errors,
+        };
+        (self.status, Json(body)).into_response()
+    }
+}
+
+type RestResult<T> = Result<T, RestError>;
+
+/// Routes of the REST API, to be nested under `/api/v1`
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/openapi.json", get(serve_openapi))
+        .route("/batches", get(list_batches).post(post_batch))
+        .route("/batches/{id}", get(get_batch).patch(patch_batch))
+        .route("/batches/{id}/commands", get(list_commands))
+        .route("/batches/{id}/errors", get(get_error_report))
+}
+
+/// The caller of a request: a personal API token given as
+/// `Authorization: Bearer <token>`, or else the session cookie
+async fn caller(qs: &QuickStatements, headers: &HeaderMap) -> RestResult<Option<Caller>> {
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim);
+    let session = match token {
+        Some(_) => None,
+        None => load_session(qs, headers).await,
+    };
+    Ok(authenticate(qs, token, None, session).await?)
+}
+
+/// `GET /batches`, newest first
+async fn list_batches(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    query: Result<Query<BatchQuery>, QueryRejection>,
+) -> RestResult<Json<BatchList>> {
+    let Query(query) = query?;
+    let qs = &state.config;
+    let caller = caller(qs, &headers).await?;
+    authorize(qs, "get_batches_info", None, false, caller.as_ref()).await?;
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_BATCH_LIMIT)
+        .clamp(1, MAX_BATCH_LIMIT);
+    let offset = query.offset.unwrap_or(0).max(0);
+    let user = query.user.as_deref().filter(|user| !user.is_empty());
+    let rows = qs
+        .storage()
+        .get_batches(user, limit, offset)
+        .await
+        .map_err(|_| RestError::internal("Could not read batches"))?;
+    let mut batches = Vec::with_capacity(rows.len());
+    for row in rows {
+        batches.push(batch(qs, row).await?);
+    }
+    Ok(Json(BatchList { batches }))
+}
+
+/// `POST /batches`: creates a batch of the caller, which the bot then runs
+async fn post_batch(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Result<Json<NewBatch>, JsonRejection>,
+) -> RestResult<(StatusCode, [(header::HeaderName, String); 1], Json<Batch>)> {
+    let Json(new_batch) = body?;
+    let qs = &state.config;
+    let caller = caller(qs, &headers).await?;
+    authorize(qs, "run_batch", None, false, caller.as_ref()).await?;
+    if new_batch.commands.is_empty() {
+        return Err(RestError::bad_request("No commands given"));
+    }
+    let site = new_batch
+        .site
+        .as_deref()
+        .unwrap_or(qs.default_site().unwrap_or("wikidata"));
+    let priority = new_batch.priority.map(|priority| {
+        qs.allowed_priority(priority.into(), caller.as_ref().map(Caller::user_name))
+    });
+    let (user_id, oauth_json) = batch_owner(qs, caller).await.map_err(|status| {
+        let message = status.strip_prefix("ERROR: ").unwrap_or(&status);
+        RestError::new(StatusCode::UNAUTHORIZED, message)
+    })?;
+    let batch_id = create_batch(
+        qs,
+        &new_batch.name,
+        site,
+        &new_batch.commands,
+        user_id,
+        oauth_json.as_deref(),
+    )
+    .await?;
+    if let Some(priority) = priority {
+        qs.storage()
+            .set_batch_priority(batch_id, priority)
+            .await
+            .map_err(|_| RestError::internal("Could not set batch priority"))?;
+    }
+    let batch = batch(qs, batch_row(qs, batch_id).await?).await?;
+    let location = format!("/api/v1/batches/{}", batch_id);
+    Ok((
+        StatusCode::CREATED,
+        [(header::LOCATION, location)],
+        Json(batch),
+    ))
+}
+
+/// `GET /batches/{id}`
+async fn get_batch(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(batch_id): Path<i64>,
+) -> RestResult<Json<Batch>> {
+    let qs = &state.config;
+    let caller = caller(qs, &headers).await?;
+    authorize(qs, "get_batch_info", Some(batch_id), false, caller.as_ref()).await?;
+    Ok(Json(batch(qs, batch_row(qs, batch_id).await?).await?))
+}
+
+/// `PATCH /batches/{id}`: starts or stops a batch, or changes its priority
+async fn patch_batch(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(batch_id): Path<i64>,
+    body: Result<Json<BatchUpdate>, JsonRejection>,
+) -> RestResult<Json<Batch>> {
+    let Json(update) = body?;
+    if update.status.is_none() && update.priority.is_none() {
+        return Err(RestError::bad_request("Nothing to change"));
+    }
+    let qs = &state.config;
+    let caller = caller(qs, &headers).await?;
+    if let Some(status) = update.status {
+        authorize(qs, status.action(), Some(batch_id), false, caller.as_ref()).await?;
+    }
+    if update.priority.is_some() {
+        authorize(
+            qs,
+            "set_batch_priority",
+            Some(batch_id),
+            false,
+            caller.as_ref(),
+        )
+        .await?;
+    }
+    match update.status {
+        Some(status) => {
+            check_batch_transition(qs, batch_id, status.as_str())
+                .await
+                .map_err(|e| RestError::transition(e, batch_id))?;
+        }
+        None => {
+            batch_row(qs, batch_id).await?;
+        }
+    }
+    if let Some(priority) = update.priority {
+        let priority = qs.allowed_priority(priority.into(), caller.as_ref().map(Caller::user_name));
+        qs.storage()
+            .set_batch_priority(batch_id, priority)
+            .await
+            .map_err(|_| RestError::internal("Could not set batch priority"))?;
+    }
+    if let Some(status) = update.status {
+        qs.storage()
+            .set_batch_status(batch_id, status.as_str(), None)
+            .await
+            .map_err(|_| RestError::internal("Could not change batch status"))?;
+    }
+    Ok(Json(batch(qs, batch_row(qs, batch_id).await?).await?))
+}
+
+/// `GET /batches/{id}/commands`, in batch order
+async fn list_commands(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(batch_id): Path<i64>,
+    query: Result<Query<CommandQuery>, QueryRejection>,
+) -> RestResult<Json<CommandList>> {
+    let Query(query) = query?;
+    let qs = &state.config;
+    let caller = caller(qs, &headers).await?;
+    authorize(
+        qs,
+        "get_commands_from_batch",
+        Some(batch_id),
+        false,
+        caller.as_ref(),
+    )
+    .await?;
+    let statuses: Vec<&str> = query
+        .status
+        .as_deref()
+        .unwrap_or("")
+        .split(',')
+        .map(str::trim)
+        .filter(|status| !status.is_empty())
+        .collect();
+    if let Some(status) = statuses.iter().find(|s| !COMMAND_STATUSES.contains(s)) {
+        return Err(RestError::bad_request(format!(
+            "Unknown command status '{}'",
+            status
+        )));
+    }
+    let start = query.start.unwrap_or(0).max(0);
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_COMMAND_LIMIT)
+        .clamp(1, MAX_COMMAND_LIMIT);
+    let error_code = query.error_code.as_deref().filter(|code| !code.is_empty());
+    if let Some(code) = error_code.filter(|code| !ERROR_CODES.contains(code)) {
+        return Err(RestError::bad_request(format!(
+            "Unknown error code '{}'",
+            code
+        )));
+    }
+    batch_row(qs, batch_id).await?;
+    let rows = qs
+        .storage()
+        .get_commands(batch_id, &statuses, error_code, start, limit)
+        .await
+        .map_err(|_| RestError::internal("Could not read commands"))?;
+    let commands = rows
+        .into_iter()
+        .map(|row| Command {
+            id: row.id,
+            num: row.num,
+            json: serde_json::from_str(&row.json).unwrap_or_else(|_| json!({})),
+            status: row.status,
+            message: row.message,
+            ts_change: row.ts_change,
+            error_code: row.error_code,
+        })
+        .collect();
+    Ok(Json(CommandList { commands }))
+}
+
+/// `GET /batches/{id}/errors`: the failed commands, grouped
+async fn get_error_report(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(batch_id): Path<i64>,
+) -> RestResult<Json<ErrorReport>> {
+    let qs = &state.config;
+    let caller = caller(qs, &headers).await?;
+    authorize(
+        qs,
+        "get_error_report",
+        Some(batch_id),
+        false,
+        caller.as_ref(),
+    )
+    .await?;
+    batch_row(qs, batch_id).await?;
+    let report = ErrorReport::for_batch(qs.storage().as_ref(), batch_id)
+        .await
+        .map_err(|_| RestError::internal("Could not read commands"))?;
+    Ok(Json(report))
+}
+
+/// `GET /openapi.json`
+async fn serve_openapi() -> Json<Value> {
+    Json(openapi())
+}
+
+/// A batch row, or 404
+async fn batch_row(qs: &QuickStatements, batch_id: i64) -> RestResult<BatchRow> {
+    match qs.storage().get_batch(batch_id).await {
+        Ok(Some(row)) => Ok(row),
+        Ok(None) => Err(RestError::not_found(batch_id)),
+        Err(_) => Err(RestError::internal("Could not read batch")),
+    }
+}
+
+/// The batch with its user name and command counts
+async fn batch(qs: &QuickStatements, row: BatchRow) -> RestResult<Batch> {
+    let mut commands: BTreeMap<String, i64> = COMMAND_STATUSES
+        .iter()
+        .map(|status| (status.to_string(), 0))
+        .collect();
+    let counts = qs
+        .storage()
+        .get_command_counts(row.id)
+        .await
+        .map_err(|_| RestError::internal("Could not read command counts"))?;
+    commands.extend(counts);
+    let user = qs.get_user_name(row.user).await.unwrap_or_default();
+    Ok(Batch {
+        id: row.id,
+        name: row.name,
+        user,
+        site: row.site,
+        status: row.status,
+        message: row.message,
+        last_item: row.last_item,
+        ts_last_change: row.ts_last_change,
+        priority: row.priority,
+        ts_not_before: row.ts_not_before,
+        recurrence: row.recurrence,
+        commands,
+    })
+}
+
+/// The OpenAPI 3.0 document of the REST API
+pub fn openapi() -> Value {
+    let mut generator = SchemaSettings::openapi3().into_generator();
+    let g = &mut generator;
+    let id = json!({
+        "name": "id",
+        "in": "path",
+        "required": true,
+        "schema": {"type": "integer", "format": "int64"},
+    });
+    let mut command_parameters = vec![id.clone()];
+    command_parameters.extend(query_parameters::<CommandQuery>(g));
+    let paths = json!({
+        "/batches": {
+            "get": {
+                "summary": "List batches, newest first",
+                "operationId": "listBatches",
+                "parameters": query_parameters::<BatchQuery>(g),
+                "responses": responses::<BatchList>(g, "The batches", &[]),
+            },
+            "post": {
+                "summary": "Create a batch, which the bot then runs",
+                "operationId": "createBatch",
+                "requestBody": request_body::<NewBatch>(g),
+                "responses": {
+                    "201": json_body::<Batch>(g, "The new batch"),
+                    "400": error(g, "Invalid request, or commands that cannot be run"),
+                    "401": error(g, "Not logged in"),
+                    "403": error(g, "The token lacks the submit scope"),
+                    "500": error(g, "Internal error"),
+                },
+            },
+        },
+        "/batches/{id}": {
+            "get": {
+                "summary": "Get a batch",
+                "operationId": "getBatch",
+                "parameters": [id.clone()],
+                "responses": responses::<Batch>(g, "The batch", &[("404", "No such batch")]),
+            },
+            "patch": {
+                "summary": "Start or stop a batch, or change its priority",
+                "operationId": "updateBatch",
+                "parameters": [id.clone()],
+                "requestBody": request_body::<BatchUpdate>(g),
+                "responses": responses::<Batch>(g, "The changed batch", &[
+                    ("400", "Invalid request"),
+                    ("404", "No such batch"),
+                    ("409", "The batch cannot change to that status"),
+                ]),
+            },
+        },
+        "/batches/{id}/commands": {
+            "get": {
+                "summary": "List the commands of a batch",
+                "operationId": "listCommands",
+                "parameters": command_parameters,
+                "responses": responses::<CommandList>(g, "The commands", &[
+                    ("400", "Invalid request"),
+                    ("404", "No such batch"),
+                ]),
+            },
+        },
+        "/batches/{id}/errors": {
+            "get": {
+                "summary": "Failed commands of a batch, grouped by error, property and entity",
+                "operationId": "getErrorReport",
+                "parameters": [id],
+                "responses": responses::<ErrorReport>(g, "The error report", &[
+                    ("404", "No such batch"),
+                ]),
+            },
+        },
+    });
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "QuickStatements",
+            "version": "1",
+        },
+        "servers": [{"url": "/api/v1"}],
+        "paths": paths,
+        "components": {
+            "schemas": generator.take_definitions(true),
+            "securitySchemes": {
+                "token": {"type": "http", "scheme": "bearer"},
+                "session": {"type": "apiKey", "in": "cookie", "name": "qs_session"},
+            },
+        },
+        "security": [{"token": []}, {"session": []}, {}],
+    })
+}
+
+fn json_body<T: JsonSchema>(g: &mut SchemaGenerator, description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": {"application/json": {"schema": g.subschema_for::<T>()}},
+    })
+}
+
+fn request_body<T: JsonSchema>(g: &mut SchemaGenerator) -> Value {
+    json!({
+        "required": true,
+        "content": {"application/json": {"schema": g.subschema_for::<T>()}},
+    })
+}
+
+fn error(g: &mut SchemaGenerator, description: &str) -> Value {
+    json_body::<ErrorBody>(g, description)
+}
+
+/// 200 with a `T`, the errors of every endpoint, and `extra` errors
+fn responses<T: JsonSchema>(
+    g: &mut SchemaGenerator,
+    description: &str,
+    extra: &[(&str, &str)],
+) -> Value {
+    let mut responses = json!({
+        "200": json_body::<T>(g, description),
+        "401": error(g, "Invalid token, or login required"),
+        "403": error(g, "Not allowed"),
+        "500": error(g, "Internal error"),
+    });
+    for (status, description) in extra {
+        responses[*status] = error(g, description);
+    }
+    responses
+}
+
+/// The fields of a query struct as OpenAPI query parameters
+fn query_parameters<T: JsonSchema>(g: &mut SchemaGenerator) -> Vec<Value> {
+    let schema = g.root_schema_for::<T>();
+    let required: Vec<&str> = schema
+        .get("required")
+        .and_then(Value::as_array)
+        .map(|names| names.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default();
+    let properties = match schema.get("properties").and_then(Value::as_object) {
+        Some(properties) => properties,
+        None => return vec![],
+    };
+    properties
+        .iter()
+        .map(|(name, property)| {
+            let mut property = property.clone();
+            let description = property
+                .as_object_mut()
+                .and_then(|p| p.remove("description"));
+            let mut parameter = json!({
+                "name": name,
+                "in": "query",
+                "required": required.contains(&name.as_str()),
+                "schema": property,
+            });
+            if let Some(description) = description {
+                parameter["description"] = description;
+            }
+            parameter
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    /// The tests act without a login, as on a standalone server
+    fn state() -> AppState {
+        let mut config = QuickStatements::new_for_tests();
+        config.set_param_for_tests("open_batch_actions", json!(true));
+        AppState {
+            config: Arc::new(config),
+        }
+    }
+
+    fn refs<'a>(value: &'a Value, found: &mut Vec<&'a str>) {
+        match value {
+            Value::Object(map) => {
+                if let Some(Value::String(r)) = map.get("$ref") {
+                    found.push(r);
+                }
+                map.values().for_each(|v| refs(v, found));
+            }
+            Value::Array(values) => values.iter().for_each(|v| refs(v, found)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn openapi_refs_resolve() {
+        let doc = openapi();
+        let mut found = vec![];
+        refs(&doc, &mut found);
+        assert!(found.contains(&"#/components/schemas/Batch"));
+        for r in found {
+            let name = r.strip_prefix("#/components/schemas/").unwrap();
+            assert!(
+                doc["components"]["schemas"].get(name).is_some(),
+                "unresolved {}",
+                r
+            );
+        }
+        let parameters = doc["paths"]["/batches/{id}/commands"]["get"]["parameters"]
+            .as_array()
+            .unwrap();
+        let names: Vec<&str> = parameters
+            .iter()
+            .map(|p| p["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, ["id", "error_code", "limit", "start", "status"]);
+    }
+
+    #[tokio::test]
+    async fn batch_round_trip() {
+        let state = state();
+        let new_batch = NewBatch {
+            name: "test".to_string(),
+            site: None,
+            commands: vec![json!({"action": "create", "type": "item"})],
+            priority: Some(-1),
+        };
+        let (status, _, Json(created)) =
+            post_batch(State(state.clone()), HeaderMap::new(), Ok(Json(new_batch)))
+                .await
+                .unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(created.priority, -1);
+        assert_eq!(created.commands["INIT"], 1);
+
+        let update = BatchUpdate {
+            status: Some(RequestedStatus::Stop),
+            priority: None,
+        };
+        let Json(stopped) = patch_batch(
+            State(state.clone()),
+            HeaderMap::new(),
+            Path(created.id),
+            Ok(Json(update)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(stopped.status, "STOP");
+
+        let query = CommandQuery {
+            status: Some("INIT".to_string()),
+            ..Default::default()
+        };
+        let Json(list) = list_commands(
+            State(state.clone()),
+            HeaderMap::new(),
+            Path(created.id),
+            Ok(Query(query)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(list.commands.len(), 1);
+        assert_eq!(list.commands[0].json["action"], "create");
+    }
+
+    #[test]
+    fn priority_must_fit_the_column() {
+        let update = json!({"priority": i64::from(i32::MAX) + 1});
+        assert!(serde_json::from_value::<BatchUpdate>(update).is_err());
+        let update = json!({"priority": i32::MIN});
+        let update: BatchUpdate = serde_json::from_value(update).unwrap();
+        assert_eq!(update.priority, Some(i32::MIN));
+    }
+
+    async fn patch_status(
+        state: &AppState,
+        batch_id: i64,
+        status: RequestedStatus,
+    ) -> RestResult<Json<Batch>> {
+        let update = BatchUpdate {
+            status: Some(status),
+            priority: None,
+        };
+        patch_batch(
+            State(state.clone()),
+            HeaderMap::new(),
+            Path(batch_id),
+            Ok(Json(update)),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn invalid_status_changes_conflict() {
+        let state = state();
+        let storage = state.config.storage().clone();
+        let commands = vec![json!({"action": "create", "type": "item"}).to_string()];
+        let batch_id = storage
+            .create_batch("test", 1, "wikidata", &commands, None)
+            .await
+            .unwrap();
+        let command = storage.get_next_command(batch_id).await.unwrap().unwrap();
+        storage
+            .set_command_status(command.id, "DONE", "", "", &command.json)
+            .await
+            .unwrap();
+        storage
+            .set_batch_status(batch_id, "DONE", None)
+            .await
+            .unwrap();
+
+        for status in [
+            RequestedStatus::Pause,
+            RequestedStatus::Init,
+            RequestedStatus::Stop,
+        ] {
+            let rejected = patch_status(&state, batch_id, status).await.unwrap_err();
+            assert_eq!(rejected.status, StatusCode::CONFLICT, "for {:?}", status);
+        }
+        let batch = storage.get_batch(batch_id).await.unwrap().unwrap();
+        assert_eq!(batch.status, "DONE");
+
+        // A stopped batch can be started, but not paused
+        storage
+            .set_batch_status(batch_id, "STOP", None)
+            .await
+            .unwrap();
+        let rejected = patch_status(&state, batch_id, RequestedStatus::Pause)
+            .await
+            .unwrap_err();
+        assert_eq!(rejected.status, StatusCode::CONFLICT);
+        let Json(started) = patch_status(&state, batch_id, RequestedStatus::Init)
+            .await
+            .unwrap();
+        assert_eq!(started.status, "INIT");
+
+        let missing = patch_status(&state, 42, RequestedStatus::Stop)
+            .await
+            .unwrap_err();
+        assert_eq!(missing.status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn invalid_commands_reject_the_batch() {
+        let state = state();
+        let new_batch = NewBatch {
+            name: "test".to_string(),
+            site: None,
+            commands: vec![
+                json!({"action": "create", "type": "item"}),
+                json!({"action": "add", "what": "label", "item": "LAST", "value": "x"}),
+            ],
+            priority: None,
+        };
+        let rejected = post_batch(State(state.clone()), HeaderMap::new(), Ok(Json(new_batch)))
+            .await
+            .unwrap_err();
+        assert_eq!(rejected.status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            rejected.errors,
+            vec![CommandError {
+                num: 1,
+                error: "Invalid command: language not set".to_string()
+            }]
+        );
+        let batches = state
+            .config
+            .storage()
+            .get_batches(None, 10, 0)
+            .await
+            .unwrap();
+        assert!(batches.is_empty());
+    }
+
+    #[tokio::test]
+    async fn errors_have_status_codes() {
+        let state = state();
+        let missing = get_batch(State(state.clone()), HeaderMap::new(), Path(42))
+            .await
+            .unwrap_err();
+        assert_eq!(missing.status, StatusCode::NOT_FOUND);
+
+        let empty = patch_batch(
+            State(state.clone()),
+            HeaderMap::new(),
+            Path(42),
+            Ok(Json(BatchUpdate::default())),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(empty.status, StatusCode::BAD_REQUEST);
+
+        let query = CommandQuery {
+            status: Some("DONE,BOGUS".to_string()),
+            ..Default::default()
+        };
+        let unknown = list_commands(
+            State(state.clone()),
+            HeaderMap::new(),
+            Path(42),
+            Ok(Query(query)),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(unknown.status, StatusCode::BAD_REQUEST);
+
+        let no_report = get_error_report(State(state.clone()), HeaderMap::new(), Path(42))
+            .await
+            .unwrap_err();
+        assert_eq!(no_report.status, StatusCode::NOT_FOUND);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, "Bearer qs_bogus".parse().unwrap());
+        let invalid = get_batch(State(state), headers, Path(42))
+            .await
+            .unwrap_err();
+        assert_eq!(invalid.status, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,353 @@
+use crate::qs_storage::OpenBatch;
+use chrono::{DateTime, NaiveDateTime, TimeDelta, Utc};
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+
+/// Format of all `ts_*` columns
+const TIMESTAMP_FORMAT: &str = "%Y%m%d%H%M%S";
+
+/// Batches with at most this many commands left go into the fast lane,
+/// unless `scheduler.small_batch_commands` is set
+const DEFAULT_SMALL_BATCH_COMMANDS: i64 = 50;
+
+/// Longest interval of a recurrence rule
+const MAX_RECURRENCE_DAYS: i64 = 366;
+
+/// The `scheduler` object in config_rs.json:
+///
+/// ```json
+/// "scheduler": {
+///     "small_batch_commands": 50,
+///     "max_running_batches": 20,
+///     "max_user_priority": 0,
+///     "user_weights": {"12345": 2},
+///     "site_weights": {"wikidata": 3, "commons": 1}
+/// }
+/// ```
+///
+/// Users and sites without a weight have weight 1. Priority goes before the
+/// weighted shares, so users other than admins can only set priorities up to
+/// `max_user_priority`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchedulerConfig {
+    pub small_batch_commands: i64,
+    /// Batches this process runs at once; no limit if unset
+    pub max_running_batches: Option<usize>,
+    /// Highest batch priority that users other than admins can set
+    pub max_user_priority: i64,
+    pub user_weights: HashMap<i64, u64>,
+    pub site_weights: HashMap<String, u64>,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self {
+            small_batch_commands: DEFAULT_SMALL_BATCH_COMMANDS,
+            max_running_batches: None,
+            max_user_priority: 0,
+            user_weights: HashMap::new(),
+            site_weights: HashMap::new(),
+        }
+    }
+}
+
+impl SchedulerConfig {
+    pub fn new_from_json(j: &Value) -> Self {
+        let weights = |key: &str| -> Vec<(String, u64)> {
+            j[key]
+                .as_object()
+                .map(|o| {
+                    o.iter()
+                        .filter_map(|(k, v)| Some((k.to_owned(), v.as_u64()?.max(1))))
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        Self {
+            small_batch_commands: j["small_batch_commands"]
+                .as_i64()
+                .unwrap_or(DEFAULT_SMALL_BATCH_COMMANDS),
+            max_running_batches: j["max_running_batches"].as_u64().map(|n| n as usize),
+            max_user_priority: j["max_user_priority"].as_i64().unwrap_or(0),
+            user_weights: weights("user_weights")
+                .into_iter()
+                .filter_map(|(k, v)| Some((k.parse().ok()?, v)))
+                .collect(),
+            site_weights: weights("site_weights").into_iter().collect(),
+        }
+    }
+
+    fn user_weight(&self, user_id: i64) -> u64 {
+        self.user_weights.get(&user_id).copied().unwrap_or(1)
+    }
+
+    fn site_weight(&self, site: &str) -> u64 {
+        self.site_weights.get(site).copied().unwrap_or(1)
+    }
+}
+
+/// Orders waiting batches for starting. Small batches come first (the fast
+/// lane), so a quick fix is not stuck behind someone's million-edit batch.
+/// Within a lane, higher priority wins; otherwise sites, then users, take
+/// turns in proportion to their weights (weighted round-robin), counting the
+/// batches they already have running. A user's own batches keep their
+/// (priority, queue) order.
+pub fn schedule(
+    config: &SchedulerConfig,
+    waiting: Vec<OpenBatch>,
+    running: &[OpenBatch],
+) -> Vec<OpenBatch> {
+    let mut user_load: HashMap<i64, u64> = HashMap::new();
+    let mut site_load: HashMap<String, u64> = HashMap::new();
+    for batch in running {
+        *user_load.entry(batch.user).or_default() += 1;
+        *site_load.entry(batch.site.to_owned()).or_default() += 1;
+    }
+
+    let (fast, slow): (Vec<_>, Vec<_>) = waiting.into_iter().partition(|b| b.small);
+    let mut ret = vec![];
+    for lane in [fast, slow] {
+        let mut queues: Vec<VecDeque<OpenBatch>> = vec![];
+        for batch in lane {
+            match queues.iter_mut().find(|q| q[0].user == batch.user) {
+                Some(queue) => queue.push_back(batch),
+                None => queues.push(VecDeque::from([batch])),
+            }
+        }
+        for queue in queues.iter_mut() {
+            // Stable, so equal priorities keep their queue order
+            queue
+                .make_contiguous()
+                .sort_by_key(|b| std::cmp::Reverse(b.priority));
+        }
+
+        while let Some(next) = next_queue(config, &queues, &site_load, &user_load) {
+            let batch = queues[next].pop_front().expect("non-empty queue");
+            *user_load.entry(batch.user).or_default() += 1;
+            *site_load.entry(batch.site.to_owned()).or_default() += 1;
+            ret.push(batch);
+        }
+    }
+    ret
+}
+
+/// The queue whose first batch goes next
+fn next_queue(
+    config: &SchedulerConfig,
+    queues: &[VecDeque<OpenBatch>],
+    site_load: &HashMap<String, u64>,
+    user_load: &HashMap<i64, u64>,
+) -> Option<usize> {
+    (0..queues.len())
+        .filter(|i| !queues[*i].is_empty())
+        .min_by(|a, b| {
+            let (a, b) = (&queues[*a][0], &queues[*b][0]);
+            b.priority
+                .cmp(&a.priority)
+                .then(compare_shares(
+                    (site_load.get(&a.site), config.site_weight(&a.site)),
+                    (site_load.get(&b.site), config.site_weight(&b.site)),
+                ))
+                .then(compare_shares(
+                    (user_load.get(&a.user), config.user_weight(a.user)),
+                    (user_load.get(&b.user), config.user_weight(b.user)),
+                ))
+        })
+}
+
+/// A `ts_*` timestamp, as UTC
+pub fn parse_timestamp(ts: &str) -> Option<DateTime<Utc>> {
+    if ts.len() != 14 {
+        return None;
+    }
+    NaiveDateTime::parse_from_str(ts, TIMESTAMP_FORMAT)
+        .ok()
+        .map(|t| t.and_utc())
+}
+
+/// The interval of a recurrence rule: `hourly`, `daily`, `weekly`, or a
+/// number of hours, days or weeks (`6h`, `2d`, `4w`), up to a year
+pub fn parse_recurrence(rule: &str) -> Option<TimeDelta> {
+    match rule {
+        "hourly" => return Some(TimeDelta::hours(1)),
+        "daily" => return Some(TimeDelta::days(1)),
+        "weekly" => return Some(TimeDelta::weeks(1)),
+        _ => {}
+    }
+    let unit = rule.chars().last()?;
+    let count: i64 = rule[..rule.len() - unit.len_utf8()].parse().ok()?;
+    if count < 1 {
+        return None;
+    }
+    let interval = match unit {
+        'h' => TimeDelta::try_hours(count),
+        'd' => TimeDelta::try_days(count),
+        'w' => TimeDelta::try_weeks(count),
+        _ => None,
+    }?;
+    Some(interval).filter(|interval| *interval <= TimeDelta::days(MAX_RECURRENCE_DAYS))
+}
+
+/// The next start of a recurring batch: the first `not_before + n * interval`
+/// after `now`, so the batch keeps its time slot however long a run takes.
+/// Without a previous `not_before`, one interval from now. `None` if that is
+/// out of range.
+pub fn next_occurrence(
+    not_before: &str,
+    interval: TimeDelta,
+    now: DateTime<Utc>,
+) -> Option<String> {
+    let next = match parse_timestamp(not_before) {
+        Some(start) if start <= now => {
+            let periods = (now - start)
+                .num_seconds()
+                .checked_div(interval.num_seconds())?
+                + 1;
+            let offset = interval.checked_mul(i32::try_from(periods).ok()?)?;
+            start.checked_add_signed(offset)?
+        }
+        Some(start) => start,
+        None => now.checked_add_signed(interval)?,
+    };
+    Some(next.format(TIMESTAMP_FORMAT).to_string())
+}
+
+/// Compares load/weight without floating point
+fn compare_shares(a: (Option<&u64>, u64), b: (Option<&u64>, u64)) -> std::cmp::Ordering {
+    let load = |x: Option<&u64>| x.copied().unwrap_or(0);
+    (load(a.0) * b.1).cmp(&(load(b.0) * a.1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch(id: i64, user: i64, site: &str) -> OpenBatch {
+        OpenBatch {
+            id,
+            user,
+            site: site.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn ids(batches: &[OpenBatch]) -> Vec<i64> {
+        batches.iter().map(|b| b.id).collect()
+    }
+
+    #[test]
+    fn users_take_turns() {
+        let waiting = vec![
+            batch(1, 1, "wikidata"),
+            batch(2, 1, "wikidata"),
+            batch(3, 1, "wikidata"),
+            batch(4, 2, "wikidata"),
+        ];
+
+        let order = schedule(&SchedulerConfig::default(), waiting, &[]);
+
+        assert_eq!(ids(&order), vec![1, 4, 2, 3]);
+    }
+
+    #[test]
+    fn running_batches_count_towards_the_share() {
+        let waiting = vec![batch(1, 1, "wikidata"), batch(2, 2, "wikidata")];
+        let running = vec![batch(10, 1, "wikidata")];
+
+        let order = schedule(&SchedulerConfig::default(), waiting, &running);
+
+        assert_eq!(ids(&order), vec![2, 1]);
+    }
+
+    #[test]
+    fn weights_set_the_share() {
+        let config = SchedulerConfig::new_from_json(&json!({"user_weights":{"1":2}}));
+        let waiting = vec![
+            batch(1, 1, "wikidata"),
+            batch(2, 1, "wikidata"),
+            batch(3, 1, "wikidata"),
+            batch(4, 2, "wikidata"),
+            batch(5, 2, "wikidata"),
+        ];
+
+        let order = schedule(&config, waiting, &[]);
+
+        assert_eq!(ids(&order), vec![1, 4, 2, 3, 5]);
+    }
+
+    #[test]
+    fn sites_take_turns_before_users() {
+        let config = SchedulerConfig::new_from_json(&json!({"site_weights":{"wikidata":2}}));
+        let waiting = vec![
+            batch(1, 1, "wikidata"),
+            batch(2, 2, "wikidata"),
+            batch(3, 3, "wikidata"),
+            batch(4, 4, "commons"),
+            batch(5, 5, "commons"),
+        ];
+
+        let order = schedule(&config, waiting, &[]);
+
+        assert_eq!(ids(&order), vec![1, 4, 2, 3, 5]);
+    }
+
+    #[test]
+    fn recurrence_rules() {
+        assert_eq!(parse_recurrence("weekly"), Some(TimeDelta::weeks(1)));
+        assert_eq!(parse_recurrence("6h"), Some(TimeDelta::hours(6)));
+        assert_eq!(parse_recurrence("2d"), Some(TimeDelta::days(2)));
+        assert_eq!(parse_recurrence("0d"), None);
+        assert_eq!(parse_recurrence("d"), None);
+        assert_eq!(parse_recurrence("monthly"), None);
+        assert_eq!(parse_recurrence(""), None);
+        assert_eq!(parse_recurrence("52w"), Some(TimeDelta::weeks(52)));
+        assert_eq!(parse_recurrence("99999999w"), None);
+        assert_eq!(parse_recurrence("9999h"), None);
+    }
+
+    #[test]
+    fn next_occurrence_keeps_the_time_slot() {
+        let now = parse_timestamp("20240110093000").unwrap();
+        let week = TimeDelta::weeks(1);
+
+        assert_eq!(
+            next_occurrence("20240101120000", week, now).as_deref(),
+            Some("20240115120000")
+        );
+        assert_eq!(
+            next_occurrence("20240201120000", week, now).as_deref(),
+            Some("20240201120000")
+        );
+        assert_eq!(
+            next_occurrence("", week, now).as_deref(),
+            Some("20240117093000")
+        );
+    }
+
+    #[test]
+    fn next_occurrence_out_of_range() {
+        let now = parse_timestamp("20240110093000").unwrap();
+        assert_eq!(next_occurrence("", TimeDelta::MAX, now), None);
+        assert_eq!(
+            next_occurrence(
+                "20240101120000",
+                TimeDelta::seconds(1),
+                DateTime::<Utc>::MAX_UTC
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn fast_lane_and_priority_go_first() {
+        let mut small = batch(1, 1, "wikidata");
+        small.small = true;
+        let mut urgent = batch(3, 1, "wikidata");
+        urgent.priority = 10;
+        let waiting = vec![batch(2, 1, "wikidata"), urgent, small];
+
+        let order = schedule(&SchedulerConfig::default(), waiting, &[]);
+
+        assert_eq!(ids(&order), vec![1, 3, 2]);
+    }
+}
@@ -1,15 +1,78 @@
 use axum::extract::{Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
 use axum::response::{IntoResponse, Json, Redirect, Response};
 use axum::routing::get;
 use axum::Router;
-use serde::Deserialize;
+use futures::stream::{self, Stream};
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
 use std::sync::Arc;
+use std::time::Duration;
 use tower_http::services::ServeDir;
 
+use crate::error::{QsError, QsResult};
+use crate::qs_bot::QuickStatementsBot;
+use crate::qs_command::{LastEntityState, QuickStatementsCommand};
 use crate::qs_config::QuickStatements;
+use crate::qs_error_report::ErrorReport;
+use crate::qs_metrics;
+use crate::qs_oauth::{random_token, Credentials, OAuthClient, PendingLogin};
 use crate::qs_parser::QuickStatementsParser;
+use crate::qs_rest;
+use crate::qs_scheduler::{parse_recurrence, parse_timestamp};
+use crate::qs_storage::{
+    timestamp_after, ApiTokenRow, BatchRow, ChangeScope, CommandRow, COMMAND_STATUSES,
+};
+
+/// Cookie holding the ID of the web session
+const SESSION_COOKIE: &str = "qs_session";
+
+/// How long a login lasts
+const SESSION_LIFETIME: Duration = Duration::from_secs(30 * 24 * 3600);
+
+/// How long the user has to approve a login on the wiki
+const PENDING_LOGIN_LIFETIME: Duration = Duration::from_secs(3600);
+
+/// Actions changing the batch given by `batch` (`batch_id` for reset_errors):
+/// only its owner or an admin may run them
+const BATCH_ACTIONS: &[&str] = &[
+    "start_batch",
+    "stop_batch",
+    "pause_batch",
+    "resume_batch",
+    "set_batch_priority",
+    "create_schedule",
+    "cancel_schedule",
+    "reset_errors",
+];
+
+/// Actions creating batches or editing as the user; also `import` with `submit`
+const LOGIN_ACTIONS: &[&str] = &["run_batch", "run_single_command"];
+
+/// Actions only a logged-in web session can run, not an API token
+const SESSION_ACTIONS: &[&str] = &[
+    "get_token",
+    "create_api_token",
+    "list_api_tokens",
+    "revoke_api_token",
+];
+
+/// Scopes of personal API tokens: `read` for actions that change nothing,
+/// `submit` to create, start and reset batches, `stop` to stop them
+const TOKEN_SCOPES: &[&str] = &["read", "submit", "stop"];
+
+/// Name of the token `get_token` manages for the user page
+const DEFAULT_TOKEN_NAME: &str = "default";
+
+/// How often `/events` looks for changes
+const EVENT_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Changed commands `/events` reads per query
+const EVENT_PAGE_SIZE: i64 = 500;
 
 #[derive(Clone)]
 pub struct AppState {
@@ -23,7 +86,12 @@ pub fn build_router(config: Arc<QuickStatements>) -> Router {
     // API routes
     let api = Router::new()
         .route("/api.php", get(api_handler).post(api_handler_post))
-        .route("/config.json", get(serve_config));
+        .route("/config.json", get(serve_config))
+        .route("/metrics", get(serve_metrics))
+        .route("/healthz", get(serve_healthz))
+        .route("/readyz", get(serve_readyz))
+        .route("/events", get(serve_events))
+        .nest("/api/v1", qs_rest::router());
 
     // Combine: API first, then static file serving for everything else
     Router::new()
@@ -32,13 +100,22 @@ pub fn build_router(config: Arc<QuickStatements>) -> Router {
         .with_state(state)
 }
 
+/// Just the metrics and health endpoints, for the bot process
+pub fn build_status_router(config: Arc<QuickStatements>) -> Router {
+    Router::new()
+        .route("/metrics", get(serve_metrics))
+        .route("/healthz", get(serve_healthz))
+        .route("/readyz", get(serve_readyz))
+        .with_state(AppState { config })
+}
+
 // ---- Query parameter structs ----
 
 #[derive(Deserialize, Default, Debug)]
 #[allow(dead_code)]
 struct ApiParams {
     action: Option<String>,
-    // get_batch_info / start_batch / stop_batch
+    // get_batch_info / start_batch / stop_batch / get_error_report
     batch: Option<String>,
     // get_batches_info
     user: Option<String>,
@@ -61,50 +138,507 @@ struct ApiParams {
     // run_batch
     name: Option<String>,
     commands: Option<String>,
+    // run_batch / set_batch_priority
+    priority: Option<String>,
+    // create_schedule; `rerun` to run all commands of a DONE batch again
+    not_before: Option<String>,
+    recurrence: Option<String>,
+    rerun: Option<String>,
     // get_token
     force_generate: Option<String>,
-    // reset_errors
+    // API token authentication
+    username: Option<String>,
+    token: Option<String>,
+    // import
+    submit: Option<String>,
+    // create_api_token (with `name`) / revoke_api_token
+    scopes: Option<String>,
+    token_id: Option<String>,
+    // oauth_redirect callback: OAuth 1.0a / OAuth 2.0
+    oauth_verifier: Option<String>,
+    oauth_token: Option<String>,
+    code: Option<String>,
+    state: Option<String>,
+    // reset_errors (with `batch_id`) / get_commands_from_batch, optionally
+    // only for commands that failed with error code `error`
     batch_id: Option<String>,
+    error: Option<String>,
+    // resume_batch from this command
+    num: Option<String>,
     // get_batch (by temp id)
     id: Option<String>,
     // JSONP
     callback: Option<String>,
 }
 
+/// A web session, stored as JSON in the `session` table and identified by
+/// the `qs_session` cookie
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub(crate) struct Session {
+    #[serde(skip)]
+    id: String,
+    #[serde(default)]
+    user_id: i64,
+    /// `userinfo` of the wiki user, as reported by `is_logged_in`
+    #[serde(default)]
+    userinfo: Value,
+    #[serde(default)]
+    credentials: Option<Credentials>,
+    /// A login waiting for the wiki to redirect back
+    #[serde(default)]
+    pending: Option<PendingLogin>,
+}
+
+impl Session {
+    fn new() -> Self {
+        Self {
+            // 256 bits, so session IDs cannot be guessed
+            id: format!("{}{}", random_token(), random_token()),
+            ..Default::default()
+        }
+    }
+
+    fn is_logged_in(&self) -> bool {
+        self.user_id > 0 && self.credentials.is_some()
+    }
+
+    fn user_name(&self) -> &str {
+        self.userinfo["name"].as_str().unwrap_or_default()
+    }
+}
+
+fn session_id_from_headers(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, id)| id.to_string())
+}
+
+pub(crate) async fn load_session(qs: &QuickStatements, headers: &HeaderMap) -> Option<Session> {
+    let id = session_id_from_headers(headers)?;
+    let data = match qs.storage().get_session(&id, &qs.timestamp()).await {
+        Ok(data) => data?,
+        Err(e) => {
+            log::error!("Cannot read session: {}", e);
+            return None;
+        }
+    };
+    let mut session: Session = serde_json::from_str(&data).ok()?;
+    session.id = id;
+    Some(session)
+}
+
+async fn save_session(qs: &QuickStatements, session: &Session, lifetime: Duration) -> QsResult<()> {
+    qs.storage()
+        .set_session(
+            &session.id,
+            session.user_id,
+            &json!(session).to_string(),
+            &timestamp_after(lifetime),
+        )
+        .await
+}
+
+async fn delete_session(qs: &QuickStatements, session: &Session) {
+    if let Err(e) = qs.storage().delete_session(&session.id).await {
+        log::error!("Cannot delete session: {}", e);
+    }
+}
+
+/// Who a request acts for: a logged-in web session, or a personal API token
+/// given as `username` and `token`
+pub(crate) enum Caller {
+    Session(Session),
+    Token {
+        token: ApiTokenRow,
+        user_name: String,
+    },
+}
+
+impl Caller {
+    fn user_id(&self) -> i64 {
+        match self {
+            Self::Session(session) => session.user_id,
+            Self::Token { token, .. } => token.user_id,
+        }
+    }
+
+    pub(crate) fn user_name(&self) -> &str {
+        match self {
+            Self::Session(session) => session.user_name(),
+            Self::Token { user_name, .. } => user_name,
+        }
+    }
+
+    /// Sessions may do everything, API tokens what their scopes allow
+    fn has_scope(&self, scope: &str) -> bool {
+        match self {
+            Self::Session(_) => true,
+            Self::Token { token, .. } => token.scopes().any(|s| s == scope),
+        }
+    }
+
+    fn session(&self) -> Option<&Session> {
+        match self {
+            Self::Session(session) => Some(session),
+            Self::Token { .. } => None,
+        }
+    }
+}
+
+/// Only the hash of an API token is stored
+fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// The API token scope an action needs
+fn required_scope(action: &str) -> &'static str {
+    match action {
+        "stop_batch" | "pause_batch" | "cancel_schedule" => "stop",
+        "run_batch" | "import" | "run_single_command" | "start_batch" | "resume_batch"
+        | "set_batch_priority" | "create_schedule" | "reset_errors" => "submit",
+        _ => "read",
+    }
+}
+
+/// `Set-Cookie` value for the session cookie; a zero lifetime removes it
+fn session_cookie(qs: &QuickStatements, session_id: &str, lifetime: Duration) -> String {
+    format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly;{} SameSite=Lax",
+        SESSION_COOKIE,
+        session_id,
+        lifetime.as_secs(),
+        if qs.secure_cookie() { " Secure;" } else { "" }
+    )
+}
+
 /// Serve config.json dynamically from the loaded config
 async fn serve_config(State(state): State<AppState>) -> Json<Value> {
     Json(state.config.frontend_config().clone())
 }
 
+async fn serve_metrics(State(state): State<AppState>) -> Response {
+    (
+        [(header::CONTENT_TYPE, qs_metrics::CONTENT_TYPE)],
+        state.config.metrics().render(),
+    )
+        .into_response()
+}
+
+/// A DB that does not answer a ping within this time counts as unreachable
+const HEALTH_DB_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// State of this process for `/healthz` and `/readyz`; ready means the DB is
+/// reachable and the process is not shutting down
+async fn health_report(config: &QuickStatements) -> (bool, Value) {
+    let db_ok = tokio::time::timeout(HEALTH_DB_TIMEOUT, config.db_ping())
+        .await
+        .unwrap_or(false);
+    let shutting_down = config.is_shutting_down();
+    let oldest_command = config
+        .oldest_in_flight_command()
+        .await
+        .map(|(batch_id, command_id, age)| {
+            json!({"batch_id": batch_id, "command_id": command_id, "age_s": age.as_secs()})
+        });
+    let last_edits: serde_json::Map<String, Value> = config
+        .rate_governor()
+        .last_successful_edits()
+        .into_iter()
+        .map(|(site, ts)| (site, json!(ts)))
+        .collect();
+    let ready = db_ok && !shutting_down;
+    let report = json!({
+        "status": if ready { "OK" } else { "NOT READY" },
+        "db": if db_ok { "OK" } else { "UNREACHABLE" },
+        "shutting_down": shutting_down,
+        "running_bots": config.number_of_bots_running().await,
+        "oldest_in_flight_command": oldest_command,
+        "last_successful_edit": last_edits,
+    });
+    (ready, report)
+}
+
+/// Liveness: answers as long as the process does
+async fn serve_healthz(State(state): State<AppState>) -> Json<Value> {
+    Json(health_report(&state.config).await.1)
+}
+
+/// Readiness: 503 while the DB is unreachable or the process shuts down
+async fn serve_readyz(State(state): State<AppState>) -> Response {
+    let (ready, report) = health_report(&state.config).await;
+    let status = match ready {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, Json(report)).into_response()
+}
+
+/// Server-Sent Events with the progress of `batch`, or of all batches of
+/// `user`: a `batch` event with the batch and its command counts whenever its
+/// status or message changes (and at the start, for a single batch), and a
+/// `command` event for every command status change. Authenticated like
+/// `api.php`.
+async fn serve_events(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<ApiParams>,
+) -> Response {
+    let qs = &state.config;
+    let session = load_session(qs, &headers).await;
+    let caller = match authenticate(
+        qs,
+        params.token.as_deref(),
+        params.username.as_deref(),
+        session,
+    )
+    .await
+    {
+        Ok(caller) => caller,
+        Err(denied) => return denied.into_response(),
+    };
+    let batch_id = params.batch.as_deref().and_then(|s| s.parse::<i64>().ok());
+    if let Err(denied) = authorize(qs, "get_batch_info", batch_id, false, caller.as_ref()).await {
+        return denied.into_response();
+    }
+    let user = params.user.filter(|user| !user.is_empty());
+    let scope = match (batch_id, user) {
+        (Some(batch_id), _) => {
+            if !matches!(qs.storage().get_batch(batch_id).await, Ok(Some(_))) {
+                let status = format!("ERROR: batch {} not found", batch_id);
+                return (StatusCode::NOT_FOUND, Json(json!({ "status": status }))).into_response();
+            }
+            ChangeScope::Batch(batch_id)
+        }
+        (None, Some(user)) => ChangeScope::User(user),
+        (None, None) => {
+            let status = "ERROR: batch or user parameter required";
+            return (StatusCode::BAD_REQUEST, Json(json!({ "status": status }))).into_response();
+        }
+    };
+    Sse::new(BatchEvents::new(state.config.clone(), scope).into_stream())
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+/// The change feed behind `/events`. Bots run in other processes, so this
+/// polls the database for commands changed since a cursor on
+/// (ts_change, id), and for batches changed since the last poll.
+struct BatchEvents {
+    qs: Arc<QuickStatements>,
+    scope: ChangeScope,
+    command_ts: String,
+    command_id: i64,
+    /// Status last sent for each command changed at `command_ts`, as a
+    /// command can change again within the same second
+    sent_commands: HashMap<i64, String>,
+    batch_ts: String,
+    /// (status, message) last sent for each batch
+    sent_batches: HashMap<i64, (String, String)>,
+}
+
+impl BatchEvents {
+    fn new(qs: Arc<QuickStatements>, scope: ChangeScope) -> Self {
+        let now = qs.timestamp();
+        // A single batch is sent once at the start, to have a baseline
+        let batch_ts = match scope {
+            ChangeScope::Batch(_) => String::new(),
+            ChangeScope::User(_) => now.clone(),
+        };
+        Self {
+            qs,
+            scope,
+            command_ts: now,
+            command_id: 0,
+            sent_commands: HashMap::new(),
+            batch_ts,
+            sent_batches: HashMap::new(),
+        }
+    }
+
+    fn into_stream(self) -> impl Stream<Item = Result<Event, Infallible>> {
+        let mut interval = tokio::time::interval(EVENT_POLL_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        stream::unfold(
+            (self, VecDeque::new(), interval),
+            |(mut feed, mut queue, mut interval)| async move {
+                loop {
+                    if let Some(event) = queue.pop_front() {
+                        return Some((Ok(event), (feed, queue, interval)));
+                    }
+                    interval.tick().await;
+                    match feed.poll().await {
+                        Ok(events) => queue.extend(events),
+                        Err(e) => log::warn!("Cannot read changes for /events: {}", e),
+                    }
+                }
+            },
+        )
+    }
+
+    /// Events for everything that changed since the last poll
+    async fn poll(&mut self) -> QsResult<Vec<Event>> {
+        let mut events = vec![];
+        let batches = self
+            .qs
+            .storage()
+            .get_changed_batches(&self.scope, &self.batch_ts)
+            .await?;
+        for batch in batches {
+            self.batch_ts = batch.ts_last_change.clone();
+            let sent = (batch.status.clone(), batch.message.clone());
+            if self.sent_batches.get(&batch.id) == Some(&sent) {
+                continue;
+            }
+            self.sent_batches.insert(batch.id, sent);
+            events.push(batch_event(&self.qs, &batch).await);
+        }
+        loop {
+            let commands = self
+                .qs
+                .storage()
+                .get_changed_commands(
+                    &self.scope,
+                    &self.command_ts,
+                    self.command_id,
+                    EVENT_PAGE_SIZE,
+                )
+                .await?;
+            let more = commands.len() as i64 == EVENT_PAGE_SIZE;
+            for command in commands {
+                if command.ts_change != self.command_ts {
+                    self.command_ts = command.ts_change.clone();
+                    self.sent_commands.clear();
+                }
+                self.command_id = command.id;
+                if self.sent_commands.get(&command.id) == Some(&command.status) {
+                    continue;
+                }
+                self.sent_commands
+                    .insert(command.id, command.status.clone());
+                let data = command_json(&command).to_string();
+                events.push(Event::default().event("command").data(data));
+            }
+            if !more {
+                break;
+            }
+        }
+        // Read the commands of the last second again next time, unless there
+        // are too many, e.g. after resetting a large batch
+        if (self.sent_commands.len() as i64) < EVENT_PAGE_SIZE {
+            self.command_id = 0;
+        }
+        Ok(events)
+    }
+}
+
+/// A `batch` event, shaped like a batch of `get_batch_info`
+async fn batch_event(qs: &QuickStatements, batch: &BatchRow) -> Event {
+    let user_name = qs.get_user_name(batch.user).await.unwrap_or_default();
+    let data = json!({
+        "batch": batch.to_json(&user_name),
+        "commands": get_command_counts(qs, batch.id).await,
+    });
+    Event::default().event("batch").data(data.to_string())
+}
+
 // GET handler
-async fn api_handler(State(state): State<AppState>, Query(params): Query<ApiParams>) -> Response {
-    handle_api(state, params).await
+async fn api_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<ApiParams>,
+) -> Response {
+    let session = load_session(&state.config, &headers).await;
+    handle_api(state, params, session, true).await
 }
 
 // POST handler — axum can parse Form or Query; we merge both.
 async fn api_handler_post(
     State(state): State<AppState>,
+    headers: HeaderMap,
     axum::extract::Form(params): axum::extract::Form<ApiParams>,
 ) -> Response {
-    handle_api(state, params).await
+    let session = load_session(&state.config, &headers).await;
+    handle_api(state, params, session, false).await
 }
 
-async fn handle_api(state: AppState, params: ApiParams) -> Response {
+/// Runs an api.php action; `via_get` for GET requests, which cannot change
+/// anything for a session
+async fn handle_api(
+    state: AppState,
+    mut params: ApiParams,
+    session: Option<Session>,
+    via_get: bool,
+) -> Response {
     let action = params.action.as_deref().unwrap_or("");
+    match action {
+        "oauth_redirect" => return action_oauth_redirect(&state, &params, session).await,
+        "logout" => return action_logout(&state, session).await,
+        _ => {}
+    }
+    let caller = match authenticate(
+        &state.config,
+        params.token.as_deref(),
+        params.username.as_deref(),
+        session,
+    )
+    .await
+    {
+        Ok(caller) => caller,
+        Err(denied) => return denied.into_response(),
+    };
+    // The Lax session cookie comes along on cross-site links, so any page
+    // could run these for a logged-in user with a GET
+    if via_get && matches!(caller, Some(Caller::Session(_))) && changes_state(action, &params) {
+        return Denied::new(
+            StatusCode::METHOD_NOT_ALLOWED,
+            format!("Use POST for action '{}'", action),
+        )
+        .into_response();
+    }
+    // Actions read `batch` or `batch_id`, so both are set to the one batch
+    // that is authorized
+    let batch = match batch_param(&params) {
+        Ok(batch) => batch,
+        Err(denied) => return denied.into_response(),
+    };
+    params.batch = batch.clone();
+    params.batch_id = batch;
+    let batch_id = params.batch.as_deref().and_then(|s| s.parse::<i64>().ok());
+    let submit = is_true(params.submit.as_deref());
+    if let Err(denied) = authorize(&state.config, action, batch_id, submit, caller.as_ref()).await {
+        return denied.into_response();
+    }
     let result = match action {
-        "is_logged_in" => action_is_logged_in(&params),
+        "is_logged_in" => action_is_logged_in(caller.as_ref()),
         "get_batch_info" => action_get_batch_info(&state, &params).await,
         "get_batches_info" => action_get_batches_info(&state, &params).await,
         "get_commands_from_batch" => action_get_commands_from_batch(&state, &params).await,
+        "get_error_report" => action_get_error_report(&state, &params).await,
         "start_batch" => action_start_batch(&state, &params).await,
         "stop_batch" => action_stop_batch(&state, &params).await,
-        "import" => action_import(&params).await,
-        "run_batch" => action_run_batch(&state, &params).await,
-        "run_single_command" => action_run_single_command(&state, &params).await,
-        "get_token" => action_get_token(),
+        "pause_batch" => action_pause_batch(&state, &params).await,
+        "resume_batch" => action_resume_batch(&state, &params).await,
+        "set_batch_priority" => action_set_batch_priority(&state, &params, caller.as_ref()).await,
+        "create_schedule" => action_create_schedule(&state, &params).await,
+        "get_schedules" => action_get_schedules(&state, &params).await,
+        "cancel_schedule" => action_cancel_schedule(&state, &params).await,
+        "import" => action_import(&state, &params, caller).await,
+        "run_batch" => action_run_batch(&state, &params, caller).await,
+        "run_single_command" => action_run_single_command(&state, &params, caller).await,
+        "get_token" => action_get_token(&state, &params, caller).await,
+        "create_api_token" => action_create_api_token(&state, &params, caller).await,
+        "list_api_tokens" => action_list_api_tokens(&state, caller).await,
+        "revoke_api_token" => action_revoke_api_token(&state, &params, caller).await,
         "reset_errors" => action_reset_errors(&state, &params).await,
-        "get_batch" => action_get_batch(&params),
-        "oauth_redirect" => return Redirect::to("/").into_response(),
+        "get_batch" => action_get_batch(&state, &params).await,
         _ => json!({"status": format!("ERROR: Unknown action '{}'", action)}),
     };
 
@@ -121,18 +655,302 @@ async fn handle_api(state: AppState, params: ApiParams) -> Response {
     Json(result).into_response()
 }
 
+/// The batch an action is about, given as `batch`, or `batch_id` for
+/// reset_errors; 400 if both are given and differ
+fn batch_param(params: &ApiParams) -> Result<Option<String>, Denied> {
+    match (&params.batch, &params.batch_id) {
+        (Some(batch), Some(batch_id)) if batch != batch_id => Err(Denied::new(
+            StatusCode::BAD_REQUEST,
+            "batch and batch_id differ",
+        )),
+        (batch, batch_id) => Ok(batch.clone().or(batch_id.clone())),
+    }
+}
+
+/// The caller of a request: an API token if one is given, which must then be
+/// valid and belong to `user_name` if that is given (else 401), or else a
+/// logged-in session
+pub(crate) async fn authenticate(
+    qs: &QuickStatements,
+    token: Option<&str>,
+    user_name: Option<&str>,
+    session: Option<Session>,
+) -> Result<Option<Caller>, Denied> {
+    let token = match token {
+        Some(token) if !token.is_empty() => token,
+        _ => return Ok(session.filter(Session::is_logged_in).map(Caller::Session)),
+    };
+    let invalid = || Denied::new(StatusCode::UNAUTHORIZED, "Invalid token");
+    let token = match qs.storage().get_api_token(&hash_token(token)).await {
+        Ok(Some(token)) => token,
+        Ok(None) => return Err(invalid()),
+        Err(_) => {
+            return Err(Denied::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Could not read token",
+            ))
+        }
+    };
+    let owner = qs.get_user_name(token.user_id).await.unwrap_or_default();
+    if user_name.is_some_and(|user_name| owner.replace('_', " ") != user_name.replace('_', " ")) {
+        return Err(invalid());
+    }
+    if let Err(e) = qs
+        .storage()
+        .set_api_token_last_used(token.id, &qs.timestamp())
+        .await
+    {
+        log::error!("Cannot record API token use: {}", e);
+    }
+    Ok(Some(Caller::Token {
+        token,
+        user_name: owner,
+    }))
+}
+
+/// Checks that the caller may run the api.php `action` on `batch_id` (`submit`
+/// for `import` creating a batch): 401 without a login, 403 for someone else's
+/// batch or a token without the scope. A standalone server without OAuth can
+/// let requests without a login through with `open_batch_actions`.
+pub(crate) async fn authorize(
+    qs: &QuickStatements,
+    action: &str,
+    batch_id: Option<i64>,
+    submit: bool,
+    caller: Option<&Caller>,
+) -> Result<(), Denied> {
+    if SESSION_ACTIONS.contains(&action) {
+        return match caller {
+            Some(Caller::Session(_)) => Ok(()),
+            Some(Caller::Token { .. }) => Err(Denied::new(
+                StatusCode::FORBIDDEN,
+                "API tokens cannot manage tokens",
+            )),
+            None => Err(Denied::new(StatusCode::UNAUTHORIZED, "Not logged in")),
+        };
+    }
+    let scope = required_scope(action);
+    if caller.is_some_and(|caller| !caller.has_scope(scope)) {
+        return Err(Denied::new(
+            StatusCode::FORBIDDEN,
+            format!("The token lacks the '{}' scope", scope),
+        ));
+    }
+    let batch_action = BATCH_ACTIONS.contains(&action);
+    let login_action = LOGIN_ACTIONS.contains(&action) || (action == "import" && submit);
+    if !(batch_action || login_action) {
+        return Ok(());
+    }
+    let caller = match caller {
+        Some(caller) => caller,
+        None if qs.open_batch_actions() => return Ok(()),
+        None => return Err(Denied::new(StatusCode::UNAUTHORIZED, "Not logged in")),
+    };
+    if !batch_action || qs.is_admin(caller.user_name()) {
+        return Ok(());
+    }
+    // Without a valid batch ID, ownership cannot be checked; an unknown batch
+    // is reported by the action itself
+    let batch_id = match batch_id {
+        Some(batch_id) => batch_id,
+        None => {
+            return Err(Denied::new(
+                StatusCode::BAD_REQUEST,
+                "A valid batch ID is required",
+            ))
+        }
+    };
+    match qs.storage().get_batch(batch_id).await {
+        Ok(Some(batch)) if batch.user != caller.user_id() => {
+            log::warn!(
+                batch = batch_id, user = caller.user_id();
+                "Denied {} on batch of user {}", action, batch.user
+            );
+            Err(Denied::new(
+                StatusCode::FORBIDDEN,
+                format!("Not allowed to change batch {}", batch_id),
+            ))
+        }
+        Ok(_) => Ok(()),
+        Err(_) => Err(Denied::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Could not read batch",
+        )),
+    }
+}
+
+/// Why `authenticate` or `authorize` turned a request down
+#[derive(Debug)]
+pub(crate) struct Denied {
+    pub(crate) status: StatusCode,
+    pub(crate) message: String,
+}
+
+impl Denied {
+    fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    /// The api.php response
+    fn into_response(self) -> Response {
+        let status = format!("ERROR: {}", self.message);
+        (self.status, Json(json!({ "status": status }))).into_response()
+    }
+}
+
+/// Whether the api.php `action` changes batches or tokens
+fn changes_state(action: &str, params: &ApiParams) -> bool {
+    BATCH_ACTIONS.contains(&action)
+        || LOGIN_ACTIONS.contains(&action)
+        || (SESSION_ACTIONS.contains(&action) && action != "list_api_tokens")
+        || (action == "import" && is_true(params.submit.as_deref()))
+}
+
+/// A boolean API parameter: "1" or "true"
+fn is_true(param: Option<&str>) -> bool {
+    matches!(param, Some("1") | Some("true"))
+}
+
 // ---- API action implementations ----
 
-/// `action=is_logged_in`
-/// In standalone mode there is no OAuth session, so we report not logged in.
-/// The frontend gracefully degrades — batch viewing still works.
-fn action_is_logged_in(_params: &ApiParams) -> Value {
-    json!({
-        "status": "OK",
-        "data": {
-            "is_logged_in": false
+/// `action=oauth_redirect` — starts a login by sending the user to the wiki,
+/// which sends them back here with `oauth_verifier` (OAuth 1.0a) or `code`
+/// (OAuth 2.0) to complete it
+async fn action_oauth_redirect(
+    state: &AppState,
+    params: &ApiParams,
+    session: Option<Session>,
+) -> Response {
+    let oauth = match state.config.oauth() {
+        Some(oauth) => oauth,
+        None => return Json(json!({"status": "ERROR: OAuth is not configured"})).into_response(),
+    };
+    let callback = match (&params.oauth_verifier, &params.code) {
+        (Some(verifier), _) => Some((params.oauth_token.as_deref(), verifier)),
+        (None, Some(code)) => Some((params.state.as_deref(), code)),
+        (None, None) => None,
+    };
+    let result = match callback {
+        None => start_login(&state.config, oauth, session).await,
+        Some((token, verifier)) => {
+            finish_login(
+                &state.config,
+                oauth,
+                session,
+                token.unwrap_or_default(),
+                verifier,
+            )
+            .await
         }
-    })
+    };
+    match result {
+        Ok((session, lifetime, url)) => (
+            [(
+                header::SET_COOKIE,
+                session_cookie(&state.config, &session.id, lifetime),
+            )],
+            Redirect::to(&url),
+        )
+            .into_response(),
+        Err(e) => {
+            log::warn!("OAuth login failed: {}", e);
+            Json(json!({"status": format!("ERROR: Login failed: {}", e)})).into_response()
+        }
+    }
+}
+
+/// A fresh session waiting for the login to be approved on the wiki, and the
+/// wiki URL to send the user to
+async fn start_login(
+    qs: &QuickStatements,
+    oauth: &OAuthClient,
+    session: Option<Session>,
+) -> QsResult<(Session, Duration, String)> {
+    let (url, pending) = oauth.start_login().await?;
+    if let Some(session) = session {
+        delete_session(qs, &session).await;
+    }
+    let session = Session {
+        pending: Some(pending),
+        ..Session::new()
+    };
+    save_session(qs, &session, PENDING_LOGIN_LIFETIME).await?;
+    Ok((session, PENDING_LOGIN_LIFETIME, url))
+}
+
+/// The logged-in session for the wiki's callback
+async fn finish_login(
+    qs: &QuickStatements,
+    oauth: &OAuthClient,
+    session: Option<Session>,
+    token: &str,
+    verifier: &str,
+) -> QsResult<(Session, Duration, String)> {
+    let no_login = || QsError::OAuthError("No login in progress".to_string());
+    let session = session.ok_or_else(no_login)?;
+    let pending = session.pending.as_ref().ok_or_else(no_login)?;
+    let credentials = oauth.finish_login(pending, token, verifier).await?;
+    let userinfo = oauth.userinfo(&credentials).await?;
+    let name = userinfo["name"]
+        .as_str()
+        .ok_or_else(|| QsError::OAuthError("No user name in userinfo".to_string()))?;
+    let user_id = qs.storage().get_or_create_user(name).await?;
+
+    // A new ID once logged in, so an ID that leaked before is worthless
+    delete_session(qs, &session).await;
+    let session = Session {
+        user_id,
+        userinfo,
+        credentials: Some(credentials),
+        ..Session::new()
+    };
+    save_session(qs, &session, SESSION_LIFETIME).await?;
+    Ok((session, SESSION_LIFETIME, "/".to_string()))
+}
+
+/// `action=logout`
+async fn action_logout(state: &AppState, session: Option<Session>) -> Response {
+    if let Some(session) = &session {
+        delete_session(&state.config, session).await;
+    }
+    (
+        [(
+            header::SET_COOKIE,
+            session_cookie(&state.config, "", Duration::ZERO),
+        )],
+        Json(json!({"status": "OK"})),
+    )
+        .into_response()
+}
+
+/// `action=is_logged_in` — without a login, the frontend still lets users
+/// browse batches
+fn action_is_logged_in(caller: Option<&Caller>) -> Value {
+    match caller {
+        Some(Caller::Session(session)) => json!({
+            "status": "OK",
+            "data": {
+                "is_logged_in": true,
+                "query": {"userinfo": session.userinfo}
+            }
+        }),
+        Some(Caller::Token { user_name, .. }) => json!({
+            "status": "OK",
+            "data": {
+                "is_logged_in": true,
+                "query": {"userinfo": {"name": user_name}}
+            }
+        }),
+        None => json!({
+            "status": "OK",
+            "data": {
+                "is_logged_in": false
+            }
+        }),
+    }
 }
 
 /// `action=get_batch_info`
@@ -207,18 +1025,37 @@ async fn action_get_commands_from_batch(state: &AppState, params: &ApiParams) ->
         .and_then(|s| s.parse().ok())
         .unwrap_or(0);
     let filter = params.filter.as_deref().unwrap_or("");
+    let error_code = params.error.as_deref().filter(|e| !e.is_empty());
 
-    let commands = get_commands(&state.config, batch_id, start, limit, filter).await;
+    let commands = get_commands(&state.config, batch_id, start, limit, filter, error_code).await;
 
     json!({"status": "OK", "data": commands})
 }
 
+/// `action=get_error_report` — the failed commands of a batch, grouped
+async fn action_get_error_report(state: &AppState, params: &ApiParams) -> Value {
+    let batch_id: i64 = match params.batch.as_deref().and_then(|s| s.parse().ok()) {
+        Some(id) => id,
+        None => return json!({"status": "ERROR: batch parameter required"}),
+    };
+    if get_batch_row(&state.config, batch_id).await.is_none() {
+        return json!({"status": format!("ERROR: batch {} not found", batch_id)});
+    }
+    match ErrorReport::for_batch(state.config.storage().as_ref(), batch_id).await {
+        Ok(report) => json!({"status": "OK", "data": report}),
+        Err(_) => json!({"status": "ERROR: Could not read commands"}),
+    }
+}
+
 /// `action=start_batch`
 async fn action_start_batch(state: &AppState, params: &ApiParams) -> Value {
     let batch_id: i64 = match params.batch.as_deref().and_then(|s| s.parse().ok()) {
         Some(id) => id,
         None => return json!({"status": "ERROR: batch parameter required"}),
     };
+    if let Err(e) = check_batch_transition(&state.config, batch_id, "INIT").await {
+        return e.to_json(batch_id);
+    }
 
     match set_batch_status_simple(&state.config, batch_id, "INIT").await {
         true => json!({"status": "OK"}),
@@ -232,6 +1069,9 @@ async fn action_stop_batch(state: &AppState, params: &ApiParams) -> Value {
         Some(id) => id,
         None => return json!({"status": "ERROR: batch parameter required"}),
     };
+    if let Err(e) = check_batch_transition(&state.config, batch_id, "STOP").await {
+        return e.to_json(batch_id);
+    }
 
     match set_batch_status_simple(&state.config, batch_id, "STOP").await {
         true => json!({"status": "OK"}),
@@ -239,140 +1079,586 @@ async fn action_stop_batch(state: &AppState, params: &ApiParams) -> Value {
     }
 }
 
-/// `action=import`
-async fn action_import(params: &ApiParams) -> Value {
-    let data = match params.data.as_deref() {
-        Some(d) if !d.is_empty() => d,
-        _ => return json!({"status": "ERROR: no data provided"}),
+/// `action=pause_batch` — like STOP, the bot lets go of the batch after the
+/// current command, but PAUSE marks it as meant to be resumed
+async fn action_pause_batch(state: &AppState, params: &ApiParams) -> Value {
+    let batch_id: i64 = match params.batch.as_deref().and_then(|s| s.parse().ok()) {
+        Some(id) => id,
+        None => return json!({"status": "ERROR: batch parameter required"}),
     };
-    let format = params.format.as_deref().unwrap_or("v1");
-    let compress = params.compress.as_deref().unwrap_or("1") != "0";
+    let qs = &state.config;
+    if let Err(e) = check_batch_transition(qs, batch_id, "PAUSE").await {
+        return e.to_json(batch_id);
+    }
 
-    if format == "v1" {
-        import_v1(data, compress).await
-    } else if format == "csv" {
-        // CSV import is a pass-through to V1 after converting
-        import_csv(data, compress).await
-    } else {
-        json!({"status": format!("ERROR: Unknown format {}", format)})
+    match set_batch_status_simple(qs, batch_id, "PAUSE").await {
+        true => json!({"status": "OK"}),
+        false => json!({"status": "ERROR: Could not pause batch"}),
     }
 }
 
-/// `action=run_batch`
-async fn action_run_batch(state: &AppState, params: &ApiParams) -> Value {
-    let name = params.name.as_deref().unwrap_or("");
-    let site = params
-        .site
-        .as_deref()
-        .unwrap_or(state.config.default_site().unwrap_or("wikidata"));
-    let commands_str = match params.commands.as_deref() {
-        Some(c) => c,
-        None => return json!({"status": "ERROR: commands parameter required"}),
-    };
-    let commands: Vec<Value> = match serde_json::from_str(commands_str) {
-        Ok(c) => c,
-        Err(e) => return json!({"status": format!("ERROR: Cannot parse commands JSON: {}", e)}),
+/// `action=resume_batch` — continues a paused batch; with `num`, any batch
+/// runs again from that command on, skipping the commands before it
+async fn action_resume_batch(state: &AppState, params: &ApiParams) -> Value {
+    let batch_id: i64 = match params.batch.as_deref().and_then(|s| s.parse().ok()) {
+        Some(id) => id,
+        None => return json!({"status": "ERROR: batch parameter required"}),
     };
-
-    match create_batch(&state.config, name, site, &commands).await {
-        Some(batch_id) => json!({"status": "OK", "batch_id": batch_id}),
-        None => json!({"status": "ERROR: Could not create batch"}),
+    let qs = &state.config;
+    if let Some(num) = params.num.as_deref() {
+        let num: i64 = match num.parse() {
+            Ok(num) if num >= 0 => num,
+            _ => return json!({"status": "ERROR: num must be a command number"}),
+        };
+        return match qs.storage().restart_batch_from(batch_id, num).await {
+            Ok(count) => json!({"status": "OK", "init": count}),
+            Err(e @ QsError::BatchNotIdle(_)) => json!({"status": format!("ERROR: {}", e)}),
+            Err(_) => json!({"status": "ERROR: Could not resume batch"}),
+        };
+    }
+    match check_batch_transition(qs, batch_id, "INIT").await {
+        Ok(batch) if batch.status == "PAUSE" => {}
+        Ok(_) => return json!({"status": "ERROR: Batch is not paused"}),
+        Err(e) => return e.to_json(batch_id),
     }
-}
 
-/// `action=run_single_command`
-async fn action_run_single_command(_state: &AppState, _params: &ApiParams) -> Value {
-    // This would need OAuth to actually run.
-    // For standalone mode, we return an error suggesting background mode.
-    json!({"status": "ERROR: Direct command execution requires OAuth authentication. Use background mode instead."})
+    match set_batch_status_simple(qs, batch_id, "INIT").await {
+        true => json!({"status": "OK"}),
+        false => json!({"status": "ERROR: Could not resume batch"}),
+    }
 }
 
-/// `action=get_token`
-fn action_get_token() -> Value {
-    // No OAuth in standalone mode
-    json!({
-        "status": "OK",
-        "data": {
-            "token": "",
-            "is_logged_in": false
-        }
-    })
+/// `action=set_batch_priority` — higher priority batches are started first;
+/// only admins can go above `scheduler.max_user_priority`
+async fn action_set_batch_priority(
+    state: &AppState,
+    params: &ApiParams,
+    caller: Option<&Caller>,
+) -> Value {
+    let batch_id: i64 = match params.batch.as_deref().and_then(|s| s.parse().ok()) {
+        Some(id) => id,
+        None => return json!({"status": "ERROR: batch parameter required"}),
+    };
+    // The column is an INT
+    let priority: i32 = match params.priority.as_deref() {
+        Some(priority) => match priority.parse() {
+            Ok(priority) => priority,
+            Err(_) => return json!({"status": "ERROR: priority must be a 32-bit integer"}),
+        },
+        None => return json!({"status": "ERROR: numeric priority parameter required"}),
+    };
+    let priority = state
+        .config
+        .allowed_priority(priority.into(), caller.map(Caller::user_name));
+
+    match state
+        .config
+        .storage()
+        .set_batch_priority(batch_id, priority)
+        .await
+    {
+        Ok(()) => json!({"status": "OK"}),
+        Err(_) => json!({"status": "ERROR: Could not set batch priority"}),
+    }
 }
 
-/// `action=reset_errors`
-async fn action_reset_errors(state: &AppState, params: &ApiParams) -> Value {
-    let batch_id: i64 = match params.batch_id.as_deref().and_then(|s| s.parse().ok()) {
+/// `action=create_schedule` — start a batch no earlier than `not_before`
+/// (YYYYMMDDHHMMSS, UTC) and/or rerun it by a `recurrence` rule whenever it finishes
+async fn action_create_schedule(state: &AppState, params: &ApiParams) -> Value {
+    let batch_id: i64 = match params.batch.as_deref().and_then(|s| s.parse().ok()) {
         Some(id) => id,
-        None => return json!({"status": "ERROR: batch_id parameter required"}),
+        None => return json!({"status": "ERROR: batch parameter required"}),
     };
+    let not_before = params.not_before.as_deref().unwrap_or("");
+    let recurrence = params.recurrence.as_deref().unwrap_or("");
+    if not_before.is_empty() && recurrence.is_empty() {
+        return json!({"status": "ERROR: not_before or recurrence parameter required"});
+    }
+    if !not_before.is_empty() && parse_timestamp(not_before).is_none() {
+        return json!({"status": "ERROR: not_before must be YYYYMMDDHHMMSS"});
+    }
+    if !recurrence.is_empty() && parse_recurrence(recurrence).is_none() {
+        return json!({"status": "ERROR: recurrence must be hourly, daily, weekly, or a number with h/d/w"});
+    }
 
-    let count = reset_error_commands(&state.config, batch_id).await;
+    let storage = state.config.storage();
+    let batch = match storage.get_batch(batch_id).await {
+        Ok(Some(batch)) => batch,
+        Ok(None) => return json!({"status": format!("ERROR: batch {} not found", batch_id)}),
+        Err(_) => return json!({"status": "ERROR: Could not read batch"}),
+    };
+    // Only waiting and finished batches get (back) into the queue; a stopped
+    // or paused batch would never fire
+    match batch.status.as_str() {
+        "INIT" | "DONE" => {}
+        "RUN" => return json!({"status": "ERROR: Cannot schedule a running batch"}),
+        status => {
+            return json!({"status": format!(
+                "ERROR: Cannot schedule a batch with status {}; start or reset it first",
+                status
+            )})
+        }
+    }
+    // Scheduling a finished batch runs all its edits again
+    if batch.status == "DONE" && !is_true(params.rerun.as_deref()) {
+        return json!({"status": "ERROR: Batch is done; set rerun=1 to run all its commands again"});
+    }
+    if storage
+        .set_batch_schedule(batch_id, not_before, recurrence)
+        .await
+        .is_err()
+    {
+        return json!({"status": "ERROR: Could not schedule batch"});
+    }
+    // A finished batch only runs again once it is back in the queue
+    if batch.status == "DONE"
+        && storage
+            .reschedule_batch(batch_id, not_before)
+            .await
+            .is_err()
+    {
+        return json!({"status": "ERROR: Could not schedule batch"});
+    }
+    json!({"status": "OK"})
+}
+
+/// `action=get_schedules` — batches waiting for their start time, or recurring
+async fn action_get_schedules(state: &AppState, params: &ApiParams) -> Value {
+    let qs = &state.config;
+    let user_name = params.user.as_deref().filter(|u| !u.is_empty());
+    let rows = match qs
+        .storage()
+        .get_scheduled_batches(user_name, &qs.timestamp())
+        .await
+    {
+        Ok(rows) => rows,
+        Err(_) => return json!({"status": "ERROR: Could not read schedules"}),
+    };
+    let mut batches = vec![];
+    for batch in &rows {
+        let user_name = qs.get_user_name(batch.user).await.unwrap_or_default();
+        batches.push(batch.to_json(&user_name));
+    }
+    json!({"status": "OK", "data": batches})
+}
+
+/// `action=cancel_schedule` — drops the start time and recurrence; a batch
+/// that has not started yet is stopped
+async fn action_cancel_schedule(state: &AppState, params: &ApiParams) -> Value {
+    let batch_id: i64 = match params.batch.as_deref().and_then(|s| s.parse().ok()) {
+        Some(id) => id,
+        None => return json!({"status": "ERROR: batch parameter required"}),
+    };
+    let qs = &state.config;
+    let batch = match qs.storage().get_batch(batch_id).await {
+        Ok(Some(batch)) => batch,
+        Ok(None) => return json!({"status": format!("ERROR: batch {} not found", batch_id)}),
+        Err(_) => return json!({"status": "ERROR: Could not read batch"}),
+    };
+    let waiting = batch.status == "INIT" && batch.ts_not_before > qs.timestamp();
+    if qs
+        .storage()
+        .set_batch_schedule(batch_id, "", "")
+        .await
+        .is_err()
+    {
+        return json!({"status": "ERROR: Could not cancel schedule"});
+    }
+    if waiting && !set_batch_status_simple(qs, batch_id, "STOP").await {
+        return json!({"status": "ERROR: Could not stop batch"});
+    }
+    json!({"status": "OK"})
+}
+
+/// `action=import` — parses commands; with `submit=1` they are run as a new
+/// batch, as scripts do with an API token, and with `temporary=1` they are
+/// stored for `get_batch`, which returns them by the ID given as `data`
+async fn action_import(state: &AppState, params: &ApiParams, caller: Option<Caller>) -> Value {
+    let data = match params.data.as_deref() {
+        Some(d) if !d.is_empty() => d,
+        _ => return json!({"status": "ERROR: no data provided"}),
+    };
+    let format = params.format.as_deref().unwrap_or("v1");
+    let compress = params.compress.as_deref().unwrap_or("1") != "0";
+
+    let result = if format == "v1" {
+        import_v1(data, compress).await
+    } else if format == "csv" {
+        // CSV import is a pass-through to V1 after converting
+        import_csv(data, compress).await
+    } else {
+        json!({"status": format!("ERROR: Unknown format {}", format)})
+    };
+    if result["status"] != "OK" {
+        return result;
+    }
+    if is_true(params.temporary.as_deref()) {
+        // Like submitted batches, stored ones need a login once there is OAuth
+        let user_id = match caller.as_ref() {
+            Some(caller) => caller.user_id(),
+            None if state.config.oauth().is_none() => 0,
+            None => return json!({"status": "ERROR: Not logged in"}),
+        };
+        return store_temporary_batch(&state.config, user_id, result).await;
+    }
+    if !is_true(params.submit.as_deref()) {
+        return result;
+    }
+
+    if let Some(errors) = result["errors"].as_array() {
+        return json!({
+            "status": format!("ERROR: {} commands could not be parsed", errors.len()),
+            "errors": errors
+        });
+    }
+    let commands = result["data"]["commands"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default();
+    if commands.is_empty() {
+        return json!({"status": "ERROR: no commands to submit"});
+    }
+    let name = params.batchname.as_deref().unwrap_or("");
+    let site = params
+        .site
+        .as_deref()
+        .unwrap_or(state.config.default_site().unwrap_or("wikidata"));
+    let (user_id, oauth_json) = match batch_owner(&state.config, caller).await {
+        Ok(owner) => owner,
+        Err(status) => return json!({ "status": status }),
+    };
+    match create_batch(
+        &state.config,
+        name,
+        site,
+        &commands,
+        user_id,
+        oauth_json.as_deref(),
+    )
+    .await
+    {
+        Ok(batch_id) => json!({"status": "OK", "batch_id": batch_id}),
+        Err(e) => e.to_json(),
+    }
+}
+
+/// `action=run_batch`
+async fn action_run_batch(state: &AppState, params: &ApiParams, caller: Option<Caller>) -> Value {
+    let name = params.name.as_deref().unwrap_or("");
+    let site = params
+        .site
+        .as_deref()
+        .unwrap_or(state.config.default_site().unwrap_or("wikidata"));
+    let commands_str = match params.commands.as_deref() {
+        Some(c) => c,
+        None => return json!({"status": "ERROR: commands parameter required"}),
+    };
+    let commands: Vec<Value> = match serde_json::from_str(commands_str) {
+        Ok(c) => c,
+        Err(e) => return json!({"status": format!("ERROR: Cannot parse commands JSON: {}", e)}),
+    };
+    let priority: Option<i32> = match params.priority.as_deref() {
+        Some(p) => match p.parse() {
+            Ok(p) => Some(p),
+            Err(_) => return json!({"status": "ERROR: priority must be a 32-bit integer"}),
+        },
+        None => None,
+    };
+    let priority = priority.map(|priority| {
+        state
+            .config
+            .allowed_priority(priority.into(), caller.as_ref().map(Caller::user_name))
+    });
+
+    let (user_id, oauth_json) = match batch_owner(&state.config, caller).await {
+        Ok(owner) => owner,
+        Err(status) => return json!({ "status": status }),
+    };
+
+    let batch_id = match create_batch(
+        &state.config,
+        name,
+        site,
+        &commands,
+        user_id,
+        oauth_json.as_deref(),
+    )
+    .await
+    {
+        Ok(batch_id) => batch_id,
+        Err(e) => return e.to_json(),
+    };
+    if let Some(priority) = priority {
+        if state
+            .config
+            .storage()
+            .set_batch_priority(batch_id, priority)
+            .await
+            .is_err()
+        {
+            return json!({"status": "ERROR: Could not set batch priority"});
+        }
+    }
+    json!({"status": "OK", "batch_id": batch_id})
+}
+
+/// `action=run_single_command` — runs one command right away with the
+/// caller's login, for the "run in browser" mode of the frontend, which
+/// keeps LAST between commands as `last_item`. The command comes back with
+/// `status` "done" or "error".
+async fn action_run_single_command(
+    state: &AppState,
+    params: &ApiParams,
+    caller: Option<Caller>,
+) -> Value {
+    let command_json: Value = match params.command.as_deref().map(serde_json::from_str) {
+        Some(Ok(j @ Value::Object(_))) => j,
+        Some(Ok(_)) => return json!({"status": "ERROR: command must be a JSON object"}),
+        Some(Err(e)) => {
+            return json!({"status": format!("ERROR: Cannot parse command JSON: {}", e)})
+        }
+        None => return json!({"status": "ERROR: command parameter required"}),
+    };
+    let site = params
+        .site
+        .as_deref()
+        .unwrap_or(state.config.default_site().unwrap_or("wikidata"));
+    let (user_id, oauth_json) = match batch_owner(&state.config, caller).await {
+        Ok(owner) => owner,
+        Err(status) => return json!({ "status": status }),
+    };
+
+    let mut command = QuickStatementsCommand::new_from_json(&command_json);
+    let mut bot = QuickStatementsBot::new(state.config.clone(), None, user_id);
+    let last_item = params.last_item.as_deref().unwrap_or("");
+    bot.set_last_state(LastEntityState::decode(last_item));
+    let result = match bot.start_without_batch(site, oauth_json.as_deref()).await {
+        Ok(()) => bot.execute_command(&mut command).await,
+        Err(e) => Err(e),
+    };
+
+    let mut j = command.json;
+    let (status, result) = match result {
+        Ok(result) => {
+            j["status"] = json!("done");
+            ("OK".to_string(), result)
+        }
+        Err(e) => {
+            log::warn!(user = user_id, site = site; "Single command failed: {}", e);
+            j["status"] = json!("error");
+            j["message"] = json!(e.to_string());
+            j["error_code"] = json!(e.code());
+            (format!("ERROR: {}", e), Value::Null)
+        }
+    };
+    // The frontend copies `message` into `meta`
+    if !j["meta"].is_object() {
+        j["meta"] = json!({});
+    }
+    json!({
+        "status": status,
+        "command": j,
+        "last_item": bot.last_state().encode(),
+        "result": result,
+    })
+}
+
+/// `action=get_token` — the user page's API token, with all scopes. Tokens
+/// are stored hashed, so one is only shown when it is generated: if the user
+/// has none, or with `force_generate=1`, which revokes the old one.
+async fn action_get_token(state: &AppState, params: &ApiParams, caller: Option<Caller>) -> Value {
+    let mut session = match caller {
+        Some(Caller::Session(session)) => session,
+        _ => return json!({"status": "ERROR: Not logged in"}),
+    };
+    let storage = state.config.storage();
+    let old_tokens: Vec<ApiTokenRow> = match storage.get_api_tokens(session.user_id).await {
+        Ok(tokens) => tokens
+            .into_iter()
+            .filter(|token| token.name == DEFAULT_TOKEN_NAME)
+            .collect(),
+        Err(_) => return json!({"status": "ERROR: Could not read tokens"}),
+    };
+    if !old_tokens.is_empty() && !is_true(params.force_generate.as_deref()) {
+        return json!({"status": "OK", "data": {"token": "", "has_token": true}});
+    }
+    for token in &old_tokens {
+        if storage
+            .revoke_api_token(token.id, session.user_id)
+            .await
+            .is_err()
+        {
+            return json!({"status": "ERROR: Could not revoke the old token"});
+        }
+    }
+    let scopes = TOKEN_SCOPES.join(",");
+    match issue_api_token(&state.config, &mut session, DEFAULT_TOKEN_NAME, &scopes).await {
+        Ok((_, token)) => json!({"status": "OK", "data": {"token": token, "has_token": true}}),
+        Err(status) => json!({ "status": status }),
+    }
+}
+
+/// `action=create_api_token` — a named token with the comma-separated `scopes`
+/// (default: read); the token is only shown in this response
+async fn action_create_api_token(
+    state: &AppState,
+    params: &ApiParams,
+    caller: Option<Caller>,
+) -> Value {
+    let mut session = match caller {
+        Some(Caller::Session(session)) => session,
+        _ => return json!({"status": "ERROR: Not logged in"}),
+    };
+    let name = match params.name.as_deref().map(str::trim) {
+        Some(name) if !name.is_empty() => name,
+        _ => return json!({"status": "ERROR: name parameter required"}),
+    };
+    let scopes: Vec<&str> = params
+        .scopes
+        .as_deref()
+        .unwrap_or("read")
+        .split(',')
+        .map(str::trim)
+        .filter(|scope| !scope.is_empty())
+        .collect();
+    if let Some(scope) = scopes.iter().find(|scope| !TOKEN_SCOPES.contains(scope)) {
+        return json!({"status": format!("ERROR: Unknown scope '{}'", scope)});
+    }
+    if scopes.is_empty() {
+        return json!({"status": "ERROR: scopes parameter required"});
+    }
+    let scopes = scopes.join(",");
+    match issue_api_token(&state.config, &mut session, name, &scopes).await {
+        Ok((token_id, token)) => json!({
+            "status": "OK",
+            "data": {
+                "id": token_id,
+                "name": name,
+                "token": token,
+                "scopes": scopes.split(',').collect::<Vec<_>>()
+            }
+        }),
+        Err(status) => json!({ "status": status }),
+    }
+}
+
+/// `action=list_api_tokens` — the user's tokens that were not revoked
+async fn action_list_api_tokens(state: &AppState, caller: Option<Caller>) -> Value {
+    let session = match caller.as_ref().and_then(Caller::session) {
+        Some(session) => session,
+        None => return json!({"status": "ERROR: Not logged in"}),
+    };
+    match state.config.storage().get_api_tokens(session.user_id).await {
+        Ok(tokens) => json!({
+            "status": "OK",
+            "data": tokens.iter().map(ApiTokenRow::to_json).collect::<Vec<_>>()
+        }),
+        Err(_) => json!({"status": "ERROR: Could not read tokens"}),
+    }
+}
+
+/// `action=revoke_api_token`
+async fn action_revoke_api_token(
+    state: &AppState,
+    params: &ApiParams,
+    caller: Option<Caller>,
+) -> Value {
+    let session = match caller.as_ref().and_then(Caller::session) {
+        Some(session) => session,
+        None => return json!({"status": "ERROR: Not logged in"}),
+    };
+    let token_id: i64 = match params.token_id.as_deref().and_then(|s| s.parse().ok()) {
+        Some(id) => id,
+        None => return json!({"status": "ERROR: token_id parameter required"}),
+    };
+    match state
+        .config
+        .storage()
+        .revoke_api_token(token_id, session.user_id)
+        .await
+    {
+        Ok(true) => json!({"status": "OK"}),
+        Ok(false) => json!({"status": format!("ERROR: token {} not found", token_id)}),
+        Err(_) => json!({"status": "ERROR: Could not revoke token"}),
+    }
+}
+
+/// `action=reset_errors` — with `error`, only commands that failed with that
+/// error code are retried, e.g. `permission-denied` after a revoked OAuth grant
+/// was renewed
+async fn action_reset_errors(state: &AppState, params: &ApiParams) -> Value {
+    let batch_id: i64 = match params.batch_id.as_deref().and_then(|s| s.parse().ok()) {
+        Some(id) => id,
+        None => return json!({"status": "ERROR: batch_id parameter required"}),
+    };
+    let error = params.error.as_deref().filter(|e| !e.is_empty());
+
+    let count = reset_error_commands(&state.config, batch_id, error).await;
     json!({"status": "OK", "init": count})
 }
 
+/// Stores the parsed commands of an `import` as a temporary batch. Commands
+/// that could not be parsed are reported, but not stored.
+async fn store_temporary_batch(qs: &QuickStatements, user_id: i64, result: Value) -> Value {
+    let id = random_token();
+    let expires = timestamp_after(qs.temporary_batch_lifetime());
+    if let Err(e) = qs
+        .storage()
+        .create_temporary_batch(&id, user_id, &result["data"].to_string(), &expires)
+        .await
+    {
+        log::error!("Cannot store temporary batch: {}", e);
+        return json!({"status": "ERROR: Could not store temporary batch"});
+    }
+    let mut ret = json!({"status": "OK", "data": id});
+    if let Some(errors) = result.get("errors") {
+        ret["errors"] = errors.to_owned();
+    }
+    ret
+}
+
+/// Temporary batch IDs are tokens from `random_token`
+fn is_temporary_batch_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 64 && id.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
 /// `action=get_batch` — load a temporary batch by ID
-fn action_get_batch(params: &ApiParams) -> Value {
+async fn action_get_batch(state: &AppState, params: &ApiParams) -> Value {
     let id = params.id.as_deref().unwrap_or("");
     if id.is_empty() {
         return json!({"status": "ERROR: id parameter required"});
     }
-    // Try to read the temp file
-    let path = format!("public_html/tmp/{}", id);
-    match std::fs::read_to_string(&path) {
-        Ok(content) => match serde_json::from_str::<Value>(&content) {
+    if !is_temporary_batch_id(id) {
+        return json!({"status": "ERROR: invalid id"});
+    }
+    let qs = &state.config;
+    match qs.storage().get_temporary_batch(id, &qs.timestamp()).await {
+        Ok(Some(data)) => match serde_json::from_str::<Value>(&data) {
             Ok(data) => json!({"status": "OK", "id": id, "data": data}),
-            Err(_) => json!({"status": "ERROR: invalid tmp file content"}),
+            Err(_) => json!({"status": "ERROR: invalid temporary batch content"}),
         },
-        Err(_) => json!({"status": format!("ERROR: tmp file {} not found", id)}),
+        Ok(None) => json!({"status": format!("ERROR: temporary batch {} not found", id)}),
+        Err(_) => json!({"status": "ERROR: Could not read temporary batch"}),
     }
 }
 
 // ---- Database helper functions ----
 
-use mysql_async as my;
-use mysql_async::prelude::*;
-
-/// Row layout of the `batch` table:
-/// (id, name, user, site, status, message, last_item, ts_last_change)
-type BatchRow = (i64, String, i64, String, String, String, String, String);
-
 /// Get a single batch's metadata
 async fn get_batch_row(qs: &QuickStatements, batch_id: i64) -> Option<Value> {
-    let sql = "SELECT id, `name`, `user`, site, `status`, message, last_item, ts_last_change FROM batch WHERE id=:batch_id";
-    let mut conn = qs.get_db_conn().await.ok()?;
-    let rows: Vec<BatchRow> = conn.exec(sql, my::params! {batch_id}).await.ok()?;
-    let row = rows.first()?;
-
-    let user_name = qs.get_user_name(row.2).await.unwrap_or_default();
-
-    Some(json!({
-        "id": row.0,
-        "name": row.1,
-        "user": user_name,
-        "site": row.3,
-        "status": row.4,
-        "message": row.5,
-        "last_item": row.6,
-        "ts_last_change": row.7,
-    }))
+    let batch = qs.storage().get_batch(batch_id).await.ok()??;
+    let user_name = qs.get_user_name(batch.user).await.unwrap_or_default();
+    Some(batch.to_json(&user_name))
 }
 
 /// Get command status counts for a batch
 async fn get_command_counts(qs: &QuickStatements, batch_id: i64) -> Value {
-    let sql =
-        "SELECT `status`, COUNT(*) AS cnt FROM command WHERE batch_id=:batch_id GROUP BY `status`";
     let mut counts = json!({
         "INIT": 0, "RUN": 0, "DONE": 0, "ERROR": 0, "BLOCKED": 0, "STOP": 0
     });
-
-    if let Ok(mut conn) = qs.get_db_conn().await {
-        if let Ok(rows) = conn
-            .exec::<(String, i64), _, _>(sql, my::params! {batch_id})
-            .await
-        {
-            for (status, cnt) in rows {
-                counts[&status] = json!(cnt);
-            }
+    if let Ok(rows) = qs.storage().get_command_counts(batch_id).await {
+        for (status, cnt) in rows {
+            counts[&status] = json!(cnt);
         }
     }
     counts
@@ -380,187 +1666,273 @@ async fn get_command_counts(qs: &QuickStatements, batch_id: i64) -> Value {
 
 /// Get list of batches, optionally filtered by user
 async fn get_batches(qs: &QuickStatements, user_filter: &str, limit: i64, offset: i64) -> Value {
-    let mut conn = match qs.get_db_conn().await {
-        Ok(c) => c,
-        Err(_) => return json!({}),
-    };
-
-    let (sql, query_params) = if user_filter.is_empty() {
-        let sql = "SELECT b.id, b.`name`, b.`user`, b.site, b.`status`, b.message, b.last_item, b.ts_last_change FROM batch b ORDER BY b.id DESC LIMIT :limit OFFSET :offset";
-        (sql.to_string(), my::params! { limit, offset })
-    } else {
-        // Resolve user name to user id
-        let auth_db = "s53220__quickstatements_auth";
-        let sql = format!(
-            "SELECT b.id, b.`name`, b.`user`, b.site, b.`status`, b.message, b.last_item, b.ts_last_change FROM batch b WHERE b.`user` IN (SELECT user_id FROM {}.user WHERE name=:user_filter) ORDER BY b.id DESC LIMIT :limit OFFSET :offset",
-            auth_db
-        );
-        (sql, my::params! { user_filter, limit, offset })
-    };
-
-    let rows: Vec<BatchRow> = match conn.exec(&sql, query_params).await {
-        Ok(r) => r,
+    let user_name = Some(user_filter).filter(|u| !u.is_empty());
+    let rows = match qs.storage().get_batches(user_name, limit, offset).await {
+        Ok(rows) => rows,
         Err(_) => return json!({}),
     };
 
     let mut result = json!({});
-    for row in &rows {
-        let user_name = qs.get_user_name(row.2).await.unwrap_or_default();
-        let batch_id = row.0;
-        let counts = get_command_counts(qs, batch_id).await;
-
-        result[batch_id.to_string()] = json!({
-            "batch": {
-                "id": row.0,
-                "name": row.1,
-                "user": user_name,
-                "site": row.3,
-                "status": row.4,
-                "message": row.5,
-                "last_item": row.6,
-                "ts_last_change": row.7,
-            },
+    for batch in &rows {
+        let user_name = qs.get_user_name(batch.user).await.unwrap_or_default();
+        let counts = get_command_counts(qs, batch.id).await;
+        result[batch.id.to_string()] = json!({
+            "batch": batch.to_json(&user_name),
             "commands": counts,
         });
     }
     result
 }
 
-/// Get commands from a batch with pagination and optional status filter.
-/// Numeric and validated values are interpolated safely; the status filter is
-/// validated against a whitelist before being embedded in SQL.
+/// Get commands from a batch with pagination and optional status and error
+/// code filters. The status filter is validated against a whitelist; unknown
+/// statuses are ignored.
 async fn get_commands(
     qs: &QuickStatements,
     batch_id: i64,
     start: i64,
     limit: i64,
     filter: &str,
+    error_code: Option<&str>,
 ) -> Value {
-    let mut conn = match qs.get_db_conn().await {
-        Ok(c) => c,
-        Err(_) => return json!([]),
-    };
-
-    const VALID_STATUSES: &[&str] = &["INIT", "RUN", "DONE", "ERROR", "BLOCKED", "STOP"];
-
-    // Whitelist-validate filter statuses to prevent SQL injection.
     let filter_statuses: Vec<&str> = if filter.is_empty() {
         vec![]
     } else {
         filter
             .split(',')
             .map(|s| s.trim())
-            .filter(|s| VALID_STATUSES.contains(s))
+            .filter(|s| COMMAND_STATUSES.contains(s))
             .collect()
     };
-
-    // Safe: batch_id, start, limit are all i64 parsed by the caller.
-    let mut sql = format!(
-        "SELECT id, batch_id, num, json, `status`, message, ts_change FROM command WHERE batch_id={}",
-        batch_id
-    );
-
-    if !filter_statuses.is_empty() {
-        let quoted: Vec<String> = filter_statuses.iter().map(|s| format!("'{}'", s)).collect();
-        sql += &format!(" AND `status` IN ({})", quoted.join(","));
-    }
-
-    sql += " ORDER BY num";
-    if limit > 0 {
-        sql += &format!(" LIMIT {}", limit);
-    }
-    if start > 0 {
-        sql += &format!(" OFFSET {}", start);
-    }
-
-    let rows: Vec<(i64, i64, i64, String, String, String, String)> = match conn.exec(&sql, ()).await
+    let rows = match qs
+        .storage()
+        .get_commands(batch_id, &filter_statuses, error_code, start, limit)
+        .await
     {
         Ok(r) => r,
         Err(_) => return json!([]),
     };
 
-    let commands: Vec<Value> = rows
-        .iter()
-        .map(|row| {
-            let cmd_json: Value = serde_json::from_str(&row.3).unwrap_or(json!({}));
-            json!({
-                "id": row.0,
-                "batch_id": row.1,
-                "num": row.2,
-                "json": cmd_json,
-                "status": row.4,
-                "message": row.5,
-                "ts_change": row.6,
-            })
-        })
-        .collect();
+    let commands: Vec<Value> = rows.iter().map(command_json).collect();
 
     json!(commands)
 }
 
+/// A command as the frontend expects it
+fn command_json(row: &CommandRow) -> Value {
+    let cmd_json: Value = serde_json::from_str(&row.json).unwrap_or(json!({}));
+    json!({
+        "id": row.id,
+        "batch_id": row.batch_id,
+        "num": row.num,
+        "json": cmd_json,
+        "status": row.status,
+        "message": row.message,
+        "ts_change": row.ts_change,
+        "error_code": row.error_code,
+    })
+}
+
 /// Set batch status (for start/stop)
-async fn set_batch_status_simple(qs: &QuickStatements, batch_id: i64, status: &str) -> bool {
-    let ts = qs.timestamp();
-    let sql = r#"UPDATE `batch` SET `status`=:status, `ts_last_change`=:ts WHERE id=:batch_id"#;
-    match qs.get_db_conn().await {
-        Ok(mut conn) => conn
-            .exec_drop(sql, my::params! {status, ts, batch_id})
-            .await
-            .is_ok(),
-        Err(_) => false,
+/// Why a batch action cannot set a batch to a status
+pub(crate) enum TransitionError {
+    NotFound,
+    /// The batch cannot change from its status to the requested one
+    Invalid(&'static str),
+    /// The database failed
+    Failed,
+}
+
+impl TransitionError {
+    /// The api.php response
+    fn to_json(&self, batch_id: i64) -> Value {
+        match self {
+            Self::NotFound => json!({"status": format!("ERROR: Batch #{} not found", batch_id)}),
+            Self::Invalid(message) => json!({"status": format!("ERROR: {}", message)}),
+            Self::Failed => json!({"status": "ERROR: Could not read batch"}),
+        }
     }
 }
 
-/// Create a new batch in the database and insert commands
-async fn create_batch(
+/// Checks that a batch action may set the batch to `status` (INIT, STOP or
+/// PAUSE), returning the batch. Only waiting or running batches can be
+/// paused; a DONE batch only starts again if commands of it were reset, as
+/// running all of them again needs a schedule with `rerun=1`.
+pub(crate) async fn check_batch_transition(
     qs: &QuickStatements,
-    name: &str,
-    site: &str,
-    commands: &[Value],
-) -> Option<i64> {
-    let mut conn = qs.get_db_conn().await.ok()?;
-    let ts = qs.timestamp();
-    let user: i64 = 0; // No OAuth user in standalone mode
-
-    // Insert batch
-    let ts_batch = ts.clone();
-    conn.exec_drop(
-        "INSERT INTO batch (`name`, `user`, site, `status`, ts_last_change) VALUES (:name, :user, :site, 'INIT', :ts_batch)",
-        my::params! {name, user, site, ts_batch},
-    )
-    .await
-    .ok()?;
+    batch_id: i64,
+    status: &str,
+) -> Result<BatchRow, TransitionError> {
+    let batch = match qs.storage().get_batch(batch_id).await {
+        Ok(Some(batch)) => batch,
+        Ok(None) => return Err(TransitionError::NotFound),
+        Err(_) => return Err(TransitionError::Failed),
+    };
+    match (batch.status.as_str(), status) {
+        ("INIT" | "RUN", "PAUSE") => {}
+        (_, "PAUSE") => {
+            return Err(TransitionError::Invalid(
+                "Only waiting or running batches can be paused",
+            ))
+        }
+        ("DONE", "STOP") => return Err(TransitionError::Invalid("Batch is already done")),
+        ("DONE", _) => {
+            let counts = qs
+                .storage()
+                .get_command_counts(batch_id)
+                .await
+                .map_err(|_| TransitionError::Failed)?;
+            if !counts.iter().any(|(s, count)| s == "INIT" && *count > 0) {
+                return Err(TransitionError::Invalid(
+                    "Batch is done; schedule it with rerun=1 to run it again",
+                ));
+            }
+        }
+        _ => {}
+    }
+    Ok(batch)
+}
 
-    let batch_id: i64 = conn
-        .exec_first("SELECT LAST_INSERT_ID()", ())
+async fn set_batch_status_simple(qs: &QuickStatements, batch_id: i64, status: &str) -> bool {
+    qs.storage()
+        .set_batch_status(batch_id, status, None)
         .await
-        .ok()??;
-
-    // Insert commands
-    for (num, cmd) in commands.iter().enumerate() {
-        let json_str = serde_json::to_string(cmd).unwrap_or_else(|_| "{}".to_string());
-        let num = num as i64;
-        let ts_cmd = ts.clone();
-        conn.exec_drop(
-            "INSERT INTO command (batch_id, num, json, `status`, ts_change) VALUES (:batch_id, :num, :json_str, 'INIT', :ts_cmd)",
-            my::params! {batch_id, num, json_str, ts_cmd},
+        .is_ok()
+}
+
+/// The `batch_oauth` entry for the login of a session, whose OAuth 2.0
+/// tokens are refreshed if they are about to expire. Errors are API status
+/// strings.
+async fn session_oauth_json(qs: &QuickStatements, session: &mut Session) -> Result<String, String> {
+    let oauth = qs.oauth().ok_or("ERROR: OAuth is not configured")?;
+    let mut credentials = session.credentials.clone().ok_or("ERROR: Not logged in")?;
+    if credentials.needs_refresh() {
+        credentials = match qs.refresh_oauth(&credentials).await {
+            Ok(credentials) => credentials,
+            Err(e) => {
+                log::warn!(user = session.user_id; "Cannot refresh OAuth token: {}", e);
+                delete_session(qs, session).await;
+                return Err("ERROR: Login expired, please log in again".to_string());
+            }
+        };
+        session.credentials = Some(credentials.clone());
+        if let Err(e) = save_session(qs, session, SESSION_LIFETIME).await {
+            log::error!("Cannot save session: {}", e);
+        }
+    }
+    Ok(oauth.batch_json(&credentials).to_string())
+}
+
+/// Creates an API token carrying the login of the session, returning its ID
+/// and the token
+async fn issue_api_token(
+    qs: &QuickStatements,
+    session: &mut Session,
+    name: &str,
+    scopes: &str,
+) -> Result<(i64, String), String> {
+    let oauth_json = session_oauth_json(qs, session).await?;
+    let token = format!("qs_{}{}", random_token(), random_token());
+    match qs
+        .storage()
+        .create_api_token(
+            session.user_id,
+            name,
+            scopes,
+            &hash_token(&token),
+            &oauth_json,
         )
         .await
-        .ok()?;
+    {
+        Ok(token_id) => Ok((token_id, token)),
+        Err(e) => {
+            log::error!(user = session.user_id; "Cannot create API token: {}", e);
+            Err("ERROR: Could not create token".to_string())
+        }
+    }
+}
+
+/// The user new batches belong to and the `batch_oauth` entry they run with:
+/// the caller's if OAuth is configured, else user 0, whose batches run with
+/// the bot account. Errors are API status strings.
+pub(crate) async fn batch_owner(
+    qs: &QuickStatements,
+    caller: Option<Caller>,
+) -> Result<(i64, Option<String>), String> {
+    if qs.oauth().is_none() {
+        return Ok((0, None));
+    }
+    match caller {
+        Some(Caller::Session(mut session)) => {
+            let oauth_json = session_oauth_json(qs, &mut session).await?;
+            Ok((session.user_id, Some(oauth_json)))
+        }
+        Some(Caller::Token { token, .. }) => Ok((token.user_id, Some(token.serialized_json))),
+        None => Err("ERROR: Not logged in".to_string()),
     }
+}
+
+/// Why `create_batch` did not create a batch
+pub(crate) enum BatchError {
+    /// Commands lacking what their action needs, by position in the batch
+    Invalid(Vec<(usize, String)>),
+    /// The database failed
+    Failed,
+}
 
-    Some(batch_id)
+impl BatchError {
+    /// The api.php response
+    fn to_json(&self) -> Value {
+        match self {
+            Self::Invalid(errors) => json!({
+                "status": format!("ERROR: {} commands are invalid", errors.len()),
+                "errors": errors
+                    .iter()
+                    .map(|(num, error)| json!({"num": num, "error": error}))
+                    .collect::<Vec<Value>>(),
+            }),
+            Self::Failed => json!({"status": "ERROR: Could not create batch"}),
+        }
+    }
+}
+
+/// Create a new batch in the database and insert commands. The whole batch is
+/// rejected if any command could not be run.
+pub(crate) async fn create_batch(
+    qs: &QuickStatements,
+    name: &str,
+    site: &str,
+    commands: &[Value],
+    user_id: i64,
+    oauth_json: Option<&str>,
+) -> Result<i64, BatchError> {
+    let errors: Vec<(usize, String)> = commands
+        .iter()
+        .enumerate()
+        .filter_map(|(num, cmd)| {
+            let error = QuickStatementsCommand::new_from_json(cmd)
+                .validate()
+                .err()?;
+            Some((num, error.to_string()))
+        })
+        .collect();
+    if !errors.is_empty() {
+        return Err(BatchError::Invalid(errors));
+    }
+    let commands: Vec<String> = commands.iter().map(Value::to_string).collect();
+    qs.storage()
+        .create_batch(name, user_id, site, &commands, oauth_json)
+        .await
+        .map_err(|e| {
+            log::error!("Cannot create batch: {}", e);
+            BatchError::Failed
+        })
 }
 
 /// Reset ERROR commands back to INIT
-async fn reset_error_commands(qs: &QuickStatements, batch_id: i64) -> i64 {
-    let ts = qs.timestamp();
-    let sql = r#"UPDATE command SET `status`='INIT', message='', ts_change=:ts WHERE batch_id=:batch_id AND `status`='ERROR'"#;
-    match qs.get_db_conn().await {
-        Ok(mut conn) => match conn.exec_iter(sql, my::params! {ts, batch_id}).await {
-            Ok(result) => result.affected_rows() as i64,
-            Err(_) => 0,
-        },
+async fn reset_error_commands(qs: &QuickStatements, batch_id: i64, error: Option<&str>) -> i64 {
+    match qs.storage().reset_error_commands(batch_id, error).await {
+        Ok(count) => count as i64,
         Err(_) => 0,
     }
 }
@@ -634,3 +2006,623 @@ async fn import_csv(data: &str, compress: bool) -> Value {
 
     import_v1(&v1_lines.join("\n"), compress).await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qs_fake_wiki::FakeWiki;
+
+    fn qs() -> QuickStatements {
+        let mut qs = QuickStatements::new_for_tests();
+        qs.set_param_for_tests("admins", json!(["Admin User"]));
+        qs
+    }
+
+    fn session(user_id: i64, user_name: &str) -> Caller {
+        Caller::Session(Session {
+            user_id,
+            userinfo: json!({ "name": user_name }),
+            ..Default::default()
+        })
+    }
+
+    /// A user with a batch
+    async fn user_with_batch(qs: &QuickStatements, user_name: &str) -> (i64, i64) {
+        let user_id = qs.storage().get_or_create_user(user_name).await.unwrap();
+        let commands = vec![json!({"action": "create", "type": "item"}).to_string()];
+        let batch_id = qs
+            .storage()
+            .create_batch("test", user_id, "wikidata", &commands, None)
+            .await
+            .unwrap();
+        (user_id, batch_id)
+    }
+
+    fn status(result: Result<(), Denied>) -> StatusCode {
+        result
+            .err()
+            .map(|denied| denied.status)
+            .unwrap_or(StatusCode::OK)
+    }
+
+    #[test]
+    fn session_cookie_is_secure_unless_configured() {
+        let mut qs = qs();
+        let cookie = session_cookie(&qs, "abc", Duration::from_secs(60));
+        assert_eq!(
+            cookie,
+            format!(
+                "{}=abc; Path=/; Max-Age=60; HttpOnly; Secure; SameSite=Lax",
+                SESSION_COOKIE
+            )
+        );
+
+        qs.set_param_for_tests("oauth", json!({"secure_cookie": false}));
+        let cookie = session_cookie(&qs, "abc", Duration::from_secs(60));
+        assert_eq!(
+            cookie,
+            format!(
+                "{}=abc; Path=/; Max-Age=60; HttpOnly; SameSite=Lax",
+                SESSION_COOKIE
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn only_owner_and_admins_change_a_batch() {
+        let qs = qs();
+        let (alice, batch_id) = user_with_batch(&qs, "Alice").await;
+        let (bob, _) = user_with_batch(&qs, "Bob").await;
+        let batch = Some(batch_id);
+        let owner = session(alice, "Alice");
+        let other = session(bob, "Bob");
+        let admin = session(bob + 1, "Admin_User");
+
+        assert_eq!(
+            status(authorize(&qs, "stop_batch", batch, false, Some(&owner)).await),
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(authorize(&qs, "stop_batch", batch, false, Some(&other)).await),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(authorize(&qs, "reset_errors", batch, false, Some(&admin)).await),
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(authorize(&qs, "stop_batch", batch, false, None).await),
+            StatusCode::UNAUTHORIZED
+        );
+        // Reading is open to everyone
+        assert_eq!(
+            status(authorize(&qs, "get_batch_info", batch, false, None).await),
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(authorize(&qs, "run_batch", None, false, None).await),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(authorize(&qs, "run_batch", None, false, Some(&other)).await),
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn batch_action_without_batch_id_is_denied() {
+        let qs = qs();
+        let (alice, _) = user_with_batch(&qs, "Alice").await;
+        let caller = session(alice, "Alice");
+        assert_eq!(
+            status(authorize(&qs, "reset_errors", None, false, Some(&caller)).await),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn open_batch_actions_only_skip_the_login() {
+        let mut qs = qs();
+        qs.set_param_for_tests("open_batch_actions", json!(true));
+        let (alice, batch_id) = user_with_batch(&qs, "Alice").await;
+        let (bob, _) = user_with_batch(&qs, "Bob").await;
+        let batch = Some(batch_id);
+        assert_eq!(
+            status(authorize(&qs, "stop_batch", batch, false, None).await),
+            StatusCode::OK
+        );
+        let other = session(bob, "Bob");
+        assert_eq!(
+            status(authorize(&qs, "stop_batch", batch, false, Some(&other)).await),
+            StatusCode::FORBIDDEN
+        );
+        let owner = session(alice, "Alice");
+        assert_eq!(
+            status(authorize(&qs, "stop_batch", batch, false, Some(&owner)).await),
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn only_admins_raise_priority() {
+        let qs = qs();
+        let (alice, batch_id) = user_with_batch(&qs, "Alice").await;
+        let storage = qs.storage().clone();
+        let state = AppState {
+            config: Arc::new(qs),
+        };
+        let params = ApiParams {
+            batch: Some(batch_id.to_string()),
+            priority: Some("10".to_string()),
+            ..Default::default()
+        };
+
+        let owner = session(alice, "Alice");
+        let result = action_set_batch_priority(&state, &params, Some(&owner)).await;
+        assert_eq!(result["status"], "OK");
+        let batch = storage.get_batch(batch_id).await.unwrap().unwrap();
+        assert_eq!(batch.priority, 0);
+
+        let admin = session(alice + 1, "Admin User");
+        let result = action_set_batch_priority(&state, &params, Some(&admin)).await;
+        assert_eq!(result["status"], "OK");
+        let batch = storage.get_batch(batch_id).await.unwrap().unwrap();
+        assert_eq!(batch.priority, 10);
+    }
+
+    #[tokio::test]
+    async fn priority_must_fit_the_column() {
+        let qs = qs();
+        let (_, batch_id) = user_with_batch(&qs, "Alice").await;
+        let state = AppState {
+            config: Arc::new(qs),
+        };
+        let admin = session(0, "Admin User");
+        let params = ApiParams {
+            batch: Some(batch_id.to_string()),
+            priority: Some((i64::from(i32::MAX) + 1).to_string()),
+            ..Default::default()
+        };
+        let result = action_set_batch_priority(&state, &params, Some(&admin)).await;
+        assert_eq!(result["status"], "ERROR: priority must be a 32-bit integer");
+    }
+
+    #[tokio::test]
+    async fn scheduling_a_done_batch_needs_rerun() {
+        let qs = qs();
+        let (_, batch_id) = user_with_batch(&qs, "Alice").await;
+        let storage = qs.storage().clone();
+        let command = storage.get_next_command(batch_id).await.unwrap().unwrap();
+        storage
+            .set_command_status(command.id, "DONE", "", "", &command.json)
+            .await
+            .unwrap();
+        storage
+            .set_batch_status(batch_id, "DONE", None)
+            .await
+            .unwrap();
+        let state = AppState {
+            config: Arc::new(qs),
+        };
+        let mut params = ApiParams {
+            batch: Some(batch_id.to_string()),
+            not_before: Some("20300101000000".to_string()),
+            ..Default::default()
+        };
+
+        let result = action_create_schedule(&state, &params).await;
+        assert_ne!(result["status"], "OK");
+        let batch = storage.get_batch(batch_id).await.unwrap().unwrap();
+        assert_eq!(batch.status, "DONE");
+        assert_eq!(
+            storage.get_command_counts(batch_id).await.unwrap(),
+            vec![("DONE".to_string(), 1)]
+        );
+
+        params.rerun = Some("1".to_string());
+        let result = action_create_schedule(&state, &params).await;
+        assert_eq!(result["status"], "OK");
+        let batch = storage.get_batch(batch_id).await.unwrap().unwrap();
+        assert_eq!(batch.status, "INIT");
+        assert_eq!(batch.ts_not_before, "20300101000000");
+        assert_eq!(
+            storage.get_command_counts(batch_id).await.unwrap(),
+            vec![("INIT".to_string(), 1)]
+        );
+    }
+
+    #[tokio::test]
+    async fn stopped_batches_cannot_be_scheduled() {
+        let qs = qs();
+        let (_, batch_id) = user_with_batch(&qs, "Alice").await;
+        let storage = qs.storage().clone();
+        let state = AppState {
+            config: Arc::new(qs),
+        };
+        let params = ApiParams {
+            batch: Some(batch_id.to_string()),
+            not_before: Some("20300101000000".to_string()),
+            rerun: Some("1".to_string()),
+            ..Default::default()
+        };
+
+        for status in ["STOP", "PAUSE", "ERROR", "BLOCKED"] {
+            storage
+                .set_batch_status(batch_id, status, None)
+                .await
+                .unwrap();
+            let result = action_create_schedule(&state, &params).await;
+            assert_ne!(result["status"], "OK", "{}", status);
+            let batch = storage.get_batch(batch_id).await.unwrap().unwrap();
+            assert_eq!(batch.status, status);
+            assert_eq!(batch.ts_not_before, "");
+        }
+    }
+
+    fn token(user_id: i64, user_name: &str, scopes: &str) -> Caller {
+        Caller::Token {
+            token: ApiTokenRow {
+                user_id,
+                scopes: scopes.to_string(),
+                ..Default::default()
+            },
+            user_name: user_name.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn tokens_need_the_scope() {
+        let qs = qs();
+        let (alice, batch_id) = user_with_batch(&qs, "Alice").await;
+        let batch = Some(batch_id);
+        let read = token(alice, "Alice", "read");
+        let stop = token(alice, "Alice", "read,stop");
+        assert_eq!(
+            status(authorize(&qs, "get_batch_info", batch, false, Some(&read)).await),
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(authorize(&qs, "stop_batch", batch, false, Some(&read)).await),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(authorize(&qs, "stop_batch", batch, false, Some(&stop)).await),
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(authorize(&qs, "start_batch", batch, false, Some(&stop)).await),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(authorize(&qs, "import", None, true, Some(&stop)).await),
+            StatusCode::FORBIDDEN
+        );
+        // A token is limited to the batches of its owner, like a session
+        let (bob, _) = user_with_batch(&qs, "Bob").await;
+        let other = token(bob, "Bob", "read,submit,stop");
+        assert_eq!(
+            status(authorize(&qs, "stop_batch", batch, false, Some(&other)).await),
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[tokio::test]
+    async fn tokens_cannot_manage_tokens() {
+        let qs = qs();
+        let (alice, _) = user_with_batch(&qs, "Alice").await;
+        let all_scopes = token(alice, "Alice", "read,submit,stop");
+        let session = session(alice, "Alice");
+        for action in SESSION_ACTIONS {
+            assert_eq!(
+                status(authorize(&qs, action, None, false, Some(&all_scopes)).await),
+                StatusCode::FORBIDDEN
+            );
+            assert_eq!(
+                status(authorize(&qs, action, None, false, None).await),
+                StatusCode::UNAUTHORIZED
+            );
+            assert_eq!(
+                status(authorize(&qs, action, None, false, Some(&session)).await),
+                StatusCode::OK
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn revoked_tokens_are_invalid() {
+        let qs = qs();
+        let (alice, _) = user_with_batch(&qs, "Alice").await;
+        let token_id = qs
+            .storage()
+            .create_api_token(alice, "test", "read", &hash_token("secret"), "{}")
+            .await
+            .unwrap();
+        let caller = |result: Result<Option<Caller>, Denied>| match result {
+            Ok(Some(caller)) => Ok(caller.user_id()),
+            Ok(None) => Ok(0),
+            Err(denied) => Err(denied.status),
+        };
+
+        assert_eq!(
+            caller(authenticate(&qs, Some("secret"), Some("Alice"), None).await),
+            Ok(alice)
+        );
+        assert_eq!(
+            caller(authenticate(&qs, Some("secret"), Some("Bob"), None).await),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            caller(authenticate(&qs, Some("guessed"), None, None).await),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+
+        assert!(qs
+            .storage()
+            .revoke_api_token(token_id, alice)
+            .await
+            .unwrap());
+        assert_eq!(
+            caller(authenticate(&qs, Some("secret"), None, None).await),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+    }
+
+    #[test]
+    fn batch_param_is_unambiguous() {
+        let params = |batch: Option<&str>, batch_id: Option<&str>| ApiParams {
+            batch: batch.map(str::to_string),
+            batch_id: batch_id.map(str::to_string),
+            ..Default::default()
+        };
+        let batch = |params: ApiParams| batch_param(&params).map_err(|denied| denied.status);
+        assert_eq!(batch(params(Some("1"), None)), Ok(Some("1".to_string())));
+        assert_eq!(batch(params(None, Some("2"))), Ok(Some("2".to_string())));
+        assert_eq!(
+            batch(params(Some("2"), Some("2"))),
+            Ok(Some("2".to_string()))
+        );
+        assert_eq!(batch(params(None, None)), Ok(None));
+        assert_eq!(
+            batch(params(Some("1"), Some("2"))),
+            Err(StatusCode::BAD_REQUEST)
+        );
+    }
+
+    #[tokio::test]
+    async fn reset_errors_cannot_name_another_batch() {
+        let qs = qs();
+        let (_, own) = user_with_batch(&qs, "Alice").await;
+        let (_, victim) = user_with_batch(&qs, "Bob").await;
+        let state = AppState {
+            config: Arc::new(qs),
+        };
+        let params = ApiParams {
+            action: Some("reset_errors".to_string()),
+            batch: Some(own.to_string()),
+            batch_id: Some(victim.to_string()),
+            ..Default::default()
+        };
+        let response = handle_api(state.clone(), params, None, false).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let params = ApiParams {
+            action: Some("reset_errors".to_string()),
+            batch: Some("abc".to_string()),
+            batch_id: Some(victim.to_string()),
+            ..Default::default()
+        };
+        let response = handle_api(state, params, None, false).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn get_batch_rejects_malformed_ids() {
+        let state = AppState {
+            config: Arc::new(qs()),
+        };
+        let too_long = "a".repeat(65);
+        for id in ["../x", "a/b", "abc.json", "a b", "", too_long.as_str()] {
+            let params = ApiParams {
+                id: Some(id.to_string()),
+                ..Default::default()
+            };
+            let result = action_get_batch(&state, &params).await;
+            assert!(
+                result["status"].as_str().unwrap().starts_with("ERROR"),
+                "for {:?}",
+                id
+            );
+            assert!(result.get("data").is_none(), "for {:?}", id);
+        }
+    }
+
+    #[tokio::test]
+    async fn temporary_import_round_trips_through_get_batch() {
+        let state = AppState {
+            config: Arc::new(qs()),
+        };
+        let params = ApiParams {
+            data: Some("Q1\tLen\t\"one\"\nQ2\tP31\tQ5".to_string()),
+            temporary: Some("1".to_string()),
+            ..Default::default()
+        };
+        let imported = action_import(&state, &params, None).await;
+        assert_eq!(imported["status"], "OK");
+        let id = imported["data"].as_str().unwrap();
+        assert!(is_temporary_batch_id(id));
+
+        let params = ApiParams {
+            id: Some(id.to_string()),
+            ..Default::default()
+        };
+        let loaded = action_get_batch(&state, &params).await;
+        assert_eq!(loaded["status"], "OK");
+        assert_eq!(loaded["id"], id);
+        let commands = loaded["data"]["commands"].as_array().unwrap();
+        assert_eq!(commands.len(), 2);
+        assert_eq!(commands[0]["what"], "label");
+        assert_eq!(commands[0]["value"], "one");
+        assert_eq!(commands[1]["item"], "Q2");
+        assert_eq!(commands[1]["property"], "P31");
+    }
+
+    #[tokio::test]
+    async fn temporary_import_needs_a_login_with_oauth() {
+        let mut qs = qs();
+        qs.set_oauth_for_tests(json!({"consumer_key": "key", "consumer_secret": "secret"}));
+        let state = AppState {
+            config: Arc::new(qs),
+        };
+        let params = ApiParams {
+            data: Some("Q1\tLen\t\"one\"".to_string()),
+            temporary: Some("1".to_string()),
+            ..Default::default()
+        };
+        let imported = action_import(&state, &params, None).await;
+        assert_eq!(imported["status"], "ERROR: Not logged in");
+
+        let imported = action_import(&state, &params, Some(oauth_token(1))).await;
+        assert_eq!(imported["status"], "OK");
+        assert!(is_temporary_batch_id(imported["data"].as_str().unwrap()));
+    }
+
+    /// A server with OAuth whose single commands edit the fake wiki
+    fn single_command_state(wiki: &FakeWiki) -> AppState {
+        let mut qs = qs();
+        qs.set_param_for_tests(
+            "config",
+            json!({"site": "wikidata", "sites": {"wikidata": {"api": wiki.api_url()}}}),
+        );
+        qs.set_oauth_for_tests(json!({"consumer_key": "key", "consumer_secret": "secret"}));
+        AppState {
+            config: Arc::new(qs),
+        }
+    }
+
+    /// A token carrying the OAuth 1.0a login it was created with
+    fn oauth_token(user_id: i64) -> Caller {
+        let oauth = json!({
+            "g_consumer_key": "key",
+            "g_consumer_secret": "secret",
+            "g_token_key": "token",
+            "g_token_secret": "token_secret",
+        });
+        Caller::Token {
+            token: ApiTokenRow {
+                user_id,
+                scopes: "submit".to_string(),
+                serialized_json: oauth.to_string(),
+                ..Default::default()
+            },
+            user_name: "Alice".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn run_single_command_keeps_last_item() {
+        let wiki = FakeWiki::start().await;
+        let state = single_command_state(&wiki);
+        let params = |command: Value, last_item: &str| ApiParams {
+            command: Some(command.to_string()),
+            last_item: Some(last_item.to_string()),
+            ..Default::default()
+        };
+        let create = json!({"action": "create", "type": "item"});
+
+        let denied = action_run_single_command(&state, &params(create.clone(), ""), None).await;
+        assert_eq!(denied["status"], "ERROR: Not logged in");
+        assert!(wiki.edits().is_empty());
+
+        let created =
+            action_run_single_command(&state, &params(create, ""), Some(oauth_token(1))).await;
+        assert_eq!(created["status"], "OK");
+        assert_eq!(created["command"]["status"], "done");
+        let new_id = created["last_item"].as_str().unwrap().to_string();
+        assert!(new_id.starts_with('Q'));
+
+        let label = json!({"action": "add", "what": "label", "item": "LAST",
+            "language": "en", "value": "new"});
+        let labeled =
+            action_run_single_command(&state, &params(label, &new_id), Some(oauth_token(1))).await;
+        assert_eq!(labeled["status"], "OK");
+        assert_eq!(labeled["last_item"], new_id.as_str());
+        let edits = wiki.edits();
+        assert_eq!(edits.len(), 2);
+        assert_eq!(edits[1]["action"], "wbsetlabel");
+        assert_eq!(edits[1]["id"], new_id);
+        let entity = wiki.entity(&new_id).unwrap();
+        assert_eq!(entity["labels"]["en"]["value"], "new");
+
+        let missing = json!({"action": "add", "what": "label", "item": "Q999",
+            "language": "en", "value": "x"});
+        let failed =
+            action_run_single_command(&state, &params(missing, &new_id), Some(oauth_token(1)))
+                .await;
+        assert!(failed["status"].as_str().unwrap().starts_with("ERROR: "));
+        assert_eq!(failed["command"]["status"], "error");
+        assert_eq!(failed["command"]["error_code"], "entity-missing");
+        assert_eq!(failed["last_item"], new_id.as_str());
+        assert_eq!(wiki.edits().len(), 2);
+    }
+
+    /// A logged-in web session of the user
+    fn web_session(user_id: i64, user_name: &str) -> Session {
+        Session {
+            user_id,
+            userinfo: json!({ "name": user_name }),
+            credentials: Some(Credentials::V2 {
+                access_token: "access".to_string(),
+                refresh_token: String::new(),
+                expires: String::new(),
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn sessions_change_nothing_with_get() {
+        let qs = qs();
+        let (alice, batch_id) = user_with_batch(&qs, "Alice").await;
+        let storage = qs.storage().clone();
+        let state = AppState {
+            config: Arc::new(qs),
+        };
+        let params = || ApiParams {
+            action: Some("stop_batch".to_string()),
+            batch: Some(batch_id.to_string()),
+            ..Default::default()
+        };
+
+        let session = Some(web_session(alice, "Alice"));
+        let response = handle_api(state.clone(), params(), session, true).await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        let batch = storage.get_batch(batch_id).await.unwrap().unwrap();
+        assert_eq!(batch.status, "INIT");
+
+        let session = Some(web_session(alice, "Alice"));
+        let params = ApiParams {
+            action: Some("get_token".to_string()),
+            force_generate: Some("1".to_string()),
+            ..Default::default()
+        };
+        let response = handle_api(state.clone(), params, session, true).await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert!(storage.get_api_tokens(alice).await.unwrap().is_empty());
+
+        // Reading is fine with GET
+        let session = Some(web_session(alice, "Alice"));
+        let params = ApiParams {
+            action: Some("get_batch_info".to_string()),
+            batch: Some(batch_id.to_string()),
+            ..Default::default()
+        };
+        let response = handle_api(state.clone(), params, session, true).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let session = Some(web_session(alice, "Alice"));
+        let response = handle_api(state, params(), session, false).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let batch = storage.get_batch(batch_id).await.unwrap().unwrap();
+        assert_eq!(batch.status, "STOP");
+    }
+}
@@ -0,0 +1,391 @@
+use crate::error::QsResult;
+use async_trait::async_trait;
+use chrono::prelude::{Datelike, Utc};
+use serde_json::Value;
+use std::fmt::Debug;
+use std::time::Duration;
+
+/// One row of the `command` table
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CommandRow {
+    pub id: i64,
+    pub batch_id: i64,
+    /// Position in the batch
+    pub num: i64,
+    pub json: String,
+    pub status: String,
+    pub message: String,
+    pub ts_change: String,
+    /// Why the command failed, see `QsError::code`; empty unless it did
+    pub error_code: String,
+}
+
+/// Command statuses as stored in the `command` table
+pub const COMMAND_STATUSES: &[&str] = &["INIT", "RUN", "DONE", "ERROR", "BLOCKED", "STOP"];
+
+/// The batches a change feed follows: one batch, or all batches of a user
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChangeScope {
+    Batch(i64),
+    /// By user name
+    User(String),
+}
+
+/// One row of the `batch` table
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BatchRow {
+    pub id: i64,
+    pub name: String,
+    pub user: i64,
+    pub site: String,
+    pub status: String,
+    pub message: String,
+    pub last_item: String,
+    pub ts_last_change: String,
+    pub priority: i64,
+    /// Not started before this `timestamp()`; empty for any time
+    pub ts_not_before: String,
+    /// Recurrence rule, see `qs_scheduler::parse_recurrence`; empty for none
+    pub recurrence: String,
+}
+
+impl BatchRow {
+    /// The batch as the frontend expects it, with the user ID resolved to a name
+    pub fn to_json(&self, user_name: &str) -> Value {
+        json!({
+            "id": self.id,
+            "name": self.name,
+            "user": user_name,
+            "site": self.site,
+            "status": self.status,
+            "message": self.message,
+            "last_item": self.last_item,
+            "ts_last_change": self.ts_last_change,
+            "priority": self.priority,
+            "ts_not_before": self.ts_not_before,
+            "recurrence": self.recurrence,
+        })
+    }
+}
+
+/// One row of the `api_token` table; the token itself is only stored hashed
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ApiTokenRow {
+    pub id: i64,
+    pub user_id: i64,
+    pub name: String,
+    /// Comma-separated, see `qs_server::TOKEN_SCOPES`
+    pub scopes: String,
+    /// The `batch_oauth` entry batches submitted with the token get
+    pub serialized_json: String,
+    pub ts_created: String,
+    pub ts_last_used: String,
+}
+
+impl ApiTokenRow {
+    pub fn scopes(&self) -> impl Iterator<Item = &str> {
+        self.scopes.split(',').filter(|scope| !scope.is_empty())
+    }
+
+    /// The token for its owner, without the OAuth credentials
+    pub fn to_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "name": self.name,
+            "scopes": self.scopes().collect::<Vec<_>>(),
+            "ts_created": self.ts_created,
+            "ts_last_used": self.ts_last_used,
+        })
+    }
+}
+
+/// An INIT/RUN batch as seen by the scheduler
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OpenBatch {
+    pub id: i64,
+    pub user: i64,
+    pub site: String,
+    pub priority: i64,
+    /// At most `small_batch_commands` INIT commands left
+    pub small: bool,
+}
+
+/// Commands are inserted this many rows per statement when a batch is created.
+/// Four placeholders each stay within SQLite's default limit of 999.
+pub const COMMAND_INSERT_ROWS: usize = 200;
+
+/// Current time in the `YYYYMMDDHHMMSS` format used by all `ts_*` columns
+pub fn timestamp() -> String {
+    Utc::now().format("%Y%m%d%H%M%S").to_string()
+}
+
+/// The latest time the `ts_*` columns can hold
+const MAX_TIMESTAMP: &str = "99991231235959";
+
+/// `timestamp()` of the moment `duration` from now, or `MAX_TIMESTAMP` if
+/// that is too far ahead
+pub fn timestamp_after(duration: Duration) -> String {
+    chrono::Duration::from_std(duration)
+        .ok()
+        .and_then(|duration| Utc::now().checked_add_signed(duration))
+        .filter(|time| time.year() <= 9999)
+        .map(|time| time.format("%Y%m%d%H%M%S").to_string())
+        .unwrap_or_else(|| MAX_TIMESTAMP.to_string())
+}
+
+/// Everything QuickStatements keeps in a database: batches, their commands,
+/// the LAST state of a running batch, users and per-batch OAuth credentials.
+///
+/// Errors are always propagated, so callers can tell "not found" (`Ok(None)`)
+/// from "DB down" (`Err`).
+#[async_trait]
+pub trait QsStorage: Debug + Send + Sync {
+    /// Lightweight check: can we reach the database at all?
+    async fn ping(&self) -> bool;
+
+    /// Applies all schema migrations not yet recorded in the database,
+    /// returning the versions applied by this call
+    async fn migrate(&self) -> QsResult<Vec<u32>>;
+
+    // ---- Batches ----
+
+    async fn get_batch(&self, batch_id: i64) -> QsResult<Option<BatchRow>>;
+
+    /// Newest batches first, optionally only those of the user with that name
+    async fn get_batches(
+        &self,
+        user_name: Option<&str>,
+        limit: i64,
+        offset: i64,
+    ) -> QsResult<Vec<BatchRow>>;
+
+    /// All INIT/RUN batches that may start at `now`, least recently changed first.
+    /// Batches with at most `small_batch_commands` INIT commands left are marked `small`.
+    async fn get_open_batches(
+        &self,
+        small_batch_commands: i64,
+        now: &str,
+    ) -> QsResult<Vec<OpenBatch>>;
+
+    /// Creates an INIT batch with the given (JSON-encoded) commands and, if
+    /// given, the serialized OAuth parameters to run it with, returning its ID.
+    /// All or nothing: a failure leaves no partial batch behind.
+    async fn create_batch(
+        &self,
+        name: &str,
+        user_id: i64,
+        site: &str,
+        commands: &[String],
+        oauth_json: Option<&str>,
+    ) -> QsResult<i64>;
+
+    /// Sets the batch status; `None` leaves the message untouched
+    async fn set_batch_status(
+        &self,
+        batch_id: i64,
+        status: &str,
+        message: Option<&str>,
+    ) -> QsResult<()>;
+
+    /// Sets an INIT/RUN batch to RUN, and puts its RUN/BLOCKED commands back to INIT
+    async fn restart_batch(&self, batch_id: i64) -> QsResult<()>;
+
+    /// Sets a batch to INIT to run again from command `num`: commands from there
+    /// on that are not DONE go back to INIT, INIT commands before it are skipped
+    /// as STOP. Returns how many commands will run. Only for PAUSE, STOP and
+    /// DONE batches (else `BatchNotIdle`), as a bot may be running the others.
+    async fn restart_batch_from(&self, batch_id: i64, num: i64) -> QsResult<u64>;
+
+    /// Puts all RUN and ERROR batches back to INIT, except those with a lease
+    /// that has not expired at `now`
+    async fn reset_stale_batches(&self, now: &str) -> QsResult<()>;
+
+    /// Sets DONE batches (with IDs above `min_batch_id`) that still have INIT commands back to INIT
+    async fn reinitialize_open_batches(&self, min_batch_id: i64) -> QsResult<()>;
+
+    async fn set_last_item(&self, batch_id: i64, last_item: &str) -> QsResult<()>;
+
+    async fn set_batch_priority(&self, batch_id: i64, priority: i64) -> QsResult<()>;
+
+    /// Sets the start time and recurrence rule (both may be empty)
+    async fn set_batch_schedule(
+        &self,
+        batch_id: i64,
+        not_before: &str,
+        recurrence: &str,
+    ) -> QsResult<()>;
+
+    /// Batches that recur or wait for a start time after `now`, newest first,
+    /// optionally only those of the user with that name
+    async fn get_scheduled_batches(
+        &self,
+        user_name: Option<&str>,
+        now: &str,
+    ) -> QsResult<Vec<BatchRow>>;
+
+    /// Puts a finished batch and all its commands back to INIT, to start again
+    /// at `not_before`
+    async fn reschedule_batch(&self, batch_id: i64, not_before: &str) -> QsResult<()>;
+
+    // ---- Leases ----
+
+    /// Takes or renews the lease on an INIT/RUN batch for `owner` until `expires`.
+    /// Fails (`Ok(false)`) while another owner holds a lease that has not expired at `now`.
+    async fn acquire_lease(
+        &self,
+        batch_id: i64,
+        owner: &str,
+        now: &str,
+        expires: &str,
+    ) -> QsResult<bool>;
+
+    /// Gives up the lease, if `owner` still holds it
+    async fn release_lease(&self, batch_id: i64, owner: &str) -> QsResult<()>;
+
+    /// (batch_id, user_id, owner) of all leases that have not expired at `now`
+    async fn get_live_leases(&self, now: &str) -> QsResult<Vec<(i64, i64, String)>>;
+
+    // ---- Commands ----
+
+    async fn get_command(&self, command_id: i64) -> QsResult<Option<CommandRow>>;
+
+    /// The INIT command with the lowest `num` in the batch
+    async fn get_next_command(&self, batch_id: i64) -> QsResult<Option<CommandRow>>;
+
+    /// Commands of a batch ordered by `num`; empty `statuses` means all,
+    /// `error_code` only those failed with that code, `limit <= 0` means no limit
+    async fn get_commands(
+        &self,
+        batch_id: i64,
+        statuses: &[&str],
+        error_code: Option<&str>,
+        start: i64,
+        limit: i64,
+    ) -> QsResult<Vec<CommandRow>>;
+
+    /// (status, count) for all command statuses present in the batch
+    async fn get_command_counts(&self, batch_id: i64) -> QsResult<Vec<(String, i64)>>;
+
+    /// Sets status and message of a command; `error_code` is empty unless it failed
+    async fn set_command_status(
+        &self,
+        command_id: i64,
+        status: &str,
+        message: &str,
+        error_code: &str,
+        json: &str,
+    ) -> QsResult<()>;
+
+    /// Puts the ERROR commands of a batch back to INIT, returning how many. With
+    /// `error_code`, only those that failed with it.
+    async fn reset_error_commands(&self, batch_id: i64, error_code: Option<&str>) -> QsResult<u64>;
+
+    // ---- Change feed ----
+
+    /// Batches in `scope` changed at or after the `timestamp()` `since`,
+    /// oldest change first
+    async fn get_changed_batches(
+        &self,
+        scope: &ChangeScope,
+        since: &str,
+    ) -> QsResult<Vec<BatchRow>>;
+
+    /// Up to `limit` commands in `scope` whose (ts_change, id) comes after
+    /// (`since`, `after_id`), oldest change first. With `after_id` 0, these
+    /// are the commands changed at or after `since`.
+    async fn get_changed_commands(
+        &self,
+        scope: &ChangeScope,
+        since: &str,
+        after_id: i64,
+        limit: i64,
+    ) -> QsResult<Vec<CommandRow>>;
+
+    // ---- Users and OAuth ----
+
+    async fn get_user_name(&self, user_id: i64) -> QsResult<Option<String>>;
+
+    /// The serialized OAuth parameters stored for a batch
+    async fn get_oauth_for_batch(&self, batch_id: i64) -> QsResult<Option<String>>;
+
+    async fn set_oauth_for_batch(&self, batch_id: i64, serialized_json: &str) -> QsResult<()>;
+
+    /// Replaces every copy of a batch OAuth entry in `batch_oauth` and
+    /// `api_token`, e.g. of one login after its tokens were refreshed
+    async fn replace_oauth_credentials(&self, old_json: &str, new_json: &str) -> QsResult<()>;
+
+    /// The ID of the user with that wiki user name, adding the user if needed
+    async fn get_or_create_user(&self, name: &str) -> QsResult<i64>;
+
+    // ---- Web sessions ----
+
+    /// The data of a session that has not expired at `now`
+    async fn get_session(&self, session_id: &str, now: &str) -> QsResult<Option<String>>;
+
+    /// Creates or updates a session, dropping expired ones
+    async fn set_session(
+        &self,
+        session_id: &str,
+        user_id: i64,
+        data: &str,
+        expires: &str,
+    ) -> QsResult<()>;
+
+    async fn delete_session(&self, session_id: &str) -> QsResult<()>;
+
+    // ---- Personal API tokens ----
+
+    async fn create_api_token(
+        &self,
+        user_id: i64,
+        name: &str,
+        scopes: &str,
+        token_hash: &str,
+        serialized_json: &str,
+    ) -> QsResult<i64>;
+
+    /// The token with that hash, unless it was revoked
+    async fn get_api_token(&self, token_hash: &str) -> QsResult<Option<ApiTokenRow>>;
+
+    /// The tokens of a user that were not revoked
+    async fn get_api_tokens(&self, user_id: i64) -> QsResult<Vec<ApiTokenRow>>;
+
+    /// Revokes a token of that user, returning whether there was one
+    async fn revoke_api_token(&self, token_id: i64, user_id: i64) -> QsResult<bool>;
+
+    async fn set_api_token_last_used(&self, token_id: i64, now: &str) -> QsResult<()>;
+
+    // ---- Temporary batches ----
+
+    /// Stores parsed commands under a random ID, dropping expired ones
+    async fn create_temporary_batch(
+        &self,
+        id: &str,
+        user_id: i64,
+        data: &str,
+        expires: &str,
+    ) -> QsResult<()>;
+
+    /// The data of a temporary batch that has not expired at `now`
+    async fn get_temporary_batch(&self, id: &str, now: &str) -> QsResult<Option<String>>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamp_after_is_capped() {
+        let soon = timestamp_after(Duration::from_secs(60));
+        assert_eq!(soon.len(), 14);
+        assert!(soon > timestamp());
+        assert_eq!(
+            timestamp_after(Duration::from_secs(u64::MAX)),
+            MAX_TIMESTAMP
+        );
+        assert_eq!(
+            timestamp_after(Duration::from_secs(10_000 * 365 * 86_400)),
+            MAX_TIMESTAMP
+        );
+    }
+}
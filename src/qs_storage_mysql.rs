@@ -0,0 +1,865 @@
+use crate::error::{QsError, QsResult};
+use crate::qs_migrations::{pending, statements, MYSQL_MIGRATIONS};
+use crate::qs_storage::{
+    timestamp, ApiTokenRow, BatchRow, ChangeScope, CommandRow, OpenBatch, QsStorage,
+    COMMAND_INSERT_ROWS,
+};
+use async_trait::async_trait;
+use mysql_async as my;
+use mysql_async::from_row;
+use mysql_async::prelude::*;
+use serde_json::Value;
+
+/// Users and OAuth credentials live in a separate schema on ToolsDB;
+/// this is its name unless `mysql.auth_schema` is set
+const DEFAULT_AUTH_DB: &str = "s53220__quickstatements_auth";
+
+/// Row layout of the `batch` table as selected by `BATCH_COLUMNS`
+type BatchTuple = (
+    i64,
+    String,
+    i64,
+    String,
+    String,
+    String,
+    String,
+    String,
+    i64,
+    String,
+    String,
+);
+
+const BATCH_COLUMNS: &str = "id,`name`,`user`,site,`status`,message,last_item,ts_last_change,`priority`,ts_not_before,recurrence";
+
+/// Row layout of the `command` table as selected by `COMMAND_COLUMNS`
+type CommandTuple = (i64, i64, i64, String, String, String, String, String);
+
+const COMMAND_COLUMNS: &str = "id,batch_id,num,json,`status`,message,ts_change,error_code";
+
+/// Row layout of the `api_token` table as selected by `API_TOKEN_COLUMNS`
+type ApiTokenTuple = (i64, i64, String, String, String, String, String);
+
+const API_TOKEN_COLUMNS: &str = "id,user_id,`name`,scopes,serialized_json,ts_created,ts_last_used";
+
+/// The production backend: ToolsDB (MariaDB; see `MYSQL_MIGRATIONS`).
+#[derive(Debug, Clone)]
+pub struct MysqlStorage {
+    pool: my::Pool,
+    auth_db: String,
+}
+
+impl MysqlStorage {
+    /// Creates the pool from the `mysql` object in config_rs.json.
+    /// Connections are only made on first use.
+    pub fn new_from_params(params: &Value) -> Self {
+        if !params["mysql"].is_object() {
+            panic!("MysqlStorage::new_from_params: No mysql info in params");
+        }
+        let port = params["mysql"]["port"].as_u64().unwrap_or(3306) as u16;
+        let host = params["mysql"]["host"].as_str().expect("No host");
+        let schema = params["mysql"]["schema"].as_str().expect("No schema");
+        let user = params["mysql"]["user"].as_str().expect("No user");
+        let pass = params["mysql"]["pass"].as_str().expect("No pass");
+        let auth_db = params["mysql"]["auth_schema"]
+            .as_str()
+            .unwrap_or(DEFAULT_AUTH_DB)
+            .to_string();
+        let opts = my::OptsBuilder::default()
+            .ip_or_hostname(host)
+            .db_name(Some(schema))
+            .user(Some(user))
+            .pass(Some(pass))
+            .tcp_port(port);
+
+        Self {
+            pool: my::Pool::new(opts),
+            auth_db,
+        }
+    }
+
+    fn api_token_from_tuple(t: ApiTokenTuple) -> ApiTokenRow {
+        ApiTokenRow {
+            id: t.0,
+            user_id: t.1,
+            name: t.2,
+            scopes: t.3,
+            serialized_json: t.4,
+            ts_created: t.5,
+            ts_last_used: t.6,
+        }
+    }
+
+    fn command_from_tuple(t: CommandTuple) -> CommandRow {
+        CommandRow {
+            id: t.0,
+            batch_id: t.1,
+            num: t.2,
+            json: t.3,
+            status: t.4,
+            message: t.5,
+            ts_change: t.6,
+            error_code: t.7,
+        }
+    }
+
+    fn batch_from_tuple(t: BatchTuple) -> BatchRow {
+        BatchRow {
+            id: t.0,
+            name: t.1,
+            user: t.2,
+            site: t.3,
+            status: t.4,
+            message: t.5,
+            last_item: t.6,
+            ts_last_change: t.7,
+            priority: t.8,
+            ts_not_before: t.9,
+            recurrence: t.10,
+        }
+    }
+
+    /// SQL condition for `column` holding a batch ID in `scope`, with its
+    /// parameter
+    fn scope_condition(&self, scope: &ChangeScope, column: &str) -> (String, my::Value) {
+        match scope {
+            ChangeScope::Batch(batch_id) => (format!("{}=?", column), (*batch_id).into()),
+            ChangeScope::User(user_name) => (
+                format!(
+                    "{} IN (SELECT id FROM batch WHERE `user` IN (SELECT id FROM {}.user WHERE name=?))",
+                    column, self.auth_db
+                ),
+                user_name.as_str().into(),
+            ),
+        }
+    }
+}
+
+#[async_trait]
+impl QsStorage for MysqlStorage {
+    async fn ping(&self) -> bool {
+        match self.pool.get_conn().await {
+            Ok(mut conn) => conn.query_drop("SELECT 1").await.is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    async fn migrate(&self) -> QsResult<Vec<u32>> {
+        let mut conn = self.pool.get_conn().await?;
+        conn.query_drop("CREATE TABLE IF NOT EXISTS `schema_migrations` (`version` INT UNSIGNED NOT NULL PRIMARY KEY, `name` VARCHAR(255) NOT NULL, `ts_applied` VARCHAR(14) NOT NULL)").await?;
+        let applied: Vec<u32> = conn.query("SELECT version FROM schema_migrations").await?;
+        let mut ret = vec![];
+        for migration in pending(MYSQL_MIGRATIONS, &applied) {
+            // DDL commits implicitly in MySQL, so there is no transaction to wrap
+            // this in; migrations are written to be safely re-runnable instead.
+            let sql = migration.sql.replace("{auth_db}", &self.auth_db);
+            for statement in statements(&sql) {
+                conn.query_drop(statement).await?;
+            }
+            let (version, name, ts) = (migration.version, migration.name, timestamp());
+            conn.exec_drop(
+                "INSERT INTO schema_migrations (version,name,ts_applied) VALUES (:version,:name,:ts)",
+                params! {version, name, ts},
+            )
+            .await?;
+            ret.push(migration.version);
+        }
+        Ok(ret)
+    }
+
+    async fn get_batch(&self, batch_id: i64) -> QsResult<Option<BatchRow>> {
+        let sql = format!("SELECT {} FROM batch WHERE id=:batch_id", BATCH_COLUMNS);
+        let rows = self
+            .pool
+            .get_conn()
+            .await?
+            .exec_iter(sql, params! {batch_id})
+            .await?
+            .map_and_drop(from_row::<BatchTuple>)
+            .await?;
+        Ok(rows.into_iter().next().map(Self::batch_from_tuple))
+    }
+
+    async fn get_batches(
+        &self,
+        user_name: Option<&str>,
+        limit: i64,
+        offset: i64,
+    ) -> QsResult<Vec<BatchRow>> {
+        let mut conn = self.pool.get_conn().await?;
+        let rows: Vec<BatchTuple> = match user_name {
+            None => {
+                let sql = format!(
+                    "SELECT {} FROM batch ORDER BY id DESC LIMIT :limit OFFSET :offset",
+                    BATCH_COLUMNS
+                );
+                conn.exec(sql, params! {limit, offset}).await?
+            }
+            Some(user_name) => {
+                let sql = format!(
+                    "SELECT {} FROM batch WHERE `user` IN (SELECT id FROM {}.user WHERE name=:user_name) ORDER BY id DESC LIMIT :limit OFFSET :offset",
+                    BATCH_COLUMNS, self.auth_db
+                );
+                conn.exec(sql, params! {user_name, limit, offset}).await?
+            }
+        };
+        Ok(rows.into_iter().map(Self::batch_from_tuple).collect())
+    }
+
+    async fn get_open_batches(
+        &self,
+        small_batch_commands: i64,
+        now: &str,
+    ) -> QsResult<Vec<OpenBatch>> {
+        // Looking up a single row past the threshold (via the batch_status_num
+        // index) instead of counting, as batches can have millions of commands
+        let sql = "SELECT id,`user`,site,`priority`,(SELECT num FROM command WHERE batch_id=batch.id AND `status`='INIT' ORDER BY num LIMIT 1 OFFSET :small_batch_commands) IS NULL FROM batch WHERE `status` IN ('INIT','RUN') AND ts_not_before<=:now ORDER BY `ts_last_change`";
+        let rows = self
+            .pool
+            .get_conn()
+            .await?
+            .exec_iter(sql, params! {small_batch_commands, now})
+            .await?
+            .map_and_drop(from_row::<(i64, i64, String, i64, bool)>)
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(id, user, site, priority, small)| OpenBatch {
+                id,
+                user,
+                site,
+                priority,
+                small,
+            })
+            .collect())
+    }
+
+    async fn create_batch(
+        &self,
+        name: &str,
+        user_id: i64,
+        site: &str,
+        commands: &[String],
+        oauth_json: Option<&str>,
+    ) -> QsResult<i64> {
+        let mut conn = self.pool.get_conn().await?;
+        // Dropped without commit on any error, which rolls back
+        let mut tx = conn.start_transaction(my::TxOpts::default()).await?;
+        let ts = timestamp();
+        tx.exec_drop(
+            "INSERT INTO batch (`name`, `user`, site, `status`, ts_created, ts_last_change) VALUES (:name, :user_id, :site, 'INIT', :ts, :ts)",
+            params! {name, user_id, site, "ts" => &ts},
+        )
+        .await?;
+        let batch_id: i64 = tx
+            .exec_first("SELECT LAST_INSERT_ID()", ())
+            .await?
+            .ok_or("No LAST_INSERT_ID() after creating batch")?;
+
+        for (chunk_num, chunk) in commands.chunks(COMMAND_INSERT_ROWS).enumerate() {
+            let sql = format!(
+                "INSERT INTO command (batch_id, num, json, `status`, ts_change) VALUES {}",
+                vec!["(?, ?, ?, 'INIT', ?)"; chunk.len()].join(", ")
+            );
+            let first_num = chunk_num * COMMAND_INSERT_ROWS;
+            let values: Vec<my::Value> = chunk
+                .iter()
+                .enumerate()
+                .flat_map(|(i, json)| {
+                    [
+                        batch_id.into(),
+                        ((first_num + i) as i64).into(),
+                        json.as_str().into(),
+                        ts.as_str().into(),
+                    ]
+                })
+                .collect();
+            tx.exec_drop(sql, values).await?;
+        }
+        if let Some(serialized_json) = oauth_json {
+            let sql = format!(
+                r#"INSERT INTO {}.batch_oauth (batch_id,serialized_json) VALUES (:batch_id,:serialized_json)"#,
+                self.auth_db
+            );
+            tx.exec_drop(sql, params! {batch_id, serialized_json})
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(batch_id)
+    }
+
+    async fn set_batch_status(
+        &self,
+        batch_id: i64,
+        status: &str,
+        message: Option<&str>,
+    ) -> QsResult<()> {
+        let ts = timestamp();
+        let mut conn = self.pool.get_conn().await?;
+        match message {
+            Some(message) => {
+                let sql = r#"UPDATE `batch` SET `status`=:status,`message`=:message,`ts_last_change`=:ts WHERE id=:batch_id"#;
+                conn.exec_drop(sql, params! {status,message,ts,batch_id})
+                    .await?
+            }
+            None => {
+                let sql = r#"UPDATE `batch` SET `status`=:status,`ts_last_change`=:ts WHERE id=:batch_id"#;
+                conn.exec_drop(sql, params! {status,ts,batch_id}).await?
+            }
+        }
+        Ok(())
+    }
+
+    async fn restart_batch(&self, batch_id: i64) -> QsResult<()> {
+        let mut conn = self.pool.get_conn().await?;
+        let ts = timestamp();
+        // Only (re)start batches that are still INIT or RUN, so a user STOP issued
+        // between batch selection and this update is not overwritten.
+        conn.exec_drop(r#"UPDATE `batch` SET `status`="RUN",`message`="",`ts_last_change`=:ts WHERE id=:batch_id AND `status` IN ("INIT","RUN")"#, params!{ts,batch_id}).await?;
+        let ts = timestamp();
+        conn.exec_drop(r#"UPDATE `command` SET `status`="INIT",`message`="",`error_code`="",`ts_change`=:ts WHERE `status` IN ("RUN","BLOCKED") AND `batch_id`=:batch_id"#, params!{ts,batch_id}).await?;
+        Ok(())
+    }
+
+    async fn restart_batch_from(&self, batch_id: i64, num: i64) -> QsResult<u64> {
+        let mut conn = self.pool.get_conn().await?;
+        let mut tx = conn.start_transaction(my::TxOpts::default()).await?;
+        let ts = timestamp();
+        tx.exec_drop(r#"UPDATE `batch` SET `status`="INIT",`message`="",`ts_last_change`=:ts WHERE id=:batch_id AND `status` IN ("PAUSE","STOP","DONE")"#, params!{"ts" => &ts,batch_id}).await?;
+        if tx.affected_rows() == 0 {
+            // Dropping the transaction rolls it back
+            return Err(QsError::BatchNotIdle(batch_id));
+        }
+        tx.exec_drop(r#"UPDATE `command` SET `status`="STOP",`ts_change`=:ts WHERE `batch_id`=:batch_id AND `num`<:num AND `status`="INIT""#, params!{"ts" => &ts,batch_id,num}).await?;
+        tx.exec_drop(r#"UPDATE `command` SET `status`="INIT",`message`="",`error_code`="",`ts_change`=:ts WHERE `batch_id`=:batch_id AND `num`>=:num AND `status`!="DONE""#, params!{"ts" => &ts,batch_id,num}).await?;
+        let queued = tx.affected_rows();
+        tx.commit().await?;
+        Ok(queued)
+    }
+
+    async fn reset_stale_batches(&self, now: &str) -> QsResult<()> {
+        let mut conn = self.pool.get_conn().await?;
+        let ts = timestamp();
+        conn.exec_drop(r#"UPDATE `batch` SET `status`="INIT",`message`="",`ts_last_change`=:ts WHERE `status` IN ("RUN","ERROR") AND (`lease_owner`="" OR `lease_expires`<:now)"#, params!{ts,now}).await?;
+        // Commands left mid-execution (status=RUN) are reset per batch by
+        // restart_batch() when the batch is picked up again; a global
+        // `UPDATE command WHERE status="RUN"` would full-scan the huge
+        // command table and exceed max_statement_time on ToolsDB.
+        Ok(())
+    }
+
+    async fn reinitialize_open_batches(&self, min_batch_id: i64) -> QsResult<()> {
+        let sql = "UPDATE batch SET status='INIT' WHERE status='DONE' AND id IN (SELECT DISTINCT batch_id FROM command WHERE status='INIT' and batch_id>:min_id)";
+        self.pool
+            .get_conn()
+            .await?
+            .exec_drop(sql, params! {"min_id" => min_batch_id})
+            .await?;
+        Ok(())
+    }
+
+    async fn set_last_item(&self, batch_id: i64, last_item: &str) -> QsResult<()> {
+        let ts = timestamp();
+        let sql = r#"UPDATE `batch` SET `ts_last_change`=:ts,`last_item`=:last_item WHERE `id`=:batch_id"#;
+        self.pool
+            .get_conn()
+            .await?
+            .exec_drop(sql, params! {ts,last_item,batch_id})
+            .await?;
+        Ok(())
+    }
+
+    async fn set_batch_priority(&self, batch_id: i64, priority: i64) -> QsResult<()> {
+        let sql = r#"UPDATE `batch` SET `priority`=:priority WHERE `id`=:batch_id"#;
+        self.pool
+            .get_conn()
+            .await?
+            .exec_drop(sql, params! {priority,batch_id})
+            .await?;
+        Ok(())
+    }
+
+    async fn set_batch_schedule(
+        &self,
+        batch_id: i64,
+        not_before: &str,
+        recurrence: &str,
+    ) -> QsResult<()> {
+        let sql = r#"UPDATE `batch` SET `ts_not_before`=:not_before,`recurrence`=:recurrence WHERE `id`=:batch_id"#;
+        self.pool
+            .get_conn()
+            .await?
+            .exec_drop(sql, params! {not_before,recurrence,batch_id})
+            .await?;
+        Ok(())
+    }
+
+    async fn get_scheduled_batches(
+        &self,
+        user_name: Option<&str>,
+        now: &str,
+    ) -> QsResult<Vec<BatchRow>> {
+        let mut conn = self.pool.get_conn().await?;
+        let rows: Vec<BatchTuple> = match user_name {
+            None => {
+                let sql = format!(
+                    "SELECT {} FROM batch WHERE (recurrence!='' OR ts_not_before>:now) ORDER BY id DESC",
+                    BATCH_COLUMNS
+                );
+                conn.exec(sql, params! {now}).await?
+            }
+            Some(user_name) => {
+                let sql = format!(
+                    "SELECT {} FROM batch WHERE (recurrence!='' OR ts_not_before>:now) AND `user` IN (SELECT id FROM {}.user WHERE name=:user_name) ORDER BY id DESC",
+                    BATCH_COLUMNS, self.auth_db
+                );
+                conn.exec(sql, params! {now, user_name}).await?
+            }
+        };
+        Ok(rows.into_iter().map(Self::batch_from_tuple).collect())
+    }
+
+    async fn reschedule_batch(&self, batch_id: i64, not_before: &str) -> QsResult<()> {
+        let mut conn = self.pool.get_conn().await?;
+        let mut tx = conn.start_transaction(my::TxOpts::default()).await?;
+        let ts = timestamp();
+        tx.exec_drop(r#"UPDATE `batch` SET `status`="INIT",`message`="",`last_item`="",`ts_not_before`=:not_before,`ts_last_change`=:ts WHERE id=:batch_id"#, params!{not_before,"ts" => &ts,batch_id}).await?;
+        tx.exec_drop(r#"UPDATE `command` SET `status`="INIT",`message`="",`error_code`="",`ts_change`=:ts WHERE `batch_id`=:batch_id"#, params!{"ts" => &ts,batch_id}).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn acquire_lease(
+        &self,
+        batch_id: i64,
+        owner: &str,
+        now: &str,
+        expires: &str,
+    ) -> QsResult<bool> {
+        let mut conn = self.pool.get_conn().await?;
+        conn.exec_drop(r#"UPDATE `batch` SET `lease_owner`=:owner,`lease_expires`=:expires WHERE id=:batch_id AND `status` IN ("INIT","RUN") AND (`lease_owner` IN ("",:owner) OR `lease_expires`<:now)"#, params!{owner,expires,batch_id,now}).await?;
+        // affected_rows() only counts changed rows, and a renewal within the
+        // same second changes nothing; ask who holds the lease instead
+        let holder: Option<String> = conn
+            .exec_first(
+                r#"SELECT `lease_owner` FROM `batch` WHERE id=:batch_id AND `status` IN ("INIT","RUN")"#,
+                params! {batch_id},
+            )
+            .await?;
+        Ok(holder.as_deref() == Some(owner))
+    }
+
+    async fn release_lease(&self, batch_id: i64, owner: &str) -> QsResult<()> {
+        let sql = r#"UPDATE `batch` SET `lease_owner`="",`lease_expires`="" WHERE id=:batch_id AND `lease_owner`=:owner"#;
+        self.pool
+            .get_conn()
+            .await?
+            .exec_drop(sql, params! {batch_id, owner})
+            .await?;
+        Ok(())
+    }
+
+    async fn get_live_leases(&self, now: &str) -> QsResult<Vec<(i64, i64, String)>> {
+        let sql = r#"SELECT id,`user`,`lease_owner` FROM `batch` WHERE `lease_owner`!="" AND `lease_expires`>=:now"#;
+        let rows = self.pool.get_conn().await?.exec(sql, params! {now}).await?;
+        Ok(rows)
+    }
+
+    async fn get_command(&self, command_id: i64) -> QsResult<Option<CommandRow>> {
+        let sql = format!(
+            "SELECT {} FROM command WHERE id=:command_id",
+            COMMAND_COLUMNS
+        );
+        let rows = self
+            .pool
+            .get_conn()
+            .await?
+            .exec_iter(sql, params! {command_id})
+            .await?
+            .map_and_drop(from_row::<CommandTuple>)
+            .await?;
+        Ok(rows.into_iter().next().map(Self::command_from_tuple))
+    }
+
+    async fn get_next_command(&self, batch_id: i64) -> QsResult<Option<CommandRow>> {
+        let sql = format!(
+            "SELECT {} FROM command WHERE batch_id=:batch_id AND status IN ('INIT') ORDER BY num LIMIT 1",
+            COMMAND_COLUMNS
+        );
+        let rows = self
+            .pool
+            .get_conn()
+            .await?
+            .exec_iter(sql, params! {batch_id})
+            .await?
+            .map_and_drop(from_row::<CommandTuple>)
+            .await?;
+        Ok(rows.into_iter().next().map(Self::command_from_tuple))
+    }
+
+    async fn get_commands(
+        &self,
+        batch_id: i64,
+        statuses: &[&str],
+        error_code: Option<&str>,
+        start: i64,
+        limit: i64,
+    ) -> QsResult<Vec<CommandRow>> {
+        // Positional placeholders only, so nothing user-supplied ends up in the SQL
+        let mut sql = format!("SELECT {} FROM command WHERE batch_id=?", COMMAND_COLUMNS);
+        let mut values: Vec<my::Value> = vec![batch_id.into()];
+        if !statuses.is_empty() {
+            let placeholders = vec!["?"; statuses.len()].join(",");
+            sql += &format!(" AND `status` IN ({})", placeholders);
+            values.extend(statuses.iter().map(|s| my::Value::from(*s)));
+        }
+        if let Some(error_code) = error_code {
+            sql += " AND `error_code`=?";
+            values.push(error_code.into());
+        }
+        sql += " ORDER BY num";
+        if limit > 0 {
+            sql += " LIMIT ?";
+            values.push(limit.into());
+            if start > 0 {
+                sql += " OFFSET ?";
+                values.push(start.into());
+            }
+        } else if start > 0 {
+            // MySQL has no OFFSET without LIMIT
+            sql += " LIMIT 18446744073709551615 OFFSET ?";
+            values.push(start.into());
+        }
+        let rows: Vec<CommandTuple> = self.pool.get_conn().await?.exec(sql, values).await?;
+        Ok(rows.into_iter().map(Self::command_from_tuple).collect())
+    }
+
+    async fn get_command_counts(&self, batch_id: i64) -> QsResult<Vec<(String, i64)>> {
+        let sql =
+            "SELECT `status`, COUNT(*) AS cnt FROM command WHERE batch_id=:batch_id GROUP BY `status`";
+        let rows = self
+            .pool
+            .get_conn()
+            .await?
+            .exec(sql, params! {batch_id})
+            .await?;
+        Ok(rows)
+    }
+
+    async fn set_command_status(
+        &self,
+        command_id: i64,
+        status: &str,
+        message: &str,
+        error_code: &str,
+        json: &str,
+    ) -> QsResult<()> {
+        let ts = timestamp();
+        let sql = r#"UPDATE `command` SET `ts_change`=:ts,`json`=:json,`status`=:status,`message`=:message,`error_code`=:error_code WHERE `id`=:command_id"#;
+        self.pool
+            .get_conn()
+            .await?
+            .exec_drop(sql, params! {ts,json,status,message,error_code,command_id})
+            .await?;
+        Ok(())
+    }
+
+    async fn reset_error_commands(&self, batch_id: i64, error_code: Option<&str>) -> QsResult<u64> {
+        let ts = timestamp();
+        let mut conn = self.pool.get_conn().await?;
+        match error_code {
+            Some(error_code) => {
+                let sql = r#"UPDATE command SET `status`='INIT', message='', error_code='', ts_change=:ts WHERE batch_id=:batch_id AND `status`='ERROR' AND error_code=:error_code"#;
+                conn.exec_drop(sql, params! {ts, batch_id, error_code})
+                    .await?;
+            }
+            None => {
+                let sql = r#"UPDATE command SET `status`='INIT', message='', error_code='', ts_change=:ts WHERE batch_id=:batch_id AND `status`='ERROR'"#;
+                conn.exec_drop(sql, params! {ts, batch_id}).await?;
+            }
+        }
+        Ok(conn.affected_rows())
+    }
+
+    async fn get_changed_batches(
+        &self,
+        scope: &ChangeScope,
+        since: &str,
+    ) -> QsResult<Vec<BatchRow>> {
+        let (condition, value) = self.scope_condition(scope, "id");
+        let sql = format!(
+            "SELECT {} FROM batch WHERE {} AND ts_last_change>=? ORDER BY ts_last_change,id",
+            BATCH_COLUMNS, condition
+        );
+        let values: Vec<my::Value> = vec![value, since.into()];
+        let rows: Vec<BatchTuple> = self.pool.get_conn().await?.exec(sql, values).await?;
+        Ok(rows.into_iter().map(Self::batch_from_tuple).collect())
+    }
+
+    async fn get_changed_commands(
+        &self,
+        scope: &ChangeScope,
+        since: &str,
+        after_id: i64,
+        limit: i64,
+    ) -> QsResult<Vec<CommandRow>> {
+        let (condition, value) = self.scope_condition(scope, "batch_id");
+        let sql = format!(
+            "SELECT {} FROM command WHERE {} AND (ts_change>? OR (ts_change=? AND id>?)) ORDER BY ts_change,id LIMIT ?",
+            COMMAND_COLUMNS, condition
+        );
+        let values: Vec<my::Value> = vec![
+            value,
+            since.into(),
+            since.into(),
+            after_id.into(),
+            limit.into(),
+        ];
+        let rows: Vec<CommandTuple> = self.pool.get_conn().await?.exec(sql, values).await?;
+        Ok(rows.into_iter().map(Self::command_from_tuple).collect())
+    }
+
+    async fn get_user_name(&self, user_id: i64) -> QsResult<Option<String>> {
+        let sql = format!(
+            r#"SELECT name FROM {}.user WHERE id=:user_id"#,
+            self.auth_db
+        );
+        let rows = self
+            .pool
+            .get_conn()
+            .await?
+            .exec_iter(sql, params! {user_id})
+            .await?
+            .map_and_drop(from_row::<String>)
+            .await?;
+        Ok(rows.into_iter().next())
+    }
+
+    async fn get_oauth_for_batch(&self, batch_id: i64) -> QsResult<Option<String>> {
+        let sql = format!(
+            r#"SELECT serialized_json FROM {}.batch_oauth WHERE batch_id=:batch_id"#,
+            self.auth_db
+        );
+        let rows = self
+            .pool
+            .get_conn()
+            .await?
+            .exec_iter(sql, params! {batch_id})
+            .await?
+            .map_and_drop(from_row::<String>)
+            .await?;
+        Ok(rows.into_iter().next())
+    }
+
+    async fn set_oauth_for_batch(&self, batch_id: i64, serialized_json: &str) -> QsResult<()> {
+        let sql = format!(
+            r#"REPLACE INTO {}.batch_oauth (batch_id,serialized_json) VALUES (:batch_id,:serialized_json)"#,
+            self.auth_db
+        );
+        self.pool
+            .get_conn()
+            .await?
+            .exec_drop(sql, params! {batch_id, serialized_json})
+            .await?;
+        Ok(())
+    }
+
+    async fn replace_oauth_credentials(&self, old_json: &str, new_json: &str) -> QsResult<()> {
+        let mut conn = self.pool.get_conn().await?;
+        for table in ["batch_oauth", "api_token"] {
+            let sql = format!(
+                r#"UPDATE {}.{} SET serialized_json=:new_json WHERE serialized_json=:old_json"#,
+                self.auth_db, table
+            );
+            conn.exec_drop(sql, params! {old_json, new_json}).await?;
+        }
+        Ok(())
+    }
+
+    async fn get_or_create_user(&self, name: &str) -> QsResult<i64> {
+        let mut conn = self.pool.get_conn().await?;
+        let sql = format!(
+            r#"INSERT IGNORE INTO {}.user (name) VALUES (:name)"#,
+            self.auth_db
+        );
+        conn.exec_drop(sql, params! {name}).await?;
+        let sql = format!(r#"SELECT id FROM {}.user WHERE name=:name"#, self.auth_db);
+        let rows = conn
+            .exec_iter(sql, params! {name})
+            .await?
+            .map_and_drop(from_row::<i64>)
+            .await?;
+        rows.into_iter()
+            .next()
+            .ok_or_else(|| QsError::DatabaseError(format!("User '{}' was not stored", name)))
+    }
+
+    async fn get_session(&self, session_id: &str, now: &str) -> QsResult<Option<String>> {
+        let sql = format!(
+            r#"SELECT data FROM {}.session WHERE id=:session_id AND ts_expires>:now"#,
+            self.auth_db
+        );
+        let rows = self
+            .pool
+            .get_conn()
+            .await?
+            .exec_iter(sql, params! {session_id, now})
+            .await?
+            .map_and_drop(from_row::<String>)
+            .await?;
+        Ok(rows.into_iter().next())
+    }
+
+    async fn set_session(
+        &self,
+        session_id: &str,
+        user_id: i64,
+        data: &str,
+        expires: &str,
+    ) -> QsResult<()> {
+        let mut conn = self.pool.get_conn().await?;
+        let now = timestamp();
+        let sql = format!(
+            r#"DELETE FROM {}.session WHERE ts_expires<=:now"#,
+            self.auth_db
+        );
+        conn.exec_drop(sql, params! {now}).await?;
+        let sql = format!(
+            r#"REPLACE INTO {}.session (id,user_id,data,ts_expires) VALUES (:session_id,:user_id,:data,:expires)"#,
+            self.auth_db
+        );
+        conn.exec_drop(sql, params! {session_id, user_id, data, expires})
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_session(&self, session_id: &str) -> QsResult<()> {
+        let sql = format!(
+            r#"DELETE FROM {}.session WHERE id=:session_id"#,
+            self.auth_db
+        );
+        self.pool
+            .get_conn()
+            .await?
+            .exec_drop(sql, params! {session_id})
+            .await?;
+        Ok(())
+    }
+
+    async fn create_api_token(
+        &self,
+        user_id: i64,
+        name: &str,
+        scopes: &str,
+        token_hash: &str,
+        serialized_json: &str,
+    ) -> QsResult<i64> {
+        let mut conn = self.pool.get_conn().await?;
+        let sql = format!(
+            r#"INSERT INTO {}.api_token (user_id,`name`,scopes,token_hash,serialized_json,ts_created) VALUES (:user_id,:name,:scopes,:token_hash,:serialized_json,:ts)"#,
+            self.auth_db
+        );
+        conn.exec_drop(
+            sql,
+            params! {user_id, name, scopes, token_hash, serialized_json, "ts" => timestamp()},
+        )
+        .await?;
+        let token_id: i64 = conn
+            .exec_first("SELECT LAST_INSERT_ID()", ())
+            .await?
+            .ok_or("No LAST_INSERT_ID() after creating API token")?;
+        Ok(token_id)
+    }
+
+    async fn get_api_token(&self, token_hash: &str) -> QsResult<Option<ApiTokenRow>> {
+        let sql = format!(
+            r#"SELECT {} FROM {}.api_token WHERE token_hash=:token_hash AND ts_revoked=''"#,
+            API_TOKEN_COLUMNS, self.auth_db
+        );
+        let rows = self
+            .pool
+            .get_conn()
+            .await?
+            .exec_iter(sql, params! {token_hash})
+            .await?
+            .map_and_drop(from_row::<ApiTokenTuple>)
+            .await?;
+        Ok(rows.into_iter().next().map(Self::api_token_from_tuple))
+    }
+
+    async fn get_api_tokens(&self, user_id: i64) -> QsResult<Vec<ApiTokenRow>> {
+        let sql = format!(
+            r#"SELECT {} FROM {}.api_token WHERE user_id=:user_id AND ts_revoked='' ORDER BY id"#,
+            API_TOKEN_COLUMNS, self.auth_db
+        );
+        let rows = self
+            .pool
+            .get_conn()
+            .await?
+            .exec_iter(sql, params! {user_id})
+            .await?
+            .map_and_drop(from_row::<ApiTokenTuple>)
+            .await?;
+        Ok(rows.into_iter().map(Self::api_token_from_tuple).collect())
+    }
+
+    async fn revoke_api_token(&self, token_id: i64, user_id: i64) -> QsResult<bool> {
+        let mut conn = self.pool.get_conn().await?;
+        let sql = format!(
+            r#"UPDATE {}.api_token SET ts_revoked=:ts WHERE id=:token_id AND user_id=:user_id AND ts_revoked=''"#,
+            self.auth_db
+        );
+        conn.exec_drop(sql, params! {token_id, user_id, "ts" => timestamp()})
+            .await?;
+        Ok(conn.affected_rows() > 0)
+    }
+
+    async fn set_api_token_last_used(&self, token_id: i64, now: &str) -> QsResult<()> {
+        let sql = format!(
+            r#"UPDATE {}.api_token SET ts_last_used=:now WHERE id=:token_id"#,
+            self.auth_db
+        );
+        self.pool
+            .get_conn()
+            .await?
+            .exec_drop(sql, params! {token_id, now})
+            .await?;
+        Ok(())
+    }
+
+    async fn create_temporary_batch(
+        &self,
+        id: &str,
+        user_id: i64,
+        data: &str,
+        expires: &str,
+    ) -> QsResult<()> {
+        let mut conn = self.pool.get_conn().await?;
+        let now = timestamp();
+        conn.exec_drop(
+            r#"DELETE FROM temporary_batch WHERE ts_expires<=:now"#,
+            params! {now},
+        )
+        .await?;
+        conn.exec_drop(
+            r#"INSERT INTO temporary_batch (id,user_id,data,ts_expires) VALUES (:id,:user_id,:data,:expires)"#,
+            params! {id, user_id, data, expires},
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn get_temporary_batch(&self, id: &str, now: &str) -> QsResult<Option<String>> {
+        let rows = self
+            .pool
+            .get_conn()
+            .await?
+            .exec_iter(
+                r#"SELECT data FROM temporary_batch WHERE id=:id AND ts_expires>:now"#,
+                params! {id, now},
+            )
+            .await?
+            .map_and_drop(from_row::<String>)
+            .await?;
+        Ok(rows.into_iter().next())
+    }
+}
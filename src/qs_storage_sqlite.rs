@@ -0,0 +1,1412 @@
+use crate::error::{QsError, QsResult};
+use crate::qs_migrations::{pending, SQLITE_MIGRATIONS};
+use crate::qs_storage::{
+    timestamp, ApiTokenRow, BatchRow, ChangeScope, CommandRow, OpenBatch, QsStorage,
+    COMMAND_INSERT_ROWS,
+};
+use async_trait::async_trait;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Row};
+use std::sync::{Arc, Mutex};
+
+const BATCH_COLUMNS: &str =
+    "id,name,user,site,status,message,last_item,ts_last_change,priority,ts_not_before,recurrence";
+const COMMAND_COLUMNS: &str = "id,batch_id,num,json,status,message,ts_change,error_code";
+const API_TOKEN_COLUMNS: &str = "id,user_id,name,scopes,serialized_json,ts_created,ts_last_used";
+
+/// An embedded backend for running the bot locally and in CI, without a MySQL server.
+/// The connection is used from blocking tasks, one statement group at a time.
+#[derive(Debug, Clone)]
+pub struct SqliteStorage {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStorage {
+    /// Opens (or creates) the database file and applies any pending migrations.
+    pub fn open(path: &str) -> QsResult<Self> {
+        Self::new_from_connection(Connection::open(path)?)
+    }
+
+    /// A private, empty database that disappears with the last clone of this storage.
+    pub fn open_in_memory() -> QsResult<Self> {
+        Self::new_from_connection(Connection::open_in_memory()?)
+    }
+
+    fn new_from_connection(mut conn: Connection) -> QsResult<Self> {
+        Self::apply_migrations(&mut conn)?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Each migration runs in its own transaction, together with its
+    /// `schema_migrations` entry.
+    fn apply_migrations(conn: &mut Connection) -> rusqlite::Result<Vec<u32>> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS schema_migrations (version INTEGER PRIMARY KEY, name TEXT NOT NULL, ts_applied TEXT NOT NULL)",
+        )?;
+        let applied: Vec<u32> = {
+            let mut stmt = conn.prepare("SELECT version FROM schema_migrations")?;
+            let rows = stmt.query_map([], |row| row.get(0))?;
+            rows.collect::<rusqlite::Result<_>>()?
+        };
+        let mut ret = vec![];
+        for migration in pending(SQLITE_MIGRATIONS, &applied) {
+            let tx = conn.transaction()?;
+            tx.execute_batch(migration.sql)?;
+            tx.execute(
+                "INSERT INTO schema_migrations (version,name,ts_applied) VALUES (?1,?2,?3)",
+                params![migration.version, migration.name, timestamp()],
+            )?;
+            tx.commit()?;
+            ret.push(migration.version);
+        }
+        Ok(ret)
+    }
+
+    /// Runs `f` with the connection on the blocking thread pool.
+    async fn call<T, F>(&self, f: F) -> QsResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn
+                .lock()
+                .map_err(|_| QsError::DatabaseError("SQLite connection lock poisoned".into()))?;
+            f(&mut conn).map_err(QsError::from)
+        })
+        .await
+        .map_err(|e| QsError::DatabaseError(format!("spawn_blocking failed: {}", e)))?
+    }
+
+    fn batch_from_row(row: &Row) -> rusqlite::Result<BatchRow> {
+        Ok(BatchRow {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            user: row.get(2)?,
+            site: row.get(3)?,
+            status: row.get(4)?,
+            message: row.get(5)?,
+            last_item: row.get(6)?,
+            ts_last_change: row.get(7)?,
+            priority: row.get(8)?,
+            ts_not_before: row.get(9)?,
+            recurrence: row.get(10)?,
+        })
+    }
+
+    fn api_token_from_row(row: &Row) -> rusqlite::Result<ApiTokenRow> {
+        Ok(ApiTokenRow {
+            id: row.get(0)?,
+            user_id: row.get(1)?,
+            name: row.get(2)?,
+            scopes: row.get(3)?,
+            serialized_json: row.get(4)?,
+            ts_created: row.get(5)?,
+            ts_last_used: row.get(6)?,
+        })
+    }
+
+    /// SQL condition for `column` holding a batch ID in `scope`, with its
+    /// `?1` parameter
+    fn scope_condition(scope: &ChangeScope, column: &str) -> (String, rusqlite::types::Value) {
+        match scope {
+            ChangeScope::Batch(batch_id) => (format!("{}=?1", column), (*batch_id).into()),
+            ChangeScope::User(user_name) => (
+                format!(
+                    "{} IN (SELECT id FROM batch WHERE user IN (SELECT id FROM user WHERE name=?1))",
+                    column
+                ),
+                user_name.clone().into(),
+            ),
+        }
+    }
+
+    fn command_from_row(row: &Row) -> rusqlite::Result<CommandRow> {
+        Ok(CommandRow {
+            id: row.get(0)?,
+            batch_id: row.get(1)?,
+            num: row.get(2)?,
+            json: row.get(3)?,
+            status: row.get(4)?,
+            message: row.get(5)?,
+            ts_change: row.get(6)?,
+            error_code: row.get(7)?,
+        })
+    }
+}
+
+#[async_trait]
+impl QsStorage for SqliteStorage {
+    async fn ping(&self) -> bool {
+        self.call(|conn| conn.query_row("SELECT 1", [], |row| row.get::<_, i64>(0)))
+            .await
+            .is_ok()
+    }
+
+    async fn migrate(&self) -> QsResult<Vec<u32>> {
+        self.call(Self::apply_migrations).await
+    }
+
+    async fn get_batch(&self, batch_id: i64) -> QsResult<Option<BatchRow>> {
+        self.call(move |conn| {
+            let sql = format!("SELECT {} FROM batch WHERE id=?1", BATCH_COLUMNS);
+            conn.query_row(&sql, params![batch_id], Self::batch_from_row)
+                .optional()
+        })
+        .await
+    }
+
+    async fn get_batches(
+        &self,
+        user_name: Option<&str>,
+        limit: i64,
+        offset: i64,
+    ) -> QsResult<Vec<BatchRow>> {
+        let user_name = user_name.map(|s| s.to_string());
+        self.call(move |conn| match user_name {
+            None => {
+                let sql = format!(
+                    "SELECT {} FROM batch ORDER BY id DESC LIMIT ?1 OFFSET ?2",
+                    BATCH_COLUMNS
+                );
+                let mut stmt = conn.prepare(&sql)?;
+                let rows = stmt.query_map(params![limit, offset], Self::batch_from_row)?;
+                rows.collect()
+            }
+            Some(user_name) => {
+                let sql = format!(
+                    "SELECT {} FROM batch WHERE user IN (SELECT id FROM user WHERE name=?1) ORDER BY id DESC LIMIT ?2 OFFSET ?3",
+                    BATCH_COLUMNS
+                );
+                let mut stmt = conn.prepare(&sql)?;
+                let rows =
+                    stmt.query_map(params![user_name, limit, offset], Self::batch_from_row)?;
+                rows.collect()
+            }
+        })
+        .await
+    }
+
+    async fn get_open_batches(
+        &self,
+        small_batch_commands: i64,
+        now: &str,
+    ) -> QsResult<Vec<OpenBatch>> {
+        let now = now.to_string();
+        self.call(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id,user,site,priority,(SELECT num FROM command WHERE batch_id=batch.id AND status='INIT' ORDER BY num LIMIT 1 OFFSET ?1) IS NULL FROM batch WHERE status IN ('INIT','RUN') AND ts_not_before<=?2 ORDER BY ts_last_change",
+            )?;
+            let rows = stmt.query_map(params![small_batch_commands, now], |row| {
+                Ok(OpenBatch {
+                    id: row.get(0)?,
+                    user: row.get(1)?,
+                    site: row.get(2)?,
+                    priority: row.get(3)?,
+                    small: row.get(4)?,
+                })
+            })?;
+            rows.collect()
+        })
+        .await
+    }
+
+    async fn create_batch(
+        &self,
+        name: &str,
+        user_id: i64,
+        site: &str,
+        commands: &[String],
+        oauth_json: Option<&str>,
+    ) -> QsResult<i64> {
+        let (name, site, commands) = (name.to_string(), site.to_string(), commands.to_vec());
+        let oauth_json = oauth_json.map(|s| s.to_string());
+        self.call(move |conn| {
+            let ts = timestamp();
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT INTO batch (name,user,site,status,ts_created,ts_last_change) VALUES (?1,?2,?3,'INIT',?4,?4)",
+                params![name, user_id, site, ts],
+            )?;
+            let batch_id = tx.last_insert_rowid();
+            for (chunk_num, chunk) in commands.chunks(COMMAND_INSERT_ROWS).enumerate() {
+                let sql = format!(
+                    "INSERT INTO command (batch_id,num,json,status,ts_change) VALUES {}",
+                    vec!["(?,?,?,'INIT',?)"; chunk.len()].join(",")
+                );
+                let first_num = chunk_num * COMMAND_INSERT_ROWS;
+                let values = chunk.iter().enumerate().flat_map(|(i, json)| {
+                    [
+                        rusqlite::types::Value::from(batch_id),
+                        ((first_num + i) as i64).into(),
+                        json.to_owned().into(),
+                        ts.to_owned().into(),
+                    ]
+                });
+                tx.execute(&sql, params_from_iter(values))?;
+            }
+            if let Some(serialized_json) = oauth_json {
+                tx.execute(
+                    "INSERT INTO batch_oauth (batch_id,serialized_json) VALUES (?1,?2)",
+                    params![batch_id, serialized_json],
+                )?;
+            }
+            tx.commit()?;
+            Ok(batch_id)
+        })
+        .await
+    }
+
+    async fn set_batch_status(
+        &self,
+        batch_id: i64,
+        status: &str,
+        message: Option<&str>,
+    ) -> QsResult<()> {
+        let status = status.to_string();
+        let message = message.map(|s| s.to_string());
+        self.call(move |conn| {
+            let ts = timestamp();
+            match message {
+                Some(message) => conn.execute(
+                    "UPDATE batch SET status=?1,message=?2,ts_last_change=?3 WHERE id=?4",
+                    params![status, message, ts, batch_id],
+                ),
+                None => conn.execute(
+                    "UPDATE batch SET status=?1,ts_last_change=?2 WHERE id=?3",
+                    params![status, ts, batch_id],
+                ),
+            }
+            .map(|_| ())
+        })
+        .await
+    }
+
+    async fn restart_batch(&self, batch_id: i64) -> QsResult<()> {
+        self.call(move |conn| {
+            let ts = timestamp();
+            conn.execute(
+                "UPDATE batch SET status='RUN',message='',ts_last_change=?1 WHERE id=?2 AND status IN ('INIT','RUN')",
+                params![ts, batch_id],
+            )?;
+            conn.execute(
+                "UPDATE command SET status='INIT',message='',error_code='',ts_change=?1 WHERE status IN ('RUN','BLOCKED') AND batch_id=?2",
+                params![ts, batch_id],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn restart_batch_from(&self, batch_id: i64, num: i64) -> QsResult<u64> {
+        let queued = self.call(move |conn| {
+            let ts = timestamp();
+            let tx = conn.transaction()?;
+            let restarted = tx.execute(
+                "UPDATE batch SET status='INIT',message='',ts_last_change=?1 WHERE id=?2 AND status IN ('PAUSE','STOP','DONE')",
+                params![ts, batch_id],
+            )?;
+            if restarted == 0 {
+                return Ok(None);
+            }
+            tx.execute(
+                "UPDATE command SET status='STOP',ts_change=?1 WHERE batch_id=?2 AND num<?3 AND status='INIT'",
+                params![ts, batch_id, num],
+            )?;
+            let queued = tx.execute(
+                "UPDATE command SET status='INIT',message='',error_code='',ts_change=?1 WHERE batch_id=?2 AND num>=?3 AND status!='DONE'",
+                params![ts, batch_id, num],
+            )?;
+            tx.commit()?;
+            Ok(Some(queued as u64))
+        })
+        .await?;
+        queued.ok_or(QsError::BatchNotIdle(batch_id))
+    }
+
+    async fn reset_stale_batches(&self, now: &str) -> QsResult<()> {
+        let now = now.to_string();
+        self.call(move |conn| {
+            conn.execute(
+                "UPDATE batch SET status='INIT',message='',ts_last_change=?1 WHERE status IN ('RUN','ERROR') AND (lease_owner='' OR lease_expires<?2)",
+                params![timestamp(), now],
+            )
+            .map(|_| ())
+        })
+        .await
+    }
+
+    async fn reinitialize_open_batches(&self, min_batch_id: i64) -> QsResult<()> {
+        self.call(move |conn| {
+            conn.execute(
+                "UPDATE batch SET status='INIT' WHERE status='DONE' AND id IN (SELECT DISTINCT batch_id FROM command WHERE status='INIT' AND batch_id>?1)",
+                params![min_batch_id],
+            )
+            .map(|_| ())
+        })
+        .await
+    }
+
+    async fn set_last_item(&self, batch_id: i64, last_item: &str) -> QsResult<()> {
+        let last_item = last_item.to_string();
+        self.call(move |conn| {
+            conn.execute(
+                "UPDATE batch SET ts_last_change=?1,last_item=?2 WHERE id=?3",
+                params![timestamp(), last_item, batch_id],
+            )
+            .map(|_| ())
+        })
+        .await
+    }
+
+    async fn set_batch_priority(&self, batch_id: i64, priority: i64) -> QsResult<()> {
+        self.call(move |conn| {
+            conn.execute(
+                "UPDATE batch SET priority=?1 WHERE id=?2",
+                params![priority, batch_id],
+            )
+            .map(|_| ())
+        })
+        .await
+    }
+
+    async fn set_batch_schedule(
+        &self,
+        batch_id: i64,
+        not_before: &str,
+        recurrence: &str,
+    ) -> QsResult<()> {
+        let (not_before, recurrence) = (not_before.to_string(), recurrence.to_string());
+        self.call(move |conn| {
+            conn.execute(
+                "UPDATE batch SET ts_not_before=?1,recurrence=?2 WHERE id=?3",
+                params![not_before, recurrence, batch_id],
+            )
+            .map(|_| ())
+        })
+        .await
+    }
+
+    async fn get_scheduled_batches(
+        &self,
+        user_name: Option<&str>,
+        now: &str,
+    ) -> QsResult<Vec<BatchRow>> {
+        let (user_name, now) = (user_name.map(|s| s.to_string()), now.to_string());
+        self.call(move |conn| {
+            let sql = format!(
+                "SELECT {} FROM batch WHERE (recurrence!='' OR ts_not_before>?1) AND (?2 IS NULL OR user IN (SELECT id FROM user WHERE name=?2)) ORDER BY id DESC",
+                BATCH_COLUMNS
+            );
+            let mut stmt = conn.prepare(&sql)?;
+            let rows = stmt.query_map(params![now, user_name], Self::batch_from_row)?;
+            rows.collect()
+        })
+        .await
+    }
+
+    async fn reschedule_batch(&self, batch_id: i64, not_before: &str) -> QsResult<()> {
+        let not_before = not_before.to_string();
+        self.call(move |conn| {
+            let ts = timestamp();
+            let tx = conn.transaction()?;
+            tx.execute(
+                "UPDATE batch SET status='INIT',message='',last_item='',ts_not_before=?1,ts_last_change=?2 WHERE id=?3",
+                params![not_before, ts, batch_id],
+            )?;
+            tx.execute(
+                "UPDATE command SET status='INIT',message='',error_code='',ts_change=?1 WHERE batch_id=?2",
+                params![ts, batch_id],
+            )?;
+            tx.commit()
+        })
+        .await
+    }
+
+    async fn acquire_lease(
+        &self,
+        batch_id: i64,
+        owner: &str,
+        now: &str,
+        expires: &str,
+    ) -> QsResult<bool> {
+        let (owner, now, expires) = (owner.to_string(), now.to_string(), expires.to_string());
+        self.call(move |conn| {
+            conn.execute(
+                "UPDATE batch SET lease_owner=?1,lease_expires=?2 WHERE id=?3 AND status IN ('INIT','RUN') AND (lease_owner IN ('',?1) OR lease_expires<?4)",
+                params![owner, expires, batch_id, now],
+            )
+            .map(|n| n == 1)
+        })
+        .await
+    }
+
+    async fn release_lease(&self, batch_id: i64, owner: &str) -> QsResult<()> {
+        let owner = owner.to_string();
+        self.call(move |conn| {
+            conn.execute(
+                "UPDATE batch SET lease_owner='',lease_expires='' WHERE id=?1 AND lease_owner=?2",
+                params![batch_id, owner],
+            )
+            .map(|_| ())
+        })
+        .await
+    }
+
+    async fn get_live_leases(&self, now: &str) -> QsResult<Vec<(i64, i64, String)>> {
+        let now = now.to_string();
+        self.call(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id,user,lease_owner FROM batch WHERE lease_owner!='' AND lease_expires>=?1",
+            )?;
+            let rows = stmt.query_map(params![now], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })?;
+            rows.collect()
+        })
+        .await
+    }
+
+    async fn get_command(&self, command_id: i64) -> QsResult<Option<CommandRow>> {
+        self.call(move |conn| {
+            let sql = format!("SELECT {} FROM command WHERE id=?1", COMMAND_COLUMNS);
+            conn.query_row(&sql, params![command_id], Self::command_from_row)
+                .optional()
+        })
+        .await
+    }
+
+    async fn get_next_command(&self, batch_id: i64) -> QsResult<Option<CommandRow>> {
+        self.call(move |conn| {
+            let sql = format!(
+                "SELECT {} FROM command WHERE batch_id=?1 AND status='INIT' ORDER BY num LIMIT 1",
+                COMMAND_COLUMNS
+            );
+            conn.query_row(&sql, params![batch_id], Self::command_from_row)
+                .optional()
+        })
+        .await
+    }
+
+    async fn get_commands(
+        &self,
+        batch_id: i64,
+        statuses: &[&str],
+        error_code: Option<&str>,
+        start: i64,
+        limit: i64,
+    ) -> QsResult<Vec<CommandRow>> {
+        let statuses: Vec<String> = statuses.iter().map(|s| s.to_string()).collect();
+        let error_code = error_code.map(|s| s.to_string());
+        self.call(move |conn| {
+            let mut sql = format!("SELECT {} FROM command WHERE batch_id=?", COMMAND_COLUMNS);
+            let mut values: Vec<rusqlite::types::Value> = vec![batch_id.into()];
+            if !statuses.is_empty() {
+                sql += &format!(" AND status IN ({})", vec!["?"; statuses.len()].join(","));
+                values.extend(statuses.into_iter().map(rusqlite::types::Value::from));
+            }
+            if let Some(error_code) = error_code {
+                sql += " AND error_code=?";
+                values.push(error_code.into());
+            }
+            // A negative LIMIT means "no limit" in SQLite
+            sql += " ORDER BY num LIMIT ? OFFSET ?";
+            values.push(if limit > 0 { limit } else { -1 }.into());
+            values.push(start.max(0).into());
+            let mut stmt = conn.prepare(&sql)?;
+            let rows = stmt.query_map(params_from_iter(values), Self::command_from_row)?;
+            rows.collect()
+        })
+        .await
+    }
+
+    async fn get_command_counts(&self, batch_id: i64) -> QsResult<Vec<(String, i64)>> {
+        self.call(move |conn| {
+            let mut stmt = conn
+                .prepare("SELECT status,COUNT(*) FROM command WHERE batch_id=?1 GROUP BY status")?;
+            let rows = stmt.query_map(params![batch_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            rows.collect()
+        })
+        .await
+    }
+
+    async fn set_command_status(
+        &self,
+        command_id: i64,
+        status: &str,
+        message: &str,
+        error_code: &str,
+        json: &str,
+    ) -> QsResult<()> {
+        let (status, message, json) = (status.to_string(), message.to_string(), json.to_string());
+        let error_code = error_code.to_string();
+        self.call(move |conn| {
+            conn.execute(
+                "UPDATE command SET ts_change=?1,json=?2,status=?3,message=?4,error_code=?5 WHERE id=?6",
+                params![timestamp(), json, status, message, error_code, command_id],
+            )
+            .map(|_| ())
+        })
+        .await
+    }
+
+    async fn reset_error_commands(&self, batch_id: i64, error_code: Option<&str>) -> QsResult<u64> {
+        let error_code = error_code.map(|s| s.to_string());
+        self.call(move |conn| {
+            let mut sql = "UPDATE command SET status='INIT',message='',error_code='',ts_change=?1 WHERE batch_id=?2 AND status='ERROR'".to_string();
+            let mut values: Vec<rusqlite::types::Value> = vec![timestamp().into(), batch_id.into()];
+            if let Some(error_code) = error_code {
+                sql += " AND error_code=?3";
+                values.push(error_code.into());
+            }
+            conn.execute(&sql, params_from_iter(values))
+                .map(|n| n as u64)
+        })
+        .await
+    }
+
+    async fn get_changed_batches(
+        &self,
+        scope: &ChangeScope,
+        since: &str,
+    ) -> QsResult<Vec<BatchRow>> {
+        let (condition, value) = Self::scope_condition(scope, "id");
+        let since = since.to_string();
+        self.call(move |conn| {
+            let sql = format!(
+                "SELECT {} FROM batch WHERE {} AND ts_last_change>=?2 ORDER BY ts_last_change,id",
+                BATCH_COLUMNS, condition
+            );
+            let mut stmt = conn.prepare(&sql)?;
+            let rows = stmt.query_map(params![value, since], Self::batch_from_row)?;
+            rows.collect()
+        })
+        .await
+    }
+
+    async fn get_changed_commands(
+        &self,
+        scope: &ChangeScope,
+        since: &str,
+        after_id: i64,
+        limit: i64,
+    ) -> QsResult<Vec<CommandRow>> {
+        let (condition, value) = Self::scope_condition(scope, "batch_id");
+        let since = since.to_string();
+        self.call(move |conn| {
+            let sql = format!(
+                "SELECT {} FROM command WHERE {} AND (ts_change>?2 OR (ts_change=?2 AND id>?3)) ORDER BY ts_change,id LIMIT ?4",
+                COMMAND_COLUMNS, condition
+            );
+            let mut stmt = conn.prepare(&sql)?;
+            let rows = stmt.query_map(
+                params![value, since, after_id, limit],
+                Self::command_from_row,
+            )?;
+            rows.collect()
+        })
+        .await
+    }
+
+    async fn get_user_name(&self, user_id: i64) -> QsResult<Option<String>> {
+        self.call(move |conn| {
+            conn.query_row(
+                "SELECT name FROM user WHERE id=?1",
+                params![user_id],
+                |row| row.get(0),
+            )
+            .optional()
+        })
+        .await
+    }
+
+    async fn get_oauth_for_batch(&self, batch_id: i64) -> QsResult<Option<String>> {
+        self.call(move |conn| {
+            conn.query_row(
+                "SELECT serialized_json FROM batch_oauth WHERE batch_id=?1",
+                params![batch_id],
+                |row| row.get(0),
+            )
+            .optional()
+        })
+        .await
+    }
+
+    async fn set_oauth_for_batch(&self, batch_id: i64, serialized_json: &str) -> QsResult<()> {
+        let serialized_json = serialized_json.to_string();
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO batch_oauth (batch_id,serialized_json) VALUES (?1,?2)",
+                params![batch_id, serialized_json],
+            )
+            .map(|_| ())
+        })
+        .await
+    }
+
+    async fn replace_oauth_credentials(&self, old_json: &str, new_json: &str) -> QsResult<()> {
+        let (old_json, new_json) = (old_json.to_string(), new_json.to_string());
+        self.call(move |conn| {
+            conn.execute(
+                "UPDATE batch_oauth SET serialized_json=?1 WHERE serialized_json=?2",
+                params![new_json, old_json],
+            )?;
+            conn.execute(
+                "UPDATE api_token SET serialized_json=?1 WHERE serialized_json=?2",
+                params![new_json, old_json],
+            )
+            .map(|_| ())
+        })
+        .await
+    }
+
+    async fn get_or_create_user(&self, name: &str) -> QsResult<i64> {
+        let name = name.to_string();
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT OR IGNORE INTO user (name) VALUES (?1)",
+                params![name],
+            )?;
+            conn.query_row("SELECT id FROM user WHERE name=?1", params![name], |row| {
+                row.get(0)
+            })
+        })
+        .await
+    }
+
+    async fn get_session(&self, session_id: &str, now: &str) -> QsResult<Option<String>> {
+        let (session_id, now) = (session_id.to_string(), now.to_string());
+        self.call(move |conn| {
+            conn.query_row(
+                "SELECT data FROM session WHERE id=?1 AND ts_expires>?2",
+                params![session_id, now],
+                |row| row.get(0),
+            )
+            .optional()
+        })
+        .await
+    }
+
+    async fn set_session(
+        &self,
+        session_id: &str,
+        user_id: i64,
+        data: &str,
+        expires: &str,
+    ) -> QsResult<()> {
+        let (session_id, data, expires) = (
+            session_id.to_string(),
+            data.to_string(),
+            expires.to_string(),
+        );
+        let now = timestamp();
+        self.call(move |conn| {
+            conn.execute("DELETE FROM session WHERE ts_expires<=?1", params![now])?;
+            conn.execute(
+                "INSERT OR REPLACE INTO session (id,user_id,data,ts_expires) VALUES (?1,?2,?3,?4)",
+                params![session_id, user_id, data, expires],
+            )
+            .map(|_| ())
+        })
+        .await
+    }
+
+    async fn delete_session(&self, session_id: &str) -> QsResult<()> {
+        let session_id = session_id.to_string();
+        self.call(move |conn| {
+            conn.execute("DELETE FROM session WHERE id=?1", params![session_id])
+                .map(|_| ())
+        })
+        .await
+    }
+
+    async fn create_api_token(
+        &self,
+        user_id: i64,
+        name: &str,
+        scopes: &str,
+        token_hash: &str,
+        serialized_json: &str,
+    ) -> QsResult<i64> {
+        let (name, scopes, token_hash, serialized_json) = (
+            name.to_string(),
+            scopes.to_string(),
+            token_hash.to_string(),
+            serialized_json.to_string(),
+        );
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT INTO api_token (user_id,name,scopes,token_hash,serialized_json,ts_created) VALUES (?1,?2,?3,?4,?5,?6)",
+                params![user_id, name, scopes, token_hash, serialized_json, timestamp()],
+            )?;
+            Ok(conn.last_insert_rowid())
+        })
+        .await
+    }
+
+    async fn get_api_token(&self, token_hash: &str) -> QsResult<Option<ApiTokenRow>> {
+        let token_hash = token_hash.to_string();
+        self.call(move |conn| {
+            let sql = format!(
+                "SELECT {} FROM api_token WHERE token_hash=?1 AND ts_revoked=''",
+                API_TOKEN_COLUMNS
+            );
+            conn.query_row(&sql, params![token_hash], Self::api_token_from_row)
+                .optional()
+        })
+        .await
+    }
+
+    async fn get_api_tokens(&self, user_id: i64) -> QsResult<Vec<ApiTokenRow>> {
+        self.call(move |conn| {
+            let sql = format!(
+                "SELECT {} FROM api_token WHERE user_id=?1 AND ts_revoked='' ORDER BY id",
+                API_TOKEN_COLUMNS
+            );
+            let mut stmt = conn.prepare(&sql)?;
+            let rows = stmt.query_map(params![user_id], Self::api_token_from_row)?;
+            rows.collect()
+        })
+        .await
+    }
+
+    async fn revoke_api_token(&self, token_id: i64, user_id: i64) -> QsResult<bool> {
+        self.call(move |conn| {
+            conn.execute(
+                "UPDATE api_token SET ts_revoked=?1 WHERE id=?2 AND user_id=?3 AND ts_revoked=''",
+                params![timestamp(), token_id, user_id],
+            )
+            .map(|changed| changed > 0)
+        })
+        .await
+    }
+
+    async fn set_api_token_last_used(&self, token_id: i64, now: &str) -> QsResult<()> {
+        let now = now.to_string();
+        self.call(move |conn| {
+            conn.execute(
+                "UPDATE api_token SET ts_last_used=?1 WHERE id=?2",
+                params![now, token_id],
+            )
+            .map(|_| ())
+        })
+        .await
+    }
+
+    async fn create_temporary_batch(
+        &self,
+        id: &str,
+        user_id: i64,
+        data: &str,
+        expires: &str,
+    ) -> QsResult<()> {
+        let (id, data, expires) = (id.to_string(), data.to_string(), expires.to_string());
+        let now = timestamp();
+        self.call(move |conn| {
+            conn.execute(
+                "DELETE FROM temporary_batch WHERE ts_expires<=?1",
+                params![now],
+            )?;
+            conn.execute(
+                "INSERT INTO temporary_batch (id,user_id,data,ts_expires) VALUES (?1,?2,?3,?4)",
+                params![id, user_id, data, expires],
+            )
+            .map(|_| ())
+        })
+        .await
+    }
+
+    async fn get_temporary_batch(&self, id: &str, now: &str) -> QsResult<Option<String>> {
+        let (id, now) = (id.to_string(), now.to_string());
+        self.call(move |conn| {
+            conn.query_row(
+                "SELECT data FROM temporary_batch WHERE id=?1 AND ts_expires>?2",
+                params![id, now],
+                |row| row.get(0),
+            )
+            .optional()
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn storage_with_batch(commands: usize) -> (SqliteStorage, i64) {
+        let storage = SqliteStorage::open_in_memory().unwrap();
+        let commands: Vec<String> = (0..commands)
+            .map(|num| format!(r#"{{"action":"create","type":"item","num":{}}}"#, num))
+            .collect();
+        let batch_id = storage
+            .create_batch("test", 1, "wikidata", &commands, None)
+            .await
+            .unwrap();
+        (storage, batch_id)
+    }
+
+    #[tokio::test]
+    async fn create_batch_stores_batch_and_commands() {
+        let (storage, batch_id) = storage_with_batch(3).await;
+
+        let batch = storage.get_batch(batch_id).await.unwrap().unwrap();
+        assert_eq!(batch.name, "test");
+        assert_eq!(batch.site, "wikidata");
+        assert_eq!(batch.status, "INIT");
+        let ts_created: String = storage
+            .call(move |conn| {
+                conn.query_row(
+                    "SELECT ts_created FROM batch WHERE id=?1",
+                    params![batch_id],
+                    |row| row.get(0),
+                )
+            })
+            .await
+            .unwrap();
+        assert_eq!(ts_created, batch.ts_last_change);
+        assert_eq!(
+            storage.get_command_counts(batch_id).await.unwrap(),
+            vec![("INIT".to_string(), 3)]
+        );
+        let open = storage.get_open_batches(10, &timestamp()).await.unwrap();
+        assert_eq!(
+            open.iter().map(|b| (b.id, b.user)).collect::<Vec<_>>(),
+            vec![(batch_id, 1)]
+        );
+    }
+
+    #[tokio::test]
+    async fn create_batch_stores_oauth_with_the_batch() {
+        let storage = SqliteStorage::open_in_memory().unwrap();
+        let oauth = r#"{"g_consumer_key":"x"}"#;
+        let batch_id = storage
+            .create_batch("test", 1, "wikidata", &[], Some(oauth))
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.get_oauth_for_batch(batch_id).await.unwrap(),
+            Some(oauth.to_string())
+        );
+
+        // Without a place for the OAuth parameters, no batch is created
+        storage
+            .call(|conn| conn.execute("DROP TABLE batch_oauth", []))
+            .await
+            .unwrap();
+        assert!(storage
+            .create_batch("test", 1, "wikidata", &[], Some(oauth))
+            .await
+            .is_err());
+        let open = storage.get_open_batches(10, &timestamp()).await.unwrap();
+        assert_eq!(
+            open.iter().map(|b| b.id).collect::<Vec<_>>(),
+            vec![batch_id]
+        );
+    }
+
+    #[tokio::test]
+    async fn create_batch_numbers_commands_across_inserts() {
+        let count = 2 * COMMAND_INSERT_ROWS + 1;
+        let (storage, batch_id) = storage_with_batch(count).await;
+
+        let commands = storage
+            .get_commands(batch_id, &[], None, 0, 0)
+            .await
+            .unwrap();
+        assert_eq!(commands.len(), count);
+        for (num, command) in commands.iter().enumerate() {
+            assert_eq!(command.num, num as i64);
+            assert!(command.json.contains(&format!(r#""num":{}}}"#, num)));
+        }
+    }
+
+    #[tokio::test]
+    async fn next_command_skips_finished_commands() {
+        let (storage, batch_id) = storage_with_batch(2).await;
+        let first = storage.get_next_command(batch_id).await.unwrap().unwrap();
+        assert_eq!(first.num, 0);
+
+        storage
+            .set_command_status(first.id, "DONE", "", "", "{}")
+            .await
+            .unwrap();
+
+        let second = storage.get_next_command(batch_id).await.unwrap().unwrap();
+        assert_eq!(second.num, 1);
+    }
+
+    #[tokio::test]
+    async fn get_commands_filters_and_pages() {
+        let (storage, batch_id) = storage_with_batch(5).await;
+        let first = storage.get_next_command(batch_id).await.unwrap().unwrap();
+        storage
+            .set_command_status(first.id, "ERROR", "oops", "", "{}")
+            .await
+            .unwrap();
+
+        let errors = storage
+            .get_commands(batch_id, &["ERROR"], None, 0, 0)
+            .await
+            .unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "oops");
+
+        let page = storage
+            .get_commands(batch_id, &[], None, 1, 2)
+            .await
+            .unwrap();
+        assert_eq!(page.iter().map(|c| c.num).collect::<Vec<_>>(), vec![1, 2]);
+
+        assert_eq!(
+            storage.reset_error_commands(batch_id, None).await.unwrap(),
+            1
+        );
+        assert!(storage
+            .get_commands(batch_id, &["ERROR"], None, 0, 0)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn reset_error_commands_by_code() {
+        let (storage, batch_id) = storage_with_batch(3).await;
+        let commands = storage
+            .get_commands(batch_id, &[], None, 0, 0)
+            .await
+            .unwrap();
+        for (command, error_code) in
+            commands
+                .iter()
+                .zip(["permission-denied", "permission-denied", "constraint"])
+        {
+            storage
+                .set_command_status(command.id, "ERROR", "oops", error_code, "{}")
+                .await
+                .unwrap();
+        }
+
+        let constraint = storage
+            .get_commands(batch_id, &["ERROR"], Some("constraint"), 0, 0)
+            .await
+            .unwrap();
+        assert_eq!(
+            constraint.iter().map(|c| c.num).collect::<Vec<_>>(),
+            vec![2]
+        );
+        assert_eq!(constraint[0].error_code, "constraint");
+
+        assert_eq!(
+            storage
+                .reset_error_commands(batch_id, Some("permission-denied"))
+                .await
+                .unwrap(),
+            2
+        );
+        let commands = storage
+            .get_commands(batch_id, &[], None, 0, 0)
+            .await
+            .unwrap();
+        let codes: Vec<&str> = commands.iter().map(|c| c.error_code.as_str()).collect();
+        assert_eq!(codes, vec!["", "", "constraint"]);
+    }
+
+    #[tokio::test]
+    async fn restart_batch_from_skips_earlier_commands() {
+        let (storage, batch_id) = storage_with_batch(4).await;
+        let commands = storage
+            .get_commands(batch_id, &[], None, 0, 0)
+            .await
+            .unwrap();
+        storage
+            .set_command_status(commands[2].id, "DONE", "", "", "{}")
+            .await
+            .unwrap();
+        storage
+            .set_command_status(commands[3].id, "ERROR", "oops", "", "{}")
+            .await
+            .unwrap();
+        storage
+            .set_batch_status(batch_id, "PAUSE", None)
+            .await
+            .unwrap();
+
+        assert_eq!(storage.restart_batch_from(batch_id, 1).await.unwrap(), 2);
+
+        let batch = storage.get_batch(batch_id).await.unwrap().unwrap();
+        assert_eq!(batch.status, "INIT");
+        let statuses: Vec<String> = storage
+            .get_commands(batch_id, &[], None, 0, 0)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|c| c.status)
+            .collect();
+        assert_eq!(statuses, vec!["STOP", "INIT", "DONE", "INIT"]);
+    }
+
+    #[tokio::test]
+    async fn restart_batch_from_leaves_running_batch_alone() {
+        let (storage, batch_id) = storage_with_batch(2).await;
+        storage
+            .set_batch_status(batch_id, "RUN", None)
+            .await
+            .unwrap();
+        let running = storage.get_next_command(batch_id).await.unwrap().unwrap();
+        storage
+            .set_command_status(running.id, "RUN", "", "", "{}")
+            .await
+            .unwrap();
+
+        let result = storage.restart_batch_from(batch_id, 0).await;
+        assert!(matches!(result, Err(QsError::BatchNotIdle(id)) if id == batch_id));
+
+        let batch = storage.get_batch(batch_id).await.unwrap().unwrap();
+        assert_eq!(batch.status, "RUN");
+        let statuses: Vec<String> = storage
+            .get_commands(batch_id, &[], None, 0, 0)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|c| c.status)
+            .collect();
+        assert_eq!(statuses, vec!["RUN", "INIT"]);
+    }
+
+    #[tokio::test]
+    async fn restart_batch_requeues_running_commands() {
+        let (storage, batch_id) = storage_with_batch(1).await;
+        let command = storage.get_next_command(batch_id).await.unwrap().unwrap();
+        storage
+            .set_command_status(command.id, "RUN", "", "", "{}")
+            .await
+            .unwrap();
+
+        storage.restart_batch(batch_id).await.unwrap();
+
+        let batch = storage.get_batch(batch_id).await.unwrap().unwrap();
+        assert_eq!(batch.status, "RUN");
+        let command = storage.get_command(command.id).await.unwrap().unwrap();
+        assert_eq!(command.status, "INIT");
+    }
+
+    #[tokio::test]
+    async fn open_batches_carry_priority_and_size() {
+        let (storage, batch_id) = storage_with_batch(3).await;
+        storage.set_batch_priority(batch_id, 5).await.unwrap();
+
+        let now = timestamp();
+        let open = storage.get_open_batches(3, &now).await.unwrap();
+        assert_eq!(open[0].priority, 5);
+        assert_eq!(open[0].site, "wikidata");
+        assert!(open[0].small);
+        assert!(!storage.get_open_batches(2, &now).await.unwrap()[0].small);
+
+        // Only commands still to be run count
+        let first = storage.get_next_command(batch_id).await.unwrap().unwrap();
+        storage
+            .set_command_status(first.id, "DONE", "", "", "{}")
+            .await
+            .unwrap();
+        assert!(storage.get_open_batches(2, &now).await.unwrap()[0].small);
+    }
+
+    #[tokio::test]
+    async fn scheduled_batch_waits_and_reruns() {
+        let (storage, batch_id) = storage_with_batch(2).await;
+        storage
+            .set_batch_schedule(batch_id, "20240101120000", "weekly")
+            .await
+            .unwrap();
+
+        assert!(storage
+            .get_open_batches(10, "20240101115959")
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            storage
+                .get_open_batches(10, "20240101120000")
+                .await
+                .unwrap()[0]
+                .id,
+            batch_id
+        );
+        let scheduled = storage.get_scheduled_batches(None, "20240101115959").await;
+        assert_eq!(scheduled.unwrap()[0].recurrence, "weekly");
+
+        let first = storage.get_next_command(batch_id).await.unwrap().unwrap();
+        storage
+            .set_command_status(first.id, "DONE", "", "", "{}")
+            .await
+            .unwrap();
+        storage
+            .set_batch_status(batch_id, "DONE", None)
+            .await
+            .unwrap();
+        storage
+            .reschedule_batch(batch_id, "20240108120000")
+            .await
+            .unwrap();
+
+        let batch = storage.get_batch(batch_id).await.unwrap().unwrap();
+        assert_eq!(batch.status, "INIT");
+        assert_eq!(batch.ts_not_before, "20240108120000");
+        assert_eq!(
+            storage.get_command_counts(batch_id).await.unwrap(),
+            vec![("INIT".to_string(), 2)]
+        );
+    }
+
+    #[tokio::test]
+    async fn lease_is_exclusive_until_it_expires() {
+        let (storage, batch_id) = storage_with_batch(1).await;
+        let (now, later) = ("20240101000000", "20240101000500");
+
+        assert!(storage
+            .acquire_lease(batch_id, "a", now, later)
+            .await
+            .unwrap());
+        // Renewal by the owner, but not by anyone else
+        assert!(storage
+            .acquire_lease(batch_id, "a", now, later)
+            .await
+            .unwrap());
+        assert!(!storage
+            .acquire_lease(batch_id, "b", now, later)
+            .await
+            .unwrap());
+        assert_eq!(
+            storage.get_live_leases(now).await.unwrap(),
+            vec![(batch_id, 1, "a".to_string())]
+        );
+
+        // A crashed owner stops renewing: the lease can be taken over once expired
+        let after_expiry = "20240101000501";
+        assert!(storage
+            .get_live_leases(after_expiry)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(storage
+            .acquire_lease(batch_id, "b", after_expiry, "20240101001000")
+            .await
+            .unwrap());
+
+        // Only the current owner can release it
+        storage.release_lease(batch_id, "a").await.unwrap();
+        assert_eq!(
+            storage.get_live_leases(after_expiry).await.unwrap().len(),
+            1
+        );
+        storage.release_lease(batch_id, "b").await.unwrap();
+        assert!(storage
+            .get_live_leases(after_expiry)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn reset_stale_batches_keeps_leased_batches() {
+        let (storage, leased) = storage_with_batch(1).await;
+        let unleased = storage
+            .create_batch("other", 1, "wikidata", &[], None)
+            .await
+            .unwrap();
+        for batch_id in [leased, unleased] {
+            storage
+                .set_batch_status(batch_id, "RUN", None)
+                .await
+                .unwrap();
+        }
+        let now = "20240101000000";
+        storage
+            .acquire_lease(leased, "a", now, "20240101000500")
+            .await
+            .unwrap();
+
+        storage.reset_stale_batches(now).await.unwrap();
+
+        assert_eq!(
+            storage.get_batch(leased).await.unwrap().unwrap().status,
+            "RUN"
+        );
+        assert_eq!(
+            storage.get_batch(unleased).await.unwrap().unwrap().status,
+            "INIT"
+        );
+    }
+
+    #[tokio::test]
+    async fn migrations_are_applied_once() {
+        let storage = SqliteStorage::open_in_memory().unwrap();
+
+        // Everything was applied on open
+        assert!(storage.migrate().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn oauth_is_stored_per_batch() {
+        let (storage, batch_id) = storage_with_batch(0).await;
+        assert_eq!(storage.get_oauth_for_batch(batch_id).await.unwrap(), None);
+
+        storage
+            .set_oauth_for_batch(batch_id, r#"{"g_consumer_key":"x"}"#)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            storage.get_oauth_for_batch(batch_id).await.unwrap(),
+            Some(r#"{"g_consumer_key":"x"}"#.to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn users_are_created_once() {
+        let storage = SqliteStorage::open_in_memory().unwrap();
+        let id = storage.get_or_create_user("Magnus Manske").await.unwrap();
+        assert!(id > 0);
+        assert_eq!(
+            storage.get_or_create_user("Magnus Manske").await.unwrap(),
+            id
+        );
+        assert_eq!(
+            storage.get_user_name(id).await.unwrap(),
+            Some("Magnus Manske".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn api_tokens_are_found_by_hash_until_revoked() {
+        let storage = SqliteStorage::open_in_memory().unwrap();
+        let token_id = storage
+            .create_api_token(3, "nightly", "read,submit", "hash", "{}")
+            .await
+            .unwrap();
+
+        let token = storage.get_api_token("hash").await.unwrap().unwrap();
+        assert_eq!((token.id, token.user_id), (token_id, 3));
+        assert_eq!(token.scopes().collect::<Vec<_>>(), vec!["read", "submit"]);
+        assert_eq!(storage.get_api_tokens(3).await.unwrap(), vec![token]);
+
+        // Only the owner can revoke a token
+        assert!(!storage.revoke_api_token(token_id, 4).await.unwrap());
+        assert!(storage.revoke_api_token(token_id, 3).await.unwrap());
+        assert_eq!(storage.get_api_token("hash").await.unwrap(), None);
+        assert!(storage.get_api_tokens(3).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn sessions_expire() {
+        let storage = SqliteStorage::open_in_memory().unwrap();
+        storage
+            .set_session("abc", 0, "{}", "20300101000000")
+            .await
+            .unwrap();
+        storage
+            .set_session("abc", 3, r#"{"user_name":"X"}"#, "20300101000000")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            storage.get_session("abc", "20250101000000").await.unwrap(),
+            Some(r#"{"user_name":"X"}"#.to_string())
+        );
+        assert_eq!(
+            storage.get_session("abc", "20300101000000").await.unwrap(),
+            None
+        );
+
+        storage.delete_session("abc").await.unwrap();
+        assert_eq!(
+            storage.get_session("abc", "20250101000000").await.unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn temporary_batches_expire() {
+        let storage = SqliteStorage::open_in_memory().unwrap();
+        storage
+            .create_temporary_batch("abc", 3, r#"{"commands":[]}"#, "20300101000000")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            storage
+                .get_temporary_batch("abc", "20250101000000")
+                .await
+                .unwrap(),
+            Some(r#"{"commands":[]}"#.to_string())
+        );
+        assert_eq!(
+            storage
+                .get_temporary_batch("abc", "20300101000000")
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            storage
+                .get_temporary_batch("def", "20250101000000")
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn changes_are_paged_by_timestamp_and_id() {
+        let storage = SqliteStorage::open_in_memory().unwrap();
+        let user_id = storage.get_or_create_user("Alice").await.unwrap();
+        let commands = vec!["{}".to_string(); 3];
+        let batch_id = storage
+            .create_batch("test", user_id, "wikidata", &commands, None)
+            .await
+            .unwrap();
+        storage
+            .create_batch("other", user_id + 1, "wikidata", &commands, None)
+            .await
+            .unwrap();
+        let scope = ChangeScope::Batch(batch_id);
+
+        let page = storage
+            .get_changed_commands(&scope, "", 0, 2)
+            .await
+            .unwrap();
+        assert_eq!(page.iter().map(|c| c.num).collect::<Vec<_>>(), vec![0, 1]);
+        let rest = storage
+            .get_changed_commands(&scope, &page[1].ts_change, page[1].id, 10)
+            .await
+            .unwrap();
+        assert_eq!(rest.iter().map(|c| c.num).collect::<Vec<_>>(), vec![2]);
+
+        let user = ChangeScope::User("Alice".to_string());
+        let all = storage
+            .get_changed_commands(&user, "", 0, 10)
+            .await
+            .unwrap();
+        assert!(all.iter().all(|c| c.batch_id == batch_id));
+        assert_eq!(all.len(), 3);
+        assert!(storage
+            .get_changed_commands(&user, "99990101000000", 0, 10)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let batches = storage.get_changed_batches(&user, "").await.unwrap();
+        assert_eq!(
+            batches.iter().map(|b| b.id).collect::<Vec<_>>(),
+            vec![batch_id]
+        );
+        assert!(storage
+            .get_changed_batches(&scope, "99990101000000")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,334 @@
+use crate::error::QsResult;
+use crate::qs_metrics::Metrics;
+use crate::qs_storage::{ApiTokenRow, BatchRow, ChangeScope, CommandRow, OpenBatch, QsStorage};
+use async_trait::async_trait;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Wraps another storage and records the latency of every call in the
+/// `qs_db_query_duration_seconds` histogram, labelled with the method name
+#[derive(Debug)]
+pub struct TimedStorage {
+    inner: Arc<dyn QsStorage>,
+    metrics: Arc<Metrics>,
+}
+
+impl TimedStorage {
+    pub fn new(inner: Arc<dyn QsStorage>, metrics: Arc<Metrics>) -> Self {
+        Self { inner, metrics }
+    }
+
+    async fn timed<T>(&self, operation: &str, f: impl Future<Output = T>) -> T {
+        let start = Instant::now();
+        let ret = f.await;
+        self.metrics.observe_db(operation, start.elapsed());
+        ret
+    }
+}
+
+#[async_trait]
+impl QsStorage for TimedStorage {
+    async fn ping(&self) -> bool {
+        let f = self.inner.ping();
+        self.timed("ping", f).await
+    }
+
+    async fn migrate(&self) -> QsResult<Vec<u32>> {
+        let f = self.inner.migrate();
+        self.timed("migrate", f).await
+    }
+
+    async fn get_batch(&self, batch_id: i64) -> QsResult<Option<BatchRow>> {
+        let f = self.inner.get_batch(batch_id);
+        self.timed("get_batch", f).await
+    }
+
+    async fn get_batches(
+        &self,
+        user_name: Option<&str>,
+        limit: i64,
+        offset: i64,
+    ) -> QsResult<Vec<BatchRow>> {
+        let f = self.inner.get_batches(user_name, limit, offset);
+        self.timed("get_batches", f).await
+    }
+
+    async fn get_open_batches(
+        &self,
+        small_batch_commands: i64,
+        now: &str,
+    ) -> QsResult<Vec<OpenBatch>> {
+        let f = self.inner.get_open_batches(small_batch_commands, now);
+        self.timed("get_open_batches", f).await
+    }
+
+    async fn create_batch(
+        &self,
+        name: &str,
+        user_id: i64,
+        site: &str,
+        commands: &[String],
+        oauth_json: Option<&str>,
+    ) -> QsResult<i64> {
+        let f = self
+            .inner
+            .create_batch(name, user_id, site, commands, oauth_json);
+        self.timed("create_batch", f).await
+    }
+
+    async fn set_batch_status(
+        &self,
+        batch_id: i64,
+        status: &str,
+        message: Option<&str>,
+    ) -> QsResult<()> {
+        let f = self.inner.set_batch_status(batch_id, status, message);
+        self.timed("set_batch_status", f).await
+    }
+
+    async fn restart_batch(&self, batch_id: i64) -> QsResult<()> {
+        let f = self.inner.restart_batch(batch_id);
+        self.timed("restart_batch", f).await
+    }
+
+    async fn restart_batch_from(&self, batch_id: i64, num: i64) -> QsResult<u64> {
+        let f = self.inner.restart_batch_from(batch_id, num);
+        self.timed("restart_batch_from", f).await
+    }
+
+    async fn reset_stale_batches(&self, now: &str) -> QsResult<()> {
+        let f = self.inner.reset_stale_batches(now);
+        self.timed("reset_stale_batches", f).await
+    }
+
+    async fn reinitialize_open_batches(&self, min_batch_id: i64) -> QsResult<()> {
+        let f = self.inner.reinitialize_open_batches(min_batch_id);
+        self.timed("reinitialize_open_batches", f).await
+    }
+
+    async fn set_last_item(&self, batch_id: i64, last_item: &str) -> QsResult<()> {
+        let f = self.inner.set_last_item(batch_id, last_item);
+        self.timed("set_last_item", f).await
+    }
+
+    async fn set_batch_priority(&self, batch_id: i64, priority: i64) -> QsResult<()> {
+        let f = self.inner.set_batch_priority(batch_id, priority);
+        self.timed("set_batch_priority", f).await
+    }
+
+    async fn set_batch_schedule(
+        &self,
+        batch_id: i64,
+        not_before: &str,
+        recurrence: &str,
+    ) -> QsResult<()> {
+        let f = self
+            .inner
+            .set_batch_schedule(batch_id, not_before, recurrence);
+        self.timed("set_batch_schedule", f).await
+    }
+
+    async fn get_scheduled_batches(
+        &self,
+        user_name: Option<&str>,
+        now: &str,
+    ) -> QsResult<Vec<BatchRow>> {
+        let f = self.inner.get_scheduled_batches(user_name, now);
+        self.timed("get_scheduled_batches", f).await
+    }
+
+    async fn reschedule_batch(&self, batch_id: i64, not_before: &str) -> QsResult<()> {
+        let f = self.inner.reschedule_batch(batch_id, not_before);
+        self.timed("reschedule_batch", f).await
+    }
+
+    async fn acquire_lease(
+        &self,
+        batch_id: i64,
+        owner: &str,
+        now: &str,
+        expires: &str,
+    ) -> QsResult<bool> {
+        let f = self.inner.acquire_lease(batch_id, owner, now, expires);
+        self.timed("acquire_lease", f).await
+    }
+
+    async fn release_lease(&self, batch_id: i64, owner: &str) -> QsResult<()> {
+        let f = self.inner.release_lease(batch_id, owner);
+        self.timed("release_lease", f).await
+    }
+
+    async fn get_live_leases(&self, now: &str) -> QsResult<Vec<(i64, i64, String)>> {
+        let f = self.inner.get_live_leases(now);
+        self.timed("get_live_leases", f).await
+    }
+
+    async fn get_command(&self, command_id: i64) -> QsResult<Option<CommandRow>> {
+        let f = self.inner.get_command(command_id);
+        self.timed("get_command", f).await
+    }
+
+    async fn get_next_command(&self, batch_id: i64) -> QsResult<Option<CommandRow>> {
+        let f = self.inner.get_next_command(batch_id);
+        self.timed("get_next_command", f).await
+    }
+
+    async fn get_commands(
+        &self,
+        batch_id: i64,
+        statuses: &[&str],
+        error_code: Option<&str>,
+        start: i64,
+        limit: i64,
+    ) -> QsResult<Vec<CommandRow>> {
+        let f = self
+            .inner
+            .get_commands(batch_id, statuses, error_code, start, limit);
+        self.timed("get_commands", f).await
+    }
+
+    async fn get_command_counts(&self, batch_id: i64) -> QsResult<Vec<(String, i64)>> {
+        let f = self.inner.get_command_counts(batch_id);
+        self.timed("get_command_counts", f).await
+    }
+
+    async fn set_command_status(
+        &self,
+        command_id: i64,
+        status: &str,
+        message: &str,
+        error_code: &str,
+        json: &str,
+    ) -> QsResult<()> {
+        let f = self
+            .inner
+            .set_command_status(command_id, status, message, error_code, json);
+        self.timed("set_command_status", f).await
+    }
+
+    async fn reset_error_commands(&self, batch_id: i64, error_code: Option<&str>) -> QsResult<u64> {
+        let f = self.inner.reset_error_commands(batch_id, error_code);
+        self.timed("reset_error_commands", f).await
+    }
+
+    async fn get_changed_batches(
+        &self,
+        scope: &ChangeScope,
+        since: &str,
+    ) -> QsResult<Vec<BatchRow>> {
+        let f = self.inner.get_changed_batches(scope, since);
+        self.timed("get_changed_batches", f).await
+    }
+
+    async fn get_changed_commands(
+        &self,
+        scope: &ChangeScope,
+        since: &str,
+        after_id: i64,
+        limit: i64,
+    ) -> QsResult<Vec<CommandRow>> {
+        let f = self
+            .inner
+            .get_changed_commands(scope, since, after_id, limit);
+        self.timed("get_changed_commands", f).await
+    }
+
+    async fn get_user_name(&self, user_id: i64) -> QsResult<Option<String>> {
+        let f = self.inner.get_user_name(user_id);
+        self.timed("get_user_name", f).await
+    }
+
+    async fn get_oauth_for_batch(&self, batch_id: i64) -> QsResult<Option<String>> {
+        let f = self.inner.get_oauth_for_batch(batch_id);
+        self.timed("get_oauth_for_batch", f).await
+    }
+
+    async fn set_oauth_for_batch(&self, batch_id: i64, serialized_json: &str) -> QsResult<()> {
+        let f = self.inner.set_oauth_for_batch(batch_id, serialized_json);
+        self.timed("set_oauth_for_batch", f).await
+    }
+
+    async fn replace_oauth_credentials(&self, old_json: &str, new_json: &str) -> QsResult<()> {
+        let f = self.inner.replace_oauth_credentials(old_json, new_json);
+        self.timed("replace_oauth_credentials", f).await
+    }
+
+    async fn get_or_create_user(&self, name: &str) -> QsResult<i64> {
+        let f = self.inner.get_or_create_user(name);
+        self.timed("get_or_create_user", f).await
+    }
+
+    async fn get_session(&self, session_id: &str, now: &str) -> QsResult<Option<String>> {
+        let f = self.inner.get_session(session_id, now);
+        self.timed("get_session", f).await
+    }
+
+    async fn set_session(
+        &self,
+        session_id: &str,
+        user_id: i64,
+        data: &str,
+        expires: &str,
+    ) -> QsResult<()> {
+        let f = self.inner.set_session(session_id, user_id, data, expires);
+        self.timed("set_session", f).await
+    }
+
+    async fn delete_session(&self, session_id: &str) -> QsResult<()> {
+        let f = self.inner.delete_session(session_id);
+        self.timed("delete_session", f).await
+    }
+
+    async fn create_api_token(
+        &self,
+        user_id: i64,
+        name: &str,
+        scopes: &str,
+        token_hash: &str,
+        serialized_json: &str,
+    ) -> QsResult<i64> {
+        let f = self
+            .inner
+            .create_api_token(user_id, name, scopes, token_hash, serialized_json);
+        self.timed("create_api_token", f).await
+    }
+
+    async fn get_api_token(&self, token_hash: &str) -> QsResult<Option<ApiTokenRow>> {
+        let f = self.inner.get_api_token(token_hash);
+        self.timed("get_api_token", f).await
+    }
+
+    async fn get_api_tokens(&self, user_id: i64) -> QsResult<Vec<ApiTokenRow>> {
+        let f = self.inner.get_api_tokens(user_id);
+        self.timed("get_api_tokens", f).await
+    }
+
+    async fn revoke_api_token(&self, token_id: i64, user_id: i64) -> QsResult<bool> {
+        let f = self.inner.revoke_api_token(token_id, user_id);
+        self.timed("revoke_api_token", f).await
+    }
+
+    async fn set_api_token_last_used(&self, token_id: i64, now: &str) -> QsResult<()> {
+        let f = self.inner.set_api_token_last_used(token_id, now);
+        self.timed("set_api_token_last_used", f).await
+    }
+
+    async fn create_temporary_batch(
+        &self,
+        id: &str,
+        user_id: i64,
+        data: &str,
+        expires: &str,
+    ) -> QsResult<()> {
+        let f = self
+            .inner
+            .create_temporary_batch(id, user_id, data, expires);
+        self.timed("create_temporary_batch", f).await
+    }
+
+    async fn get_temporary_batch(&self, id: &str, now: &str) -> QsResult<Option<String>> {
+        let f = self.inner.get_temporary_batch(id, now);
+        self.timed("get_temporary_batch", f).await
+    }
+}